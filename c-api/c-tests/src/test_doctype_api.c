@@ -109,6 +109,66 @@ static void test_get_user_data(void *user_data) {
     );
 }
 
+//-------------------------------------------------------------------------
+EXPECT_OUTPUT(
+    set_doctype_fields_output_sink,
+    "<!DOCTYPE svg PUBLIC \"-//W3C//DTD SVG 1.1//EN\">",
+    &EXPECTED_USER_DATA,
+    sizeof(EXPECTED_USER_DATA)
+)
+
+static lol_html_rewriter_directive_t set_doctype_fields(
+    lol_html_doctype_t *doctype,
+    void *user_data
+) {
+    UNUSED(user_data);
+
+    const char *name = "svg";
+    const char *public_id = "-//W3C//DTD SVG 1.1//EN";
+    const char *invalid_name = "foo bar";
+
+    note("Set name");
+    ok(!lol_html_doctype_name_set(doctype, name, strlen(name)));
+    ok(lol_html_doctype_name_set(doctype, invalid_name, strlen(invalid_name)) == -1);
+
+    lol_html_str_t msg = lol_html_take_last_error();
+
+    str_eq(msg, "Doctype name can't be empty or contain whitespace or `>`.");
+
+    lol_html_str_free(msg);
+
+    note("Set public identifier");
+    ok(!lol_html_doctype_public_id_set(doctype, public_id, strlen(public_id)));
+
+    note("Remove system identifier");
+    ok(!lol_html_doctype_system_id_set(doctype, NULL, 0));
+
+    return LOL_HTML_CONTINUE;
+}
+
+static void test_set_doctype_fields(void *user_data) {
+    lol_html_rewriter_builder_t *builder = lol_html_rewriter_builder_new();
+
+    lol_html_rewriter_builder_add_document_content_handlers(
+        builder,
+        &set_doctype_fields,
+        NULL,
+        NULL,
+        NULL,
+        NULL,
+        NULL,
+        NULL,
+        NULL
+    );
+
+    run_rewriter(
+        builder,
+        "<!DOCTYPE math SYSTEM \"http://www.w3.org/Math/DTD/mathml1/mathml.dtd\">",
+        set_doctype_fields_output_sink,
+        user_data
+    );
+}
+
 //-------------------------------------------------------------------------
 EXPECT_OUTPUT(
     remove_doctype_output_sink,
@@ -187,6 +247,7 @@ void test_doctype_api() {
 
     test_get_doctype_fields(&user_data);
     test_get_user_data(&user_data);
+    test_set_doctype_fields(&user_data);
     test_remove_doctype(&user_data);
     test_stop(&user_data);
 }
@@ -6,7 +6,7 @@
 #include "test_util.h"
 
 void test_unsupported_selector() {
-    const char *selector_str = "p:last-child";
+    const char *selector_str = "p:hover";
     lol_html_selector_t *selector = lol_html_selector_parse(selector_str, strlen(selector_str));
 
     ok(selector == NULL);
@@ -16,4 +16,15 @@ void test_unsupported_selector() {
     str_eq(msg, "Unsupported pseudo-class or pseudo-element in selector.");
 
     lol_html_str_free(msg);
+
+    selector_str = "p:has(b)";
+    selector = lol_html_selector_parse(selector_str, strlen(selector_str));
+
+    ok(selector == NULL);
+
+    msg = lol_html_take_last_error();
+
+    str_eq(msg, "Unsupported pseudo-class or pseudo-element in selector.");
+
+    lol_html_str_free(msg);
 }
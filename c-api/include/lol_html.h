@@ -149,6 +149,9 @@ typedef struct lol_html_CStreamingHandler {
 // Returns NULL if parsing error occurs. The actual error message
 // can be obtained using `lol_html_take_last_error` function.
 //
+// Selectors with `:has()` are not supported, as they are only matched
+// for end tag handlers.
+//
 // WARNING: Selector SHOULD NOT be deallocated if there are any active rewriter
 // builders that accepted it as an argument to `lol_html_rewriter_builder_add_element_content_handlers()`
 // method. Deallocate all dependant rewriter builders first and then
@@ -212,6 +215,9 @@ void lol_html_rewriter_builder_add_document_content_handlers(
 // stops immediately and `write()` or `end()` of the rewriter methods
 // return an error code.
 //
+// Comment and text handlers are not supported for the selectors that are
+// matched lazily (e.g. `li:last-child`).
+//
 // Returns 0 in case of success and -1 otherwise. The actual error message
 // can be obtained using `lol_html_take_last_error` function.
 //
@@ -319,16 +325,52 @@ void lol_html_rewriter_free(lol_html_rewriter_t *rewriter);
 // The `data` field will be NULL if the doctype doesn't have a name.
 lol_html_str_t lol_html_doctype_name_get(const lol_html_doctype_t *doctype);
 
+// Sets doctype's name.
+//
+// Name should be a valid UTF8-string.
+//
+// Returns 0 in case of success and -1 otherwise. The actual error message
+// can be obtained using `lol_html_take_last_error` function.
+int lol_html_doctype_name_set(
+    lol_html_doctype_t *doctype,
+    const char *name,
+    size_t name_len
+);
+
 // Returns doctype's PUBLIC identifier.
 //
 // The `data` field will be NULL if the doctype doesn't have a PUBLIC identifier.
 lol_html_str_t lol_html_doctype_public_id_get(const lol_html_doctype_t *doctype);
 
+// Sets doctype's PUBLIC identifier.
+//
+// Identifier should be a valid UTF8-string. The identifier is removed if `public_id` is NULL.
+//
+// Returns 0 in case of success and -1 otherwise. The actual error message
+// can be obtained using `lol_html_take_last_error` function.
+int lol_html_doctype_public_id_set(
+    lol_html_doctype_t *doctype,
+    const char *public_id,
+    size_t public_id_len
+);
+
 // Returns doctype's SYSTEM identifier.
 //
 // The `data` field will be NULL if the doctype doesn't have a SYSTEM identifier.
 lol_html_str_t lol_html_doctype_system_id_get(const lol_html_doctype_t *doctype);
 
+// Sets doctype's SYSTEM identifier.
+//
+// Identifier should be a valid UTF8-string. The identifier is removed if `system_id` is NULL.
+//
+// Returns 0 in case of success and -1 otherwise. The actual error message
+// can be obtained using `lol_html_take_last_error` function.
+int lol_html_doctype_system_id_set(
+    lol_html_doctype_t *doctype,
+    const char *system_id,
+    size_t system_id_len
+);
+
 // Attaches custom user data to the doctype.
 //
 // The same doctype can be passed to multiple handlers if it has been
@@ -342,6 +384,46 @@ void lol_html_doctype_user_data_set(
 // Returns user data attached to the doctype.
 void *lol_html_doctype_user_data_get(const lol_html_doctype_t *doctype);
 
+// Inserts the content string before the doctype either as raw text or as HTML.
+//
+// Content should be a valid UTF8-string.
+//
+// Returns 0 in case of success and -1 otherwise. The actual error message
+// can be obtained using `lol_html_take_last_error` function.
+int lol_html_doctype_before(
+    lol_html_doctype_t *doctype,
+    const char *content,
+    size_t content_len,
+    bool is_html
+);
+
+// Inserts the content string after the doctype either as raw text or as HTML.
+//
+// Content should be a valid UTF8-string.
+//
+// Returns 0 in case of success and -1 otherwise. The actual error message
+// can be obtained using `lol_html_take_last_error` function.
+int lol_html_doctype_after(
+    lol_html_doctype_t *doctype,
+    const char *content,
+    size_t content_len,
+    bool is_html
+);
+
+// Replace the doctype with the content of the string which is interpreted
+// either as raw text or as HTML.
+//
+// Content should be a valid UTF8-string.
+//
+// Returns 0 in case of success and -1 otherwise. The actual error message
+// can be obtained using `lol_html_take_last_error` function.
+int lol_html_doctype_replace(
+    lol_html_doctype_t *doctype,
+    const char *content,
+    size_t content_len,
+    bool is_html
+);
+
 // Removes the doctype.
 void lol_html_doctype_remove(lol_html_doctype_t *doctype);
 
@@ -1020,6 +1102,54 @@ int lol_html_text_chunk_streaming_after(lol_html_text_chunk_t *text_chunk,
 int lol_html_text_chunk_streaming_replace(lol_html_text_chunk_t *text_chunk,
                                           lol_html_streaming_handler_t *streaming_writer);
 
+//[`Doctype::streaming_before`]
+//
+// The [`CStreamingHandler`] contains callbacks that will be called
+// when the content needs to be written.
+//
+// `streaming_writer` is copied immediately, and doesn't have a stable address.
+// `streaming_writer` may be used from another thread (`Send`), but it's only going
+// to be used by one thread at a time (`!Sync`).
+//
+//`doctype`
+// must be valid and non-`NULL`. If `streaming_writer` is `NULL`, an error will be reported.
+//
+// Returns 0 on success.
+int lol_html_doctype_streaming_before(lol_html_doctype_t *doctype,
+                                      lol_html_streaming_handler_t *streaming_writer);
+
+//[`Doctype::streaming_after`]
+//
+// The [`CStreamingHandler`] contains callbacks that will be called
+// when the content needs to be written.
+//
+// `streaming_writer` is copied immediately, and doesn't have a stable address.
+// `streaming_writer` may be used from another thread (`Send`), but it's only going
+// to be used by one thread at a time (`!Sync`).
+//
+//`doctype`
+// must be valid and non-`NULL`. If `streaming_writer` is `NULL`, an error will be reported.
+//
+// Returns 0 on success.
+int lol_html_doctype_streaming_after(lol_html_doctype_t *doctype,
+                                     lol_html_streaming_handler_t *streaming_writer);
+
+//[`Doctype::streaming_replace`]
+//
+// The [`CStreamingHandler`] contains callbacks that will be called
+// when the content needs to be written.
+//
+// `streaming_writer` is copied immediately, and doesn't have a stable address.
+// `streaming_writer` may be used from another thread (`Send`), but it's only going
+// to be used by one thread at a time (`!Sync`).
+//
+//`doctype`
+// must be valid and non-`NULL`. If `streaming_writer` is `NULL`, an error will be reported.
+//
+// Returns 0 on success.
+int lol_html_doctype_streaming_replace(lol_html_doctype_t *doctype,
+                                       lol_html_streaming_handler_t *streaming_writer);
+
 // Write another piece of UTF-8 data to the output. Returns `0` on success, and `-1` if it wasn't valid UTF-8.
 // All pointers must be non-NULL.
 int lol_html_streaming_sink_write_str(lol_html_streaming_sink_t *sink,
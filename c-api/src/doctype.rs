@@ -5,16 +5,68 @@ pub unsafe extern "C" fn lol_html_doctype_name_get(doctype: *const Doctype) -> S
     Str::from_opt(to_ref!(doctype).name())
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn lol_html_doctype_name_set(
+    doctype: *mut Doctype,
+    name: *const c_char,
+    name_len: size_t,
+) -> c_int {
+    let doctype = to_ref_mut!(doctype);
+    let name = unwrap_or_ret_err_code! { to_str!(name, name_len) };
+
+    unwrap_or_ret_err_code! { doctype.set_name(name) };
+
+    0
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn lol_html_doctype_public_id_get(doctype: *const Doctype) -> Str {
     Str::from_opt(to_ref!(doctype).public_id())
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn lol_html_doctype_public_id_set(
+    doctype: *mut Doctype,
+    public_id: *const c_char,
+    public_id_len: size_t,
+) -> c_int {
+    let doctype = to_ref_mut!(doctype);
+
+    let public_id = if public_id.is_null() {
+        None
+    } else {
+        Some(unwrap_or_ret_err_code! { to_str!(public_id, public_id_len) })
+    };
+
+    unwrap_or_ret_err_code! { doctype.set_public_id(public_id) };
+
+    0
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn lol_html_doctype_system_id_get(doctype: *const Doctype) -> Str {
     Str::from_opt(to_ref!(doctype).system_id())
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn lol_html_doctype_system_id_set(
+    doctype: *mut Doctype,
+    system_id: *const c_char,
+    system_id_len: size_t,
+) -> c_int {
+    let doctype = to_ref_mut!(doctype);
+
+    let system_id = if system_id.is_null() {
+        None
+    } else {
+        Some(unwrap_or_ret_err_code! { to_str!(system_id, system_id_len) })
+    };
+
+    unwrap_or_ret_err_code! { doctype.set_system_id(system_id) };
+
+    0
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn lol_html_doctype_user_data_set(
     doctype: *mut Doctype,
@@ -29,6 +81,12 @@ pub unsafe extern "C" fn lol_html_doctype_user_data_get(doctype: *const Doctype)
 }
 
 impl_content_mutation_handlers! { doctype: Doctype [
+    lol_html_doctype_before => before,
+    lol_html_doctype_after => after,
+    lol_html_doctype_replace => replace,
     @VOID lol_html_doctype_remove => remove,
     @BOOL lol_html_doctype_is_removed => removed,
+    @STREAM lol_html_doctype_streaming_before => streaming_before,
+    @STREAM lol_html_doctype_streaming_after => streaming_after,
+    @STREAM lol_html_doctype_streaming_replace => streaming_replace,
 ] }
@@ -58,6 +58,8 @@ pub unsafe extern "C" fn lol_html_rewriter_build(
         strict,
         enable_esi_tags: false,
         adjust_charset_on_meta_tag: false,
+        handler_ordering: HandlerOrdering::Registration,
+        enable_selector_matching_stats: false,
     };
 
     let output_sink = ExternOutputSink::new(output_sink, output_sink_user_data);
@@ -90,6 +92,8 @@ pub unsafe extern "C" fn unstable_lol_html_rewriter_build_with_esi_tags(
         strict,
         enable_esi_tags: true,
         adjust_charset_on_meta_tag: false,
+        handler_ordering: HandlerOrdering::Registration,
+        enable_selector_matching_stats: false,
     };
 
     let output_sink = ExternOutputSink::new(output_sink, output_sink_user_data);
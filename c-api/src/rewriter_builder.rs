@@ -162,6 +162,17 @@ pub unsafe extern "C" fn lol_html_rewriter_builder_add_element_content_handlers(
     let selector = to_ref!(selector);
     let builder = to_ref_mut!(builder);
 
+    // NOTE: comment and text handlers are never invoked for the selectors that are matched lazily.
+    if (comments_handler.is_some() || text_handler.is_some())
+        && selector.analyze().is_matched_lazily
+    {
+        let err = lol_html::errors::SelectorError::UnsupportedContentHandlers;
+
+        crate::errors::LAST_ERROR.with(|e| *e.borrow_mut() = Some(err.into()));
+
+        return -1;
+    }
+
     let handlers = ExternElementContentHandlers {
         element: ExternHandler::new(element_handler, element_handler_user_data),
         comments: ExternHandler::new(comments_handler, comments_handler_user_data),
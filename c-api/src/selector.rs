@@ -6,7 +6,17 @@ pub unsafe extern "C" fn lol_html_selector_parse(
     selector_len: size_t,
 ) -> *mut Selector {
     let selector = unwrap_or_ret_null! { to_str!(selector, selector_len) };
-    let selector = unwrap_or_ret_null! { selector.parse::<Selector>() };
+    let selector = unwrap_or_ret_null! { selector.parse::<Selector>().map_err(|e| e.error()) };
+
+    // NOTE: selectors with `:has()` only support end tag handlers, which can't be set
+    // with the C API.
+    if selector.analyze().is_matched_on_end_tag {
+        let err = lol_html::errors::SelectorError::UnsupportedPseudoClassOrElement;
+
+        crate::errors::LAST_ERROR.with(|e| *e.borrow_mut() = Some(err.into()));
+
+        return ptr::null_mut();
+    }
 
     to_ptr_mut(selector)
 }
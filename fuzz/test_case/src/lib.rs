@@ -12,7 +12,7 @@ use std::ffi::{CStr, CString};
 use encoding_rs::*;
 use lol_html::html_content::ContentType;
 use lol_html::{comments, doc_comments, doc_text, element, streaming, text};
-use lol_html::{HtmlRewriter, MemorySettings, Settings};
+use lol_html::{HandlerOrdering, HtmlRewriter, MemorySettings, Settings};
 
 include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
 
@@ -176,6 +176,8 @@ fn run_rewriter_iter(data: &[u8], selector: &str, encoding: &'static Encoding) {
             memory_settings: MemorySettings::new(),
             strict: false,
             adjust_charset_on_meta_tag: false,
+            handler_ordering: HandlerOrdering::Registration,
+            enable_selector_matching_stats: false,
         },
         |_: &[u8]| {},
     );
@@ -72,7 +72,8 @@ impl HTMLRewriter {
                     output_sink,
                 } = std::mem::replace(&mut self.0, RewriterState::After)
                 {
-                    let rewriter = NativeHTMLRewriter::new(settings, output_sink);
+                    let rewriter =
+                        NativeHTMLRewriter::try_new(settings, output_sink).into_js_result()?;
 
                     self.0 = RewriterState::During(rewriter);
                     self.inner_mut()
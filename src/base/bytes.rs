@@ -52,7 +52,14 @@ impl<'b> Bytes<'b> {
 
     #[inline]
     pub fn as_string(&self, encoding: &'static Encoding) -> String {
-        encoding.decode(self).0.into_owned()
+        self.as_str(encoding).into_owned()
+    }
+
+    /// Decodes the bytes without copying them, if they are valid in the `encoding`
+    /// and are the same in UTF-8.
+    #[inline]
+    pub fn as_str(&self, encoding: &'static Encoding) -> Cow<'_, str> {
+        encoding.decode(self).0
     }
 
     #[inline]
@@ -0,0 +1,223 @@
+use super::named_character_references::NAMED_CHARACTER_REFERENCES;
+use memchr::memchr;
+use std::borrow::Cow;
+
+const MAX_NAME_LENGTH: usize = 32;
+
+/// Replacements for the numeric character references in the C1 control range.
+///
+/// See: <https://html.spec.whatwg.org/multipage/parsing.html#numeric-character-reference-end-state>
+static C1_REPLACEMENTS: [Option<char>; 32] = [
+    Some('\u{20AC}'),
+    None,
+    Some('\u{201A}'),
+    Some('\u{0192}'),
+    Some('\u{201E}'),
+    Some('\u{2026}'),
+    Some('\u{2020}'),
+    Some('\u{2021}'),
+    Some('\u{02C6}'),
+    Some('\u{2030}'),
+    Some('\u{0160}'),
+    Some('\u{2039}'),
+    Some('\u{0152}'),
+    None,
+    Some('\u{017D}'),
+    None,
+    None,
+    Some('\u{2018}'),
+    Some('\u{2019}'),
+    Some('\u{201C}'),
+    Some('\u{201D}'),
+    Some('\u{2022}'),
+    Some('\u{2013}'),
+    Some('\u{2014}'),
+    Some('\u{02DC}'),
+    Some('\u{2122}'),
+    Some('\u{0161}'),
+    Some('\u{203A}'),
+    Some('\u{0153}'),
+    None,
+    Some('\u{017E}'),
+    Some('\u{0178}'),
+];
+
+/// Decodes [character references] in the text, the same way an HTML parser does.
+///
+/// In attribute values (`in_attribute`) named character references without the trailing
+/// semicolon are not decoded if they are followed by `=` or an alphanumeric character
+/// (e.g. `?a=1&copy=2`), as required by the specification.
+///
+/// [character references]: https://html.spec.whatwg.org/multipage/syntax.html#character-references
+pub(crate) fn decode_character_references(text: &str, in_attribute: bool) -> Cow<'_, str> {
+    let Some(first_amp_pos) = memchr(b'&', text.as_bytes()) else {
+        return Cow::Borrowed(text);
+    };
+
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    let mut amp_pos = first_amp_pos;
+
+    loop {
+        decoded.push_str(&rest[..amp_pos]);
+        rest = &rest[amp_pos + 1..];
+
+        let consumed = match rest.as_bytes().first() {
+            Some(b'#') => decode_numeric(&rest[1..], &mut decoded).map(|len| len + 1),
+            Some(c) if c.is_ascii_alphanumeric() => decode_named(rest, in_attribute, &mut decoded),
+            _ => None,
+        };
+
+        match consumed {
+            Some(len) => rest = &rest[len..],
+            None => decoded.push('&'),
+        }
+
+        match memchr(b'&', rest.as_bytes()) {
+            Some(pos) => amp_pos = pos,
+            None => break,
+        }
+    }
+
+    decoded.push_str(rest);
+
+    Cow::Owned(decoded)
+}
+
+/// Returns the length of the consumed reference (excluding `&`), if it's decoded.
+fn decode_named(text: &str, in_attribute: bool, output: &mut String) -> Option<usize> {
+    let bytes = text.as_bytes();
+
+    let alnum_len = bytes
+        .iter()
+        .take(MAX_NAME_LENGTH)
+        .take_while(|c| c.is_ascii_alphanumeric())
+        .count();
+
+    let max_len = if bytes.get(alnum_len) == Some(&b';') {
+        alnum_len + 1
+    } else {
+        alnum_len
+    };
+
+    // NOTE: the longest matching name wins, e.g. `&notin;` is `∉`, but `&notit;` is `¬it;`.
+    let (name, replacement) = (1..=max_len).rev().find_map(|len| {
+        let name = &text[..len];
+
+        NAMED_CHARACTER_REFERENCES
+            .binary_search_by(|(n, _)| n.as_bytes().cmp(name.as_bytes()))
+            .ok()
+            .map(|idx| (name, NAMED_CHARACTER_REFERENCES[idx].1))
+    })?;
+
+    if in_attribute && !name.ends_with(';') {
+        if let Some(&next) = bytes.get(name.len()) {
+            if next == b'=' || next.is_ascii_alphanumeric() {
+                return None;
+            }
+        }
+    }
+
+    output.push_str(replacement);
+
+    Some(name.len())
+}
+
+/// Returns the length of the consumed reference (excluding `&#`), if it's decoded.
+fn decode_numeric(text: &str, output: &mut String) -> Option<usize> {
+    let bytes = text.as_bytes();
+
+    let (radix, prefix_len) = match bytes.first() {
+        Some(b'x' | b'X') => (16, 1),
+        _ => (10, 0),
+    };
+
+    let digits_len = bytes[prefix_len..]
+        .iter()
+        .take_while(|&&c| (c as char).is_digit(radix))
+        .count();
+
+    if digits_len == 0 {
+        return None;
+    }
+
+    let code_point = text[prefix_len..prefix_len + digits_len]
+        .bytes()
+        .try_fold(0u32, |acc, c| {
+            let digit = (c as char).to_digit(radix)?;
+
+            acc.checked_mul(radix)?.checked_add(digit)
+        });
+
+    let ch = match code_point {
+        Some(0x80..=0x9F) => code_point
+            .and_then(|c| C1_REPLACEMENTS[(c - 0x80) as usize])
+            .or_else(|| code_point.and_then(char::from_u32)),
+        Some(0) | None => None,
+        Some(c) => char::from_u32(c),
+    };
+
+    output.push(ch.unwrap_or(char::REPLACEMENT_CHARACTER));
+
+    let mut len = prefix_len + digits_len;
+
+    if bytes.get(len) == Some(&b';') {
+        len += 1;
+    }
+
+    Some(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn named_character_references_are_sorted() {
+        assert!(NAMED_CHARACTER_REFERENCES
+            .windows(2)
+            .all(|w| w[0].0.as_bytes() < w[1].0.as_bytes()));
+
+        assert!(NAMED_CHARACTER_REFERENCES
+            .iter()
+            .all(|(name, _)| name.len() <= MAX_NAME_LENGTH + 1));
+    }
+
+    #[test]
+    fn decode_in_text() {
+        for (text, expected) in [
+            ("no references", "no references"),
+            ("a &amp; b", "a & b"),
+            ("&lt;div&gt;", "<div>"),
+            ("&AMP &copy2", "& ©2"),
+            ("&notin; &notit;", "∉ ¬it;"),
+            ("&nosuchref; & &;", "&nosuchref; & &;"),
+            ("&#65;&#x42;&#X43", "ABC"),
+            ("&#x;&#;", "&#x;&#;"),
+            (
+                "&#0;&#xD800;&#x110000;&#99999999999;",
+                "\u{FFFD}\u{FFFD}\u{FFFD}\u{FFFD}",
+            ),
+            ("&#x80;&#x81;&#150;", "€\u{81}–"),
+            ("&acE;", "\u{223E}\u{333}"),
+            ("&CounterClockwiseContourIntegral;", "∳"),
+        ] {
+            assert_eq!(decode_character_references(text, false), expected);
+        }
+    }
+
+    #[test]
+    fn decode_in_attribute() {
+        for (text, expected) in [
+            ("a&amp;b", "a&b"),
+            ("?a=1&copy=2", "?a=1&copy=2"),
+            ("?a=1&copyright", "?a=1&copyright"),
+            ("?a=1&copy;=2", "?a=1©=2"),
+            ("&copy ", "© "),
+            ("&copy", "©"),
+            ("&#38;x", "&x"),
+        ] {
+            assert_eq!(decode_character_references(text, true), expected);
+        }
+    }
+}
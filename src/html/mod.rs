@@ -4,10 +4,13 @@ use memchr::{memchr, memchr3};
 #[macro_use]
 mod tag;
 
+mod character_references;
 mod local_name;
+mod named_character_references;
 mod namespace;
 mod text_type;
 
+pub(crate) use self::character_references::decode_character_references;
 pub use self::local_name::{LocalName, LocalNameHash};
 pub use self::namespace::Namespace;
 pub use self::tag::Tag;
@@ -45,14 +48,16 @@ pub(crate) fn escape_body_text(mut content: &str, output_handler: &mut impl FnMu
     }
 }
 
-/// Replace `"` with `&quot;` ONLY, leaving `&` unescaped
-pub(crate) fn escape_double_quotes_only(
+/// Replace the `quote` (`"` or `'`) with `&quot;` or `&#39;` ONLY, leaving `&` unescaped
+pub(crate) fn escape_quotes_only(
     content: &Bytes<'_>,
+    quote: u8,
     output_handler: &mut dyn FnMut(&[u8]),
 ) {
+    let escaped_quote: &[u8] = if quote == b'"' { b"&quot;" } else { b"&#39;" };
     let mut content = &**content;
     loop {
-        if let Some(pos) = memchr(b'"', content) {
+        if let Some(pos) = memchr(quote, content) {
             let Some((chunk_before, rest)) = content
                 .split_at_checked(pos)
                 .and_then(|(before, rest)| Some((before, rest.get(1..)?)))
@@ -64,7 +69,7 @@ pub(crate) fn escape_double_quotes_only(
             if !chunk_before.is_empty() {
                 (output_handler)(chunk_before);
             }
-            (output_handler)(b"&quot;");
+            (output_handler)(escaped_quote);
         } else {
             if !content.is_empty() {
                 (output_handler)(content);
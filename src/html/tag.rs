@@ -48,6 +48,7 @@ declare_tags! {
     H6 = 421u64,
     Head = 436_425u64,
     Hr = 439u64,
+    Html = 452_177u64,
     I = 14u64,
     Iframe = 482_056_778u64,
     Img = 14_924u64,
@@ -40,18 +40,21 @@ mod transform_stream;
 use cfg_if::cfg_if;
 
 pub use self::rewriter::{
-    rewrite_str, AsciiCompatibleEncoding, CommentHandler, DoctypeHandler, DocumentContentHandlers,
-    ElementContentHandlers, ElementHandler, EndHandler, EndTagHandler, HandlerResult, HandlerTypes,
-    HtmlRewriter, LocalHandlerTypes, MemorySettings, RewriteStrSettings, Settings, TextHandler,
+    rewrite_str, AsciiCompatibleEncoding, CommentHandler, CompiledSelectors, DoctypeHandler,
+    DocumentContentHandlers, ElementContentHandlers, ElementEndTagHandler, ElementHandler,
+    EndHandler, EndTagHandler, HandlerId, HandlerOrdering, HandlerResult, HandlerTypes,
+    HtmlRewriter, InnerTextHandler, LocalHandlerTypes, MemorySettings, RewriteStrSettings,
+    SelectorMatchingStats, SelectorStats, Settings, SubtreeHandler, TextHandler, TextNodeHandler,
 };
-pub use self::selectors_vm::Selector;
+pub use self::selectors_vm::{AttributeValueMatcher, Selector, SelectorAnalysis, Specificity};
 pub use self::transform_stream::OutputSink;
 
 /// These module contains types to work with [`Send`]able [`HtmlRewriter`]s.
 pub mod send {
     use crate::rewriter::{
-        CommentHandlerSend, DoctypeHandlerSend, ElementHandlerSend, EndHandlerSend,
-        EndTagHandlerSend, TextHandlerSend,
+        CommentHandlerSend, DoctypeHandlerSend, ElementEndTagHandlerSend, ElementHandlerSend,
+        EndHandlerSend, EndTagHandlerSend, InnerTextHandlerSend, SubtreeHandlerSend,
+        TextHandlerSend, TextNodeHandlerSend,
     };
     pub use crate::rewriter::{IntoHandler, SendHandlerTypes};
 
@@ -73,12 +76,20 @@ pub mod send {
     pub type DoctypeHandler<'h> = DoctypeHandlerSend<'h>;
     /// [`ElementHandler`](crate::ElementHandler) for [`Send`]able [`HtmlRewriter`](crate::HtmlRewriter)s.
     pub type ElementHandler<'h> = ElementHandlerSend<'h>;
+    /// [`ElementEndTagHandler`](crate::ElementEndTagHandler) for [`Send`]able [`HtmlRewriter`](crate::HtmlRewriter)s.
+    pub type ElementEndTagHandler<'h> = ElementEndTagHandlerSend<'h>;
     /// [`EndHandler`](crate::EndHandler) for [`Send`]able [`HtmlRewriter`](crate::HtmlRewriter)s.
     pub type EndHandler<'h> = EndHandlerSend<'h>;
     /// [`EndTagHandler`](crate::EndTagHandler) for [`Send`]able [`HtmlRewriter`](crate::HtmlRewriter)s.
     pub type EndTagHandler<'h> = EndTagHandlerSend<'h>;
+    /// [`InnerTextHandler`](crate::InnerTextHandler) for [`Send`]able [`HtmlRewriter`](crate::HtmlRewriter)s.
+    pub type InnerTextHandler<'h> = InnerTextHandlerSend<'h>;
+    /// [`SubtreeHandler`](crate::SubtreeHandler) for [`Send`]able [`HtmlRewriter`](crate::HtmlRewriter)s.
+    pub type SubtreeHandler<'h> = SubtreeHandlerSend<'h>;
     /// [`TextHandler`](crate::TextHandler) for [`Send`]able [`HtmlRewriter`](crate::HtmlRewriter)s.
     pub type TextHandler<'h> = TextHandlerSend<'h>;
+    /// [`TextNodeHandler`](crate::TextNodeHandler) for [`Send`]able [`HtmlRewriter`](crate::HtmlRewriter)s.
+    pub type TextNodeHandler<'h> = TextNodeHandlerSend<'h>;
 
     /// [`Element`](crate::rewritable_units::Element) for [`Send`]able [`HtmlRewriter`](crate::HtmlRewriter)s.
     pub type Element<'r, 't> = crate::rewritable_units::Element<'r, 't, SendHandlerTypes>;
@@ -89,17 +100,19 @@ pub mod errors {
     pub use super::memory::MemoryLimitExceededError;
     pub use super::parser::ParsingAmbiguityError;
     pub use super::rewritable_units::{
-        AttributeNameError, CommentTextError, TagNameError, Utf8Error,
+        AttributeNameError, ClassNameError, CommentTextError, DoctypeError, StyleDeclarationError,
+        TagNameError, Utf8Error,
     };
     pub use super::rewriter::RewritingError;
-    pub use super::selectors_vm::SelectorError;
+    pub use super::selectors_vm::{DetailedSelectorError, SelectorError};
 }
 
 /// HTML content descriptors that can be produced and modified by a rewriter.
 pub mod html_content {
     pub use super::rewritable_units::{
-        Attribute, Comment, ContentType, Doctype, DocumentEnd, Element, EndTag, StartTag,
-        StreamingHandler, StreamingHandlerSink, TextChunk, UserData,
+        Attribute, AttributeQuoteStyle, ClassList, Comment, ContentType, Doctype, DocumentEnd,
+        Element, EndTag, InlineStyle, InnerText, SourceLocation, StartTag, StreamingHandler,
+        StreamingHandlerSink, TextChunk, TextNode, UserData,
     };
 
     pub use super::html::TextType;
@@ -209,7 +222,7 @@ cfg_if! {
         pub use self::base::SharedEncoding;
 
         pub use self::transform_stream::{
-            StartTagHandlingResult, TransformController, TransformStream,
+            DeferredElementEvent, StartTagHandlingResult, TransformController, TransformStream,
             TransformStreamSettings
         };
 
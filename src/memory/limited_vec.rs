@@ -1,7 +1,7 @@
 #![allow(clippy::len_without_is_empty)]
 
-use std::mem::size_of;
-use std::ops::{Deref, Index, RangeBounds};
+use std::mem::{size_of, size_of_val};
+use std::ops::{Deref, Index, IndexMut, RangeBounds};
 use std::vec::Drain;
 
 use super::{MemoryLimitExceededError, SharedMemoryLimiter};
@@ -70,6 +70,14 @@ impl<T> LimitedVec<T> {
     }
 }
 
+impl<T: Copy> LimitedVec<T> {
+    pub fn extend_from_slice(&mut self, elements: &[T]) -> Result<(), MemoryLimitExceededError> {
+        self.limiter.increase_usage(size_of_val(elements))?;
+        self.vec.extend_from_slice(elements);
+        Ok(())
+    }
+}
+
 impl<T> Deref for LimitedVec<T> {
     type Target = [T];
 
@@ -87,6 +95,12 @@ impl<T> Index<usize> for LimitedVec<T> {
     }
 }
 
+impl<T> IndexMut<usize> for LimitedVec<T> {
+    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
+        &mut self.vec[index]
+    }
+}
+
 impl<T> Drop for LimitedVec<T> {
     fn drop(&mut self) {
         self.limiter.decrease_usage(size_of::<T>() * self.vec.len());
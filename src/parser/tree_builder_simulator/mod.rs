@@ -117,6 +117,8 @@ fn is_html_integration_point_in_svg(tag_name: LocalNameHash) -> bool {
 pub(crate) struct TreeBuilderSimulator {
     ns_stack: Vec<Namespace>,
     current_ns: Namespace,
+    /// The foreign namespace of the integration point the last start tag has entered.
+    integration_point_ns: Option<Namespace>,
     ambiguity_guard: AmbiguityGuard,
     strict: bool,
 }
@@ -128,6 +130,7 @@ impl TreeBuilderSimulator {
         let mut simulator = Self {
             ns_stack: Vec::with_capacity(DEFAULT_NS_STACK_CAPACITY),
             current_ns: Namespace::Html,
+            integration_point_ns: None,
             ambiguity_guard: AmbiguityGuard::default(),
             strict,
         };
@@ -145,6 +148,8 @@ impl TreeBuilderSimulator {
             self.ambiguity_guard.track_start_tag(tag_name)?;
         }
 
+        self.integration_point_ns = None;
+
         Ok(if tag_name == Tag::Svg {
             self.enter_ns(Namespace::Svg)
         } else if tag_name == Tag::Math {
@@ -186,9 +191,19 @@ impl TreeBuilderSimulator {
         false
     }
 
+    /// Returns the namespace of the element of the last start tag.
+    ///
+    /// Unlike the content of an integration point (e.g. `<foreignObject>`), the integration
+    /// point element itself is in the foreign namespace.
+    #[inline]
+    pub fn current_element_ns(&self) -> Namespace {
+        self.integration_point_ns.unwrap_or(self.current_ns)
+    }
+
     #[inline]
-    pub const fn current_ns(&self) -> Namespace {
-        self.current_ns
+    fn enter_integration_point(&mut self) -> TreeBuilderFeedback {
+        self.integration_point_ns = Some(self.current_ns);
+        self.enter_ns(Namespace::Html)
     }
 
     #[inline]
@@ -259,7 +274,7 @@ impl TreeBuilderSimulator {
                     if self_closing {
                         TreeBuilderFeedback::None
                     } else {
-                        this.enter_ns(Namespace::Html)
+                        this.enter_integration_point()
                     }
                 })
             });
@@ -307,7 +322,7 @@ impl TreeBuilderSimulator {
                                 && (eq_case_insensitive(&value, b"text/html")
                                     || eq_case_insensitive(&value, b"application/xhtml+xml"))
                             {
-                                return this.enter_ns(Namespace::Html);
+                                return this.enter_integration_point();
                             }
                         }
                     }
@@ -0,0 +1,185 @@
+use super::{Attribute, StartTag};
+use std::fmt::{self, Debug};
+use thiserror::Error;
+
+/// An error that occurs when invalid value is provided for the class name.
+#[derive(Error, Debug, Eq, PartialEq, Copy, Clone)]
+pub enum ClassNameError {
+    /// The provided value is empty.
+    #[error("Class name can't be empty.")]
+    Empty,
+
+    /// The provided value contains an ASCII whitespace character.
+    #[error("Class name can't contain whitespace.")]
+    ContainsWhitespace,
+}
+
+#[inline]
+const fn is_class_whitespace(ch: char) -> bool {
+    matches!(ch, ' ' | '\n' | '\r' | '\t' | '\x0C')
+}
+
+/// The classes of an [`Element`], mirroring the [`DOMTokenList`] of the `class` attribute.
+///
+/// The classes are parsed from the `class` attribute once the list is created, and the modified
+/// list is written back to the attribute when the list is dropped. So, the attribute is left
+/// intact unless the classes are changed, in which case they are separated by single spaces and
+/// the duplicates are removed.
+///
+/// Note that the class names are HTML source, i.e. character references in them are not decoded.
+///
+/// # Example
+/// ```
+/// use lol_html::{rewrite_str, element, RewriteStrSettings};
+///
+/// let html = rewrite_str(
+///     r#"<div class="foo  bar"></div>"#,
+///     RewriteStrSettings {
+///         element_content_handlers: vec![
+///             element!("div", |el| {
+///                 let mut classes = el.class_list();
+///
+///                 assert!(classes.contains("foo"));
+///
+///                 classes.remove("foo")?;
+///                 classes.add("baz")?;
+///                 classes.toggle("qux")?;
+///
+///                 Ok(())
+///             })
+///         ],
+///         ..RewriteStrSettings::new()
+///     }
+/// ).unwrap();
+///
+/// assert_eq!(html, r#"<div class="bar baz qux"></div>"#);
+/// ```
+///
+/// [`Element`]: super::Element
+/// [`DOMTokenList`]: https://developer.mozilla.org/en-US/docs/Web/API/DOMTokenList
+pub struct ClassList<'e, 't> {
+    start_tag: &'e mut StartTag<'t>,
+    classes: Vec<String>,
+    modified: bool,
+}
+
+impl<'e, 't> ClassList<'e, 't> {
+    pub(super) fn new(start_tag: &'e mut StartTag<'t>) -> Self {
+        let mut classes: Vec<String> = Vec::new();
+
+        let value = start_tag
+            .attributes()
+            .iter()
+            .find(|attr| attr.name() == "class")
+            .map(Attribute::value);
+
+        if let Some(value) = value {
+            for class in value.split(is_class_whitespace).filter(|c| !c.is_empty()) {
+                if !classes.iter().any(|c| c == class) {
+                    classes.push(class.to_owned());
+                }
+            }
+        }
+
+        ClassList {
+            start_tag,
+            classes,
+            modified: false,
+        }
+    }
+
+    #[inline]
+    fn validate(class: &str) -> Result<(), ClassNameError> {
+        if class.is_empty() {
+            Err(ClassNameError::Empty)
+        } else if class.contains(is_class_whitespace) {
+            Err(ClassNameError::ContainsWhitespace)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns `true` if the element has the `class`.
+    #[inline]
+    #[must_use]
+    pub fn contains(&self, class: &str) -> bool {
+        self.classes.iter().any(|c| c == class)
+    }
+
+    /// Adds the `class` to the element, if it doesn't have it already.
+    pub fn add(&mut self, class: &str) -> Result<(), ClassNameError> {
+        Self::validate(class)?;
+
+        if !self.contains(class) {
+            self.classes.push(class.to_owned());
+            self.modified = true;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the `class` from the element, if it has it.
+    pub fn remove(&mut self, class: &str) -> Result<(), ClassNameError> {
+        Self::validate(class)?;
+
+        if let Some(idx) = self.classes.iter().position(|c| c == class) {
+            self.classes.remove(idx);
+            self.modified = true;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the `class` from the element if it has it, and adds it otherwise.
+    ///
+    /// Returns `true` if the element has the `class` afterwards.
+    pub fn toggle(&mut self, class: &str) -> Result<bool, ClassNameError> {
+        if self.contains(class) {
+            self.remove(class)?;
+
+            Ok(false)
+        } else {
+            self.add(class)?;
+
+            Ok(true)
+        }
+    }
+
+    /// Returns the number of the element's classes.
+    #[inline]
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.classes.len()
+    }
+
+    /// Returns `true` if the element has no classes.
+    #[inline]
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.classes.is_empty()
+    }
+
+    /// Returns an iterator over the element's classes, in the order of their appearance.
+    #[inline]
+    pub fn iter(&self) -> impl Iterator<Item = &str> + '_ {
+        self.classes.iter().map(String::as_str)
+    }
+}
+
+impl Drop for ClassList<'_, '_> {
+    fn drop(&mut self) {
+        if self.modified {
+            // NOTE: the attribute name is always valid.
+            let _ = self
+                .start_tag
+                .set_attribute("class", &self.classes.join(" "));
+        }
+    }
+}
+
+impl Debug for ClassList<'_, '_> {
+    #[cold]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
@@ -1,12 +1,13 @@
 use super::mutations::MutationsInner;
 use super::{
-    Attribute, AttributeNameError, ContentType, EndTag, Mutations, StartTag, StreamingHandler,
-    StringChunk,
+    Attribute, AttributeNameError, AttributeQuoteStyle, ClassList, ContentType, EndTag,
+    InlineStyle, Mutations, SourceLocation, StartTag, StreamingHandler, StringChunk,
 };
 use crate::base::Bytes;
 use crate::rewriter::{HandlerTypes, LocalHandlerTypes};
 use encoding_rs::Encoding;
 use std::any::Any;
+use std::cmp::Ordering;
 use std::fmt::{self, Debug};
 use thiserror::Error;
 
@@ -45,6 +46,7 @@ pub struct Element<'r, 't, H: HandlerTypes = LocalHandlerTypes> {
     can_have_content: bool,
     should_remove_content: bool,
     encoding: &'static Encoding,
+    matched_selectors: Vec<usize>,
     user_data: Box<dyn Any>,
 }
 
@@ -62,10 +64,16 @@ impl<'r, 't, H: HandlerTypes> Element<'r, 't, H> {
             can_have_content,
             should_remove_content: false,
             encoding,
+            matched_selectors: Vec::new(),
             user_data: Box::new(()),
         }
     }
 
+    #[inline]
+    pub(crate) fn set_matched_selectors(&mut self, matched_selectors: Vec<usize>) {
+        self.matched_selectors = matched_selectors;
+    }
+
     fn tag_name_bytes_from_str(&self, name: &str) -> Result<Bytes<'static>, TagNameError> {
         match name.as_bytes().first() {
             Some(ch) if !ch.is_ascii_alphabetic() => Err(TagNameError::InvalidFirstCharacter),
@@ -155,6 +163,25 @@ impl<'r, 't, H: HandlerTypes> Element<'r, 't, H> {
         self.start_tag.self_closing()
     }
 
+    /// Returns the location of the element's start tag in the input.
+    ///
+    /// The location of the end tag is available to the [end tag handlers].
+    ///
+    /// [end tag handlers]: Self::end_tag_handlers
+    #[inline]
+    #[must_use]
+    pub fn source_location(&self) -> SourceLocation {
+        self.start_tag.source_location()
+    }
+
+    /// Returns the source markup of the element's start tag in the input's encoding, which is
+    /// retained even if the element is modified.
+    #[inline]
+    #[must_use]
+    pub fn source_html(&self) -> &[u8] {
+        self.start_tag.source_html()
+    }
+
     /// Whether the element can have inner content.
     ///
     /// Returns `true` if the element isn't a [void element in HTML][void],
@@ -178,6 +205,22 @@ impl<'r, 't, H: HandlerTypes> Element<'r, 't, H> {
         self.start_tag.namespace_uri()
     }
 
+    /// Returns the indices of the selectors in [`Settings::element_content_handlers`]
+    /// that match the element, in ascending order.
+    ///
+    /// The indices refer to the handlers as they were passed to the rewriter,
+    /// regardless of the [`HandlerOrdering`]. The handlers added to a running rewriter
+    /// follow them, see [`HandlerId::index`].
+    ///
+    /// [`Settings::element_content_handlers`]: crate::Settings::element_content_handlers
+    /// [`HandlerOrdering`]: crate::HandlerOrdering
+    /// [`HandlerId::index`]: crate::HandlerId::index
+    #[inline]
+    #[must_use]
+    pub fn matched_selectors(&self) -> &[usize] {
+        &self.matched_selectors
+    }
+
     /// Returns an immutable collection of element's attributes.
     #[inline]
     #[must_use]
@@ -226,6 +269,143 @@ impl<'r, 't, H: HandlerTypes> Element<'r, 't, H> {
         self.start_tag.remove_attribute(name);
     }
 
+    /// Returns the classes of the element, that can be modified like the [`DOMTokenList`] of
+    /// the `class` attribute.
+    ///
+    /// See [`ClassList`] for more details.
+    ///
+    /// [`DOMTokenList`]: https://developer.mozilla.org/en-US/docs/Web/API/DOMTokenList
+    #[inline]
+    #[must_use]
+    pub fn class_list(&mut self) -> ClassList<'_, 't> {
+        ClassList::new(self.start_tag)
+    }
+
+    /// Returns the CSS declarations of the element's `style` attribute, that can be examined
+    /// and modified individually.
+    ///
+    /// See [`InlineStyle`] for more details.
+    #[inline]
+    #[must_use]
+    pub fn style(&mut self) -> InlineStyle<'_, 't> {
+        InlineStyle::new(self.start_tag)
+    }
+
+    /// Inserts an attribute with `name` and `value` at the `index` in the element's attributes.
+    ///
+    /// If the `index` is greater than the number of the attributes, the attribute is added
+    /// to the end. If the element already has an attribute with the `name`, it's moved to the
+    /// `index` and gets the `value`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use lol_html::{rewrite_str, element, RewriteStrSettings};
+    ///
+    /// let html = rewrite_str(
+    ///     r#"<script src="app.js" async></script>"#,
+    ///     RewriteStrSettings {
+    ///         element_content_handlers: vec![
+    ///             element!("script", |el| {
+    ///                 el.insert_attribute_at(1, "integrity", "sha384-abc")?;
+    ///                 el.insert_attribute_at(0, "nonce", "r4nd0m")?;
+    ///
+    ///                 Ok(())
+    ///             })
+    ///         ],
+    ///         ..RewriteStrSettings::new()
+    ///     }
+    /// ).unwrap();
+    ///
+    /// assert_eq!(
+    ///     html,
+    ///     r#"<script nonce="r4nd0m" src="app.js" integrity="sha384-abc" async></script>"#
+    /// );
+    /// ```
+    #[inline]
+    pub fn insert_attribute_at(
+        &mut self,
+        index: usize,
+        name: &str,
+        value: &str,
+    ) -> Result<(), AttributeNameError> {
+        self.start_tag.insert_attribute_at(index, name, value)
+    }
+
+    /// Renames the attribute with the `name` to `new_name`, keeping its value and position,
+    /// if it is present.
+    ///
+    /// If the element also has an attribute with the `new_name`, it's removed.
+    #[inline]
+    pub fn rename_attribute(
+        &mut self,
+        name: &str,
+        new_name: &str,
+    ) -> Result<(), AttributeNameError> {
+        self.start_tag.rename_attribute(name, new_name)
+    }
+
+    /// Sorts the element's attributes by their names.
+    ///
+    /// The attributes keep their source markup, unless they are modified,
+    /// but the whitespace between them is normalized.
+    #[inline]
+    pub fn sort_attributes(&mut self) {
+        self.start_tag.sort_attributes();
+    }
+
+    /// Sorts the element's attributes with the `compare` function.
+    ///
+    /// The sort is stable, and the element's start tag is left intact if its attributes
+    /// are already sorted.
+    #[inline]
+    pub fn sort_attributes_by(
+        &mut self,
+        compare: impl FnMut(&Attribute<'t>, &Attribute<'t>) -> Ordering,
+    ) {
+        self.start_tag.sort_attributes_by(compare);
+    }
+
+    /// Returns the quote style of the element's attributes, that are serialized anew.
+    #[inline]
+    #[must_use]
+    pub fn attribute_quote_style(&self) -> AttributeQuoteStyle {
+        self.start_tag.attribute_quote_style()
+    }
+
+    /// Sets the quote style of the element's attributes, that are serialized anew, i.e.
+    /// the added, modified or renamed ones. The rest of the attributes keep their source markup.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use lol_html::{rewrite_str, element, RewriteStrSettings};
+    /// use lol_html::html_content::AttributeQuoteStyle;
+    ///
+    /// let html = rewrite_str(
+    ///     r#"<div id="foo" class=bar></div>"#,
+    ///     RewriteStrSettings {
+    ///         element_content_handlers: vec![
+    ///             element!("div", |el| {
+    ///                 el.set_attribute_quote_style(AttributeQuoteStyle::Minimal);
+    ///                 el.set_attribute("class", "bar baz")?;
+    ///                 el.set_attribute("title", "qux")?;
+    ///                 el.set_attribute("hidden", "")?;
+    ///
+    ///                 Ok(())
+    ///             })
+    ///         ],
+    ///         ..RewriteStrSettings::new()
+    ///     }
+    /// ).unwrap();
+    ///
+    /// assert_eq!(html, r#"<div id="foo" class="bar baz" title=qux hidden></div>"#);
+    /// ```
+    #[inline]
+    pub fn set_attribute_quote_style(&mut self, quote_style: AttributeQuoteStyle) {
+        self.start_tag.set_attribute_quote_style(quote_style);
+    }
+
     /// Inserts `content` before the element.
     ///
     /// Consequent calls to the method append `content` to the previously inserted content.
@@ -737,6 +917,7 @@ mod tests {
     use crate::*;
     use encoding_rs::{Encoding, EUC_JP, UTF_8};
     use rewritable_units::StreamingHandlerSink;
+    use std::borrow::Cow;
 
     fn rewrite_element(
         html: &[u8],
@@ -1008,6 +1189,44 @@ mod tests {
         });
     }
 
+    #[test]
+    fn integration_point_namespace_uri() {
+        const HTML: &str = "http://www.w3.org/1999/xhtml";
+        const SVG: &str = "http://www.w3.org/2000/svg";
+        const MATH_ML: &str = "http://www.w3.org/1998/Math/MathML";
+
+        for (html, expected) in [
+            (
+                "<svg><foreignObject><p></p></foreignObject><g></g></svg>",
+                [SVG, SVG, HTML, SVG],
+            ),
+            (
+                "<svg><desc><p></p></desc><g></g></svg>",
+                [SVG, SVG, HTML, SVG],
+            ),
+            (
+                "<svg><title><p></p></title><g></g></svg>",
+                [SVG, SVG, HTML, SVG],
+            ),
+            (
+                "<math><mi><p></p></mi><mo></mo></math>",
+                [MATH_ML, MATH_ML, HTML, MATH_ML],
+            ),
+            (
+                r#"<math><annotation-xml encoding="text/html"><p></p></annotation-xml><mo></mo></math>"#,
+                [MATH_ML, MATH_ML, HTML, MATH_ML],
+            ),
+        ] {
+            let mut namespaces = Vec::new();
+
+            rewrite_element(html.as_bytes(), UTF_8, "*", |el| {
+                namespaces.push(el.namespace_uri());
+            });
+
+            assert_eq!(namespaces, expected, "{html}");
+        }
+    }
+
     #[test]
     fn empty_attr_name() {
         rewrite_element(b"<div>", UTF_8, "div", |el| {
@@ -1124,6 +1343,26 @@ mod tests {
         }
     }
 
+    #[test]
+    fn decoded_attribute_values() {
+        for (html, enc) in encoded(r#"<a href="?x=1&amp;y=2&copy=3" title="&lt;β&gt; &copy 2024">"#)
+        {
+            rewrite_element(&html, enc, "a", |el| {
+                let attrs = el.attributes();
+
+                assert_eq!(attrs[0].value(), "?x=1&amp;y=2&copy=3");
+                assert_eq!(attrs[0].decoded_value(), "?x=1&y=2&copy=3");
+                assert_eq!(attrs[1].decoded_value(), "<β> © 2024");
+            });
+        }
+
+        rewrite_element(br#"<a href="/foo">"#, UTF_8, "a", |el| {
+            let attrs = el.attributes();
+
+            assert!(matches!(attrs[0].decoded_value(), Cow::Borrowed("/foo")));
+        });
+    }
+
     #[test]
     fn has_attr() {
         for (html, enc) in encoded("<Foo FooѦ1=Bar1 FooѤ2=Bar2>") {
@@ -1182,6 +1421,123 @@ mod tests {
         }
     }
 
+    #[test]
+    fn class_list() {
+        for (html, enc) in encoded("<div class=' foo\tbarβ  foo'>") {
+            let output = rewrite_element(&html, enc, "div", |el| {
+                let mut classes = el.class_list();
+
+                assert_eq!(classes.iter().collect::<Vec<_>>(), ["foo", "barβ"]);
+                assert!(classes.contains("barβ"));
+                assert!(!classes.contains("bar"));
+
+                classes.add("foo").unwrap();
+                classes.remove("qux").unwrap();
+            });
+
+            // NOTE: the attribute is left intact if the classes aren't changed.
+            assert_eq!(output, "<div class=' foo\tbarβ  foo'>");
+
+            let output = rewrite_element(&html, enc, "div", |el| {
+                let mut classes = el.class_list();
+
+                assert!(!classes.toggle("foo").unwrap());
+                assert!(classes.toggle("quxγ").unwrap());
+                classes.add("baz").unwrap();
+
+                assert_eq!(classes.len(), 3);
+                assert_eq!(classes.add(""), Err(ClassNameError::Empty));
+                assert_eq!(
+                    classes.remove("foo bar"),
+                    Err(ClassNameError::ContainsWhitespace)
+                );
+            });
+
+            assert_eq!(output, r#"<div class="barβ quxγ baz">"#);
+        }
+
+        let output = rewrite_element(b"<div id=foo>", UTF_8, "div", |el| {
+            assert!(el.class_list().is_empty());
+
+            el.class_list().remove("foo").unwrap();
+            el.class_list().add("foo").unwrap();
+        });
+
+        assert_eq!(output, r#"<div id=foo class="foo">"#);
+    }
+
+    #[test]
+    fn inline_style() {
+        for (html, enc) in encoded(
+            "<div style='COLOR: red; --Mainβ: blue;margin: 0 !important; margin: 1px; x: ; 1x: 1; \
+             font: 12px/1.5 \"a; b\"'>",
+        ) {
+            let output = rewrite_element(&html, enc, "div", |el| {
+                let style = el.style();
+
+                assert_eq!(
+                    style.iter().collect::<Vec<_>>(),
+                    [
+                        ("color", "red"),
+                        ("--Mainβ", "blue"),
+                        ("margin", "0"),
+                        ("font", "12px/1.5 \"a; b\"")
+                    ]
+                );
+
+                assert_eq!(style.get("Color"), Some("red"));
+                assert_eq!(style.get("--mainβ"), None);
+                assert!(style.is_important("margin"));
+                assert!(!style.is_important("color"));
+            });
+
+            // NOTE: the attribute is left intact if the declarations aren't changed.
+            assert_eq!(
+                output,
+                "<div style='COLOR: red; --Mainβ: blue;margin: 0 !important; margin: 1px; x: ; \
+                 1x: 1; font: 12px/1.5 \"a; b\"'>"
+            );
+
+            let output = rewrite_element(&html, enc, "div", |el| {
+                let mut style = el.style();
+
+                style.set("Color", " green ").unwrap();
+                style.set_important("padding", "calc(1px + 2%)").unwrap();
+                style.remove("margin");
+                style.remove("x");
+                style.remove("font");
+
+                for (name, value, err) in [
+                    ("1px", "0", StyleDeclarationError::InvalidPropertyName),
+                    ("co lor", "red", StyleDeclarationError::InvalidPropertyName),
+                    ("co\\lor", "red", StyleDeclarationError::InvalidPropertyName),
+                    ("color", "", StyleDeclarationError::InvalidValue),
+                    ("color", "red; x: y", StyleDeclarationError::InvalidValue),
+                    (
+                        "color",
+                        "red !important",
+                        StyleDeclarationError::InvalidValue,
+                    ),
+                    ("color", "url(foo", StyleDeclarationError::InvalidValue),
+                    ("font-family", "\"foo", StyleDeclarationError::InvalidValue),
+                ] {
+                    assert_eq!(style.set(name, value), Err(err), "{name}: {value}");
+                }
+            });
+
+            assert_eq!(
+                output,
+                r#"<div style="color: green; --Mainβ: blue; padding: calc(1px + 2%) !important">"#
+            );
+        }
+
+        let output = rewrite_element(b"<div>", UTF_8, "div", |el| {
+            el.style().set("display", "none").unwrap();
+        });
+
+        assert_eq!(output, r#"<div style="display: none">"#);
+    }
+
     #[test]
     fn insert_content_before() {
         for (html, enc) in encoded("<div><span>ĥi</span></div>") {
@@ -1508,6 +1864,132 @@ mod tests {
             );
         }
 
+        #[test]
+        fn insert_attr_at() {
+            test!(
+                |el| {
+                    el.insert_attribute_at(1, "a5", "42").unwrap();
+                    el.insert_attribute_at(0, "A3", "43").unwrap();
+                    el.insert_attribute_at(42, "a6", "44").unwrap();
+
+                    assert_eq!(
+                        el.insert_attribute_at(0, "a=", ""),
+                        Err(AttributeNameError::ForbiddenCharacter('='))
+                    );
+                },
+                r#"<a a3="43" a1='foo " baré " baz' a5="42" a2="foo ' bar ' baz" a4 a6="44"></a>"#
+            );
+        }
+
+        #[test]
+        fn rename_attr() {
+            test!(
+                |el| {
+                    el.rename_attribute("A2", "b2").unwrap();
+                    el.rename_attribute("a1", "a4").unwrap();
+                    el.rename_attribute("a5", "b5").unwrap();
+
+                    assert_eq!(el.get_attribute("b2").unwrap(), "foo ' bar ' baz");
+                    assert_eq!(
+                        el.rename_attribute("a3", ""),
+                        Err(AttributeNameError::Empty)
+                    );
+                },
+                r#"<a a4="foo &quot; baré &quot; baz" b2="foo ' bar ' baz" a3=foo/bar></a>"#
+            );
+        }
+
+        #[test]
+        fn rename_non_existent_attr() {
+            test!(
+                |el| {
+                    el.rename_attribute("a5", "a1").unwrap();
+                },
+                r#"<a a1='foo " baré " baz' / a2="foo ' bar ' baz" a3=foo/bar a4></a>"#
+            );
+        }
+
+        #[test]
+        fn sort_attrs() {
+            test!(
+                |el| {
+                    el.sort_attributes_by(|a, b| b.name().cmp(&a.name()));
+                },
+                r#"<a a4 a3=foo/bar a2="foo ' bar ' baz" a1='foo " baré " baz'></a>"#
+            );
+
+            // NOTE: the attributes are already sorted.
+            test!(
+                |el| {
+                    el.sort_attributes();
+                },
+                r#"<a a1='foo " baré " baz' / a2="foo ' bar ' baz" a3=foo/bar a4></a>"#
+            );
+
+            let output = rewrite_element(b"<div b=1 C=2 a=3 b2>", UTF_8, "div", |el| {
+                el.sort_attributes();
+            });
+
+            assert_eq!(output, "<div a=3 b=1 b2 C=2>");
+        }
+
+        #[test]
+        fn attr_quote_style() {
+            let rewrite = |quote_style| {
+                rewrite_element(HTML.as_bytes(), UTF_8, SELECTOR, |el| {
+                    assert_eq!(el.attribute_quote_style(), AttributeQuoteStyle::Double);
+
+                    el.set_attribute_quote_style(quote_style);
+
+                    assert_eq!(el.attribute_quote_style(), quote_style);
+
+                    el.set_attribute("a3", "foo/bar42").unwrap();
+
+                    for (name, value) in [
+                        ("b1", "42"),
+                        ("b2", ""),
+                        ("b3", "a'b"),
+                        ("b4", r#"a"b"#),
+                        ("b5", r#"a"'b"#),
+                        ("b6", "a b"),
+                    ] {
+                        el.set_attribute(name, value).unwrap();
+                    }
+                })
+            };
+
+            assert_eq!(
+                rewrite(AttributeQuoteStyle::Double),
+                concat!(
+                    r#"<a a1='foo " baré " baz' a2="foo ' bar ' baz" a3="foo/bar42" a4 "#,
+                    r#"b1="42" b2="" b3="a'b" b4="a&quot;b" b5="a&quot;'b" b6="a b"></a>"#
+                )
+            );
+
+            assert_eq!(
+                rewrite(AttributeQuoteStyle::Single),
+                concat!(
+                    r#"<a a1='foo " baré " baz' a2="foo ' bar ' baz" a3='foo/bar42' a4 "#,
+                    r#"b1='42' b2='' b3='a&#39;b' b4='a"b' b5='a"&#39;b' b6='a b'></a>"#
+                )
+            );
+
+            assert_eq!(
+                rewrite(AttributeQuoteStyle::Minimal),
+                concat!(
+                    r#"<a a1='foo " baré " baz' a2="foo ' bar ' baz" a3=foo/bar42 a4 "#,
+                    r#"b1=42 b2 b3="a'b" b4='a"b' b5="a&quot;'b" b6="a b"></a>"#
+                )
+            );
+
+            // NOTE: the quote style alone doesn't change the tag.
+            let output = rewrite_element(HTML.as_bytes(), UTF_8, SELECTOR, |el| {
+                el.set_attribute_quote_style(AttributeQuoteStyle::Single);
+            });
+
+            assert_eq!(output, HTML);
+        }
+
         #[test]
         fn self_closing_flag() {
             // NOTE: we should add space between valueless attr and self-closing slash
@@ -0,0 +1,321 @@
+use super::{Attribute, StartTag};
+use cssparser::{
+    AtRuleParser, CowRcStr, DeclarationParser, Delimiter, ParseError, Parser, ParserInput,
+    ParserState, QualifiedRuleParser, RuleBodyItemParser, RuleBodyParser,
+};
+use std::fmt::{self, Debug};
+use thiserror::Error;
+
+/// An error that occurs when invalid CSS declaration is provided for the inline style.
+#[derive(Error, Debug, Eq, PartialEq, Copy, Clone)]
+pub enum StyleDeclarationError {
+    /// The provided property name is not a CSS identifier (e.g. `1px` or `col or`).
+    #[error("The property name is not a valid CSS identifier.")]
+    InvalidPropertyName,
+
+    /// The provided value is empty or is not a valid CSS declaration value (e.g. `red; x: y`
+    /// or `url(foo`).
+    #[error("The value is not a valid CSS declaration value.")]
+    InvalidValue,
+}
+
+#[inline]
+const fn is_css_whitespace(ch: char) -> bool {
+    matches!(ch, ' ' | '\n' | '\r' | '\t' | '\x0C')
+}
+
+/// Custom property names (e.g. `--main-color`) are case-sensitive, unlike the rest.
+#[inline]
+fn normalize_name(name: &str) -> String {
+    if name.starts_with("--") {
+        name.to_owned()
+    } else {
+        name.to_ascii_lowercase()
+    }
+}
+
+#[derive(Debug)]
+struct Declaration {
+    name: String,
+    value: String,
+    important: bool,
+}
+
+struct DeclarationsParser;
+
+impl<'i> DeclarationParser<'i> for DeclarationsParser {
+    type Declaration = Declaration;
+    type Error = ();
+
+    fn parse_value<'t>(
+        &mut self,
+        name: CowRcStr<'i>,
+        input: &mut Parser<'i, 't>,
+        _declaration_start: &ParserState,
+    ) -> Result<Declaration, ParseError<'i, ()>> {
+        let start = input.position();
+
+        input.parse_until_before(Delimiter::Bang, |input| {
+            while input.next().is_ok() {}
+
+            Ok::<_, ParseError<'i, ()>>(())
+        })?;
+
+        let value = input.slice_from(start).trim_matches(is_css_whitespace);
+
+        if value.is_empty() {
+            return Err(input.new_custom_error(()));
+        }
+
+        let important = input.try_parse(cssparser::parse_important).is_ok();
+
+        input.expect_exhausted()?;
+
+        Ok(Declaration {
+            name: normalize_name(&name),
+            value: value.to_owned(),
+            important,
+        })
+    }
+}
+
+impl AtRuleParser<'_> for DeclarationsParser {
+    type Prelude = ();
+    type AtRule = Declaration;
+    type Error = ();
+}
+
+impl QualifiedRuleParser<'_> for DeclarationsParser {
+    type Prelude = ();
+    type QualifiedRule = Declaration;
+    type Error = ();
+}
+
+impl RuleBodyItemParser<'_, Declaration, ()> for DeclarationsParser {
+    fn parse_declarations(&self) -> bool {
+        true
+    }
+
+    fn parse_qualified(&self) -> bool {
+        false
+    }
+}
+
+/// Parses the declaration block, skipping the invalid declarations.
+fn parse_declarations(css: &str) -> Vec<Declaration> {
+    let mut input = ParserInput::new(css);
+    let mut input = Parser::new(&mut input);
+
+    RuleBodyParser::new(&mut input, &mut DeclarationsParser)
+        .filter_map(Result::ok)
+        .collect()
+}
+
+/// The CSS declarations of the inline style of an [`Element`], i.e. of its `style` attribute.
+///
+/// The declarations are parsed from the `style` attribute once the style is created, and the
+/// modified declarations are written back to the attribute when the style is dropped. So, the
+/// attribute is left intact unless the declarations are changed, in which case they are
+/// serialized anew, without the invalid declarations and the comments between them.
+///
+/// Note that the declarations are HTML source, i.e. character references in them are not decoded.
+///
+/// # Example
+/// ```
+/// use lol_html::{rewrite_str, element, RewriteStrSettings};
+///
+/// let html = rewrite_str(
+///     r#"<div style="color: red;margin:0 !important"></div>"#,
+///     RewriteStrSettings {
+///         element_content_handlers: vec![
+///             element!("div", |el| {
+///                 let mut style = el.style();
+///
+///                 assert_eq!(style.get("margin"), Some("0"));
+///                 assert!(style.is_important("margin"));
+///
+///                 style.set("color", "blue")?;
+///                 style.set_important("background", "url(\"bg.png\")")?;
+///                 style.remove("margin");
+///
+///                 Ok(())
+///             })
+///         ],
+///         ..RewriteStrSettings::new()
+///     }
+/// ).unwrap();
+///
+/// assert_eq!(
+///     html,
+///     r#"<div style="color: blue; background: url(&quot;bg.png&quot;) !important"></div>"#
+/// );
+/// ```
+///
+/// [`Element`]: super::Element
+pub struct InlineStyle<'e, 't> {
+    start_tag: &'e mut StartTag<'t>,
+    declarations: Vec<Declaration>,
+    modified: bool,
+}
+
+impl<'e, 't> InlineStyle<'e, 't> {
+    pub(super) fn new(start_tag: &'e mut StartTag<'t>) -> Self {
+        let mut declarations: Vec<Declaration> = Vec::new();
+
+        let value = start_tag
+            .attributes()
+            .iter()
+            .find(|attr| attr.name() == "style")
+            .map(Attribute::value);
+
+        // NOTE: if the property is declared more than once, the last declaration
+        // wins, unless only the previous one is important.
+        for declaration in parse_declarations(value.as_deref().unwrap_or_default()) {
+            match declarations.iter_mut().find(|d| d.name == declaration.name) {
+                Some(d) if d.important && !declaration.important => (),
+                Some(d) => *d = declaration,
+                None => declarations.push(declaration),
+            }
+        }
+
+        InlineStyle {
+            start_tag,
+            declarations,
+            modified: false,
+        }
+    }
+
+    #[inline]
+    fn find(&self, name: &str) -> Option<&Declaration> {
+        let name = normalize_name(name);
+
+        self.declarations.iter().find(|d| d.name == name)
+    }
+
+    /// Returns the value of the property with the `name`, without the `!important` flag.
+    ///
+    /// Returns `None` if the style doesn't declare the property.
+    #[inline]
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.find(name).map(|d| d.value.as_str())
+    }
+
+    /// Returns `true` if the property with the `name` is declared with the `!important` flag.
+    #[inline]
+    #[must_use]
+    pub fn is_important(&self, name: &str) -> bool {
+        self.find(name).is_some_and(|d| d.important)
+    }
+
+    /// Sets the `value` of the property with the `name`.
+    ///
+    /// If the style already declares the property, its value is replaced in place. Otherwise,
+    /// the declaration is added to the end of the style.
+    #[inline]
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), StyleDeclarationError> {
+        self.set_declaration(name, value, false)
+    }
+
+    /// Sets the `value` of the property with the `name` like [`InlineStyle::set`] does,
+    /// but with the `!important` flag.
+    #[inline]
+    pub fn set_important(&mut self, name: &str, value: &str) -> Result<(), StyleDeclarationError> {
+        self.set_declaration(name, value, true)
+    }
+
+    fn set_declaration(
+        &mut self,
+        name: &str,
+        value: &str,
+        important: bool,
+    ) -> Result<(), StyleDeclarationError> {
+        let mut input = ParserInput::new(name);
+        let mut input = Parser::new(&mut input);
+
+        // NOTE: the escaped identifiers are rejected as well, so the name can be
+        // serialized as is.
+        if !input.expect_ident().is_ok_and(|ident| **ident == *name) || !input.is_exhausted() {
+            return Err(StyleDeclarationError::InvalidPropertyName);
+        }
+
+        let name = normalize_name(name);
+        let value = value.trim_matches(is_css_whitespace);
+
+        // NOTE: the value must not end the declaration or swallow the following one.
+        let is_valid_value = matches!(
+            &*parse_declarations(&format!("a:{value};b:c")),
+            [a, b] if a.value == value && !a.important && a.name == "a" && b.name == "b"
+        );
+
+        if !is_valid_value {
+            return Err(StyleDeclarationError::InvalidValue);
+        }
+
+        let declaration = Declaration {
+            name,
+            value: value.to_owned(),
+            important,
+        };
+
+        match self
+            .declarations
+            .iter_mut()
+            .find(|d| d.name == declaration.name)
+        {
+            Some(d) => *d = declaration,
+            None => self.declarations.push(declaration),
+        }
+
+        self.modified = true;
+
+        Ok(())
+    }
+
+    /// Removes the declaration of the property with the `name` if it is present.
+    #[inline]
+    pub fn remove(&mut self, name: &str) {
+        let name = normalize_name(name);
+
+        if let Some(idx) = self.declarations.iter().position(|d| d.name == name) {
+            self.declarations.remove(idx);
+            self.modified = true;
+        }
+    }
+
+    /// Returns an iterator over the names and values of the declared properties,
+    /// in the order of their declaration.
+    #[inline]
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> + '_ {
+        self.declarations
+            .iter()
+            .map(|d| (d.name.as_str(), d.value.as_str()))
+    }
+}
+
+impl Drop for InlineStyle<'_, '_> {
+    fn drop(&mut self) {
+        if self.modified {
+            let css = self
+                .declarations
+                .iter()
+                .map(|d| {
+                    let important = if d.important { " !important" } else { "" };
+
+                    format!("{}: {}{important}", d.name, d.value)
+                })
+                .collect::<Vec<_>>()
+                .join("; ");
+
+            // NOTE: the attribute name is always valid.
+            let _ = self.start_tag.set_attribute("style", &css);
+        }
+    }
+}
+
+impl Debug for InlineStyle<'_, '_> {
+    #[cold]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
@@ -0,0 +1,231 @@
+use super::ContentType;
+use crate::html::escape_body_text;
+use std::fmt::{self, Debug};
+use std::ops::Range;
+
+/// The text content of an element, joined from all the text chunks of the element descendants.
+///
+/// Unlike the text of a [`TextChunk`], the inner text spans the element descendants, so it's
+/// possible to search and replace text that is split by tags, e.g. `brown fox` in
+/// `<p>The <b>brown</b> fox</p>`. The replacements are mapped back to the text chunks the
+/// replaced text belongs to, so the markup of the element is preserved.
+///
+/// The output of the element is held back until the element ends and the text is buffered in
+/// memory, which is limited by [`MemorySettings::max_allowed_memory_usage`].
+///
+/// Note that the text is HTML source, i.e. character references in it are not decoded. Text
+/// chunks removed or replaced by [`TextChunk`] handlers are not a part of the inner text.
+///
+/// # Example
+/// ```
+/// use lol_html::{rewrite_str, inner_text, RewriteStrSettings};
+/// use lol_html::html_content::ContentType;
+///
+/// let html = rewrite_str(
+///     r#"<p>The <b>brown</b> fox</p>"#,
+///     RewriteStrSettings {
+///         element_content_handlers: vec![
+///             inner_text!("p", |_, text| {
+///                 if let Some(start) = text.as_str().find("brown fox") {
+///                     text.replace_range(start..start + 9, "red panda", ContentType::Text);
+///                 }
+///
+///                 Ok(())
+///             })
+///         ],
+///         ..RewriteStrSettings::new()
+///     }
+/// ).unwrap();
+///
+/// assert_eq!(html, r#"<p>The <b>red panda</b></p>"#);
+/// ```
+///
+/// [`TextChunk`]: crate::html_content::TextChunk
+/// [`MemorySettings::max_allowed_memory_usage`]: crate::MemorySettings::max_allowed_memory_usage
+pub struct InnerText {
+    text: String,
+    text_chunk_ranges: Vec<Range<usize>>,
+    modified: bool,
+}
+
+impl InnerText {
+    #[must_use]
+    pub(crate) fn new<'t>(text_chunks: impl Iterator<Item = &'t str>) -> Self {
+        let mut text = String::new();
+        let mut text_chunk_ranges = Vec::new();
+
+        for chunk in text_chunks {
+            let start = text.len();
+
+            text.push_str(chunk);
+            text_chunk_ranges.push(start..text.len());
+        }
+
+        InnerText {
+            text,
+            text_chunk_ranges,
+            modified: false,
+        }
+    }
+
+    /// Returns the inner text.
+    #[inline]
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+
+    /// Returns the byte ranges of the text chunks in the inner text.
+    #[inline]
+    #[must_use]
+    pub fn text_chunk_ranges(&self) -> &[Range<usize>] {
+        &self.text_chunk_ranges
+    }
+
+    /// Replaces the byte `range` of the inner text with the `content`.
+    ///
+    /// The content is inserted into the text chunk the start of the range belongs to, and
+    /// the rest of the range is removed from the following text chunks. The ranges passed to
+    /// the consequent calls refer to the modified text, the same way as for
+    /// [`String::replace_range`].
+    ///
+    /// # Panics
+    /// Panics if the range is out of bounds or doesn't lie on [`char`] boundaries.
+    pub fn replace_range(&mut self, range: Range<usize>, content: &str, content_type: ContentType) {
+        let Range { start, end } = range;
+
+        assert!(
+            start <= end && self.text.is_char_boundary(start) && self.text.is_char_boundary(end),
+            "Invalid range {start}..{end} of the inner text"
+        );
+
+        // NOTE: the content is inserted into the chunk that contains the start of the range,
+        // or into the chunk that ends there, if the start lies between the chunks.
+        let Some(owner_idx) = self
+            .text_chunk_ranges
+            .iter()
+            .position(|r| r.start <= start && start < r.end)
+            .or_else(|| self.text_chunk_ranges.iter().position(|r| r.end == start))
+        else {
+            return;
+        };
+
+        let content = match content_type {
+            ContentType::Html => content.to_owned(),
+            ContentType::Text => {
+                let mut escaped = String::with_capacity(content.len());
+
+                escape_body_text(content, &mut |s| escaped.push_str(s));
+
+                escaped
+            }
+        };
+
+        let shift = |pos: usize| pos.max(end) - (end - start) + content.len();
+
+        for (idx, r) in self.text_chunk_ranges.iter_mut().enumerate() {
+            if idx == owner_idx {
+                r.end = shift(r.end);
+            } else if idx > owner_idx {
+                *r = shift(r.start)..shift(r.end);
+            }
+        }
+
+        self.text.replace_range(start..end, &content);
+        self.modified = true;
+    }
+
+    /// Returns the modified texts of the text chunks or `None` if the text isn't modified.
+    pub(crate) fn into_modified_text_chunks(self) -> Option<impl Iterator<Item = String>> {
+        let InnerText {
+            text,
+            text_chunk_ranges,
+            modified,
+        } = self;
+
+        modified.then(move || {
+            text_chunk_ranges
+                .into_iter()
+                .map(move |r| text[r].to_owned())
+        })
+    }
+}
+
+impl Debug for InnerText {
+    #[cold]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InnerText")
+            .field("text", &self.text)
+            .field("text_chunk_ranges", &self.text_chunk_ranges)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inner_text(chunks: &[&str]) -> InnerText {
+        InnerText::new(chunks.iter().copied())
+    }
+
+    fn modified_chunks(text: InnerText) -> Vec<String> {
+        text.into_modified_text_chunks().unwrap().collect()
+    }
+
+    #[test]
+    fn joined_text() {
+        let text = inner_text(&["The ", "brown", " fox"]);
+
+        assert_eq!(text.as_str(), "The brown fox");
+        assert_eq!(text.text_chunk_ranges(), [0..4, 4..9, 9..13]);
+        assert!(text.into_modified_text_chunks().is_none());
+    }
+
+    #[test]
+    fn replace_within_chunk() {
+        let mut text = inner_text(&["The ", "brown", " fox"]);
+
+        text.replace_range(5..8, "<i>", ContentType::Text);
+
+        assert_eq!(text.as_str(), "The b&lt;i&gt;n fox");
+        assert_eq!(text.text_chunk_ranges(), [0..4, 4..15, 15..19]);
+        assert_eq!(modified_chunks(text), ["The ", "b&lt;i&gt;n", " fox"]);
+    }
+
+    #[test]
+    fn replace_across_chunks() {
+        let mut text = inner_text(&["The ", "brown", " fox"]);
+
+        text.replace_range(2..11, "<b>", ContentType::Html);
+
+        assert_eq!(text.as_str(), "Th<b>ox");
+        assert_eq!(modified_chunks(text), ["Th<b>", "", "ox"]);
+    }
+
+    #[test]
+    fn insert_between_chunks() {
+        let mut text = inner_text(&["The ", "brown", " fox"]);
+
+        text.replace_range(4..4, "quick ", ContentType::Text);
+        text.replace_range(19..19, "!", ContentType::Text);
+
+        assert_eq!(text.as_str(), "The quick brown fox!");
+        assert_eq!(modified_chunks(text), ["The ", "quick brown", " fox!"]);
+    }
+
+    #[test]
+    fn no_text_chunks() {
+        let mut text = inner_text(&[]);
+
+        text.replace_range(0..0, "foo", ContentType::Text);
+
+        assert_eq!(text.as_str(), "");
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid range 1..2 of the inner text")]
+    fn char_boundary() {
+        inner_text(&["☃"]).replace_range(1..2, "", ContentType::Text);
+    }
+}
@@ -1,14 +1,20 @@
 use std::any::Any;
 
-pub(crate) use self::mutations::{Mutations, StringChunk};
+pub(crate) use self::mutations::{DynamicString, Mutations, StringChunk};
+pub(crate) use self::source_location::SourceLocator;
 pub(crate) use self::text_decoder::TextDecoder;
 pub(crate) use self::text_encoder::{IncompleteUtf8Resync, TextEncoder};
 
+pub use self::class_list::{ClassList, ClassNameError};
 pub use self::document_end::*;
 pub use self::element::*;
+pub use self::inline_style::{InlineStyle, StyleDeclarationError};
+pub use self::inner_text::InnerText;
 pub use self::mutations::{ContentType, StreamingHandler};
+pub use self::source_location::SourceLocation;
 pub use self::streaming_sink::StreamingHandlerSink;
 pub use self::text_encoder::Utf8Error;
+pub use self::text_node::TextNode;
 pub use self::tokens::*;
 
 /// Data that can be attached to a rewritable unit by a user and shared between content handler
@@ -87,11 +93,16 @@ macro_rules! impl_user_data {
 #[macro_use]
 mod mutations;
 
+mod class_list;
 mod document_end;
 mod element;
+mod inline_style;
+mod inner_text;
+mod source_location;
 mod streaming_sink;
 mod text_decoder;
 mod text_encoder;
+mod text_node;
 mod tokens;
 
 #[cfg(test)]
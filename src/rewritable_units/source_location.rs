@@ -0,0 +1,282 @@
+use crate::base::Range;
+use memchr::memchr2_iter;
+use std::fmt::{self, Display};
+use std::ops;
+
+/// The location of a rewritable unit in the input of the rewriter, e.g. to report
+/// the problems found in the document.
+///
+/// The location is given in terms of the input bytes, so it doesn't change if the preceding
+/// content is rewritten. Lines start after `\n`, `\r` or `\r\n`, and both lines and columns
+/// are counted from one, with columns counted in bytes.
+///
+/// # Example
+/// ```
+/// use lol_html::{rewrite_str, element, RewriteStrSettings};
+///
+/// rewrite_str(
+///     "<ul>\n  <li>foo</li>\n</ul>",
+///     RewriteStrSettings {
+///         element_content_handlers: vec![
+///             element!("li", |el| {
+///                 let location = el.source_location();
+///
+///                 assert_eq!(location.bytes(), 7..11);
+///                 assert_eq!((location.line(), location.column()), (2, 3));
+///
+///                 Ok(())
+///             })
+///         ],
+///         ..RewriteStrSettings::new()
+///     }
+/// ).unwrap();
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SourceLocation {
+    start: usize,
+    end: usize,
+    line: usize,
+    column: usize,
+}
+
+impl SourceLocation {
+    #[inline]
+    pub(crate) const fn new(start: usize, end: usize, line: usize, column: usize) -> Self {
+        SourceLocation {
+            start,
+            end,
+            line,
+            column,
+        }
+    }
+
+    /// Returns the byte range of the unit in the input.
+    #[inline]
+    #[must_use]
+    pub const fn bytes(&self) -> ops::Range<usize> {
+        self.start..self.end
+    }
+
+    /// Returns the line, on which the unit starts.
+    #[inline]
+    #[must_use]
+    pub const fn line(&self) -> usize {
+        self.line
+    }
+
+    /// Returns the column, at which the unit starts.
+    #[inline]
+    #[must_use]
+    pub const fn column(&self) -> usize {
+        self.column
+    }
+}
+
+impl Display for SourceLocation {
+    #[cold]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// Tracks the lines of the input, as the chunks of it are consumed by the parser.
+///
+/// The locations are expected to be requested in the order of the input, since the consumed
+/// chunks are not retained. However, the locations preceding the last requested one are still
+/// reported correctly if they are on the same line with it.
+pub(crate) struct SourceLocator {
+    chunk_offset: usize,
+    offset: usize,
+    line: usize,
+    line_start: usize,
+    /// `true` if the input consumed so far ends with `\r`, whose line break is counted once it's
+    /// known that it isn't followed by `\n`, so the `\n` of `\r\n` is on the line of `\r`.
+    after_cr: bool,
+}
+
+impl SourceLocator {
+    #[inline]
+    #[must_use]
+    pub const fn new() -> Self {
+        SourceLocator {
+            chunk_offset: 0,
+            offset: 0,
+            line: 1,
+            line_start: 0,
+            after_cr: false,
+        }
+    }
+
+    /// Returns the offset in the input of the `pos` in the current chunk.
+    #[inline]
+    pub const fn stream_offset(&self, pos: usize) -> usize {
+        self.chunk_offset + pos
+    }
+
+    /// Moves past the line breaks, that precede the `offset` in the input.
+    fn advance(&mut self, chunk: &[u8], offset: usize) {
+        if offset <= self.offset {
+            return;
+        }
+
+        let bytes = chunk
+            .get(self.offset - self.chunk_offset..offset - self.chunk_offset)
+            .unwrap_or_default();
+
+        debug_assert_eq!(bytes.len(), offset - self.offset);
+
+        if self.after_cr && bytes.first().is_some_and(|&b| b != b'\n') {
+            self.break_line(self.offset);
+        }
+
+        for pos in memchr2_iter(b'\n', b'\r', bytes) {
+            // NOTE: `\r\n` is a single line break, that ends after `\n`.
+            let ends_line = bytes[pos] == b'\n' || bytes.get(pos + 1).is_some_and(|&b| b != b'\n');
+
+            if ends_line {
+                self.break_line(self.offset + pos + 1);
+            }
+        }
+
+        if let Some(&last) = bytes.last() {
+            self.after_cr = last == b'\r';
+        }
+
+        self.offset = offset;
+    }
+
+    #[inline]
+    fn break_line(&mut self, line_start: usize) {
+        self.line += 1;
+        self.line_start = line_start;
+        self.after_cr = false;
+    }
+
+    /// Returns the location of the `start..end` range of the input, that ends
+    /// in the current `chunk` or precedes it.
+    pub fn locate_in_stream(&mut self, chunk: &[u8], start: usize, end: usize) -> SourceLocation {
+        self.advance(chunk, start);
+
+        // NOTE: the unit, that follows `\r`, is on the next line, unless it starts with `\n`.
+        if self.after_cr && start == self.offset {
+            let next = start
+                .checked_sub(self.chunk_offset)
+                .and_then(|pos| chunk.get(pos));
+
+            if next.is_some_and(|&b| b != b'\n') {
+                self.break_line(start);
+            }
+        }
+
+        debug_assert!(start >= self.line_start);
+
+        let column = start.saturating_sub(self.line_start) + 1;
+
+        SourceLocation::new(start, end, self.line, column)
+    }
+
+    /// Returns the location of the `range` of the current `chunk`.
+    #[inline]
+    pub fn locate(&mut self, chunk: &[u8], range: Range) -> SourceLocation {
+        self.locate_in_stream(
+            chunk,
+            self.stream_offset(range.start),
+            self.stream_offset(range.end),
+        )
+    }
+
+    /// Moves past the line breaks, that precede the `pos` in the current `chunk`.
+    #[inline]
+    pub fn skip_to(&mut self, chunk: &[u8], pos: usize) {
+        self.advance(chunk, self.stream_offset(pos));
+    }
+
+    /// Moves to the next chunk, that starts with the unconsumed bytes of the current one.
+    #[inline]
+    pub fn chunk_consumed(&mut self, chunk: &[u8], consumed_byte_count: usize) {
+        self.skip_to(chunk, consumed_byte_count);
+        self.chunk_offset += consumed_byte_count;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(start: usize, end: usize) -> Range {
+        Range { start, end }
+    }
+
+    fn line_and_column(location: SourceLocation) -> (usize, usize) {
+        (location.line(), location.column())
+    }
+
+    #[test]
+    fn line_breaks() {
+        let chunk = b"a\nbc\r\nd\re\n\nf";
+        let mut locator = SourceLocator::new();
+
+        for (pos, expected) in [
+            (0, (1, 1)),
+            (2, (2, 1)),
+            (3, (2, 2)),
+            (4, (2, 3)),
+            (5, (2, 4)),
+            (6, (3, 1)),
+            (8, (4, 1)),
+            (11, (6, 1)),
+        ] {
+            let location = locator.locate(chunk, range(pos, pos + 1));
+
+            assert_eq!(location.bytes(), pos..pos + 1);
+            assert_eq!(line_and_column(location), expected, "{pos}");
+        }
+    }
+
+    #[test]
+    fn chunks() {
+        let mut locator = SourceLocator::new();
+
+        locator.chunk_consumed(b"ab\r", 3);
+        locator.chunk_consumed(b"\ncd\n", 2);
+
+        let location = locator.locate(b"d\nefg", range(3, 4));
+
+        assert_eq!(location.bytes(), 8..9);
+        assert_eq!(line_and_column(location), (3, 2));
+        assert_eq!(location.to_string(), "3:2");
+    }
+
+    #[test]
+    fn line_breaks_written_byte_by_byte() {
+        let input = b"a\r\nb\rc\r\r\n\nd";
+        let mut in_one_piece = SourceLocator::new();
+        let mut byte_by_byte = SourceLocator::new();
+
+        for pos in 0..input.len() {
+            let expected = in_one_piece.locate(input, range(pos, pos + 1));
+            let chunk = &input[pos..=pos];
+            let location = byte_by_byte.locate(chunk, range(0, 1));
+
+            byte_by_byte.chunk_consumed(chunk, 1);
+
+            assert_eq!(location, expected, "{pos}");
+        }
+
+        let crlf_lf = SourceLocator::new().locate(input, range(2, 3));
+
+        assert_eq!(line_and_column(crlf_lf), (1, 3));
+    }
+
+    #[test]
+    fn preceding_location_on_the_same_line() {
+        let mut locator = SourceLocator::new();
+
+        locator.skip_to(b"a\nbcd", 5);
+
+        let location = locator.locate_in_stream(&[], 3, 5);
+
+        assert_eq!(location.bytes(), 3..5);
+        assert_eq!(line_and_column(location), (2, 2));
+    }
+}
@@ -1,11 +1,17 @@
 use crate::base::SharedEncoding;
 use crate::rewriter::RewritingError;
 use encoding_rs::{CoderResult, Decoder, Encoding, UTF_8};
+use std::borrow::Cow;
+
+/// Receives the decoded text along with the input bytes consumed since the previous call.
+type TextOutputHandler<'h> =
+    dyn FnMut(&str, &[u8], bool, &'static Encoding) -> Result<(), RewritingError> + 'h;
 
 pub(crate) struct TextDecoder {
     encoding: SharedEncoding,
     pending_text_streaming_decoder: Option<Decoder>,
     text_buffer: String,
+    unreported_input: Vec<u8>,
 }
 
 impl TextDecoder {
@@ -17,13 +23,20 @@ impl TextDecoder {
             pending_text_streaming_decoder: None,
             // TODO make adjustable
             text_buffer: String::from_utf8(vec![0u8; 1024]).unwrap(),
+            unreported_input: Vec::new(),
         }
     }
 
+    /// Returns `true` if the text node is not finished yet.
+    #[inline]
+    pub const fn has_pending(&self) -> bool {
+        self.pending_text_streaming_decoder.is_some()
+    }
+
     #[inline]
     pub fn flush_pending(
         &mut self,
-        output_handler: &mut dyn FnMut(&str, bool, &'static Encoding) -> Result<(), RewritingError>,
+        output_handler: &mut TextOutputHandler<'_>,
     ) -> Result<(), RewritingError> {
         if self.pending_text_streaming_decoder.is_some() {
             self.feed_text(&[], true, output_handler)?;
@@ -36,7 +49,7 @@ impl TextDecoder {
         &mut self,
         mut raw_input: &[u8],
         last_in_text_node: bool,
-        output_handler: &mut dyn FnMut(&str, bool, &'static Encoding) -> Result<(), RewritingError>,
+        output_handler: &mut TextOutputHandler<'_>,
     ) -> Result<(), RewritingError> {
         let encoding = self.encoding.get();
 
@@ -44,7 +57,7 @@ impl TextDecoder {
             raw_input = rest;
             let really_last = last_in_text_node && rest.is_empty();
 
-            (output_handler)(utf8_text, really_last, encoding)?;
+            (output_handler)(utf8_text, utf8_text.as_bytes(), really_last, encoding)?;
 
             if really_last {
                 debug_assert!(self.pending_text_streaming_decoder.is_none());
@@ -62,18 +75,31 @@ impl TextDecoder {
                 decoder.decode_to_str(raw_input, buffer, last_in_text_node);
 
             let finished_decoding = status == CoderResult::InputEmpty;
+            let read_input = raw_input.get(..read).unwrap_or_default();
 
             if written > 0 || last_in_text_node {
                 // the last call to feed_text() may make multiple calls to output_handler,
                 // but only one call to output_handler can be *the* last one.
                 let really_last = last_in_text_node && finished_decoding;
 
+                let input = if self.unreported_input.is_empty() {
+                    Cow::Borrowed(read_input)
+                } else {
+                    self.unreported_input.extend_from_slice(read_input);
+                    Cow::Owned(std::mem::take(&mut self.unreported_input))
+                };
+
                 (output_handler)(
                     // this will always be in bounds, but unwrap_or_default optimizes better
                     buffer.get(..written).unwrap_or_default(),
+                    &input,
                     really_last,
                     encoding,
                 )?;
+            } else {
+                // NOTE: the bytes of an incomplete character are consumed, but are not
+                // written to the output until the rest of the character is fed.
+                self.unreported_input.extend_from_slice(read_input);
             }
 
             if finished_decoding {
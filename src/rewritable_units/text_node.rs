@@ -0,0 +1,280 @@
+use super::{ContentType, TextChunk};
+use crate::html::TextType;
+use std::borrow::Cow;
+use std::fmt::{self, Debug};
+
+/// An HTML text node.
+///
+/// Unlike [`TextChunk`], the text node has the whole content of the node, as the rewriter buffers
+/// the chunks of the node until its last chunk. The buffered content is limited by
+/// [`MemorySettings::max_allowed_memory_usage`].
+///
+/// Modifications of the text node are applied to the whole content of the node at once,
+/// regardless of how many chunks it has been split into.
+///
+/// The text node handlers are invoked before the [`TextChunk`] handlers, which then receive
+/// the whole content of the node as its last chunk.
+///
+/// # Example
+/// ```
+/// use lol_html::{HtmlRewriter, Settings, text_node};
+/// use lol_html::html_content::ContentType;
+///
+/// let mut output = vec![];
+///
+/// {
+///     let mut rewriter = HtmlRewriter::new(
+///         Settings {
+///             element_content_handlers: vec![
+///                 text_node!("div", |t| {
+///                     if t.as_str() == "Hello world" {
+///                         t.replace("Goodbye world", ContentType::Text);
+///                     }
+///
+///                     Ok(())
+///                 })
+///             ],
+///             ..Settings::new()
+///         },
+///         |c: &[u8]| output.extend_from_slice(c)
+///     );
+///
+///     rewriter.write(b"<div>He").unwrap();
+///     rewriter.write(b"llo w").unwrap();
+///     rewriter.write(b"orld</div>").unwrap();
+///     rewriter.end().unwrap();
+/// }
+///
+/// assert_eq!(String::from_utf8(output).unwrap(), "<div>Goodbye world</div>");
+/// ```
+///
+/// [`MemorySettings::max_allowed_memory_usage`]: crate::MemorySettings::max_allowed_memory_usage
+pub struct TextNode<'r, 't> {
+    last_chunk: &'r mut TextChunk<'t>,
+}
+
+impl<'r, 't> TextNode<'r, 't> {
+    /// Creates the text node from the last chunk of the node, that has
+    /// the whole content of the node as its text.
+    #[inline]
+    #[must_use]
+    pub(crate) fn new(last_chunk: &'r mut TextChunk<'t>) -> Self {
+        TextNode { last_chunk }
+    }
+
+    /// Returns the textual content of the node.
+    #[inline]
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        self.last_chunk.as_str()
+    }
+
+    /// Returns the textual content of the node with [character references] decoded,
+    /// e.g. `&lt;b&gt;` is returned as `<b>`.
+    ///
+    /// The text is returned as is if its [type] doesn't allow character references
+    /// (e.g. text inside a `<script>` element).
+    ///
+    /// [character references]: https://html.spec.whatwg.org/multipage/syntax.html#character-references
+    /// [type]: #method.text_type
+    #[inline]
+    #[must_use]
+    pub fn decoded_text(&self) -> Cow<'_, str> {
+        self.last_chunk.decoded_text()
+    }
+
+    /// Sets the textual content of the node.
+    ///
+    /// Unlike [`replace`], the content is emitted as is, without HTML-escaping.
+    ///
+    /// [`replace`]: #method.replace
+    #[inline]
+    pub fn set_str(&mut self, text: String) {
+        self.last_chunk.set_str(text);
+    }
+
+    /// Returns the type of the text in the node.
+    ///
+    /// See [`TextChunk::text_type`].
+    #[inline]
+    #[must_use]
+    pub fn text_type(&self) -> TextType {
+        self.last_chunk.text_type()
+    }
+
+    /// Inserts `content` before the text node.
+    ///
+    /// Consequent calls to the method append `content` to the previously inserted content.
+    #[inline]
+    pub fn before(&mut self, content: &str, content_type: ContentType) {
+        self.last_chunk.before(content, content_type);
+    }
+
+    /// Inserts `content` after the text node.
+    ///
+    /// Consequent calls to the method prepend `content` to the previously inserted content.
+    #[inline]
+    pub fn after(&mut self, content: &str, content_type: ContentType) {
+        self.last_chunk.after(content, content_type);
+    }
+
+    /// Replaces the text node with the `content`.
+    ///
+    /// Consequent calls to the method overwrite previous replacement content.
+    #[inline]
+    pub fn replace(&mut self, content: &str, content_type: ContentType) {
+        self.last_chunk.replace(content, content_type);
+    }
+
+    /// Removes the text node.
+    #[inline]
+    pub fn remove(&mut self) {
+        self.last_chunk.remove();
+    }
+
+    /// Returns `true` if the text node has been replaced or removed.
+    #[inline]
+    #[must_use]
+    pub fn removed(&self) -> bool {
+        self.last_chunk.removed()
+    }
+}
+
+impl Debug for TextNode<'_, '_> {
+    #[cold]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TextNode")
+            .field("text", &self.as_str())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::html_content::*;
+    use crate::rewritable_units::test_utils::*;
+    use encoding_rs::{Encoding, UTF_8};
+
+    fn rewrite_text_node(
+        html: &[u8],
+        encoding: &'static Encoding,
+        mut handler: impl FnMut(&mut TextNode<'_, '_>),
+    ) -> String {
+        let mut handler_called = false;
+
+        let output = rewrite_html(
+            html,
+            encoding,
+            vec![text_node!("div", |t| {
+                handler_called = true;
+                handler(t);
+                Ok(())
+            })],
+            vec![],
+        );
+
+        assert!(handler_called);
+
+        output
+    }
+
+    #[test]
+    fn whole_text_node() {
+        for (html, enc) in encoded("<div>Hey &amp; <b>42</b> 1 &lt; 2 ☃</div>") {
+            let mut nodes = Vec::new();
+
+            let output = rewrite_text_node(&html, enc, |t| {
+                nodes.push((t.as_str().to_string(), t.decoded_text().into_owned()));
+            });
+
+            assert_eq!(
+                nodes,
+                [
+                    ("Hey &amp; ".into(), "Hey & ".into()),
+                    ("42".into(), "42".into()),
+                    (" 1 &lt; 2 ☃".into(), " 1 < 2 ☃".into()),
+                ]
+            );
+
+            assert_eq!(output, "<div>Hey &amp; <b>42</b> 1 &lt; 2 ☃</div>");
+        }
+    }
+
+    #[test]
+    fn in_place_text_modifications() {
+        for (html, enc) in encoded("<div>foo<b>bar</b></div>") {
+            let output = rewrite_text_node(&html, enc, |t| {
+                let text = t.as_str().repeat(2);
+
+                t.set_str(text);
+            });
+
+            assert_eq!(output, "<div>foofoo<b>barbar</b></div>");
+        }
+    }
+
+    #[test]
+    fn insert_content_before_and_after() {
+        for (html, enc) in encoded("<div>Hello ☃</div>") {
+            let output = rewrite_text_node(&html, enc, |t| {
+                t.before("<span>", ContentType::Html);
+                t.after("</span>", ContentType::Html);
+                t.after("<!-- ☃ -->", ContentType::Text);
+            });
+
+            assert_eq!(output, "<div><span>Hello ☃&lt;!-- ☃ --&gt;</span></div>");
+        }
+    }
+
+    #[test]
+    fn replace() {
+        for (html, enc) in encoded("<div>Hello <b>world</b></div>") {
+            let output = rewrite_text_node(&html, enc, |t| {
+                if t.as_str() == "world" {
+                    t.replace("<i>42</i>", ContentType::Html);
+
+                    assert!(t.removed());
+                }
+            });
+
+            assert_eq!(output, "<div>Hello <b><i>42</i></b></div>");
+        }
+    }
+
+    #[test]
+    fn remove() {
+        for (html, enc) in encoded("<div>Hello <b>world</b></div>") {
+            let output = rewrite_text_node(&html, enc, |t| {
+                assert!(!t.removed());
+
+                t.remove();
+
+                assert!(t.removed());
+            });
+
+            assert_eq!(output, "<div><b></b></div>");
+        }
+    }
+
+    #[test]
+    fn text_type() {
+        let output = rewrite_html(
+            b"<div>Hello</div><script>1 < 2</script>",
+            UTF_8,
+            vec![
+                text_node!("div", |t| {
+                    assert_eq!(t.text_type(), TextType::Data);
+                    Ok(())
+                }),
+                text_node!("script", |t| {
+                    assert_eq!(t.text_type(), TextType::ScriptData);
+                    assert_eq!(t.decoded_text(), "1 < 2");
+                    Ok(())
+                }),
+            ],
+            vec![],
+        );
+
+        assert_eq!(output, "<div>Hello</div><script>1 < 2</script>");
+    }
+}
@@ -1,10 +1,13 @@
 use crate::base::Bytes;
 use crate::errors::RewritingError;
-use crate::html::escape_double_quotes_only;
+use crate::html::{decode_character_references, escape_quotes_only};
 use crate::parser::AttributeBuffer;
 use crate::rewritable_units::Serialize;
 use encoding_rs::Encoding;
+use memchr::memchr;
+use std::borrow::Cow;
 use std::cell::OnceCell;
+use std::cmp::Ordering;
 use std::fmt::{self, Debug};
 use std::ops::Deref;
 use thiserror::Error;
@@ -29,6 +32,21 @@ pub enum AttributeNameError {
     UnencodableCharacter,
 }
 
+/// The quotes around the values of the attributes that are serialized anew, i.e. the added,
+/// modified or renamed ones. The rest of the attributes keep their source markup.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum AttributeQuoteStyle {
+    /// Double quotes, e.g. `name="value"`.
+    #[default]
+    Double,
+    /// Single quotes, e.g. `name='value'`.
+    Single,
+    /// No quotes if the value allows that (e.g. `name=value`), and no value if it's empty
+    /// (e.g. `name`). Otherwise, the double quotes, unless only the single quotes don't need
+    /// to be escaped in the value (e.g. `name='say "hi"'`).
+    Minimal,
+}
+
 /// An attribute of an [`Element`].
 ///
 /// This is an immutable representation of an attribute. To modify element's attributes use
@@ -59,6 +77,15 @@ impl<'i> Attribute<'i> {
         }
     }
 
+    pub(crate) fn into_owned(self) -> Attribute<'static> {
+        Attribute {
+            name: self.name.into_owned(),
+            value: self.value.into_owned(),
+            raw: self.raw.map(Bytes::into_owned),
+            encoding: self.encoding,
+        }
+    }
+
     #[inline]
     fn name_from_str(
         name: &str,
@@ -119,25 +146,89 @@ impl<'i> Attribute<'i> {
         self.value.as_string(self.encoding)
     }
 
+    /// Returns the value of the attribute with [character references] decoded,
+    /// e.g. `a&amp;b` is returned as `a&b`.
+    ///
+    /// The attribute value rules of the HTML parser are followed, so named references
+    /// without a trailing semicolon, that are followed by `=` or an alphanumeric character,
+    /// are left as is (e.g. `?a=1&copy=2`).
+    ///
+    /// [character references]: https://html.spec.whatwg.org/multipage/syntax.html#character-references
+    #[inline]
+    #[must_use]
+    pub fn decoded_value(&self) -> Cow<'_, str> {
+        match self.value.as_str(self.encoding) {
+            Cow::Borrowed(value) => decode_character_references(value, true),
+            Cow::Owned(value) => match decode_character_references(&value, true) {
+                Cow::Borrowed(_) => Cow::Owned(value),
+                Cow::Owned(decoded) => Cow::Owned(decoded),
+            },
+        }
+    }
+
     #[inline]
     fn set_value(&mut self, value: &str) {
         self.value = Bytes::from_str(value, self.encoding).into_owned();
         self.raw = None;
     }
-}
 
-impl Serialize for &Attribute<'_> {
     #[inline]
-    fn into_bytes(self, output_handler: &mut dyn FnMut(&[u8])) -> Result<(), RewritingError> {
+    fn set_name(&mut self, name: Bytes<'static>) {
+        self.name = name;
+        self.raw = None;
+    }
+
+    fn serialize(&self, quote_style: AttributeQuoteStyle, output_handler: &mut dyn FnMut(&[u8])) {
         if let Some(raw) = self.raw.as_ref() {
             output_handler(raw);
-        } else {
-            output_handler(&self.name);
-            output_handler(b"=\"");
-            escape_double_quotes_only(&self.value, output_handler);
-            output_handler(b"\"");
+            return;
         }
-        Ok(())
+
+        output_handler(&self.name);
+
+        let quote = match quote_style {
+            AttributeQuoteStyle::Double => b'"',
+            AttributeQuoteStyle::Single => b'\'',
+            AttributeQuoteStyle::Minimal => {
+                if self.value.is_empty() {
+                    return;
+                }
+
+                // NOTE: the characters that can't be used in the unquoted attribute values.
+                // See: https://html.spec.whatwg.org/multipage/syntax.html#unquoted
+                let needs_quotes = self.value.iter().any(|&b| {
+                    matches!(
+                        b,
+                        b' ' | b'\n'
+                            | b'\r'
+                            | b'\t'
+                            | b'\x0C'
+                            | b'"'
+                            | b'\''
+                            | b'='
+                            | b'<'
+                            | b'>'
+                            | b'`'
+                    )
+                });
+
+                if !needs_quotes {
+                    output_handler(b"=");
+                    output_handler(&self.value);
+                    return;
+                }
+
+                if memchr(b'"', &self.value).is_some() && memchr(b'\'', &self.value).is_none() {
+                    b'\''
+                } else {
+                    b'"'
+                }
+            }
+        };
+
+        output_handler(&[b'=', quote]);
+        escape_quotes_only(&self.value, quote, output_handler);
+        output_handler(&[quote]);
     }
 }
 
@@ -156,6 +247,7 @@ pub(crate) struct Attributes<'i> {
     attribute_buffer: &'i AttributeBuffer,
     items: OnceCell<Vec<Attribute<'i>>>,
     encoding: &'static Encoding,
+    quote_style: AttributeQuoteStyle,
 }
 
 impl<'i> Attributes<'i> {
@@ -171,6 +263,7 @@ impl<'i> Attributes<'i> {
             attribute_buffer,
             items: OnceCell::default(),
             encoding,
+            quote_style: AttributeQuoteStyle::default(),
         }
     }
 
@@ -193,6 +286,85 @@ impl<'i> Attributes<'i> {
         Ok(())
     }
 
+    /// Inserts the attribute at the `index` (or at the end, if the `index` is out of bounds),
+    /// replacing the attribute with the same `name`, if there is one.
+    pub fn insert_attribute(
+        &mut self,
+        index: usize,
+        name: &str,
+        value: &str,
+        encoding: &'static Encoding,
+    ) -> Result<(), AttributeNameError> {
+        let name = name.to_ascii_lowercase();
+        let attr = Attribute::try_from(&name, value, encoding)?;
+
+        self.remove_attribute(&name);
+
+        let items = self.as_mut_vec();
+
+        items.insert(index.min(items.len()), attr);
+
+        Ok(())
+    }
+
+    /// Renames the attribute, replacing the attribute with the `new_name`, if there is one.
+    /// Returns `false` if there is no attribute with the `name`.
+    pub fn rename_attribute(
+        &mut self,
+        name: &str,
+        new_name: &str,
+        encoding: &'static Encoding,
+    ) -> Result<bool, AttributeNameError> {
+        let name = name.to_ascii_lowercase();
+        let new_name = new_name.to_ascii_lowercase();
+        let new_name_bytes = Attribute::name_from_str(&new_name, encoding)?;
+
+        if !self.iter().any(|attr| attr.name() == name) {
+            return Ok(false);
+        }
+
+        if new_name != name {
+            self.remove_attribute(&new_name);
+        }
+
+        let items = self.as_mut_vec();
+
+        if let Some(attr) = items.iter_mut().find(|attr| attr.name() == name) {
+            attr.set_name(new_name_bytes);
+        }
+
+        Ok(true)
+    }
+
+    /// Sorts the attributes with the stable sort. Returns `false` if they are already sorted.
+    pub fn sort_attributes_by(
+        &mut self,
+        mut compare: impl FnMut(&Attribute<'i>, &Attribute<'i>) -> Ordering,
+    ) -> bool {
+        let items = self.as_mut_vec();
+
+        if items
+            .windows(2)
+            .all(|w| compare(&w[0], &w[1]) != Ordering::Greater)
+        {
+            return false;
+        }
+
+        items.sort_by(compare);
+
+        true
+    }
+
+    #[inline]
+    pub const fn quote_style(&self) -> AttributeQuoteStyle {
+        self.quote_style
+    }
+
+    #[inline]
+    pub fn set_quote_style(&mut self, quote_style: AttributeQuoteStyle) {
+        self.quote_style = quote_style;
+    }
+
     pub fn remove_attribute(&mut self, name: &str) -> bool {
         let name = name.to_ascii_lowercase();
         let items = self.as_mut_vec();
@@ -210,6 +382,26 @@ impl<'i> Attributes<'i> {
         false
     }
 
+    /// Detaches the attributes from the parsed input, e.g. to keep them
+    /// after the input chunk is gone.
+    pub fn into_owned(mut self) -> Attributes<'static> {
+        static EMPTY_INPUT: Bytes<'static> = Bytes::from_static("");
+        static EMPTY_ATTRIBUTE_BUFFER: AttributeBuffer = Vec::new();
+
+        let items = std::mem::take(self.as_mut_vec())
+            .into_iter()
+            .map(Attribute::into_owned)
+            .collect::<Vec<_>>();
+
+        Attributes {
+            input: &EMPTY_INPUT,
+            attribute_buffer: &EMPTY_ATTRIBUTE_BUFFER,
+            items: OnceCell::from(items),
+            encoding: self.encoding,
+            quote_style: self.quote_style,
+        }
+    }
+
     fn init_items(&self) -> Vec<Attribute<'i>> {
         self.attribute_buffer
             .iter()
@@ -253,7 +445,7 @@ impl Serialize for &Attributes<'_> {
             let last = self.len() - 1;
 
             for (idx, attr) in self.iter().enumerate() {
-                attr.into_bytes(output_handler)?;
+                attr.serialize(self.quote_style, output_handler);
 
                 if idx != last {
                     output_handler(b" ");
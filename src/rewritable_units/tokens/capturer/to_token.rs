@@ -1,7 +1,9 @@
 use super::TokenCaptureFlags;
 use crate::html::TextType;
 use crate::parser::{NonTagContentLexeme, NonTagContentTokenOutline, TagLexeme, TagTokenOutline};
-use crate::rewritable_units::{Attributes, Comment, Doctype, EndTag, StartTag, Token};
+use crate::rewritable_units::{
+    Attributes, Comment, Doctype, EndTag, SourceLocation, StartTag, Token,
+};
 use encoding_rs::Encoding;
 
 pub(crate) enum ToTokenResult<'i> {
@@ -14,6 +16,7 @@ pub(crate) trait ToToken {
     fn to_token(
         &self,
         capture_flags: &mut TokenCaptureFlags,
+        source_location: SourceLocation,
         encoding: &'static Encoding,
     ) -> ToTokenResult<'_>;
 }
@@ -23,6 +26,7 @@ impl ToToken for TagLexeme<'_> {
     fn to_token(
         &self,
         capture_flags: &mut TokenCaptureFlags,
+        source_location: SourceLocation,
         encoding: &'static Encoding,
     ) -> ToTokenResult<'_> {
         match *self.token_outline() {
@@ -41,6 +45,7 @@ impl ToToken for TagLexeme<'_> {
                     ns,
                     self_closing,
                     self.raw(),
+                    source_location,
                     encoding,
                 ))
             }
@@ -50,7 +55,12 @@ impl ToToken for TagLexeme<'_> {
             {
                 // NOTE: clear the flag once we've seen required end tag.
                 capture_flags.remove(TokenCaptureFlags::NEXT_END_TAG);
-                ToTokenResult::Token(EndTag::new_token(self.part(name), self.raw(), encoding))
+                ToTokenResult::Token(EndTag::new_token(
+                    self.part(name),
+                    self.raw(),
+                    source_location,
+                    encoding,
+                ))
             }
             _ => ToTokenResult::None,
         }
@@ -62,6 +72,7 @@ impl ToToken for NonTagContentLexeme<'_> {
     fn to_token(
         &self,
         capture_flags: &mut TokenCaptureFlags,
+        source_location: SourceLocation,
         encoding: &'static Encoding,
     ) -> ToTokenResult<'_> {
         match *self.token_outline() {
@@ -74,7 +85,12 @@ impl ToToken for NonTagContentLexeme<'_> {
             Some(NonTagContentTokenOutline::Comment(text))
                 if capture_flags.contains(TokenCaptureFlags::COMMENTS) =>
             {
-                ToTokenResult::Token(Comment::new_token(self.part(text), self.raw(), encoding))
+                ToTokenResult::Token(Comment::new_token(
+                    self.part(text),
+                    self.raw(),
+                    source_location,
+                    encoding,
+                ))
             }
 
             Some(NonTagContentTokenOutline::Doctype {
@@ -88,8 +104,8 @@ impl ToToken for NonTagContentLexeme<'_> {
                     self.opt_part(public_id),
                     self.opt_part(system_id),
                     force_quirks,
-                    false, // removed
                     self.raw(),
+                    source_location,
                     encoding,
                 ))
             }
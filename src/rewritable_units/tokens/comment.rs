@@ -2,7 +2,7 @@ use super::{Mutations, Token};
 use crate::base::Bytes;
 use crate::errors::RewritingError;
 use crate::html_content::StreamingHandler;
-use crate::rewritable_units::StringChunk;
+use crate::rewritable_units::{SourceLocation, StringChunk};
 use encoding_rs::Encoding;
 use std::any::Any;
 use std::fmt::{self, Debug};
@@ -10,11 +10,17 @@ use thiserror::Error;
 
 /// An error that occurs when invalid value is provided for the HTML comment text.
 #[derive(Error, Debug, Eq, PartialEq, Copy, Clone)]
+#[non_exhaustive]
 pub enum CommentTextError {
-    /// The provided value contains the `-->` character sequence that preemptively closes the comment.
-    #[error("Comment text shouldn't contain comment closing sequence (`-->`).")]
+    /// The provided value contains the `-->` or `--!>` character sequence that preemptively
+    /// closes the comment.
+    #[error("Comment text shouldn't contain comment closing sequence (`-->` or `--!>`).")]
     CommentClosingSequence,
 
+    /// The provided value starts with `>` or `->`, which closes the comment right after `<!--`.
+    #[error("Comment text shouldn't start with `>` or `->`.")]
+    AbruptClosing,
+
     /// The provided value contains a character that can't be represented in the document's [`encoding`].
     ///
     /// [`encoding`]: ../struct.Settings.html#structfield.encoding
@@ -27,7 +33,9 @@ pub enum CommentTextError {
 /// Exposes API for examination and modification of a parsed HTML comment.
 pub struct Comment<'i> {
     text: Bytes<'i>,
-    raw: Option<Bytes<'i>>,
+    raw: Bytes<'i>,
+    modified: bool,
+    source_location: SourceLocation,
     encoding: &'static Encoding,
     mutations: Mutations,
     user_data: Box<dyn Any>,
@@ -39,11 +47,14 @@ impl<'i> Comment<'i> {
     pub(super) fn new_token(
         text: Bytes<'i>,
         raw: Bytes<'i>,
+        source_location: SourceLocation,
         encoding: &'static Encoding,
     ) -> Token<'i> {
         Token::Comment(Comment {
             text,
-            raw: Some(raw),
+            raw,
+            modified: false,
+            source_location,
             encoding,
             mutations: Mutations::new(),
             user_data: Box::new(()),
@@ -58,10 +69,17 @@ impl<'i> Comment<'i> {
     }
 
     /// Sets the text of the comment.
+    ///
+    /// The text is rejected if it closes the comment early. The text of [conditional comments]
+    /// (e.g. `[if IE]><p>Legacy</p><![endif]`) is accepted as is.
+    ///
+    /// [conditional comments]: https://en.wikipedia.org/wiki/Conditional_comment
     #[inline]
     pub fn set_text(&mut self, text: &str) -> Result<(), CommentTextError> {
-        if text.contains("-->") {
+        if text.contains("-->") || text.contains("--!>") {
             Err(CommentTextError::CommentClosingSequence)
+        } else if text.starts_with('>') || text.starts_with("->") {
+            Err(CommentTextError::AbruptClosing)
         } else {
             // NOTE: if character can't be represented in the given
             // encoding then encoding_rs replaces it with a numeric
@@ -70,7 +88,7 @@ impl<'i> Comment<'i> {
             match Bytes::from_str_without_replacements(text, self.encoding) {
                 Ok(text) => {
                     self.text = text.into_owned();
-                    self.raw = None;
+                    self.modified = true;
 
                     Ok(())
                 }
@@ -234,10 +252,25 @@ impl<'i> Comment<'i> {
         self.mutations.removed()
     }
 
+    /// Returns the location of the comment in the input.
+    #[inline]
+    #[must_use]
+    pub const fn source_location(&self) -> SourceLocation {
+        self.source_location
+    }
+
+    /// Returns the source markup of the comment (including `<!--` and `-->`) in the input's
+    /// encoding, which is retained even if the comment text is modified.
+    #[inline]
+    #[must_use]
+    pub fn source(&self) -> &[u8] {
+        &self.raw
+    }
+
     #[inline]
     fn serialize_self(&self, output_handler: &mut dyn FnMut(&[u8])) -> Result<(), RewritingError> {
-        if let Some(raw) = &self.raw {
-            output_handler(raw);
+        if !self.modified {
+            output_handler(&self.raw);
         } else {
             output_handler(b"<!--");
             output_handler(&self.text);
@@ -320,6 +353,28 @@ mod tests {
         });
     }
 
+    #[test]
+    fn incorrectly_closing_text() {
+        rewrite_comment(b"<!-- foo -->", UTF_8, |c| {
+            for (text, expected_err) in [
+                ("foo --!> bar", CommentTextError::CommentClosingSequence),
+                (">foo", CommentTextError::AbruptClosing),
+                ("->foo", CommentTextError::AbruptClosing),
+            ] {
+                assert_eq!(c.set_text(text).unwrap_err(), expected_err, "{text}");
+            }
+
+            for text in [
+                "[if IE]><p>Legacy</p><![endif]",
+                "[if !IE]><!",
+                "foo->",
+                "-- >",
+            ] {
+                assert_eq!(c.set_text(text), Ok(()), "{text}");
+            }
+        });
+    }
+
     #[test]
     fn encoding_unmappable_chars_in_text() {
         rewrite_comment(b"<!-- foo -->", EUC_JP, |c| {
@@ -1,21 +1,42 @@
+use super::{Mutations, Token};
 use crate::base::Bytes;
 use crate::errors::RewritingError;
-use crate::rewritable_units::{Serialize, Token};
+use crate::html_content::StreamingHandler;
+use crate::rewritable_units::{ContentType, SourceLocation, StringChunk};
 use encoding_rs::Encoding;
 use std::any::Any;
 use std::fmt::{self, Debug};
+use thiserror::Error;
+
+/// An error that occurs when invalid value is provided for the doctype name or identifiers.
+#[derive(Error, Debug, Eq, PartialEq, Copy, Clone)]
+pub enum DoctypeError {
+    /// The provided name is empty or contains ASCII whitespace or the `>` character.
+    #[error("Doctype name can't be empty or contain whitespace or `>`.")]
+    InvalidName,
+
+    /// The provided identifier contains the `>` character or both kinds of quotes.
+    #[error("Doctype identifier can't contain `>` or both `\"` and `'`.")]
+    InvalidIdentifier,
+
+    /// The provided value contains a character that can't be represented in the document's [`encoding`].
+    ///
+    /// [`encoding`]: ../struct.Settings.html#structfield.encoding
+    #[error("Doctype value contains a character that can't be represented in the document's character encoding.")]
+    UnencodableCharacter,
+}
 
 /// A [document type declaration] preamble.
 ///
-/// Note that unlike other HTML content, `Doctype` can't be modified and should be used only for
-/// the examination purposes.
+/// Once the name or the identifiers of the doctype are modified, it's serialized anew, e.g.
+/// to normalize the legacy doctypes to `<!DOCTYPE html>`.
 ///
 /// # Example
 /// ```
 /// use lol_html::{rewrite_str, doctype, RewriteStrSettings};
 ///
-/// rewrite_str(
-///     r#"<!DOCTYPE html PUBLIC "-//W3C//DTD XHTML 1.0 Transitional//EN" "DTD/xhtml1-transitional.dtd""#,
+/// let html = rewrite_str(
+///     r#"<!DOCTYPE html PUBLIC "-//W3C//DTD XHTML 1.0 Transitional//EN" "DTD/xhtml1-transitional.dtd">"#,
 ///     RewriteStrSettings {
 ///         document_content_handlers: vec![
 ///             doctype!(|d| {
@@ -23,12 +44,17 @@ use std::fmt::{self, Debug};
 ///                 assert_eq!(d.public_id(), Some("-//W3C//DTD XHTML 1.0 Transitional//EN".into()));
 ///                 assert_eq!(d.system_id(), Some("DTD/xhtml1-transitional.dtd".into()));
 ///
+///                 d.set_public_id(None)?;
+///                 d.set_system_id(None)?;
+///
 ///                 Ok(())
 ///             })
 ///         ],
 ///         ..RewriteStrSettings::new()
 ///     }
 /// ).unwrap();
+///
+/// assert_eq!(html, "<!DOCTYPE html>");
 /// ```
 ///
 /// [document type declaration]: https://developer.mozilla.org/en-US/docs/Glossary/Doctype
@@ -37,9 +63,11 @@ pub struct Doctype<'i> {
     public_id: Option<Bytes<'i>>,
     system_id: Option<Bytes<'i>>,
     force_quirks: bool,
-    removed: bool,
     raw: Bytes<'i>,
+    modified: bool,
+    source_location: SourceLocation,
     encoding: &'static Encoding,
+    mutations: Mutations,
     user_data: Box<dyn Any>,
 }
 
@@ -51,8 +79,8 @@ impl<'i> Doctype<'i> {
         public_id: Option<Bytes<'i>>,
         system_id: Option<Bytes<'i>>,
         force_quirks: bool,
-        removed: bool,
         raw: Bytes<'i>,
+        source_location: SourceLocation,
         encoding: &'static Encoding,
     ) -> Token<'i> {
         Token::Doctype(Doctype {
@@ -60,9 +88,11 @@ impl<'i> Doctype<'i> {
             public_id,
             system_id,
             force_quirks,
-            removed,
             raw,
+            modified: false,
+            source_location,
             encoding,
+            mutations: Mutations::new(),
             user_data: Box::new(()),
         })
     }
@@ -76,6 +106,19 @@ impl<'i> Doctype<'i> {
             .map(|n| n.as_lowercase_string(self.encoding))
     }
 
+    /// Sets the name of the doctype.
+    #[inline]
+    pub fn set_name(&mut self, name: &str) -> Result<(), DoctypeError> {
+        if name.is_empty() || name.contains(|c: char| c == '>' || c.is_ascii_whitespace()) {
+            return Err(DoctypeError::InvalidName);
+        }
+
+        self.name = Some(self.encode(name)?);
+        self.modified = true;
+
+        Ok(())
+    }
+
     /// The public identifier of the doctype.
     #[inline]
     #[must_use]
@@ -83,6 +126,15 @@ impl<'i> Doctype<'i> {
         self.public_id.as_ref().map(|i| i.as_string(self.encoding))
     }
 
+    /// Sets the public identifier of the doctype, or removes it if `public_id` is `None`.
+    #[inline]
+    pub fn set_public_id(&mut self, public_id: Option<&str>) -> Result<(), DoctypeError> {
+        self.public_id = self.encode_identifier(public_id)?;
+        self.modified = true;
+
+        Ok(())
+    }
+
     /// The system identifier of the doctype.
     #[inline]
     #[must_use]
@@ -90,6 +142,36 @@ impl<'i> Doctype<'i> {
         self.system_id.as_ref().map(|i| i.as_string(self.encoding))
     }
 
+    /// Sets the system identifier of the doctype, or removes it if `system_id` is `None`.
+    #[inline]
+    pub fn set_system_id(&mut self, system_id: Option<&str>) -> Result<(), DoctypeError> {
+        self.system_id = self.encode_identifier(system_id)?;
+        self.modified = true;
+
+        Ok(())
+    }
+
+    fn encode(&self, value: &str) -> Result<Bytes<'static>, DoctypeError> {
+        // NOTE: character references are not supported in doctypes,
+        // so the unencodable characters can't be represented.
+        Bytes::from_str_without_replacements(value, self.encoding)
+            .map(Bytes::into_owned)
+            .map_err(|_| DoctypeError::UnencodableCharacter)
+    }
+
+    fn encode_identifier(
+        &self,
+        identifier: Option<&str>,
+    ) -> Result<Option<Bytes<'static>>, DoctypeError> {
+        match identifier {
+            Some(id) if id.contains('>') || (id.contains('"') && id.contains('\'')) => {
+                Err(DoctypeError::InvalidIdentifier)
+            }
+            Some(id) => self.encode(id).map(Some),
+            None => Ok(None),
+        }
+    }
+
     #[inline]
     #[cfg(feature = "integration_test")]
     #[must_use]
@@ -97,32 +179,173 @@ impl<'i> Doctype<'i> {
         self.force_quirks
     }
 
+    /// Inserts `content` before the doctype.
+    ///
+    /// Consequent calls to the method append `content` to the previously inserted content.
+    #[inline]
+    pub fn before(&mut self, content: &str, content_type: ContentType) {
+        self.mutations
+            .mutate()
+            .content_before
+            .push_back(StringChunk::from_str(content, content_type));
+    }
+
+    /// Inserts content from a [`StreamingHandler`] before the doctype.
+    ///
+    /// Consequent calls to the method append to the previously inserted content.
+    ///
+    /// Use the [`streaming!`] macro to make a `StreamingHandler` from a closure.
+    #[inline]
+    pub fn streaming_before(&mut self, string_writer: Box<dyn StreamingHandler + Send>) {
+        self.mutations
+            .mutate()
+            .content_before
+            .push_back(StringChunk::stream(string_writer));
+    }
+
+    /// Inserts `content` after the doctype.
+    ///
+    /// Consequent calls to the method prepend `content` to the previously inserted content.
+    #[inline]
+    pub fn after(&mut self, content: &str, content_type: ContentType) {
+        self.mutations
+            .mutate()
+            .content_after
+            .push_front(StringChunk::from_str(content, content_type));
+    }
+
+    /// Inserts content from a [`StreamingHandler`] after the doctype.
+    ///
+    /// Consequent calls to the method prepend to the previously inserted content.
+    ///
+    /// Use the [`streaming!`] macro to make a `StreamingHandler` from a closure.
+    #[inline]
+    pub fn streaming_after(&mut self, string_writer: Box<dyn StreamingHandler + Send>) {
+        self.mutations
+            .mutate()
+            .content_after
+            .push_front(StringChunk::stream(string_writer));
+    }
+
+    /// Replaces the doctype with the `content`.
+    ///
+    /// Consequent calls to the method overwrite previous replacement content.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use lol_html::{rewrite_str, doctype, RewriteStrSettings};
+    /// use lol_html::html_content::ContentType;
+    ///
+    /// let html = rewrite_str(
+    ///     r#"<!DOCTYPE HTML PUBLIC "-//W3C//DTD HTML 4.01//EN"><p>"#,
+    ///     RewriteStrSettings {
+    ///         document_content_handlers: vec![
+    ///             doctype!(|d| {
+    ///                 d.replace("<!DOCTYPE html>", ContentType::Html);
+    ///
+    ///                 Ok(())
+    ///             })
+    ///         ],
+    ///         ..RewriteStrSettings::new()
+    ///     }
+    /// ).unwrap();
+    ///
+    /// assert_eq!(html, "<!DOCTYPE html><p>");
+    /// ```
+    #[inline]
+    pub fn replace(&mut self, content: &str, content_type: ContentType) {
+        self.mutations
+            .mutate()
+            .replace(StringChunk::from_str(content, content_type));
+    }
+
+    /// Replaces the doctype with the content from a [`StreamingHandler`].
+    ///
+    /// Consequent calls to the method overwrite previous replacement content.
+    ///
+    /// Use the [`streaming!`] macro to make a `StreamingHandler` from a closure.
+    #[inline]
+    pub fn streaming_replace(&mut self, string_writer: Box<dyn StreamingHandler + Send>) {
+        self.mutations
+            .mutate()
+            .replace(StringChunk::stream(string_writer));
+    }
+
     /// Removes the doctype.
     #[inline]
     pub fn remove(&mut self) {
-        self.removed = true;
+        self.mutations.mutate().remove();
     }
 
     /// Returns `true` if the doctype has been replaced or removed.
     #[inline]
     #[must_use]
     pub fn removed(&self) -> bool {
-        self.removed
+        self.mutations.removed()
     }
-}
 
-impl_user_data!(Doctype<'_>);
+    /// Returns the location of the doctype in the input.
+    #[inline]
+    #[must_use]
+    pub const fn source_location(&self) -> SourceLocation {
+        self.source_location
+    }
 
-impl Serialize for &Doctype<'_> {
+    /// Returns the source markup of the doctype in the input's encoding, which is retained
+    /// even if the doctype is modified.
     #[inline]
-    fn into_bytes(self, output_handler: &mut dyn FnMut(&[u8])) -> Result<(), RewritingError> {
-        if !self.removed() {
+    #[must_use]
+    pub fn source(&self) -> &[u8] {
+        &self.raw
+    }
+
+    #[inline]
+    fn serialize_self(&self, output_handler: &mut dyn FnMut(&[u8])) -> Result<(), RewritingError> {
+        if !self.modified {
             output_handler(&self.raw);
+            return Ok(());
         }
+
+        output_handler(b"<!DOCTYPE");
+
+        if let Some(name) = &self.name {
+            output_handler(b" ");
+            output_handler(name);
+        }
+
+        let mut serialize_id = |keyword: &[u8], id: &[u8]| {
+            let quote: &[u8] = if id.contains(&b'"') { b"'" } else { b"\"" };
+
+            output_handler(keyword);
+            output_handler(quote);
+            output_handler(id);
+            output_handler(quote);
+        };
+
+        if let Some(public_id) = &self.public_id {
+            serialize_id(b" PUBLIC ", public_id);
+        }
+
+        if let Some(system_id) = &self.system_id {
+            let keyword: &[u8] = if self.public_id.is_some() {
+                b" "
+            } else {
+                b" SYSTEM "
+            };
+
+            serialize_id(keyword, system_id);
+        }
+
+        output_handler(b">");
+
         Ok(())
     }
 }
 
+impl_serialize!(Doctype);
+impl_user_data!(Doctype<'_>);
+
 impl Debug for Doctype<'_> {
     #[cold]
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -131,17 +354,18 @@ impl Debug for Doctype<'_> {
             .field("public_id", &self.public_id())
             .field("system_id", &self.system_id())
             .field("force_quirks", &self.force_quirks)
-            .field("removed", &self.removed)
+            .field("removed", &self.removed())
             .finish()
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::errors::DoctypeError;
     use crate::html_content::*;
     use crate::rewritable_units::test_utils::*;
     use crate::*;
-    use encoding_rs::{Encoding, UTF_8};
+    use encoding_rs::{Encoding, UTF_8, WINDOWS_1252};
 
     fn rewrite_doctype(
         html: &[u8],
@@ -202,4 +426,116 @@ mod tests {
             assert_eq!(output, "<html><body><p>Howdy!</p></body></html>");
         }
     }
+
+    #[test]
+    fn modified_parts() {
+        for (html, enc) in encoded(r#"<!DOCTYPE html SYSTEM "Ĥey">"#) {
+            for (public_id, system_id, expected) in [
+                (None, None, "<!DOCTYPE svg>"),
+                (None, Some("Ĥey"), r#"<!DOCTYPE svg SYSTEM "Ĥey">"#),
+                (
+                    Some("-//W3C//DTD"),
+                    None,
+                    r#"<!DOCTYPE svg PUBLIC "-//W3C//DTD">"#,
+                ),
+                (
+                    Some("-//W3C//DTD"),
+                    Some(r#"say "Ĥey""#),
+                    r#"<!DOCTYPE svg PUBLIC "-//W3C//DTD" 'say "Ĥey"'>"#,
+                ),
+            ] {
+                let output = rewrite_doctype(&html, enc, |d| {
+                    d.set_name("svg").unwrap();
+                    d.set_public_id(public_id).unwrap();
+                    d.set_system_id(system_id).unwrap();
+
+                    assert_eq!(d.name().as_deref(), Some("svg"));
+                    assert_eq!(d.public_id().as_deref(), public_id);
+                    assert_eq!(d.system_id().as_deref(), system_id);
+                });
+
+                assert_eq!(output, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn normalized_legacy_doctype() {
+        let html = concat!(
+            r#"<!DOCTYPE HTML PUBLIC "-//W3C//DTD HTML 4.01 Transitional//EN" "#,
+            r#""http://www.w3.org/TR/html4/loose.dtd"><p>Hi</p>"#
+        );
+
+        for (html, enc) in encoded(html) {
+            let output = rewrite_doctype(&html, enc, |d| {
+                d.set_name("html").unwrap();
+                d.set_public_id(None).unwrap();
+                d.set_system_id(None).unwrap();
+            });
+
+            assert_eq!(output, "<!DOCTYPE html><p>Hi</p>");
+        }
+    }
+
+    #[test]
+    fn invalid_parts() {
+        rewrite_doctype(b"<!DOCTYPE html>", UTF_8, |d| {
+            for name in ["", "foo bar", "foo>"] {
+                assert_eq!(d.set_name(name), Err(DoctypeError::InvalidName), "{name}");
+            }
+
+            for id in ["foo>", r#"'foo" bar'"#] {
+                assert_eq!(
+                    d.set_public_id(Some(id)),
+                    Err(DoctypeError::InvalidIdentifier)
+                );
+                assert_eq!(
+                    d.set_system_id(Some(id)),
+                    Err(DoctypeError::InvalidIdentifier)
+                );
+            }
+        });
+
+        rewrite_doctype(b"<!DOCTYPE html>", WINDOWS_1252, |d| {
+            assert_eq!(d.set_name("🎉"), Err(DoctypeError::UnencodableCharacter));
+        });
+    }
+
+    #[test]
+    fn with_prepends_and_appends() {
+        for (html, enc) in encoded("<!DOCTYPE html><p>Hi</p>") {
+            let output = rewrite_doctype(&html, enc, |d| {
+                d.before("<!-- before -->", ContentType::Html);
+                d.before("<foo>", ContentType::Text);
+                d.after("<!-- after -->", ContentType::Html);
+                d.streaming_after(streaming!(|h| {
+                    h.write_str("\n", ContentType::Text);
+                    Ok(())
+                }));
+            });
+
+            assert_eq!(
+                output,
+                "<!-- before -->&lt;foo&gt;<!DOCTYPE html>\n<!-- after --><p>Hi</p>"
+            );
+        }
+    }
+
+    #[test]
+    fn replaced() {
+        for (html, enc) in encoded(r#"<!DOCTYPE html SYSTEM "about:legacy-compat"><p>Hi</p>"#) {
+            let output = rewrite_doctype(&html, enc, |d| {
+                d.before("<before>", ContentType::Html);
+                d.after("<after>", ContentType::Html);
+
+                assert!(!d.removed());
+
+                d.replace("<!DOCTYPE html>", ContentType::Html);
+
+                assert!(d.removed());
+            });
+
+            assert_eq!(output, "<before><!DOCTYPE html><after><p>Hi</p>");
+        }
+    }
 }
@@ -2,7 +2,7 @@ use super::{Mutations, Token};
 use crate::base::Bytes;
 use crate::errors::RewritingError;
 use crate::html_content::{ContentType, StreamingHandler};
-use crate::rewritable_units::StringChunk;
+use crate::rewritable_units::{SourceLocation, StringChunk};
 use encoding_rs::Encoding;
 use std::fmt::{self, Debug};
 
@@ -11,7 +11,9 @@ use std::fmt::{self, Debug};
 /// Exposes API for examination and modification of a parsed HTML end tag.
 pub struct EndTag<'i> {
     name: Bytes<'i>,
-    raw: Option<Bytes<'i>>,
+    raw: Bytes<'i>,
+    modified: bool,
+    source_location: SourceLocation,
     encoding: &'static Encoding,
     pub(crate) mutations: Mutations,
 }
@@ -22,16 +24,32 @@ impl<'i> EndTag<'i> {
     pub(super) fn new_token(
         name: Bytes<'i>,
         raw: Bytes<'i>,
+        source_location: SourceLocation,
         encoding: &'static Encoding,
     ) -> Token<'i> {
         Token::EndTag(EndTag {
             name,
-            raw: Some(raw),
+            raw,
+            modified: false,
+            source_location,
             encoding,
             mutations: Mutations::new(),
         })
     }
 
+    /// Detaches the tag from the parsed input, so its output can be held back
+    /// past the end of the current input chunk.
+    pub(crate) fn into_owned(self) -> EndTag<'static> {
+        EndTag {
+            name: self.name.into_owned(),
+            raw: self.raw.into_owned(),
+            modified: self.modified,
+            source_location: self.source_location,
+            encoding: self.encoding,
+            mutations: self.mutations,
+        }
+    }
+
     /// Returns the name of the tag.
     #[inline]
     #[must_use]
@@ -56,7 +74,7 @@ impl<'i> EndTag<'i> {
     /// Sets the name of the tag.
     pub(crate) fn set_name_raw(&mut self, name: Bytes<'static>) {
         self.name = name;
-        self.raw = None;
+        self.modified = true;
     }
 
     /// Sets the name of the tag by encoding the given string.
@@ -148,10 +166,25 @@ impl<'i> EndTag<'i> {
         self.mutations.removed()
     }
 
+    /// Returns the location of the end tag in the input.
+    #[inline]
+    #[must_use]
+    pub const fn source_location(&self) -> SourceLocation {
+        self.source_location
+    }
+
+    /// Returns the source markup of the end tag in the input's encoding, which is retained
+    /// even if the end tag is modified.
+    #[inline]
+    #[must_use]
+    pub fn source_html(&self) -> &[u8] {
+        &self.raw
+    }
+
     #[inline]
     fn serialize_self(&self, output_handler: &mut dyn FnMut(&[u8])) -> Result<(), RewritingError> {
-        if let Some(raw) = &self.raw {
-            output_handler(raw);
+        if !self.modified {
+            output_handler(&self.raw);
         } else {
             output_handler(b"</");
             output_handler(&self.name);
@@ -5,7 +5,7 @@ use super::Mutations;
 use crate::errors::RewritingError;
 
 pub(super) use self::attributes::Attributes;
-pub use self::attributes::{Attribute, AttributeNameError};
+pub use self::attributes::{Attribute, AttributeNameError, AttributeQuoteStyle};
 pub use self::capturer::*;
 
 // Pub only for integration tests
@@ -60,7 +60,7 @@ mod start_tag;
 mod text_chunk;
 
 pub use self::comment::{Comment, CommentTextError};
-pub use self::doctype::Doctype;
+pub use self::doctype::{Doctype, DoctypeError};
 pub use self::end_tag::EndTag;
 pub use self::start_tag::StartTag;
 pub use self::text_chunk::TextChunk;
@@ -1,11 +1,12 @@
-use super::{Attribute, AttributeNameError, Attributes};
+use super::{Attribute, AttributeNameError, AttributeQuoteStyle, Attributes};
 use super::{Mutations, Serialize, Token};
 use crate::base::Bytes;
 use crate::errors::RewritingError;
 use crate::html::Namespace;
 use crate::html_content::{ContentType, StreamingHandler};
-use crate::rewritable_units::StringChunk;
+use crate::rewritable_units::{SourceLocation, StringChunk};
 use encoding_rs::Encoding;
+use std::cmp::Ordering;
 use std::fmt::{self, Debug};
 
 /// An HTML start tag rewritable unit.
@@ -16,7 +17,9 @@ pub struct StartTag<'i> {
     attributes: Attributes<'i>,
     ns: Namespace,
     self_closing: bool,
-    raw: Option<Bytes<'i>>,
+    raw: Bytes<'i>,
+    modified: bool,
+    source_location: SourceLocation,
     encoding: &'static Encoding,
     pub(crate) mutations: Mutations,
 }
@@ -30,6 +33,7 @@ impl<'i> StartTag<'i> {
         ns: Namespace,
         self_closing: bool,
         raw: Bytes<'i>,
+        source_location: SourceLocation,
         encoding: &'static Encoding,
     ) -> Token<'i> {
         Token::StartTag(StartTag {
@@ -37,12 +41,30 @@ impl<'i> StartTag<'i> {
             attributes,
             ns,
             self_closing,
-            raw: Some(raw),
+            raw,
+            modified: false,
+            source_location,
             encoding,
             mutations: Mutations::new(),
         })
     }
 
+    /// Detaches the tag from the parsed input, so its output can be held back
+    /// past the end of the current input chunk.
+    pub(crate) fn into_owned(self) -> StartTag<'static> {
+        StartTag {
+            name: self.name.into_owned(),
+            attributes: self.attributes.into_owned(),
+            ns: self.ns,
+            self_closing: self.self_closing,
+            raw: self.raw.into_owned(),
+            modified: self.modified,
+            source_location: self.source_location,
+            encoding: self.encoding,
+            mutations: self.mutations,
+        }
+    }
+
     #[inline]
     #[doc(hidden)]
     pub const fn encoding(&self) -> &'static Encoding {
@@ -65,7 +87,7 @@ impl<'i> StartTag<'i> {
     #[inline]
     pub fn set_name(&mut self, name: Bytes<'static>) {
         self.name = name;
-        self.raw = None;
+        self.modified = true;
     }
 
     /// Returns the [namespace URI] of the tag's element.
@@ -89,7 +111,7 @@ impl<'i> StartTag<'i> {
     #[inline]
     pub fn set_attribute(&mut self, name: &str, value: &str) -> Result<(), AttributeNameError> {
         self.attributes.set_attribute(name, value, self.encoding)?;
-        self.raw = None;
+        self.modified = true;
 
         Ok(())
     }
@@ -98,10 +120,81 @@ impl<'i> StartTag<'i> {
     #[inline]
     pub fn remove_attribute(&mut self, name: &str) {
         if self.attributes.remove_attribute(name) {
-            self.raw = None;
+            self.modified = true;
+        }
+    }
+
+    /// Inserts an attribute with `name` and `value` at the `index` in the tag's attributes.
+    ///
+    /// If the `index` is greater than the number of the attributes, the attribute is added
+    /// to the end. If the tag already has an attribute with the `name`, it's moved to the `index`
+    /// and gets the `value`.
+    #[inline]
+    pub fn insert_attribute_at(
+        &mut self,
+        index: usize,
+        name: &str,
+        value: &str,
+    ) -> Result<(), AttributeNameError> {
+        self.attributes
+            .insert_attribute(index, name, value, self.encoding)?;
+        self.modified = true;
+
+        Ok(())
+    }
+
+    /// Renames the attribute with the `name` to `new_name`, keeping its value and position,
+    /// if it is present.
+    ///
+    /// If the tag also has an attribute with the `new_name`, it's removed.
+    #[inline]
+    pub fn rename_attribute(
+        &mut self,
+        name: &str,
+        new_name: &str,
+    ) -> Result<(), AttributeNameError> {
+        if self
+            .attributes
+            .rename_attribute(name, new_name, self.encoding)?
+        {
+            self.modified = true;
+        }
+
+        Ok(())
+    }
+
+    /// Sorts the tag's attributes by their names.
+    #[inline]
+    pub fn sort_attributes(&mut self) {
+        self.sort_attributes_by(|a, b| a.name().cmp(&b.name()));
+    }
+
+    /// Sorts the tag's attributes with the `compare` function.
+    ///
+    /// The sort is stable, and the tag is left intact if its attributes are already sorted.
+    #[inline]
+    pub fn sort_attributes_by(
+        &mut self,
+        compare: impl FnMut(&Attribute<'i>, &Attribute<'i>) -> Ordering,
+    ) {
+        if self.attributes.sort_attributes_by(compare) {
+            self.modified = true;
         }
     }
 
+    /// Returns the quote style of the tag's attributes, that are serialized anew.
+    #[inline]
+    pub fn attribute_quote_style(&self) -> AttributeQuoteStyle {
+        self.attributes.quote_style()
+    }
+
+    /// Sets the quote style of the tag's attributes, that are serialized anew, i.e. the added,
+    /// modified or renamed ones. The rest of the attributes keep their source markup.
+    #[inline]
+    pub fn set_attribute_quote_style(&mut self, quote_style: AttributeQuoteStyle) {
+        self.attributes.set_quote_style(quote_style);
+    }
+
     /// Whether the tag syntactically ends with `/>`. In HTML content this is purely a decorative, unnecessary, and has no effect of any kind.
     ///
     /// The `/>` syntax only affects parsing of elements in foreign content (SVG and MathML).
@@ -117,6 +210,21 @@ impl<'i> StartTag<'i> {
         self.self_closing
     }
 
+    /// Returns the location of the tag in the input.
+    #[inline]
+    #[must_use]
+    pub const fn source_location(&self) -> SourceLocation {
+        self.source_location
+    }
+
+    /// Returns the source markup of the tag in the input's encoding, which is retained
+    /// even if the tag is modified.
+    #[inline]
+    #[must_use]
+    pub fn source_html(&self) -> &[u8] {
+        &self.raw
+    }
+
     /// If false, the tag won't be seiralized with `/>`
     ///
     /// This doesn't affect content model
@@ -198,8 +306,8 @@ impl<'i> StartTag<'i> {
     }
 
     fn serialize_self(&self, output_handler: &mut dyn FnMut(&[u8])) -> Result<(), RewritingError> {
-        if let Some(raw) = &self.raw {
-            output_handler(raw);
+        if !self.modified {
+            output_handler(&self.raw);
             return Ok(());
         }
 
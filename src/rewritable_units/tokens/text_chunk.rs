@@ -1,14 +1,18 @@
 use super::Mutations;
 use crate::base::Bytes;
 use crate::errors::RewritingError;
-use crate::html::TextType;
+use crate::html::{decode_character_references, TextType};
 use crate::html_content::{ContentType, StreamingHandler};
-use crate::rewritable_units::StringChunk;
+use crate::rewritable_units::{DynamicString, SourceLocation, StreamingHandlerSink, StringChunk};
 use encoding_rs::Encoding;
 use std::any::Any;
 use std::borrow::Cow;
 use std::fmt::{self, Debug};
 
+/// The serialized content inserted before the chunk, the text of the chunk
+/// and the serialized content inserted after the chunk.
+type TextChunkParts = (Vec<u8>, Option<String>, Vec<u8>);
+
 /// An HTML text node chunk.
 ///
 /// Since the rewriter operates on a streaming input with minimal internal buffering, HTML
@@ -62,8 +66,10 @@ use std::fmt::{self, Debug};
 /// [`last_in_text_node`]: #method.last_in_text_node
 pub struct TextChunk<'i> {
     text: Cow<'i, str>,
+    raw: &'i [u8],
     text_type: TextType,
     last_in_text_node: bool,
+    source_location: SourceLocation,
     encoding: &'static Encoding,
     mutations: Mutations,
     user_data: Box<dyn Any>,
@@ -74,14 +80,18 @@ impl<'i> TextChunk<'i> {
     #[must_use]
     pub(crate) fn new(
         text: &'i str,
+        raw: &'i [u8],
         text_type: TextType,
         last_in_text_node: bool,
+        source_location: SourceLocation,
         encoding: &'static Encoding,
     ) -> Self {
         TextChunk {
             text: text.into(),
+            raw,
             text_type,
             last_in_text_node,
+            source_location,
             encoding,
             mutations: Mutations::new(),
             user_data: Box::new(()),
@@ -95,6 +105,28 @@ impl<'i> TextChunk<'i> {
         &self.text
     }
 
+    /// Returns the textual content of the chunk with [character references] decoded,
+    /// e.g. `&lt;b&gt;` is returned as `<b>`.
+    ///
+    /// The text is returned as is if its [type] doesn't allow character references
+    /// (e.g. text inside a `<script>` element).
+    ///
+    /// # Note
+    /// A character reference can be split between two text chunks, in which case
+    /// it's not decoded. Buffer the chunks of a text node to decode it reliably.
+    ///
+    /// [character references]: https://html.spec.whatwg.org/multipage/syntax.html#character-references
+    /// [type]: #method.text_type
+    #[inline]
+    #[must_use]
+    pub fn decoded_text(&self) -> Cow<'_, str> {
+        if self.text_type.allows_html_entities() {
+            decode_character_references(&self.text, false)
+        } else {
+            Cow::Borrowed(&self.text)
+        }
+    }
+
     /// Returns the textual content of the chunk that the caller can modify.  Note that this can
     /// cause the string to be allocated.
     #[inline]
@@ -156,6 +188,26 @@ impl<'i> TextChunk<'i> {
         self.last_in_text_node
     }
 
+    /// Returns the location of the input bytes, that the chunk is decoded from.
+    ///
+    /// The bytes of a character split between the input chunks can be attributed to the preceding
+    /// text chunk, and the last chunk in a text node usually has an empty range at its end.
+    #[inline]
+    #[must_use]
+    pub const fn source_location(&self) -> SourceLocation {
+        self.source_location
+    }
+
+    /// Returns the input bytes, that the chunk is decoded from, in the input's encoding.
+    ///
+    /// The bytes are retained even if the text of the chunk is modified. Note that they can
+    /// end with an incomplete character, whose text is in the next chunk.
+    #[inline]
+    #[must_use]
+    pub const fn raw_bytes(&self) -> &[u8] {
+        self.raw
+    }
+
     /// Inserts `content` before the text chunk.
     ///
     /// Consequent calls to the method append `content` to the previously inserted content.
@@ -314,6 +366,48 @@ impl<'i> TextChunk<'i> {
         self.mutations.removed()
     }
 
+    /// Serializes the content inserted before and after the chunk separately from its text.
+    /// The text is `None` if the chunk is removed or empty, the replacement of the removed
+    /// chunk is serialized with the content inserted before it.
+    pub(crate) fn into_parts(mut self) -> Result<TextChunkParts, RewritingError> {
+        let mut content_before = Vec::new();
+        let mut content_after = Vec::new();
+
+        let Some(mutations) = self.mutations.take() else {
+            return Ok((content_before, self.text_if_not_empty(), content_after));
+        };
+
+        let encoding = self.encoding;
+
+        let encode = |string: DynamicString, output: &mut Vec<u8>| {
+            let mut output_handler = |c: &[u8]| output.extend_from_slice(c);
+            let mut sink = StreamingHandlerSink::new(encoding, &mut output_handler);
+
+            string
+                .encode(&mut sink)
+                .map_err(RewritingError::ContentHandlerError)
+        };
+
+        encode(mutations.content_before, &mut content_before)?;
+
+        let text = if mutations.removed {
+            encode(mutations.replacement, &mut content_before)?;
+
+            None
+        } else {
+            self.text_if_not_empty()
+        };
+
+        encode(mutations.content_after, &mut content_after)?;
+
+        Ok((content_before, text, content_after))
+    }
+
+    #[inline]
+    fn text_if_not_empty(self) -> Option<String> {
+        Some(self.text.into_owned()).filter(|text| !text.is_empty())
+    }
+
     #[inline]
     fn serialize_self(&self, output_handler: &mut dyn FnMut(&[u8])) -> Result<(), RewritingError> {
         if !self.text.is_empty() {
@@ -342,6 +436,7 @@ mod tests {
     use crate::rewritable_units::test_utils::*;
     use crate::*;
     use encoding_rs::{Encoding, UTF_8};
+    use std::borrow::Cow;
 
     fn rewrite_text_chunk(
         html: &[u8],
@@ -382,7 +477,14 @@ mod tests {
     #[test]
     fn in_place_text_modifications() {
         let encoding = Encoding::for_label_no_replacement(b"utf-8").unwrap();
-        let mut chunk = TextChunk::new("original text", TextType::PlainText, true, encoding);
+        let mut chunk = TextChunk::new(
+            "original text",
+            b"original text",
+            TextType::PlainText,
+            true,
+            SourceLocation::new(0, 0, 1, 1),
+            encoding,
+        );
 
         assert_eq!(chunk.as_str(), "original text");
         chunk.set_str("hello".to_owned());
@@ -391,6 +493,49 @@ mod tests {
         assert_eq!(chunk.as_str(), "hello world!");
     }
 
+    #[test]
+    fn decoded_text() {
+        let encoding = Encoding::for_label_no_replacement(b"utf-8").unwrap();
+        let text = "&lt;b&gt; &amp&notin;";
+
+        let chunk = TextChunk::new(
+            text,
+            text.as_bytes(),
+            TextType::Data,
+            true,
+            SourceLocation::new(0, 0, 1, 1),
+            encoding,
+        );
+        assert_eq!(chunk.decoded_text(), "<b> &∉");
+
+        for text_type in [
+            TextType::PlainText,
+            TextType::RawText,
+            TextType::ScriptData,
+            TextType::CDataSection,
+        ] {
+            let chunk = TextChunk::new(
+                text,
+                text.as_bytes(),
+                text_type,
+                true,
+                SourceLocation::new(0, 0, 1, 1),
+                encoding,
+            );
+            assert_eq!(chunk.decoded_text(), text);
+        }
+
+        let chunk = TextChunk::new(
+            "no references",
+            b"no references",
+            TextType::RCData,
+            true,
+            SourceLocation::new(0, 0, 1, 1),
+            encoding,
+        );
+        assert!(matches!(chunk.decoded_text(), Cow::Borrowed(_)));
+    }
+
     mod serialization {
         use super::*;
 
@@ -0,0 +1,169 @@
+use super::AsciiCompatibleEncoding;
+use crate::selectors_vm::{Ast, Compiler, Program, Selector, Specificity, SupportedHandlers};
+use std::fmt::{self, Debug};
+use std::sync::Arc;
+
+/// Selectors compiled into a matching program, that can be shared by multiple rewriters.
+///
+/// [`HtmlRewriter::new`] compiles the selectors of the element content handlers every time
+/// a rewriter is constructed. Compiled selectors are built once instead, and can be shared
+/// (e.g. behind an [`Arc`]) by the rewriters constructed with
+/// [`HtmlRewriter::with_compiled_selectors`], each of which provides its own handlers for the
+/// selectors by their indices.
+///
+/// The selectors are compiled for the given encoding, so they can only be used by the rewriters
+/// with the same [`Settings::encoding`].
+///
+/// # Example
+/// ```
+/// use lol_html::html_content::Element;
+/// use lol_html::{
+///     AsciiCompatibleEncoding, CompiledSelectors, ElementContentHandlers, HtmlRewriter, Selector,
+///     Settings,
+/// };
+/// use std::sync::Arc;
+///
+/// let selectors: [Selector; 2] = ["a[href]", "img"].map(|s| s.parse().unwrap());
+///
+/// let compiled_selectors = Arc::new(CompiledSelectors::new(
+///     &selectors,
+///     AsciiCompatibleEncoding::utf_8(),
+/// ));
+///
+/// for html in ["<a href=foo><img></a>", "<img><a href=bar></a>"] {
+///     let mut output = vec![];
+///
+///     let handlers = ElementContentHandlers::default().element(|el: &mut Element<'_, '_>| {
+///         el.set_attribute("data-rewritten", "")?;
+///         Ok(())
+///     });
+///
+///     let mut rewriter = HtmlRewriter::with_compiled_selectors(
+///         &compiled_selectors,
+///         vec![(1, handlers)],
+///         Settings::new(),
+///         |c: &[u8]| output.extend_from_slice(c),
+///     );
+///
+///     rewriter.write(html.as_bytes()).unwrap();
+///     rewriter.end().unwrap();
+///
+///     assert_eq!(
+///         String::from_utf8(output).unwrap(),
+///         html.replace("<img>", r#"<img data-rewritten="">"#)
+///     );
+/// }
+/// ```
+///
+/// [`HtmlRewriter::new`]: crate::HtmlRewriter::new
+/// [`HtmlRewriter::with_compiled_selectors`]: crate::HtmlRewriter::with_compiled_selectors
+/// [`Settings::encoding`]: crate::Settings::encoding
+pub struct CompiledSelectors {
+    program: Arc<Program<usize>>,
+    /// Payloads of the selectors of each list, along with their specificity.
+    payloads: Box<[Vec<(usize, Specificity)>]>,
+    payload_count: usize,
+    supported_handlers: Box<[SupportedHandlers]>,
+    encoding: AsciiCompatibleEncoding,
+}
+
+impl CompiledSelectors {
+    /// Compiles the `selectors` for the rewriters with the given `encoding`.
+    ///
+    /// The selectors are identified by their indices in `selectors`.
+    #[must_use]
+    pub fn new<'s>(
+        selectors: impl IntoIterator<Item = &'s Selector>,
+        encoding: AsciiCompatibleEncoding,
+    ) -> Self {
+        let selectors = selectors.into_iter().collect::<Vec<_>>();
+        let mut ast = Ast::default();
+        let mut payloads = Vec::with_capacity(selectors.len());
+        let mut supported_handlers = Vec::with_capacity(selectors.len());
+
+        // NOTE: the selector is matched with its index, and the selectors of the list with other
+        // specificities (e.g. `#foo` in `p, #foo`) are matched with the payloads following
+        // the indices, so the handlers can be ordered by the specificity of the matched one.
+        let mut payload_count = selectors.len();
+
+        for (idx, selector) in selectors.into_iter().enumerate() {
+            let specificities = selector.distinct_specificities();
+            let selector_payloads = (0..specificities.len())
+                .map(|i| {
+                    if i == 0 {
+                        idx
+                    } else {
+                        payload_count += 1;
+                        payload_count - 1
+                    }
+                })
+                .collect::<Vec<_>>();
+
+            ast.add_selector_by_specificity(selector, &selector_payloads);
+            payloads.push(selector_payloads.into_iter().zip(specificities).collect());
+            supported_handlers.push(selector.supported_handlers());
+        }
+
+        CompiledSelectors {
+            program: Arc::new(Compiler::new(encoding.into()).compile(ast)),
+            payloads: payloads.into(),
+            payload_count,
+            supported_handlers: supported_handlers.into(),
+            encoding,
+        }
+    }
+
+    /// Returns the number of the compiled selectors.
+    #[inline]
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.payloads.len()
+    }
+
+    /// Returns `true` if there are no compiled selectors.
+    #[inline]
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.payloads.is_empty()
+    }
+
+    /// Returns the encoding the selectors are compiled for.
+    #[inline]
+    #[must_use]
+    pub const fn encoding(&self) -> AsciiCompatibleEncoding {
+        self.encoding
+    }
+
+    #[inline]
+    pub(crate) fn program(&self) -> Arc<Program<usize>> {
+        Arc::clone(&self.program)
+    }
+
+    /// Returns the number of the payloads the selectors are matched with.
+    #[inline]
+    pub(crate) const fn payload_count(&self) -> usize {
+        self.payload_count
+    }
+
+    /// Returns the payloads of the selector with the given index, along with the specificity
+    /// of the selectors of the list matched with them.
+    #[inline]
+    pub(crate) fn payloads(&self, idx: usize) -> &[(usize, Specificity)] {
+        &self.payloads[idx]
+    }
+
+    #[inline]
+    pub(crate) fn supported_handlers(&self, idx: usize) -> SupportedHandlers {
+        self.supported_handlers[idx]
+    }
+}
+
+impl Debug for CompiledSelectors {
+    #[cold]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CompiledSelectors")
+            .field("len", &self.len())
+            .field("encoding", &self.encoding)
+            .finish()
+    }
+}
@@ -1,23 +1,75 @@
 use super::settings::*;
 use super::ElementDescriptor;
-use crate::rewritable_units::{DocumentEnd, Element, StartTag, Token, TokenCaptureFlags};
-use crate::selectors_vm::MatchInfo;
+use super::RewritingError;
+use crate::memory::{LimitedVec, SharedMemoryLimiter};
+use crate::rewritable_units::{
+    DocumentEnd, Element, EndTag, InnerText, StartTag, TextChunk, TextNode, Token,
+    TokenCaptureFlags,
+};
+use crate::selectors_vm::{LazyMatchResolution, MatchInfo, Specificity};
+use crate::transform_stream::{DeferredElementEvent, DeferredOutput};
+use hashbrown::HashMap;
 
 #[derive(Copy, Clone, Default, Debug, PartialEq, Eq, Hash)]
 pub(crate) struct SelectorHandlersLocator {
     pub element_handler_idx: Option<usize>,
     pub comment_handler_idx: Option<usize>,
     pub text_handler_idx: Option<usize>,
+    pub text_node_handler_idx: Option<usize>,
+    pub end_tag_handler_idx: Option<usize>,
+    pub subtree_handler_idx: Option<usize>,
+    pub inner_text_handler_idx: Option<usize>,
+    /// The index of the selector in the element content handlers of the rewriter settings,
+    /// followed by the handlers added to the running rewriter.
+    pub selector_idx: Option<usize>,
+    /// The rank the handlers are invoked with, if the element matches the selectors of the list
+    /// matched with this locator's payload.
+    pub rank: Specificity,
+}
+
+impl SelectorHandlersLocator {
+    /// Returns `false` if the selector doesn't have any handlers, e.g. if it's one of the
+    /// compiled selectors without handlers, or if its handlers have been removed.
+    #[inline]
+    const fn has_handlers(&self) -> bool {
+        self.element_handler_idx.is_some()
+            || self.comment_handler_idx.is_some()
+            || self.text_handler_idx.is_some()
+            || self.text_node_handler_idx.is_some()
+            || self.end_tag_handler_idx.is_some()
+            || self.subtree_handler_idx.is_some()
+            || self.inner_text_handler_idx.is_some()
+    }
 }
 
 struct HandlerVecItem<H> {
-    handler: H,
+    /// `None` once the handler is removed.
+    handler: Option<H>,
     user_count: usize,
+    /// Ranks of the matches the handler is active for. The handler is invoked
+    /// according to the highest of them.
+    ranks: Vec<Specificity>,
+}
+
+impl<H> HandlerVecItem<H> {
+    #[inline]
+    fn rank(&self) -> Specificity {
+        self.ranks.iter().copied().max().unwrap_or_default()
+    }
+
+    #[inline]
+    fn deactivate(&mut self) {
+        self.user_count = 0;
+        self.ranks.clear();
+    }
 }
 
 struct HandlerVec<H> {
     items: Vec<HandlerVecItem<H>>,
     user_count: usize,
+    /// `true` once the handlers are activated with a non-default rank,
+    /// so they are no longer invoked in the order of their indices.
+    is_ranked: bool,
 }
 
 impl<H> Default for HandlerVec<H> {
@@ -25,20 +77,36 @@ impl<H> Default for HandlerVec<H> {
         Self {
             items: Vec::default(),
             user_count: 0,
+            is_ranked: false,
         }
     }
 }
 
 impl<H> HandlerVec<H> {
+    /// Adds the handler. Returns the index of the handler.
     #[inline]
-    pub fn push(&mut self, handler: H, always_active: bool) {
+    pub fn push(&mut self, handler: H, always_active: bool) -> usize {
         let item = HandlerVecItem {
-            handler,
+            handler: Some(handler),
             user_count: usize::from(always_active),
+            ranks: Vec::default(),
         };
 
         self.user_count += item.user_count;
         self.items.push(item);
+
+        self.items.len() - 1
+    }
+
+    /// Drops the handler. The index of the handler stays reserved, so the indices
+    /// of the rest of the handlers don't change.
+    #[inline]
+    pub fn remove(&mut self, idx: usize) {
+        let item = &mut self.items[idx];
+
+        self.user_count -= item.user_count;
+        item.deactivate();
+        item.handler = None;
     }
 
     #[inline]
@@ -46,18 +114,67 @@ impl<H> HandlerVec<H> {
         self.items.len()
     }
 
+    /// Activates the handler for a match with the given `rank`. Handlers are invoked
+    /// in the ascending order of their ranks and then in the order of their indices.
     #[inline]
-    pub fn inc_user_count(&mut self, idx: usize) {
-        self.items[idx].user_count += 1;
+    pub fn inc_user_count(&mut self, idx: usize, rank: Specificity) {
+        let item = &mut self.items[idx];
+
+        item.user_count += 1;
+        item.ranks.push(rank);
         self.user_count += 1;
+        self.is_ranked |= rank != Specificity::default();
     }
 
     #[inline]
-    pub fn dec_user_count(&mut self, idx: usize) {
-        self.items[idx].user_count -= 1;
+    pub fn dec_user_count(&mut self, idx: usize, rank: Specificity) {
+        let item = &mut self.items[idx];
+
+        if let Some(pos) = item.ranks.iter().position(|&r| r == rank) {
+            item.ranks.swap_remove(pos);
+        }
+
+        item.user_count -= 1;
         self.user_count -= 1;
     }
 
+    /// Invokes the handlers with the given indices, matched with the given ranks, in the order
+    /// of the handlers invocation, skipping the removed ones.
+    #[inline]
+    pub fn for_each_of(
+        &mut self,
+        mut indices: Vec<(usize, Specificity)>,
+        mut cb: impl FnMut(&mut H) -> HandlerResult,
+    ) -> HandlerResult {
+        // NOTE: the handler matched more than once is invoked according to its highest rank.
+        indices.sort_unstable_by_key(|&(idx, rank)| (idx, std::cmp::Reverse(rank)));
+        indices.dedup_by_key(|&mut (idx, _)| idx);
+        indices.sort_unstable_by_key(|&(idx, rank)| (rank, idx));
+
+        for (idx, _) in indices {
+            if let Some(handler) = &mut self.items[idx].handler {
+                cb(handler)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the indices of the active handlers in the order of the handlers invocation.
+    fn active_order(&self) -> Vec<usize> {
+        let mut order = self
+            .items
+            .iter()
+            .enumerate()
+            .filter(|(_, item)| item.user_count > 0)
+            .map(|(idx, item)| (item.rank(), idx))
+            .collect::<Vec<_>>();
+
+        order.sort_unstable();
+
+        order.into_iter().map(|(_, idx)| idx).collect()
+    }
+
     #[inline]
     pub const fn has_active(&self) -> bool {
         self.user_count > 0
@@ -68,9 +185,19 @@ impl<H> HandlerVec<H> {
         &mut self,
         mut cb: impl FnMut(&mut H) -> HandlerResult,
     ) -> HandlerResult {
-        for item in &mut self.items {
-            if item.user_count > 0 {
-                cb(&mut item.handler)?;
+        if self.is_ranked {
+            for idx in self.active_order() {
+                if let Some(handler) = &mut self.items[idx].handler {
+                    cb(handler)?;
+                }
+            }
+        } else {
+            for item in &mut self.items {
+                if item.user_count > 0 {
+                    if let Some(handler) = &mut item.handler {
+                        cb(handler)?;
+                    }
+                }
             }
         }
 
@@ -82,17 +209,39 @@ impl<H> HandlerVec<H> {
         &mut self,
         mut cb: impl FnMut(&mut H) -> HandlerResult,
     ) -> HandlerResult {
-        for item in &mut self.items {
-            if item.user_count > 0 {
-                cb(&mut item.handler)?;
-                self.user_count -= item.user_count;
-                item.user_count = 0;
+        if self.is_ranked {
+            for idx in self.active_order() {
+                self.invoke_and_deactivate(idx, &mut cb)?;
+            }
+        } else {
+            for idx in 0..self.items.len() {
+                self.invoke_and_deactivate(idx, &mut cb)?;
             }
         }
 
         Ok(())
     }
 
+    #[inline]
+    fn invoke_and_deactivate(
+        &mut self,
+        idx: usize,
+        cb: &mut impl FnMut(&mut H) -> HandlerResult,
+    ) -> HandlerResult {
+        let item = &mut self.items[idx];
+
+        if item.user_count > 0 {
+            if let Some(handler) = &mut item.handler {
+                cb(handler)?;
+            }
+
+            self.user_count -= item.user_count;
+            item.deactivate();
+        }
+
+        Ok(())
+    }
+
     #[inline]
     pub fn do_for_each_active_and_remove(
         &mut self,
@@ -104,7 +253,9 @@ impl<H> HandlerVec<H> {
 
                 self.user_count -= item.user_count;
 
-                cb(item.handler)?;
+                if let Some(handler) = item.handler {
+                    cb(handler)?;
+                }
             }
         }
 
@@ -112,28 +263,90 @@ impl<H> HandlerVec<H> {
     }
 }
 
+/// Element, whose output is held back until it's known whether it matches (i.e. its matching
+/// depends on its following siblings) or, if its content is captured, until it ends.
+#[derive(Default)]
+struct DeferredElement {
+    matched_element_handlers: Vec<(usize, Specificity)>,
+    matched_end_tag_handlers: Vec<(usize, Specificity)>,
+    matched_subtree_handlers: Vec<(usize, Specificity)>,
+    matched_inner_text_handlers: Vec<(usize, Specificity)>,
+    matched_selectors: Vec<usize>,
+    /// Payload of the lazy matches of the element, that haven't been resolved yet.
+    unresolved_payload: Vec<usize>,
+    can_have_content: bool,
+    holds_back_text: bool,
+}
+
+impl DeferredElement {
+    #[inline]
+    fn is_match(&self) -> bool {
+        !self.matched_element_handlers.is_empty()
+            || !self.matched_end_tag_handlers.is_empty()
+            || !self.matched_subtree_handlers.is_empty()
+            || !self.matched_inner_text_handlers.is_empty()
+    }
+}
+
 pub(crate) struct ContentHandlersDispatcher<'h, H: HandlerTypes> {
     doctype_handlers: HandlerVec<H::DoctypeHandler<'h>>,
     comment_handlers: HandlerVec<H::CommentHandler<'h>>,
     text_handlers: HandlerVec<H::TextHandler<'h>>,
+    text_node_handlers: HandlerVec<H::TextNodeHandler<'h>>,
     end_tag_handlers: HandlerVec<H::EndTagHandler<'static>>,
+    element_end_tag_handlers: HandlerVec<H::ElementEndTagHandler<'h>>,
     element_handlers: HandlerVec<H::ElementHandler<'h>>,
+    subtree_handlers: HandlerVec<H::SubtreeHandler<'h>>,
+    inner_text_handlers: HandlerVec<H::InnerTextHandler<'h>>,
     end_handlers: HandlerVec<H::EndHandler<'h>>,
+    /// Handlers of the selectors, indexed by the payload of the selector matching VM.
+    selector_handlers: Vec<SelectorHandlersLocator>,
+    /// Payload of the selectors, indexed by the index of the selector. Selectors of a list
+    /// with different specificities are matched with a payload for each of the specificities.
+    selector_payload: HashMap<usize, Vec<usize>>,
+    handler_ordering: HandlerOrdering,
     next_element_can_have_content: bool,
+    next_element_deferred_id: Option<usize>,
+    next_element_matched_selectors: Vec<usize>,
     matched_elements_with_removed_content: usize,
+    deferred_elements: HashMap<usize, DeferredElement>,
+    deferred_element_count: usize,
+    lazily_matched_element_ids: HashMap<usize, usize>,
+    deferred_element_events: Vec<DeferredElementEvent>,
+    has_ended_deferred_element: bool,
+    text_node_buffer: LimitedVec<u8>,
+    elements_holding_back_text: usize,
 }
 
-impl<H: HandlerTypes> Default for ContentHandlersDispatcher<'_, H> {
-    fn default() -> Self {
+impl<H: HandlerTypes> ContentHandlersDispatcher<'_, H> {
+    #[inline]
+    #[must_use]
+    pub fn new(memory_limiter: SharedMemoryLimiter, handler_ordering: HandlerOrdering) -> Self {
         ContentHandlersDispatcher {
             doctype_handlers: Default::default(),
             comment_handlers: Default::default(),
             text_handlers: Default::default(),
+            text_node_handlers: Default::default(),
             end_tag_handlers: Default::default(),
+            element_end_tag_handlers: Default::default(),
             element_handlers: Default::default(),
+            subtree_handlers: Default::default(),
+            inner_text_handlers: Default::default(),
             end_handlers: Default::default(),
+            selector_handlers: Vec::default(),
+            selector_payload: HashMap::default(),
+            handler_ordering,
             next_element_can_have_content: false,
+            next_element_deferred_id: None,
+            next_element_matched_selectors: Vec::default(),
             matched_elements_with_removed_content: 0,
+            deferred_elements: HashMap::default(),
+            deferred_element_count: 0,
+            lazily_matched_element_ids: HashMap::default(),
+            deferred_element_events: Vec::default(),
+            has_ended_deferred_element: false,
+            text_node_buffer: LimitedVec::new(memory_limiter),
+            elements_holding_back_text: 0,
         }
     }
 }
@@ -153,29 +366,154 @@ impl<'h, H: HandlerTypes> ContentHandlersDispatcher<'h, H> {
             self.text_handlers.push(handler, true);
         }
 
+        if let Some(handler) = handlers.text_node {
+            self.text_node_handlers.push(handler, true);
+        }
+
         if let Some(handler) = handlers.end {
             self.end_handlers.push(handler, true);
         }
     }
 
+    /// Adds the handlers of the selector, whose list has selectors with the given `specificities`.
+    /// Returns the payload for each of the specificities, that the selectors with this specificity
+    /// should be matched with by the selector matching VM.
     #[inline]
     pub fn add_selector_associated_handlers(
         &mut self,
         handlers: ElementContentHandlers<'h, H>,
+        selector_idx: Option<usize>,
+        specificities: &[Specificity],
+    ) -> Vec<usize> {
+        let payload = self.selector_handlers.len();
+        let payloads = specificities
+            .iter()
+            .enumerate()
+            .map(|(i, &specificity)| (payload + i, specificity))
+            .collect::<Vec<_>>();
+
+        self.reserve_selector_payload(payload + payloads.len());
+        self.set_selector_associated_handlers(&payloads, handlers, selector_idx);
+
+        payloads.into_iter().map(|(payload, _)| payload).collect()
+    }
+
+    /// Reserves the payload from `0` up to `count` for the selectors of a precompiled program.
+    /// The handlers of these selectors are set with [`set_selector_associated_handlers`].
+    ///
+    /// [`set_selector_associated_handlers`]: Self::set_selector_associated_handlers
+    #[inline]
+    pub fn reserve_selector_payload(&mut self, count: usize) {
+        if self.selector_handlers.len() < count {
+            self.selector_handlers.resize(count, Default::default());
+        }
+    }
+
+    /// Sets the handlers of the selector matched with the reserved `payloads`, each of them
+    /// for the selectors of the list with the given specificity.
+    pub fn set_selector_associated_handlers(
+        &mut self,
+        payloads: &[(usize, Specificity)],
+        handlers: ElementContentHandlers<'h, H>,
+        selector_idx: Option<usize>,
+    ) {
+        let locator = self.register_selector_associated_handlers(handlers, selector_idx);
+
+        for &(payload, specificity) in payloads {
+            let rank = match self.handler_ordering {
+                HandlerOrdering::Registration => Specificity::default(),
+                HandlerOrdering::Specificity => specificity,
+            };
+
+            self.selector_handlers[payload] = SelectorHandlersLocator { rank, ..locator };
+        }
+
+        if let Some(idx) = selector_idx {
+            self.selector_payload
+                .insert(idx, payloads.iter().map(|&(payload, _)| payload).collect());
+        }
+    }
+
+    /// Drops the handlers of the selector matched with the `payload`. The handlers aren't
+    /// invoked from now on, even for the elements that have already matched the selector.
+    pub fn remove_selector_associated_handlers(&mut self, payload: usize) {
+        let locator = self.selector_handlers[payload];
+
+        self.selector_handlers[payload] = SelectorHandlersLocator {
+            selector_idx: locator.selector_idx,
+            ..SelectorHandlersLocator::default()
+        };
+
+        if let Some(idx) = locator.element_handler_idx {
+            self.element_handlers.remove(idx);
+        }
+
+        if let Some(idx) = locator.comment_handler_idx {
+            self.comment_handlers.remove(idx);
+        }
+
+        if let Some(idx) = locator.text_handler_idx {
+            self.text_handlers.remove(idx);
+        }
+
+        if let Some(idx) = locator.text_node_handler_idx {
+            self.text_node_handlers.remove(idx);
+        }
+
+        if let Some(idx) = locator.end_tag_handler_idx {
+            self.element_end_tag_handlers.remove(idx);
+        }
+
+        if let Some(idx) = locator.subtree_handler_idx {
+            self.subtree_handlers.remove(idx);
+        }
+
+        if let Some(idx) = locator.inner_text_handler_idx {
+            self.inner_text_handlers.remove(idx);
+        }
+    }
+
+    /// Returns the index of the selector matched with the `payload`.
+    #[inline]
+    pub fn selector_idx(&self, payload: usize) -> Option<usize> {
+        self.selector_handlers[payload].selector_idx
+    }
+
+    /// Returns the payloads of the selector with the given index.
+    #[inline]
+    pub fn selector_payload(&self, selector_idx: usize) -> &[usize] {
+        self.selector_payload
+            .get(&selector_idx)
+            .map_or(&[], Vec::as_slice)
+    }
+
+    fn register_selector_associated_handlers(
+        &mut self,
+        handlers: ElementContentHandlers<'h, H>,
+        selector_idx: Option<usize>,
     ) -> SelectorHandlersLocator {
         SelectorHandlersLocator {
-            element_handler_idx: handlers.element.map(|h| {
-                self.element_handlers.push(h, false);
-                self.element_handlers.len() - 1
-            }),
-            comment_handler_idx: handlers.comments.map(|h| {
-                self.comment_handlers.push(h, false);
-                self.comment_handlers.len() - 1
-            }),
-            text_handler_idx: handlers.text.map(|h| {
-                self.text_handlers.push(h, false);
-                self.text_handlers.len() - 1
-            }),
+            element_handler_idx: handlers
+                .element
+                .map(|h| self.element_handlers.push(h, false)),
+            comment_handler_idx: handlers
+                .comments
+                .map(|h| self.comment_handlers.push(h, false)),
+            text_handler_idx: handlers.text.map(|h| self.text_handlers.push(h, false)),
+            text_node_handler_idx: handlers
+                .text_node
+                .map(|h| self.text_node_handlers.push(h, false)),
+            end_tag_handler_idx: handlers
+                .end_tag
+                .map(|h| self.element_end_tag_handlers.push(h, false)),
+            subtree_handler_idx: handlers
+                .capture_subtree
+                .map(|h| self.subtree_handlers.push(h, false)),
+            inner_text_handler_idx: handlers
+                .inner_text
+                .map(|h| self.inner_text_handlers.push(h, false)),
+            selector_idx,
+            rank: Specificity::default(),
         }
     }
 
@@ -184,46 +522,304 @@ impl<'h, H: HandlerTypes> ContentHandlersDispatcher<'h, H> {
         self.matched_elements_with_removed_content > 0
     }
 
+    /// Returns the deferred element for the element, whose start tag is going to be handled next.
+    fn next_deferred_element(&mut self) -> (usize, &mut DeferredElement) {
+        let deferred_element_count = &mut self.deferred_element_count;
+
+        let id = *self.next_element_deferred_id.get_or_insert_with(|| {
+            *deferred_element_count += 1;
+            *deferred_element_count - 1
+        });
+
+        (id, self.deferred_elements.entry(id).or_default())
+    }
+
+    /// Discards the selectors matched for the previous element, if its start tag wasn't handled.
+    #[inline]
+    pub fn prepare_for_start_tag(&mut self) {
+        self.next_element_matched_selectors.clear();
+    }
+
     #[inline]
-    pub fn start_matching(&mut self, match_info: &MatchInfo<SelectorHandlersLocator>) {
-        let locator = match_info.payload;
+    pub fn start_matching(&mut self, match_info: &MatchInfo<usize>) {
+        let locator = self.selector_handlers[match_info.payload];
+
+        // NOTE: the selectors without handlers are still matched by the VM, if their program
+        // is shared with the rewriters that have handlers for them, but they shouldn't affect
+        // the output, e.g. by deferring it until their lazy matches are resolved.
+        if !locator.has_handlers() {
+            return;
+        }
+
+        // NOTE: handlers of lazily matched elements are invoked once the match
+        // is resolved, so we just need to capture the start tag for now.
+        if let Some(lazy_id) = match_info.lazy_element_id {
+            let (id, element) = self.next_deferred_element();
+
+            element.unresolved_payload.push(match_info.payload);
+            element.can_have_content = match_info.with_content;
+            element.holds_back_text |=
+                match_info.with_content && locator.inner_text_handler_idx.is_some();
+
+            self.lazily_matched_element_ids.insert(lazy_id, id);
+            self.next_element_can_have_content = match_info.with_content;
+
+            return;
+        }
+
+        // NOTE: the output of the element is held back until the element ends,
+        // so the subtree handlers can replace the whole element.
+        if let Some(idx) = locator.subtree_handler_idx {
+            let (_, element) = self.next_deferred_element();
+
+            element.matched_subtree_handlers.push((idx, locator.rank));
+            element.can_have_content = match_info.with_content;
+        }
+
+        // NOTE: the text of the element descendants is held back separately from the rest
+        // of the element output, so the inner text handlers can modify it.
+        if let Some(idx) = locator.inner_text_handler_idx {
+            let (_, element) = self.next_deferred_element();
+
+            element
+                .matched_inner_text_handlers
+                .push((idx, locator.rank));
+            element.can_have_content = match_info.with_content;
+            element.holds_back_text = match_info.with_content;
+        }
 
         if match_info.with_content {
             if let Some(idx) = locator.comment_handler_idx {
-                self.comment_handlers.inc_user_count(idx);
+                self.comment_handlers.inc_user_count(idx, locator.rank);
             }
 
             if let Some(idx) = locator.text_handler_idx {
-                self.text_handlers.inc_user_count(idx);
+                self.text_handlers.inc_user_count(idx, locator.rank);
+            }
+
+            if let Some(idx) = locator.text_node_handler_idx {
+                self.text_node_handlers.inc_user_count(idx, locator.rank);
             }
         }
 
         if let Some(idx) = locator.element_handler_idx {
-            self.element_handlers.inc_user_count(idx);
+            self.element_handlers.inc_user_count(idx, locator.rank);
+        }
+
+        if let Some(idx) = locator.selector_idx {
+            self.next_element_matched_selectors.push(idx);
         }
 
         self.next_element_can_have_content = match_info.with_content;
     }
 
+    /// `has_end_tag` is `false` if the element is closed implicitly by the end tag of its ancestor.
     #[inline]
-    pub fn stop_matching(&mut self, elem_desc: ElementDescriptor) {
-        for locator in elem_desc.matched_content_handlers {
+    pub fn stop_matching(&mut self, elem_desc: ElementDescriptor, has_end_tag: bool) {
+        for payload in elem_desc.matched_content_handlers {
+            let locator = self.selector_handlers[payload];
+
             if let Some(idx) = locator.comment_handler_idx {
-                self.comment_handlers.dec_user_count(idx);
+                self.comment_handlers.dec_user_count(idx, locator.rank);
             }
 
             if let Some(idx) = locator.text_handler_idx {
-                self.text_handlers.dec_user_count(idx);
+                self.text_handlers.dec_user_count(idx, locator.rank);
+            }
+
+            if let Some(idx) = locator.text_node_handler_idx {
+                self.text_node_handlers.dec_user_count(idx, locator.rank);
+            }
+
+            if let Some(idx) = locator.end_tag_handler_idx {
+                self.element_end_tag_handlers
+                    .inc_user_count(idx, locator.rank);
+            }
+        }
+
+        // NOTE: selectors with `:has()` are matched once a descendant of the element matches,
+        // but only end tag handlers are invoked for them, since the rest of the element has
+        // already been processed by the time the element ends.
+        for payload in elem_desc.descendant_matched_content_handlers {
+            let locator = self.selector_handlers[payload];
+
+            if let Some(idx) = locator.end_tag_handler_idx {
+                self.element_end_tag_handlers
+                    .inc_user_count(idx, locator.rank);
             }
         }
 
         if let Some(idx) = elem_desc.end_tag_handler_idx {
-            self.end_tag_handlers.inc_user_count(idx);
+            self.end_tag_handlers
+                .inc_user_count(idx, Specificity::default());
         }
 
         if elem_desc.remove_content {
             self.matched_elements_with_removed_content -= 1;
         }
+
+        if elem_desc.holds_back_text {
+            self.elements_holding_back_text -= 1;
+        }
+
+        if let Some(id) = elem_desc.deferred_element_id {
+            self.deferred_element_events
+                .push(DeferredElementEvent::Ended { id, has_end_tag });
+
+            self.has_ended_deferred_element = true;
+        }
+    }
+
+    fn resolve_deferred_element(&mut self, id: usize) {
+        let is_match = self
+            .deferred_elements
+            .get(&id)
+            .is_some_and(DeferredElement::is_match);
+
+        if !is_match {
+            self.deferred_elements.remove(&id);
+        }
+
+        self.deferred_element_events
+            .push(DeferredElementEvent::Resolved { id, is_match });
+    }
+
+    pub fn resolve_lazy_match(&mut self, resolution: LazyMatchResolution<usize>) {
+        let locator = self.selector_handlers[resolution.payload];
+
+        let Some(&id) = self.lazily_matched_element_ids.get(&resolution.element_id) else {
+            return;
+        };
+
+        let Some(element) = self.deferred_elements.get_mut(&id) else {
+            return;
+        };
+
+        // NOTE: the lazy matches of the selectors without handlers aren't tracked.
+        let Some(pos) = element
+            .unresolved_payload
+            .iter()
+            .position(|&p| p == resolution.payload)
+        else {
+            return;
+        };
+
+        if resolution.is_match {
+            if let Some(idx) = locator.element_handler_idx {
+                element.matched_element_handlers.push((idx, locator.rank));
+            }
+
+            if let Some(idx) = locator.end_tag_handler_idx {
+                element.matched_end_tag_handlers.push((idx, locator.rank));
+            }
+
+            if let Some(idx) = locator.subtree_handler_idx {
+                element.matched_subtree_handlers.push((idx, locator.rank));
+            }
+
+            if let Some(idx) = locator.inner_text_handler_idx {
+                element
+                    .matched_inner_text_handlers
+                    .push((idx, locator.rank));
+            }
+
+            if let Some(idx) = locator.selector_idx {
+                element.matched_selectors.push(idx);
+            }
+        }
+
+        element.unresolved_payload.swap_remove(pos);
+
+        if element.unresolved_payload.is_empty() {
+            self.lazily_matched_element_ids
+                .remove(&resolution.element_id);
+
+            self.resolve_deferred_element(id);
+        }
+    }
+
+    #[inline]
+    pub fn take_deferred_element_events(&mut self) -> Vec<DeferredElementEvent> {
+        std::mem::take(&mut self.deferred_element_events)
+    }
+
+    /// Invokes handlers of the deferred element. Returns `true` if the
+    /// element content should be removed.
+    pub fn handle_deferred_element(
+        &mut self,
+        id: usize,
+        start_tag: &mut StartTag<'_>,
+        end_tag: Option<&mut EndTag<'_>>,
+        content: &mut DeferredOutput,
+    ) -> Result<bool, RewritingError> {
+        let Some(mut deferred_element) = self.deferred_elements.remove(&id) else {
+            return Ok(false);
+        };
+
+        deferred_element.matched_selectors.sort_unstable();
+        deferred_element.matched_selectors.dedup();
+
+        let encoding = start_tag.encoding();
+        let mut element = Element::new(start_tag, deferred_element.can_have_content);
+
+        element.set_matched_selectors(deferred_element.matched_selectors);
+
+        // NOTE: handlers are invoked in the same order as for the eagerly matched elements.
+        self.element_handlers
+            .for_each_of(deferred_element.matched_element_handlers, |h| {
+                h(&mut element)
+            })
+            .map_err(RewritingError::ContentHandlerError)?;
+
+        if !deferred_element.matched_inner_text_handlers.is_empty() {
+            let mut inner_text = InnerText::new(content.texts());
+
+            self.inner_text_handlers
+                .for_each_of(deferred_element.matched_inner_text_handlers, |h| {
+                    h(&mut element, &mut inner_text)
+                })
+                .map_err(RewritingError::ContentHandlerError)?;
+
+            if let Some(text_chunks) = inner_text.into_modified_text_chunks() {
+                for (idx, text) in text_chunks.enumerate() {
+                    content
+                        .set_text(idx, text)
+                        .map_err(RewritingError::MemoryLimitExceeded)?;
+                }
+            }
+        }
+
+        if !deferred_element.matched_subtree_handlers.is_empty() {
+            let content = content.to_bytes();
+            let (inner_html, _) = encoding.decode_without_bom_handling(&content);
+
+            self.subtree_handlers
+                .for_each_of(deferred_element.matched_subtree_handlers, |h| {
+                    h(&mut element, &inner_html)
+                })
+                .map_err(RewritingError::ContentHandlerError)?;
+        }
+
+        let remove_content = element.should_remove_content();
+
+        if let Some(end_tag) = end_tag {
+            if let Some(handler) = element.into_end_tag_handler() {
+                handler(end_tag).map_err(RewritingError::ContentHandlerError)?;
+            }
+
+            self.element_end_tag_handlers
+                .for_each_of(deferred_element.matched_end_tag_handlers, |h| h(end_tag))
+                .map_err(RewritingError::ContentHandlerError)?;
+        }
+
+        Ok(remove_content)
+    }
+
+    /// Returns `true` if the text of the current element descendants should be held back,
+    /// so the inner text handlers can modify it.
+    #[inline]
+    pub const fn should_hold_back_text(&self) -> bool {
+        self.elements_holding_back_text > 0
     }
 
     pub fn handle_start_tag(
@@ -235,13 +831,58 @@ impl<'h, H: HandlerTypes> ContentHandlersDispatcher<'h, H> {
             start_tag.remove();
         }
 
+        let deferred_element_id = self.next_element_deferred_id.take();
+        let mut holds_back_text = false;
+        let mut matched_selectors = std::mem::take(&mut self.next_element_matched_selectors);
+
+        matched_selectors.sort_unstable();
+        matched_selectors.dedup();
+
+        if let Some(id) = deferred_element_id {
+            self.deferred_element_events
+                .push(DeferredElementEvent::Deferred {
+                    id,
+                    can_have_content: self.next_element_can_have_content,
+                });
+
+            // NOTE: if the element is deferred only because its content is captured, it's
+            // resolved right away. The output sink holds the resolution until the element ends.
+            let is_decided = self
+                .deferred_elements
+                .get(&id)
+                .is_some_and(|e| e.unresolved_payload.is_empty());
+
+            if let Some(element) = self.deferred_elements.get_mut(&id) {
+                holds_back_text = element.holds_back_text;
+
+                // NOTE: the deferred element handlers are invoked later, so they
+                // need to know about the selectors that have matched eagerly.
+                element
+                    .matched_selectors
+                    .extend_from_slice(&matched_selectors);
+            }
+
+            if is_decided {
+                self.resolve_deferred_element(id);
+            }
+        }
+
         let mut element = Element::new(start_tag, self.next_element_can_have_content);
 
+        element.set_matched_selectors(matched_selectors);
+
         self.element_handlers
             .do_for_each_active_and_deactivate(|h| h(&mut element))?;
 
         if self.next_element_can_have_content {
             if let Some(elem_desc) = current_element_data {
+                elem_desc.deferred_element_id = deferred_element_id;
+
+                if holds_back_text {
+                    elem_desc.holds_back_text = true;
+                    self.elements_holding_back_text += 1;
+                }
+
                 if element.should_remove_content() {
                     elem_desc.remove_content = true;
                     self.matched_elements_with_removed_content += 1;
@@ -266,19 +907,74 @@ impl<'h, H: HandlerTypes> ContentHandlersDispatcher<'h, H> {
         match token {
             Token::Doctype(doctype) => self.doctype_handlers.for_each_active(|h| h(doctype)),
             Token::StartTag(start_tag) => self.handle_start_tag(start_tag, current_element_data),
-            Token::EndTag(end_tag) => self
-                .end_tag_handlers
-                .do_for_each_active_and_remove(|h| h(end_tag)),
+            Token::EndTag(end_tag) => {
+                self.has_ended_deferred_element = false;
+
+                self.end_tag_handlers
+                    .do_for_each_active_and_remove(|h| h(end_tag))?;
+
+                self.element_end_tag_handlers
+                    .do_for_each_active_and_deactivate(|h| h(end_tag))
+            }
             Token::TextChunk(text) => self.text_handlers.for_each_active(|h| h(text)),
             Token::Comment(comment) => self.comment_handlers.for_each_active(|h| h(comment)),
         }
     }
 
+    /// Buffers the text chunk until the last chunk of the text node, that is then
+    /// passed to the text node handlers with the whole content of the node.
+    ///
+    /// Returns `true` if the chunk is buffered, so it's removed from the output and isn't
+    /// passed to the text handlers.
+    pub fn handle_text_node_chunk(
+        &mut self,
+        chunk: &mut TextChunk<'_>,
+    ) -> Result<bool, RewritingError> {
+        if !self.text_node_handlers.has_active() {
+            return Ok(false);
+        }
+
+        if !chunk.last_in_text_node() {
+            self.text_node_buffer
+                .extend_from_slice(chunk.as_str().as_bytes())
+                .map_err(RewritingError::MemoryLimitExceeded)?;
+
+            chunk.remove();
+
+            return Ok(true);
+        }
+
+        let buffered_text = String::from_utf8_lossy(&self.text_node_buffer).into_owned();
+
+        self.text_node_buffer.drain(..);
+
+        if !buffered_text.is_empty() {
+            let text = buffered_text + chunk.as_str();
+
+            chunk.set_str(text);
+        }
+
+        let mut text_node = TextNode::new(chunk);
+
+        self.text_node_handlers
+            .for_each_active(|h| h(&mut text_node))
+            .map_err(RewritingError::ContentHandlerError)?;
+
+        Ok(false)
+    }
+
     pub fn handle_end(&mut self, document_end: &mut DocumentEnd<'_>) -> HandlerResult {
         self.end_handlers
             .do_for_each_active_and_remove(|h| h(document_end))
     }
 
+    #[inline]
+    fn next_element_holds_back_text(&self) -> bool {
+        self.next_element_deferred_id
+            .and_then(|id| self.deferred_elements.get(&id))
+            .is_some_and(|e| e.holds_back_text)
+    }
+
     #[inline]
     pub fn get_token_capture_flags(&self) -> TokenCaptureFlags {
         let mut flags = TokenCaptureFlags::empty();
@@ -291,18 +987,28 @@ impl<'h, H: HandlerTypes> ContentHandlersDispatcher<'h, H> {
             flags |= TokenCaptureFlags::COMMENTS;
         }
 
-        if self.text_handlers.has_active() {
+        if self.text_handlers.has_active()
+            || self.text_node_handlers.has_active()
+            || self.elements_holding_back_text > 0
+            || self.next_element_holds_back_text()
+        {
             flags |= TokenCaptureFlags::TEXT;
         }
 
-        if self.end_tag_handlers.has_active() {
+        if self.end_tag_handlers.has_active() || self.element_end_tag_handlers.has_active() {
             flags |= TokenCaptureFlags::NEXT_END_TAG;
         }
 
-        if self.element_handlers.has_active() {
+        if self.element_handlers.has_active() || self.next_element_deferred_id.is_some() {
             flags |= TokenCaptureFlags::NEXT_START_TAG;
         }
 
+        // NOTE: the end tag of the deferred element should be captured,
+        // so it can be held back with the rest of the element output.
+        if self.has_ended_deferred_element {
+            flags |= TokenCaptureFlags::NEXT_END_TAG;
+        }
+
         flags
     }
 }
@@ -1,17 +1,21 @@
+mod compiled_selectors;
 mod handlers_dispatcher;
 mod rewrite_controller;
 
 #[macro_use]
 pub(crate) mod settings;
 
+pub use self::compiled_selectors::CompiledSelectors;
 use self::handlers_dispatcher::ContentHandlersDispatcher;
-use self::rewrite_controller::{ElementDescriptor, HtmlRewriteController};
+use self::rewrite_controller::{
+    ElementDescriptor, HtmlRewriteController, SelectorMatchingVmSettings,
+};
 pub use self::settings::*;
 use crate::base::SharedEncoding;
 use crate::memory::{MemoryLimitExceededError, SharedMemoryLimiter};
 use crate::parser::ParsingAmbiguityError;
 use crate::rewritable_units::Element;
-use crate::selectors_vm::{self, SelectorMatchingVm};
+use crate::selectors_vm;
 use crate::transform_stream::*;
 use encoding_rs::Encoding;
 use mime::Mime;
@@ -130,8 +134,15 @@ pub enum RewritingError {
 pub struct HtmlRewriter<'h, O: OutputSink, H: HandlerTypes = LocalHandlerTypes> {
     stream: TransformStream<HtmlRewriteController<'h, H>, O>,
     poisoned: bool,
+    handlers_error: Option<selectors_vm::SelectorError>,
 }
 
+/// Compiled selectors with the handlers for them by the selector index.
+type CompiledSelectorsHandlers<'c, 'h, H> = (
+    &'c CompiledSelectors,
+    Vec<(usize, ElementContentHandlers<'h, H>)>,
+);
+
 macro_rules! guarded {
     ($self:ident, $expr:expr) => {{
         assert!(
@@ -139,7 +150,10 @@ macro_rules! guarded {
             "Attempt to use the HtmlRewriter after a fatal error."
         );
 
-        let res = $expr;
+        let res = match $self.handlers_error.take() {
+            Some(err) => Err(RewritingError::ContentHandlerError(Box::new(err))),
+            None => $expr,
+        };
 
         if res.is_err() {
             $self.poisoned = true;
@@ -157,13 +171,135 @@ impl<'h, O: OutputSink, H: HandlerTypes> HtmlRewriter<'h, O, H> {
     ///
     /// For the convenience the [`OutputSink`] trait is implemented for closures.
     ///
+    /// If the handlers are not supported for their selector (e.g. text handlers for
+    /// `li:last-child`), the first call to [`write`] or [`end`] returns the
+    /// [`SelectorError::UnsupportedContentHandlers`] as a [`RewritingError::ContentHandlerError`].
+    /// Use [`try_new`] to get the error right away.
+    ///
     /// [`OutputSink`]: trait.OutputSink.html
+    /// [`write`]: HtmlRewriter::write
+    /// [`end`]: HtmlRewriter::end
+    /// [`try_new`]: HtmlRewriter::try_new
+    /// [`SelectorError::UnsupportedContentHandlers`]: crate::errors::SelectorError::UnsupportedContentHandlers
     pub fn new<'s>(settings: Settings<'h, 's, H>, output_sink: O) -> Self {
+        Self::with_selectors(settings, None, output_sink)
+    }
+
+    /// Constructs a new rewriter the same way as [`new`], but returns an error if the handlers
+    /// are not supported for their selector.
+    ///
+    /// # Errors
+    ///  * [`SelectorError::UnsupportedContentHandlers`] if text, comment or text node handlers
+    ///    are set for a selector that is matched lazily (e.g. `li:last-child`), or the handlers
+    ///    other than the end tag handler are set for a selector with `:has()`.
+    ///
+    /// [`new`]: HtmlRewriter::new
+    /// [`SelectorError::UnsupportedContentHandlers`]: crate::errors::SelectorError::UnsupportedContentHandlers
+    pub fn try_new<'s>(
+        settings: Settings<'h, 's, H>,
+        output_sink: O,
+    ) -> Result<Self, selectors_vm::SelectorError> {
+        Self::with_selectors(settings, None, output_sink).into_result()
+    }
+
+    /// Constructs a new rewriter for the precompiled selectors, that writes the output
+    /// to the `output_sink`.
+    ///
+    /// The `element_content_handlers` are the handlers for the compiled selectors with the given
+    /// indices. The selectors without handlers don't affect the rewriting, e.g. the output isn't
+    /// held back until it's known whether an element matches such a selector.
+    ///
+    /// The element content handlers of the `settings`, if any, are compiled for the rewriter and
+    /// are invoked after the handlers of the compiled selectors, unless the
+    /// [`HandlerOrdering::Specificity`] is used. These handlers, as well as
+    /// [`adjust_charset_on_meta_tag`](Settings::adjust_charset_on_meta_tag), make the rewriter
+    /// copy the compiled program, so it's more efficient to have all the selectors compiled.
+    ///
+    /// See [`CompiledSelectors`] for an example.
+    ///
+    /// The handlers, that are not supported for their selector, are reported the same way as
+    /// for [`new`]. Use [`try_with_compiled_selectors`] to get the error right away.
+    ///
+    /// # Panics
+    ///  * If the encoding of the `settings` differs from the encoding of the compiled selectors.
+    ///  * If the index of the handlers is out of bounds or repeated.
+    ///
+    /// [`new`]: HtmlRewriter::new
+    /// [`try_with_compiled_selectors`]: HtmlRewriter::try_with_compiled_selectors
+    pub fn with_compiled_selectors<'s>(
+        compiled_selectors: &CompiledSelectors,
+        element_content_handlers: Vec<(usize, ElementContentHandlers<'h, H>)>,
+        settings: Settings<'h, 's, H>,
+        output_sink: O,
+    ) -> Self {
+        assert_eq!(
+            settings.encoding,
+            compiled_selectors.encoding(),
+            "The selectors are compiled for a different encoding"
+        );
+
+        Self::with_selectors(
+            settings,
+            Some((compiled_selectors, element_content_handlers)),
+            output_sink,
+        )
+    }
+
+    /// Constructs a new rewriter for the precompiled selectors the same way as
+    /// [`with_compiled_selectors`], but returns an error if the handlers are not supported for
+    /// their selector.
+    ///
+    /// # Errors
+    ///  * [`SelectorError::UnsupportedContentHandlers`] if text, comment or text node handlers
+    ///    are set for a selector that is matched lazily (e.g. `li:last-child`), or the handlers
+    ///    other than the end tag handler are set for a selector with `:has()`.
+    ///
+    /// # Panics
+    ///  * If the encoding of the `settings` differs from the encoding of the compiled selectors.
+    ///  * If the index of the handlers is out of bounds or repeated.
+    ///
+    /// [`with_compiled_selectors`]: HtmlRewriter::with_compiled_selectors
+    /// [`SelectorError::UnsupportedContentHandlers`]: crate::errors::SelectorError::UnsupportedContentHandlers
+    pub fn try_with_compiled_selectors<'s>(
+        compiled_selectors: &CompiledSelectors,
+        element_content_handlers: Vec<(usize, ElementContentHandlers<'h, H>)>,
+        settings: Settings<'h, 's, H>,
+        output_sink: O,
+    ) -> Result<Self, selectors_vm::SelectorError> {
+        Self::with_compiled_selectors(
+            compiled_selectors,
+            element_content_handlers,
+            settings,
+            output_sink,
+        )
+        .into_result()
+    }
+
+    #[inline]
+    fn into_result(self) -> Result<Self, selectors_vm::SelectorError> {
+        match self.handlers_error {
+            Some(err) => Err(err),
+            None => Ok(self),
+        }
+    }
+
+    fn with_selectors<'s>(
+        settings: Settings<'h, 's, H>,
+        compiled_selectors: Option<CompiledSelectorsHandlers<'_, 'h, H>>,
+        output_sink: O,
+    ) -> Self {
         let encoding = SharedEncoding::new(settings.encoding);
-        let mut selectors_ast = selectors_vm::Ast::default();
-        let mut dispatcher = ContentHandlersDispatcher::<H>::default();
-        let has_selectors =
-            !settings.element_content_handlers.is_empty() || settings.adjust_charset_on_meta_tag;
+        let memory_limiter =
+            SharedMemoryLimiter::new(settings.memory_settings.max_allowed_memory_usage);
+        let mut dispatcher =
+            ContentHandlersDispatcher::<H>::new(memory_limiter.clone(), settings.handler_ordering);
+
+        let vm_settings = SelectorMatchingVmSettings {
+            encoding: settings.encoding.into(),
+            memory_limiter: memory_limiter.clone(),
+            enable_esi_tags: settings.enable_esi_tags,
+            enable_stats: settings.enable_selector_matching_stats,
+        };
 
         let charset_adjust_handler = if settings.adjust_charset_on_meta_tag {
             let encoding = SharedEncoding::clone(&encoding);
@@ -172,35 +308,118 @@ impl<'h, O: OutputSink, H: HandlerTypes> HtmlRewriter<'h, O, H> {
             None
         };
 
-        let element_content_handlers = charset_adjust_handler
-            .into_iter()
-            .chain(settings.element_content_handlers);
+        // NOTE: the first of the unsupported handlers is reported once the rewriter is used.
+        let mut handlers_error = None;
+        let mut check_supported = |handlers: &ElementContentHandlers<'_, H>, supported| {
+            if let Err(err) = handlers.check_supported(supported) {
+                handlers_error.get_or_insert(err);
+            }
+        };
+
+        let (selector_matching_vm, selector_count, added_handlers) = match compiled_selectors {
+            Some((compiled_selectors, handlers)) => {
+                let selector_count = compiled_selectors.len();
+
+                dispatcher.reserve_selector_payload(compiled_selectors.payload_count());
+
+                let charset_adjust_handler = charset_adjust_handler.map(|(selector, handlers)| {
+                    let payloads = dispatcher.add_selector_associated_handlers(
+                        handlers,
+                        None,
+                        &[selectors_vm::Specificity::default()],
+                    );
+
+                    (selector, payloads[0])
+                });
+
+                let mut has_handlers = vec![false; selector_count];
+                let handlers = handlers
+                    .into_iter()
+                    .inspect(|&(idx, _)| {
+                        assert!(
+                            idx < selector_count
+                                && !std::mem::replace(&mut has_handlers[idx], true),
+                            "Invalid index {idx} of the compiled selector handlers"
+                        );
+                    })
+                    .inspect(|(idx, handlers)| {
+                        check_supported(handlers, compiled_selectors.supported_handlers(*idx));
+                    });
+
+                for (idx, handlers) in handlers {
+                    dispatcher.set_selector_associated_handlers(
+                        compiled_selectors.payloads(idx),
+                        handlers,
+                        Some(idx),
+                    );
+                }
 
-        for (selector, handlers) in element_content_handlers {
-            let locator = dispatcher.add_selector_associated_handlers(handlers);
+                let mut vm = vm_settings.new_vm_with_program(compiled_selectors.program());
 
-            selectors_ast.add_selector(&selector, locator);
-        }
+                if let Some((selector, payload)) = charset_adjust_handler {
+                    vm.add_selector(&selector, payload);
+                }
+
+                (Some(vm), selector_count, settings.element_content_handlers)
+            }
+            None => {
+                let mut selectors_ast = selectors_vm::Ast::default();
+                let has_selectors = !settings.element_content_handlers.is_empty()
+                    || charset_adjust_handler.is_some();
+
+                let selector_count = settings.element_content_handlers.len();
+
+                // NOTE: the charset is adjusted before the rest of the handlers are invoked.
+                let element_content_handlers = charset_adjust_handler
+                    .map(|(selector, handlers)| {
+                        (None, selector, handlers, vec![Default::default()])
+                    })
+                    .into_iter()
+                    .chain(
+                        settings
+                            .element_content_handlers
+                            .into_iter()
+                            .enumerate()
+                            .map(|(idx, (selector, handlers))| {
+                                let specificities = selector.distinct_specificities();
+
+                                (Some(idx), selector, handlers, specificities)
+                            }),
+                    );
+
+                for (selector_idx, selector, handlers, specificities) in element_content_handlers {
+                    check_supported(&handlers, selector.supported_handlers());
+
+                    let payloads = dispatcher.add_selector_associated_handlers(
+                        handlers,
+                        selector_idx,
+                        &specificities,
+                    );
+
+                    selectors_ast.add_selector_by_specificity(&selector, &payloads);
+                }
+
+                let vm = has_selectors.then(|| vm_settings.new_vm(selectors_ast));
+
+                (vm, selector_count, Vec::new())
+            }
+        };
 
         for handlers in settings.document_content_handlers {
             dispatcher.add_document_content_handlers(handlers);
         }
 
-        let memory_limiter =
-            SharedMemoryLimiter::new(settings.memory_settings.max_allowed_memory_usage);
-
-        let selector_matching_vm = if has_selectors {
-            Some(SelectorMatchingVm::new(
-                selectors_ast,
-                settings.encoding.into(),
-                memory_limiter.clone(),
-                settings.enable_esi_tags,
-            ))
-        } else {
-            None
-        };
+        let mut controller = HtmlRewriteController::new(
+            dispatcher,
+            selector_matching_vm,
+            vm_settings,
+            selector_count,
+        );
 
-        let controller = HtmlRewriteController::new(dispatcher, selector_matching_vm);
+        for (selector, handlers) in added_handlers {
+            check_supported(&handlers, selector.supported_handlers());
+            controller.add_element_content_handlers(&selector, handlers);
+        }
 
         let stream = TransformStream::new(TransformStreamSettings {
             transform_controller: controller,
@@ -216,9 +435,92 @@ impl<'h, O: OutputSink, H: HandlerTypes> HtmlRewriter<'h, O, H> {
         HtmlRewriter {
             stream,
             poisoned: false,
+            handlers_error,
         }
     }
 
+    /// Adds the element content `handlers` for the `selector` to the rewriter. Returns the id of
+    /// the handlers, that can be used to [`remove`] them.
+    ///
+    /// The handlers are invoked for the elements, whose start tags are written to the rewriter
+    /// after the handlers are added. The elements written before are not taken into account
+    /// for matching, e.g. `body p` doesn't match the paragraphs of an already open `<body>`,
+    /// and `h1 + p` doesn't match a paragraph that follows a heading written before.
+    ///
+    /// The pseudo-classes, that depend on the preceding siblings of the element (e.g.
+    /// `:first-child`), and `:root` don't match the elements, whose preceding siblings or
+    /// ancestors the rewriter doesn't know about. This is the case for the elements, whose
+    /// parents were written to the rewriter before the first selector was added to it, or,
+    /// for the `*-of-type` pseudo-classes, before the first of them was added.
+    ///
+    /// The handlers are ordered with the rest of the handlers according to the
+    /// [`HandlerOrdering`], i.e. they are invoked after the handlers added before them, unless
+    /// the [`HandlerOrdering::Specificity`] is used.
+    ///
+    /// # Example
+    /// ```
+    /// use lol_html::{element, HtmlRewriter, Settings};
+    ///
+    /// let mut output = vec![];
+    /// let mut rewriter = HtmlRewriter::new(Settings::new(), |c: &[u8]| {
+    ///     output.extend_from_slice(c)
+    /// });
+    ///
+    /// rewriter.write(b"<p>foo</p>").unwrap();
+    ///
+    /// let (selector, handlers) = element!("p", |el| {
+    ///     el.set_attribute("class", "bar")?;
+    ///     Ok(())
+    /// });
+    ///
+    /// let id = rewriter.add_element_handler(&selector, handlers).unwrap();
+    ///
+    /// rewriter.write(b"<p>baz</p>").unwrap();
+    /// rewriter.remove_handler(id);
+    /// rewriter.write(b"<p>qux</p>").unwrap();
+    /// rewriter.end().unwrap();
+    ///
+    /// assert_eq!(
+    ///     String::from_utf8(output).unwrap(),
+    ///     r#"<p>foo</p><p class="bar">baz</p><p>qux</p>"#
+    /// );
+    /// ```
+    ///
+    /// # Errors
+    ///  * [`SelectorError::UnsupportedContentHandlers`] if text, comment or text node handlers
+    ///    are set for a selector that is matched lazily (e.g. `li:last-child`), or the handlers
+    ///    other than the end tag handler are set for a selector with `:has()`.
+    ///
+    /// [`remove`]: HtmlRewriter::remove_handler
+    /// [`SelectorError::UnsupportedContentHandlers`]: crate::errors::SelectorError::UnsupportedContentHandlers
+    pub fn add_element_handler(
+        &mut self,
+        selector: &crate::Selector,
+        handlers: ElementContentHandlers<'h, H>,
+    ) -> Result<HandlerId, selectors_vm::SelectorError> {
+        handlers.check_supported(selector.supported_handlers())?;
+
+        let selector_idx = self
+            .stream
+            .transform_controller_mut()
+            .add_element_content_handlers(selector, handlers);
+
+        Ok(HandlerId(selector_idx))
+    }
+
+    /// Removes the handlers added with [`add_element_handler`].
+    ///
+    /// The handlers are dropped right away, so they are not invoked from now on, even for the
+    /// elements that have matched before, e.g. for the text of an open element or once the
+    /// element ends.
+    ///
+    /// [`add_element_handler`]: HtmlRewriter::add_element_handler
+    pub fn remove_handler(&mut self, id: HandlerId) {
+        self.stream
+            .transform_controller_mut()
+            .remove_element_content_handlers(id.0);
+    }
+
     /// Writes a chunk of input data to the rewriter.
     ///
     /// # Panics
@@ -246,6 +548,124 @@ impl<'h, O: OutputSink, H: HandlerTypes> HtmlRewriter<'h, O, H> {
     pub fn end(mut self) -> Result<(), RewritingError> {
         guarded!(self, self.stream.end())
     }
+
+    /// Finalizes the rewriting process, the same way as [`end`], and returns the selector
+    /// matching statistics, if they are enabled with
+    /// [`Settings::enable_selector_matching_stats`].
+    ///
+    /// # Example
+    /// ```
+    /// use lol_html::{element, HtmlRewriter, Settings};
+    ///
+    /// let mut rewriter = HtmlRewriter::new(
+    ///     Settings {
+    ///         element_content_handlers: vec![
+    ///             element!("div p", |_| Ok(())),
+    ///             element!("a[href]", |_| Ok(())),
+    ///         ],
+    ///         enable_selector_matching_stats: true,
+    ///         ..Settings::new()
+    ///     },
+    ///     |_: &[u8]| {},
+    /// );
+    ///
+    /// rewriter.write(b"<div><p><a href=foo></a></p><p></p></div>").unwrap();
+    ///
+    /// let stats = rewriter.end_with_selector_matching_stats().unwrap().unwrap();
+    ///
+    /// let matches: Vec<_> = stats.selectors.iter().map(|s| s.matches).collect();
+    ///
+    /// assert_eq!(matches, [2, 1]);
+    /// ```
+    ///
+    /// # Panics
+    ///  * If previous invocation of [`write`] returned a [`RewritingError`] (these errors
+    ///    are unrecovarable).
+    ///
+    /// [`end`]: HtmlRewriter::end
+    /// [`write`]: HtmlRewriter::write
+    pub fn end_with_selector_matching_stats(
+        mut self,
+    ) -> Result<Option<SelectorMatchingStats>, RewritingError> {
+        guarded!(self, self.stream.end())?;
+
+        Ok(self
+            .stream
+            .transform_controller_mut()
+            .selector_matching_stats())
+    }
+}
+
+/// Statistics of selector matching, collected by a rewriter with
+/// [`Settings::enable_selector_matching_stats`].
+///
+/// See [`HtmlRewriter::end_with_selector_matching_stats`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SelectorMatchingStats {
+    /// The number of executed matching instructions. Each instruction matches a compound
+    /// selector (e.g. `a[href]`) against an element.
+    pub instructions: usize,
+    /// The number of executed instruction sets for the selectors with child, sibling and
+    /// `:has()` combinators (e.g. `ul > li`), that are executed for the children and the
+    /// siblings of the matched elements.
+    pub jumps: usize,
+    /// The number of ancestors scanned for the instructions of the selectors with descendant
+    /// combinators (e.g. `ul li`), that are executed for all the descendants of the matched
+    /// elements.
+    pub hereditary_jump_scans: usize,
+    /// The number of times the matching was suspended until the attributes of an element were
+    /// parsed, as they're required for the selectors like `a[href]`. The arguments of `:has()`
+    /// are matched again after that, so their instructions and jumps are counted twice.
+    pub attribute_bailouts: usize,
+    /// The statistics of each of the selectors, indexed the same way as
+    /// [`Element::matched_selectors`].
+    ///
+    /// [`Element::matched_selectors`]: crate::html_content::Element::matched_selectors
+    pub selectors: Vec<SelectorStats>,
+}
+
+/// Statistics of matching of a selector. See [`SelectorMatchingStats`] for the description
+/// of the counters.
+///
+/// The work shared by multiple selectors (e.g. the instruction for `div` in `div > a` and
+/// `div > p`) is counted for each of them, so the counters of the selectors don't add up to
+/// the totals. Likewise, the selectors of a list with different specificities (e.g. `p` and `#foo`
+/// in `p, #foo`) are matched separately, so an element matching more than one of them is counted
+/// in [`matches`](SelectorStats::matches) for each of them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SelectorStats {
+    /// The number of executed instructions of the selector.
+    pub instructions: usize,
+    /// The number of executed instruction sets of the selector.
+    pub jumps: usize,
+    /// The number of ancestors scanned for the instructions of the selector.
+    pub hereditary_jump_scans: usize,
+    /// The number of times the matching was suspended until the attributes of an element were
+    /// parsed for an instruction of the selector.
+    pub attribute_bailouts: usize,
+    /// The number of elements that match the selector.
+    pub matches: usize,
+}
+
+/// The id of the element content handlers added to a running [`HtmlRewriter`].
+///
+/// See [`HtmlRewriter::add_element_handler`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct HandlerId(usize);
+
+impl HandlerId {
+    /// Returns the index of the selector of the handlers, as reported by
+    /// [`Element::matched_selectors`]. The indices of the handlers added to the rewriter
+    /// follow the indices of the selectors in [`Settings::element_content_handlers`], or, for
+    /// the rewriters with [`CompiledSelectors`], the indices of the compiled selectors followed
+    /// by the ones of the settings.
+    ///
+    /// [`Element::matched_selectors`]: crate::html_content::Element::matched_selectors
+    #[inline]
+    #[must_use]
+    pub const fn index(self) -> usize {
+        self.0
+    }
 }
 
 // NOTE: this opaque Debug implementation is required to make
@@ -295,6 +715,10 @@ fn handler_adjust_charset_on_meta_tag<'h, H: HandlerTypes>(
         element: Some(H::new_element_handler(handler)),
         comments: None,
         text: None,
+        text_node: None,
+        end_tag: None,
+        capture_subtree: None,
+        inner_text: None,
     };
 
     (Cow::Owned("meta".parse().unwrap()), content_handlers)
@@ -350,17 +774,21 @@ pub fn rewrite_str<'h, 's, H: HandlerTypes>(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::html_content::ContentType;
+    use crate::errors::SelectorError;
+    use crate::html_content::{ContentType, SourceLocation, TextChunk};
     use crate::test_utils::{Output, ASCII_COMPATIBLE_ENCODINGS, NON_ASCII_COMPATIBLE_ENCODINGS};
+    use crate::{AttributeValueMatcher, Selector};
     use encoding_rs::Encoding;
     use itertools::Itertools;
     use static_assertions::assert_impl_all;
+    use std::cell::RefCell;
     use std::convert::TryInto;
     use std::sync::atomic::{AtomicUsize, Ordering};
     use std::sync::{Arc, Mutex};
 
     // Assert that HtmlRewriter with `SendHandlerTypes` is `Send`.
     assert_impl_all!(crate::send::HtmlRewriter<'_, Box<dyn FnMut(&[u8]) + Send>>: Send);
+    assert_impl_all!(CompiledSelectors: Send, Sync);
 
     fn write_chunks<O: OutputSink>(
         mut rewriter: HtmlRewriter<'_, O>,
@@ -387,6 +815,28 @@ mod tests {
         out
     }
 
+    /// Rewrites the `html`, marking the elements matched by the `selector` with the `m` attribute.
+    fn mark_matched(html: &str, selector: &str) -> String {
+        mark_matched_by(html, selector.parse().unwrap())
+    }
+
+    fn mark_matched_by(html: &str, selector: Selector) -> String {
+        rewrite_str(
+            html,
+            RewriteStrSettings {
+                element_content_handlers: vec![(
+                    Cow::Owned(selector),
+                    ElementContentHandlers::default().element(|el: &mut Element<'_, '_>| {
+                        el.set_attribute("m", "")?;
+                        Ok(())
+                    }),
+                )],
+                ..RewriteStrSettings::new()
+            },
+        )
+        .unwrap()
+    }
+
     #[allow(clippy::drop_non_drop)]
     #[test]
     fn handlers_lifetime_covariance() {
@@ -681,117 +1131,1888 @@ mod tests {
     }
 
     #[test]
-    fn write_esi_tags() {
-        let res = rewrite_str(
-            "<span><esi:include src=a></span>",
-            RewriteStrSettings {
-                element_content_handlers: vec![element!("esi\\:include", |el| {
-                    el.replace("?", ContentType::Text);
-                    Ok(())
-                })],
-                enable_esi_tags: true,
-                ..RewriteStrSettings::new()
-            },
-        )
-        .unwrap();
+    fn lazily_matched_elements() {
+        let rewrite = |html: &str, selector: &str| {
+            rewrite_str(
+                html,
+                RewriteStrSettings {
+                    element_content_handlers: vec![element!(selector, |el| {
+                        el.set_attribute("m", "")?;
+                        el.after("!", ContentType::Text);
+                        Ok(())
+                    })],
+                    ..RewriteStrSettings::new()
+                },
+            )
+            .unwrap()
+        };
 
-        assert_eq!(res, "<span>?</span>");
+        assert_eq!(
+            rewrite("<ul><li>1</li> <li>2</li><li>3</li> </ul>", "li:last-child"),
+            "<ul><li>1</li> <li>2</li><li m=\"\">3</li>! </ul>"
+        );
+
+        assert_eq!(
+            rewrite(
+                "<ul><li>1</li><li><ul><li>2</li><li>3</li></ul></li></ul><p>",
+                "li:last-child"
+            ),
+            "<ul><li>1</li><li m=\"\"><ul><li>2</li><li m=\"\">3</li>!</ul></li>!</ul><p>"
+        );
+
+        assert_eq!(
+            rewrite("<div><p>1</p><p>2</p><p>3</p></div>", "p:nth-last-child(2)"),
+            "<div><p>1</p><p m=\"\">2</p>!<p>3</p></div>"
+        );
+
+        assert_eq!(
+            rewrite("<div><b></b><i></i><b></b></div>", "div > :only-of-type"),
+            "<div><b></b><i m=\"\"></i>!<b></b></div>"
+        );
+
+        assert_eq!(
+            rewrite("<div><br><img></div>", ":not(:last-child)"),
+            "<div><br m=\"\">!<img></div>"
+        );
+
+        assert_eq!(
+            rewrite("<div><span>1</span>", "span:only-child"),
+            "<div><span m=\"\">1</span>!"
+        );
     }
 
     #[test]
-    fn test_rewrite_adjust_charset_on_meta_tag_attribute_charset() {
-        use crate::html_content::{ContentType, TextChunk};
+    fn content_handlers_of_lazily_matched_selectors() {
+        let mut rewriter = HtmlRewriter::new(Settings::new(), |_: &[u8]| {});
+
+        for (selector, handlers) in [
+            text!("li:last-child", |_| Ok(())),
+            comments!("p:empty", |_| Ok(())),
+            text_node!("div :is(p, a:only-child)", |_| Ok(())),
+        ] {
+            assert_eq!(
+                rewriter
+                    .add_element_handler(&selector, handlers)
+                    .unwrap_err(),
+                SelectorError::UnsupportedContentHandlers,
+                "{selector}"
+            );
+        }
 
-        let enthusiastic_text_handler = || {
-            doc_text!(move |text: &mut TextChunk<'_>| {
-                let new_text = text.as_str().replace('!', "!!!");
-                text.replace(&new_text, ContentType::Text);
-                Ok(())
-            })
+        for (selector, handlers) in [
+            element!("li:last-child", |_| Ok(())),
+            end_tag!("p:empty", |_| Ok(())),
+            text!("li:first-child", |_| Ok(())),
+        ] {
+            assert!(
+                rewriter.add_element_handler(&selector, handlers).is_ok(),
+                "{selector}"
+            );
+        }
+    }
+
+    #[test]
+    fn content_handlers_of_selectors_with_has() {
+        let mut rewriter = HtmlRewriter::new(Settings::new(), |_: &[u8]| {});
+
+        for (selector, handlers) in [
+            element!("p:has(b)", |_| Ok(())),
+            text!("p:has(b)", |_| Ok(())),
+            comments!("div:has(> p)", |_| Ok(())),
+            capture_subtree!("p:has(b)", |_, _| Ok(())),
+        ] {
+            assert_eq!(
+                rewriter
+                    .add_element_handler(&selector, handlers)
+                    .unwrap_err(),
+                SelectorError::UnsupportedContentHandlers,
+                "{selector}"
+            );
+        }
+
+        let (selector, handlers) = end_tag!("p:has(b)", |_| Ok(()));
+
+        assert!(rewriter.add_element_handler(&selector, handlers).is_ok());
+    }
+
+    #[test]
+    fn content_handlers_of_lazily_matched_selectors_in_settings() {
+        let settings = || Settings {
+            element_content_handlers: vec![
+                element!("li", |_| Ok(())),
+                comments!("li:nth-last-child(2)", |_| Ok(())),
+            ],
+            ..Settings::new()
         };
 
-        let html: Vec<u8> = [
-            r#"<meta charset="windows-1251"><html><head></head><body>I love "#
-                .as_bytes()
-                .to_vec(),
-            vec![0xd5, 0xec, 0xb3, 0xcb, 0xdc],
-            br"!</body></html>".to_vec(),
-        ]
-        .into_iter()
-        .concat();
+        assert_eq!(
+            HtmlRewriter::try_new(settings(), |_: &[u8]| {}).err(),
+            Some(SelectorError::UnsupportedContentHandlers)
+        );
 
-        let expected: Vec<u8> = html
-            .iter()
-            .copied()
-            .flat_map(|c| match c {
-                b'!' => vec![b'!', b'!', b'!'],
-                c => vec![c],
-            })
-            .collect();
+        let mut rewriter = HtmlRewriter::new(settings(), |_: &[u8]| {});
 
-        let transformed_no_charset_adjustment: Vec<u8> = rewrite_html_bytes(
-            &html,
-            Settings {
-                document_content_handlers: vec![enthusiastic_text_handler()],
-                ..Settings::new()
+        assert_eq!(
+            rewriter.write(b"<li>").unwrap_err().to_string(),
+            SelectorError::UnsupportedContentHandlers.to_string()
+        );
+
+        let err = rewrite_str(
+            "<ul><li></li></ul>",
+            RewriteStrSettings {
+                element_content_handlers: vec![text!("li:only-child", |_| Ok(()))],
+                ..RewriteStrSettings::new()
             },
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            SelectorError::UnsupportedContentHandlers.to_string()
         );
 
-        // Without charset adjustment the response has to be corrupted:
-        assert_ne!(transformed_no_charset_adjustment, expected);
+        let compiled_selectors = CompiledSelectors::new(
+            &["p".parse().unwrap(), "p:has(b)".parse().unwrap()],
+            AsciiCompatibleEncoding::utf_8(),
+        );
 
-        let transformed_charset_adjustment: Vec<u8> = rewrite_html_bytes(
-            &html,
-            Settings {
-                document_content_handlers: vec![enthusiastic_text_handler()],
-                adjust_charset_on_meta_tag: true,
-                ..Settings::new()
-            },
+        let res = HtmlRewriter::try_with_compiled_selectors(
+            &compiled_selectors,
+            vec![
+                (
+                    0,
+                    ElementContentHandlers::default().element(|_: &mut Element<'_, '_>| Ok(())),
+                ),
+                (
+                    1,
+                    ElementContentHandlers::default().element(|_: &mut Element<'_, '_>| Ok(())),
+                ),
+            ],
+            Settings::new(),
+            |_: &[u8]| {},
         );
 
-        // If it adapts the charset according to the meta tag everything will be correctly
-        // encoded in windows-1251:
-        assert_eq!(transformed_charset_adjustment, expected);
+        assert_eq!(res.err(), Some(SelectorError::UnsupportedContentHandlers));
     }
 
     #[test]
-    fn test_rewrite_adjust_charset_on_meta_tag_attribute_content_type() {
-        use crate::html_content::{ContentType, TextChunk};
-
-        let enthusiastic_text_handler = || {
-            doc_text!(move |text: &mut TextChunk<'_>| {
-                let new_text = text.as_str().replace('!', "!!!");
-                text.replace(&new_text, ContentType::Text);
-                Ok(())
-            })
-        };
-
-        let html: Vec<u8> = [
-            r#"<meta http-equiv="conTent-type" content="text/html; charset=windows-1251"><html><head>"#.as_bytes(),
-            br#"<meta charset="utf-8"></head><body>I love "#, // second one should be ignored
-            &[0xd5, 0xec, 0xb3, 0xcb, 0xdc],
-            br"!</body></html>",
-        ].concat();
+    fn lazily_matched_elements_across_chunks() {
+        let mut out = Vec::default();
 
-        let expected: Vec<u8> = html
-            .iter()
-            .copied()
-            .flat_map(|c| match c {
-                b'!' => vec![b'!', b'!', b'!'],
-                c => vec![c],
-            })
-            .collect();
-
-        let transformed_no_charset_adjustment: Vec<u8> = rewrite_html_bytes(
-            &html,
+        let mut rewriter = HtmlRewriter::new(
             Settings {
-                document_content_handlers: vec![enthusiastic_text_handler()],
+                element_content_handlers: vec![element!("li:last-of-type", |el| {
+                    el.remove_and_keep_content();
+                    Ok(())
+                })],
                 ..Settings::new()
             },
+            |c: &[u8]| out.extend_from_slice(c),
         );
 
-        // Without charset adjustment the response has to be corrupted:
-        assert_ne!(transformed_no_charset_adjustment, expected);
+        for chunk in ["<ol><l", "i>1</li><li", ">2</l", "i> </o", "l>"] {
+            rewriter.write(chunk.as_bytes()).unwrap();
+        }
+
+        rewriter.end().unwrap();
+
+        assert_eq!(String::from_utf8(out).unwrap(), "<ol><li>1</li>2 </ol>");
+    }
+
+    #[test]
+    fn empty_pseudo_class() {
+        assert_eq!(
+            mark_matched(
+                "<div></div><div> </div><div><!-- c --></div><div><p></p></div><div>a</div>",
+                "div:empty"
+            ),
+            r#"<div m=""></div><div> </div><div m=""><!-- c --></div><div><p></p></div><div>a</div>"#
+        );
+
+        assert_eq!(
+            mark_matched("<p><img><br></p><p>", ":empty"),
+            r#"<p><img m=""><br m=""></p><p m="">"#
+        );
+
+        assert_eq!(
+            mark_matched("<ul><li></li><li>1</li><li></li></ul>", "li:not(:empty)"),
+            r#"<ul><li></li><li m="">1</li><li></li></ul>"#
+        );
+
+        assert_eq!(
+            mark_matched(
+                "<ul><li></li><li>1</li><li></li></ul>",
+                "li:empty:last-child"
+            ),
+            r#"<ul><li></li><li>1</li><li m=""></li></ul>"#
+        );
+
+        assert_eq!(
+            mark_matched("<div><span></div>", "span:empty"),
+            r#"<div><span m=""></div>"#
+        );
+    }
+
+    #[test]
+    fn empty_pseudo_class_across_chunks() {
+        let mut out = Vec::default();
+
+        let mut rewriter = HtmlRewriter::new(
+            Settings {
+                element_content_handlers: vec![element!("div:empty", |el| {
+                    el.remove();
+                    Ok(())
+                })],
+                ..Settings::new()
+            },
+            |c: &[u8]| out.extend_from_slice(c),
+        );
+
+        for chunk in [
+            "<div><di",
+            "v></d",
+            "iv><!--",
+            "--></div><div>",
+            "",
+            "1</div>",
+        ] {
+            rewriter.write(chunk.as_bytes()).unwrap();
+        }
+
+        rewriter.end().unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "<div><!----></div><div>1</div>"
+        );
+    }
+
+    #[test]
+    fn root_pseudo_class() {
+        assert_eq!(
+            mark_matched("<!DOCTYPE html><html><body></body></html>", ":root"),
+            "<!DOCTYPE html><html m=\"\"><body></body></html>"
+        );
+
+        assert_eq!(
+            mark_matched("<html><b></b></html><p></p>", ":scope"),
+            "<html m=\"\"><b></b></html><p></p>"
+        );
+
+        // NOTE: the parser implies the `<html>` element, if the document doesn't start with it.
+        assert_eq!(
+            mark_matched("<!DOCTYPE html><title></title><html></html>", ":root"),
+            "<!DOCTYPE html><title></title><html></html>"
+        );
+
+        assert_eq!(
+            mark_matched("<p><b></b></p><p></p>", ":root, :root > *"),
+            "<p><b></b></p><p></p>"
+        );
+
+        assert_eq!(
+            mark_matched("<html><body></body></html>", ":not(:root)"),
+            "<html><body m=\"\"></body></html>"
+        );
+    }
+
+    #[test]
+    fn attribute_value_case_sensitivity_flags() {
+        let html = "<input type=TEXT><svg><a type=TEXT></a></svg>";
+
+        // NOTE: `type` is case-insensitive only on HTML elements.
+        assert_eq!(
+            mark_matched(html, "[type=text]"),
+            "<input type=TEXT m=\"\"><svg><a type=TEXT></a></svg>"
+        );
+
+        assert_eq!(mark_matched(html, "[type=text s]"), html);
+
+        assert_eq!(
+            mark_matched(html, "[type=text i]"),
+            "<input type=TEXT m=\"\"><svg><a type=TEXT m=\"\"></a></svg>"
+        );
+    }
+
+    #[test]
+    fn matches_attr_pseudo_class() {
+        // NOTE: a glob with a trailing `*` stands in for a regular expression.
+        let compile_pattern = |pattern: &str| -> Option<AttributeValueMatcher> {
+            let prefix = pattern.strip_suffix('*')?.to_owned();
+
+            Some(Arc::new(move |value: &str| value.starts_with(&prefix)))
+        };
+
+        let rewrite = |html: &str, selector: &str| {
+            mark_matched_by(
+                html,
+                Selector::parse_with_attr_matchers(selector, &compile_pattern).unwrap(),
+            )
+        };
+
+        let html = "<a href=/foo></a><a HREF=https://é.com></a><a></a>";
+
+        assert_eq!(
+            rewrite(html, r#"a:matches-attr(href, "https://*")"#),
+            "<a href=/foo></a><a HREF=https://é.com m=\"\"></a><a></a>"
+        );
+
+        assert_eq!(
+            rewrite(html, r#"a:not(:matches-attr(href, "https://*"))"#),
+            "<a href=/foo m=\"\"></a><a HREF=https://é.com></a><a m=\"\"></a>"
+        );
+
+        assert_eq!(
+            rewrite(
+                html,
+                r#":matches-attr(href, "/*"), :matches-attr(href, "https://é*")"#
+            ),
+            "<a href=/foo m=\"\"></a><a HREF=https://é.com m=\"\"></a><a></a>"
+        );
+
+        assert_eq!(
+            Selector::parse_with_attr_matchers(
+                r#"a:matches-attr(href, "https")"#,
+                &compile_pattern
+            )
+            .unwrap_err()
+            .error(),
+            SelectorError::InvalidAttributeValuePattern
+        );
+    }
+
+    #[test]
+    fn element_end_tag_handlers() {
+        let rewrite = |html: &str, selector: &str| {
+            rewrite_str(
+                html,
+                RewriteStrSettings {
+                    element_content_handlers: vec![
+                        end_tag!(selector, |t| {
+                            t.before("!", ContentType::Text);
+                            Ok(())
+                        }),
+                        element!("b", |el| {
+                            let handler: EndTagHandler<'_> = Box::new(|t| {
+                                t.before("?", ContentType::Text);
+                                Ok(())
+                            });
+
+                            el.end_tag_handlers().unwrap().push(handler);
+
+                            Ok(())
+                        }),
+                    ],
+                    ..RewriteStrSettings::new()
+                },
+            )
+            .unwrap()
+        };
+
+        assert_eq!(
+            rewrite("<div><b>1</b><i>2</i><br></div>", "div > *"),
+            "<div><b>1?!</b><i>2!</i><br></div>"
+        );
+
+        assert_eq!(
+            rewrite("<ul><li>1</li><li>2</li></ul>", "li:last-child"),
+            "<ul><li>1</li><li>2!</li></ul>"
+        );
+    }
+
+    #[test]
+    fn source_locations() {
+        let html = "<!DOCTYPE html>\r\n<div class=x>\n  h\u{e9}llo<!-- c -->\r</div>";
+
+        for chunk_size in [1, 2, 3, 7, html.len()] {
+            let events = RefCell::new(Vec::new());
+            let text = RefCell::new(None::<(SourceLocation, usize, String)>);
+
+            let mut rewriter = HtmlRewriter::new(
+                Settings {
+                    element_content_handlers: vec![
+                        element!("div", |el| {
+                            let location = el.source_location();
+
+                            events
+                                .borrow_mut()
+                                .push(format!("div {location} {:?}", location.bytes()));
+                            Ok(())
+                        }),
+                        end_tag!("div", |t| {
+                            let location = t.source_location();
+
+                            events
+                                .borrow_mut()
+                                .push(format!("/div {location} {:?}", location.bytes()));
+                            Ok(())
+                        }),
+                    ],
+                    document_content_handlers: vec![
+                        doctype!(|d| {
+                            let location = d.source_location();
+
+                            events
+                                .borrow_mut()
+                                .push(format!("doctype {location} {:?}", location.bytes()));
+                            Ok(())
+                        }),
+                        doc_comments!(|c| {
+                            let location = c.source_location();
+
+                            events
+                                .borrow_mut()
+                                .push(format!("comment {location} {:?}", location.bytes()));
+                            Ok(())
+                        }),
+                        doc_text!(|t| {
+                            let location = t.source_location();
+                            let mut text = text.borrow_mut();
+                            let (_, end, node_text) = text.get_or_insert_with(|| {
+                                (location, location.bytes().start, String::new())
+                            });
+
+                            // NOTE: the chunks of a text node are contiguous in the input.
+                            assert_eq!(*end, location.bytes().start);
+
+                            *end = location.bytes().end;
+                            node_text.push_str(t.as_str());
+
+                            if t.last_in_text_node() {
+                                let (first, end, node_text) = text.take().unwrap();
+                                let bytes = first.bytes().start..end;
+
+                                assert_eq!(html.get(bytes.clone()), Some(&*node_text));
+
+                                events.borrow_mut().push(format!("text {first} {bytes:?}"));
+                            }
+
+                            Ok(())
+                        }),
+                    ],
+                    ..Settings::new()
+                },
+                |_: &[u8]| {},
+            );
+
+            for chunk in html.as_bytes().chunks(chunk_size) {
+                rewriter.write(chunk).unwrap();
+            }
+
+            rewriter.end().unwrap();
+
+            assert_eq!(
+                events.into_inner(),
+                [
+                    "doctype 1:1 0..15",
+                    "text 1:16 15..17",
+                    "div 2:1 17..30",
+                    "text 2:14 30..39",
+                    "comment 3:9 39..49",
+                    "text 3:19 49..50",
+                    "/div 4:1 50..56",
+                ],
+                "chunk size: {chunk_size}"
+            );
+        }
+    }
+
+    #[test]
+    fn source_locations_of_text_written_byte_by_byte() {
+        let html = "<p>a\r\nb\rc</p>";
+        let chunks = RefCell::new(Vec::new());
+
+        let mut rewriter = HtmlRewriter::new(
+            Settings {
+                document_content_handlers: vec![doc_text!(|t| {
+                    if !t.as_str().is_empty() {
+                        chunks.borrow_mut().push(format!(
+                            "{:?} {}",
+                            t.as_str(),
+                            t.source_location()
+                        ));
+                    }
+
+                    Ok(())
+                })],
+                ..Settings::new()
+            },
+            |_: &[u8]| {},
+        );
+
+        for byte in html.as_bytes() {
+            rewriter.write(&[*byte]).unwrap();
+        }
+
+        rewriter.end().unwrap();
+
+        assert_eq!(
+            chunks.into_inner(),
+            [
+                "\"a\" 1:4",
+                "\"\\r\" 1:5",
+                "\"\\n\" 1:6",
+                "\"b\" 2:1",
+                "\"\\r\" 2:2",
+                "\"c\" 3:1",
+            ]
+        );
+    }
+
+    #[test]
+    fn source_markup() {
+        let enc = encoding_rs::WINDOWS_1251;
+        let (html, _, _) = enc.encode(
+            "<!doctype html><p class=x>\u{41f}\u{440}\u{438}<!-- \u{43c}\u{438}\u{440} --></p>",
+        );
+
+        for chunk_size in [1, html.len()] {
+            let sources = RefCell::new(Vec::new());
+            let text = RefCell::new(Vec::new());
+            let mut out = Vec::new();
+
+            let mut rewriter = HtmlRewriter::new(
+                Settings {
+                    element_content_handlers: vec![
+                        element!("p", |el| {
+                            el.set_attribute("class", "y")?;
+                            sources.borrow_mut().push(el.source_html().to_vec());
+                            Ok(())
+                        }),
+                        end_tag!("p", |t| {
+                            t.set_name_str("div".into());
+                            sources.borrow_mut().push(t.source_html().to_vec());
+                            Ok(())
+                        }),
+                    ],
+                    document_content_handlers: vec![
+                        doctype!(|d| {
+                            sources.borrow_mut().push(d.source().to_vec());
+                            Ok(())
+                        }),
+                        doc_comments!(|c| {
+                            c.set_text("foo")?;
+                            sources.borrow_mut().push(c.source().to_vec());
+                            Ok(())
+                        }),
+                        doc_text!(|t| {
+                            text.borrow_mut().extend_from_slice(t.raw_bytes());
+                            t.set_str(String::new());
+                            Ok(())
+                        }),
+                    ],
+                    encoding: enc.try_into().unwrap(),
+                    ..Settings::new()
+                },
+                |c: &[u8]| out.extend_from_slice(c),
+            );
+
+            for chunk in html.chunks(chunk_size) {
+                rewriter.write(chunk).unwrap();
+            }
+
+            rewriter.end().unwrap();
+
+            assert_eq!(
+                sources.into_inner(),
+                [
+                    &b"<!doctype html>"[..],
+                    b"<p class=x>",
+                    &enc.encode("<!-- \u{43c}\u{438}\u{440} -->").0,
+                    b"</p>",
+                ]
+            );
+
+            assert_eq!(text.into_inner(), &*enc.encode("\u{41f}\u{440}\u{438}").0);
+            assert_eq!(out, b"<!doctype html><p class=\"y\"><!--foo--></div>");
+        }
+    }
+
+    #[test]
+    fn has_pseudo_class() {
+        let rewrite = |html: &str, selector: &str| {
+            rewrite_str(
+                html,
+                RewriteStrSettings {
+                    element_content_handlers: vec![end_tag!(selector, |t| {
+                        t.before("!", ContentType::Text);
+                        Ok(())
+                    })],
+                    ..RewriteStrSettings::new()
+                },
+            )
+            .unwrap()
+        };
+
+        assert_eq!(
+            rewrite(
+                "<article><p><img></p></article><article><p></p></article>",
+                "article:has(img)"
+            ),
+            "<article><p><img></p>!</article><article><p></p></article>"
+        );
+
+        assert_eq!(
+            rewrite(
+                "<div><div><a></a></div><p><a></a></p></div>",
+                "div:has(> a)"
+            ),
+            "<div><div><a></a>!</div><p><a></a></p></div>"
+        );
+
+        assert_eq!(
+            rewrite(
+                "<div><div><a href=x></a></div></div><div><a></a></div>",
+                ":has(a[href])"
+            ),
+            "<div><div><a href=x></a>!</div>!</div><div><a></a></div>"
+        );
+
+        assert_eq!(
+            rewrite(
+                "<ul><li>1</li></ul><ul><li>1</li><li>2</li></ul>",
+                "ul:has(> li:nth-child(2), > .x)"
+            ),
+            "<ul><li>1</li></ul><ul><li>1</li><li>2</li>!</ul>"
+        );
+
+        assert_eq!(
+            rewrite("<p><svg><path/></svg></p><p><svg></svg></p>", "p:has(path)"),
+            "<p><svg><path/></svg>!</p><p><svg></svg></p>"
+        );
+
+        assert_eq!(
+            rewrite("<div><b></b></div><div><i></i></div>", "div:has(:not(i))"),
+            "<div><b></b>!</div><div><i></i></div>"
+        );
+    }
+
+    #[test]
+    fn namespaced_selectors() {
+        let html = rewrite_str(
+            concat!(
+                r#"<a href="/"></a>"#,
+                r#"<svg><a href="/"></a><foreignObject><a href="/"></a></foreignObject></svg>"#,
+                r#"<math><mi></mi></math>"#
+            ),
+            RewriteStrSettings {
+                element_content_handlers: vec![
+                    element!("svg|a", |el| {
+                        el.set_attribute("class", "svg")?;
+                        Ok(())
+                    }),
+                    element!("html|a", |el| {
+                        el.set_attribute("class", "html")?;
+                        Ok(())
+                    }),
+                    element!("math|*", |el| {
+                        el.set_attribute("class", "math")?;
+                        Ok(())
+                    }),
+                    element!(":not(html|*)", |el| {
+                        el.set_attribute("foreign", "")?;
+                        Ok(())
+                    }),
+                ],
+                ..RewriteStrSettings::new()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            html,
+            concat!(
+                r#"<a href="/" class="html"></a>"#,
+                r#"<svg foreign=""><a href="/" class="svg" foreign=""></a>"#,
+                r#"<foreignObject foreign=""><a href="/" class="html"></a></foreignObject></svg>"#,
+                r#"<math class="math" foreign=""><mi class="math" foreign=""></mi></math>"#
+            )
+        );
+    }
+
+    #[test]
+    fn is_and_where_pseudo_classes() {
+        assert_eq!(
+            mark_matched(r#"<p class="a b"></p><p class="c"></p>"#, ":is(.a, .b)"),
+            r#"<p class="a b" m=""></p><p class="c"></p>"#
+        );
+
+        assert_eq!(
+            mark_matched(
+                "<ul><li></li></ul><ol><li></li></ol><div><li></li></div>",
+                ":where(ul, ol) > li"
+            ),
+            "<ul><li m=\"\"></li></ul><ol><li m=\"\"></li></ol><div><li></li></div>"
+        );
+
+        assert_eq!(
+            mark_matched(
+                "<nav><li></li></nav><header><ul><li></li></ul><li></li></header>",
+                ":is(nav, header ul) > li"
+            ),
+            "<nav><li m=\"\"></li></nav><header><ul><li m=\"\"></li></ul><li></li></header>"
+        );
+
+        assert_eq!(
+            mark_matched(
+                "<ul><div><li></li></div></ul><div><ul><li></li></ul></div><ul><li></li></ul>",
+                "div :is(ul li)"
+            ),
+            "<ul><div><li m=\"\"></li></div></ul><div><ul><li m=\"\"></li></ul></div><ul><li></li></ul>"
+        );
+
+        assert_eq!(
+            mark_matched(
+                r#"<p class="a"></p><p class="a b"></p><p class="c"></p><p class="d"></p>"#,
+                ":is(.a:is(.b, .c), .d)"
+            ),
+            r#"<p class="a"></p><p class="a b" m=""></p><p class="c"></p><p class="d" m=""></p>"#
+        );
+
+        assert_eq!(
+            mark_matched(
+                "<h1></h1><p></p><h2></h2><p></p><h3></h3><p></p>",
+                ":is(h1, h2) + p"
+            ),
+            "<h1></h1><p m=\"\"></p><h2></h2><p m=\"\"></p><h3></h3><p></p>"
+        );
+
+        assert_eq!(
+            mark_matched(
+                r#"<div><p class="a b"></p><p class="a b"></p></div><div><p class="a b"></p></div>"#,
+                ":is(.a:last-child, .b:not(:only-child))"
+            ),
+            r#"<div><p class="a b" m=""></p><p class="a b" m=""></p></div><div><p class="a b" m=""></p></div>"#
+        );
+    }
+
+    #[test]
+    fn complex_negation() {
+        assert_eq!(
+            mark_matched("<nav><li></li></nav><ul><li></li></ul>", "li:not(nav li)"),
+            "<nav><li></li></nav><ul><li m=\"\"></li></ul>"
+        );
+
+        assert_eq!(
+            mark_matched("<ul><li></li></ul><ol><li></li></ol>", "li:not(ul > li)"),
+            "<ul><li></li></ul><ol><li m=\"\"></li></ol>"
+        );
+
+        assert_eq!(
+            mark_matched(
+                r#"<p class="x"></p><p class="x y"></p><p class="y"></p>"#,
+                "p:not(.x.y)"
+            ),
+            r#"<p class="x" m=""></p><p class="x y"></p><p class="y" m=""></p>"#
+        );
+
+        assert_eq!(
+            mark_matched("<a></a><b></b><i></i>", ":not(:is(a, b), html, head, body)"),
+            "<a></a><b></b><i m=\"\"></i>"
+        );
+
+        assert_eq!(
+            mark_matched(
+                "<b><ul><li></li></ul></b><ul><li></li></ul>",
+                "li:not(b :is(ul li))"
+            ),
+            "<b><ul><li></li></ul></b><ul><li m=\"\"></li></ul>"
+        );
+
+        assert_eq!(
+            mark_matched(
+                r#"<div><p><a></a></p></div><div class="x"><p><a></a></p></div>"#,
+                "div:not(.x) > p a"
+            ),
+            r#"<div><p><a m=""></a></p></div><div class="x"><p><a></a></p></div>"#
+        );
+
+        assert_eq!(
+            mark_matched(
+                r#"<main><p><a></a></p></main><aside><p><a></a></p></aside>"#,
+                "p:not(main *) a"
+            ),
+            r#"<main><p><a></a></p></main><aside><p><a m=""></a></p></aside>"#
+        );
+    }
+
+    #[test]
+    fn specificity_handler_ordering() {
+        let rewrite = |handler_ordering| {
+            let calls = RefCell::new(Vec::new());
+
+            let record = |name, el: &Element<'_, '_>| {
+                calls
+                    .borrow_mut()
+                    .push((name, el.tag_name(), el.matched_selectors().to_vec()));
+            };
+
+            let handler = |name| {
+                move |el: &mut Element<'_, '_>| {
+                    record(name, el);
+                    Ok(())
+                }
+            };
+
+            rewrite_str(
+                r#"<div id="foo" class="bar"><p class="bar"></p></div><div></div>"#,
+                RewriteStrSettings {
+                    element_content_handlers: vec![
+                        element!("#foo", handler("id")),
+                        element!("div.bar", handler("class")),
+                        element!("*", handler("universal")),
+                        element!("div:not(.baz)", handler("negation")),
+                        element!("div", handler("type")),
+                        inner_text!("div", |el, _| {
+                            record("inner_text", el);
+                            Ok(())
+                        }),
+                    ],
+                    handler_ordering,
+                    ..RewriteStrSettings::new()
+                },
+            )
+            .unwrap();
+
+            calls.into_inner()
+        };
+
+        let div = || "div".to_string();
+        let p = || "p".to_string();
+
+        assert_eq!(
+            rewrite(HandlerOrdering::Registration),
+            [
+                ("id", div(), vec![0, 1, 2, 3, 4, 5]),
+                ("class", div(), vec![0, 1, 2, 3, 4, 5]),
+                ("universal", div(), vec![0, 1, 2, 3, 4, 5]),
+                ("negation", div(), vec![0, 1, 2, 3, 4, 5]),
+                ("type", div(), vec![0, 1, 2, 3, 4, 5]),
+                ("universal", p(), vec![2]),
+                ("inner_text", div(), vec![0, 1, 2, 3, 4, 5]),
+                ("universal", div(), vec![2, 3, 4, 5]),
+                ("negation", div(), vec![2, 3, 4, 5]),
+                ("type", div(), vec![2, 3, 4, 5]),
+                ("inner_text", div(), vec![2, 3, 4, 5]),
+            ]
+        );
+
+        assert_eq!(
+            rewrite(HandlerOrdering::Specificity),
+            [
+                ("universal", div(), vec![0, 1, 2, 3, 4, 5]),
+                ("type", div(), vec![0, 1, 2, 3, 4, 5]),
+                ("class", div(), vec![0, 1, 2, 3, 4, 5]),
+                ("negation", div(), vec![0, 1, 2, 3, 4, 5]),
+                ("id", div(), vec![0, 1, 2, 3, 4, 5]),
+                ("universal", p(), vec![2]),
+                ("inner_text", div(), vec![0, 1, 2, 3, 4, 5]),
+                ("universal", div(), vec![2, 3, 4, 5]),
+                ("type", div(), vec![2, 3, 4, 5]),
+                ("negation", div(), vec![2, 3, 4, 5]),
+                ("inner_text", div(), vec![2, 3, 4, 5]),
+            ]
+        );
+    }
+
+    #[test]
+    fn specificity_handler_ordering_of_selector_lists() {
+        let selectors = [
+            ("p, #x", "list"),
+            ("p.a", "class"),
+            ("p", "type"),
+            ("p:last-child, #x:last-child", "lazy_list"),
+            (".a:last-child", "lazy_class"),
+        ];
+
+        let html =
+            r#"<div><p class="a"></p><p id="x" class="a"></p></div><div><p class="a"></p></div>"#;
+
+        let calls = RefCell::new(Vec::new());
+        let handlers = |name| {
+            let calls = &calls;
+
+            ElementContentHandlers::default().element(move |_: &mut Element<'_, '_>| {
+                calls.borrow_mut().push(name);
+                Ok(())
+            })
+        };
+
+        let sink = |_: &[u8]| {};
+        let settings = || Settings {
+            handler_ordering: HandlerOrdering::Specificity,
+            ..Settings::new()
+        };
+
+        let compiled_selectors = CompiledSelectors::new(
+            &selectors.map(|(s, _)| s.parse().unwrap()),
+            AsciiCompatibleEncoding::utf_8(),
+        );
+
+        let rewriters = [
+            HtmlRewriter::new(
+                Settings {
+                    element_content_handlers: selectors
+                        .iter()
+                        .map(|&(s, name)| (Cow::Owned(s.parse().unwrap()), handlers(name)))
+                        .collect(),
+                    ..settings()
+                },
+                sink,
+            ),
+            HtmlRewriter::with_compiled_selectors(
+                &compiled_selectors,
+                selectors
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, &(_, name))| (idx, handlers(name)))
+                    .collect(),
+                settings(),
+                sink,
+            ),
+            {
+                let mut rewriter = HtmlRewriter::new(settings(), sink);
+
+                for (s, name) in selectors {
+                    rewriter
+                        .add_element_handler(&s.parse().unwrap(), handlers(name))
+                        .unwrap();
+                }
+
+                rewriter
+            },
+        ];
+
+        for mut rewriter in rewriters {
+            rewriter.write(html.as_bytes()).unwrap();
+            rewriter.end().unwrap();
+
+            // NOTE: `p, #x` is ranked as a type selector, unless the element matches `#x`.
+            assert_eq!(
+                std::mem::take(&mut *calls.borrow_mut()),
+                [
+                    "list",
+                    "type",
+                    "class",
+                    "type",
+                    "class",
+                    "list",
+                    "lazy_class",
+                    "lazy_list",
+                    "list",
+                    "type",
+                    "class",
+                    "lazy_list",
+                    "lazy_class",
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn has_pseudo_class_across_chunks() {
+        let mut out = Vec::default();
+
+        let mut rewriter = HtmlRewriter::new(
+            Settings {
+                element_content_handlers: vec![end_tag!("section:has(h1.title)", |t| {
+                    t.remove();
+                    Ok(())
+                })],
+                ..Settings::new()
+            },
+            |c: &[u8]| out.extend_from_slice(c),
+        );
+
+        for chunk in [
+            "<section><h1 cla",
+            "ss=title>1</h1></sec",
+            "tion><section><h1>2</h1></section>",
+        ] {
+            rewriter.write(chunk.as_bytes()).unwrap();
+        }
+
+        rewriter.end().unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "<section><h1 class=title>1</h1><section><h1>2</h1></section>"
+        );
+    }
+
+    #[test]
+    fn added_handlers() {
+        let mut out = Vec::default();
+
+        let mut rewriter = HtmlRewriter::new(
+            Settings {
+                element_content_handlers: vec![element!("meta", |_| Ok(()))],
+                ..Settings::new()
+            },
+            |c: &[u8]| out.extend_from_slice(c),
+        );
+
+        rewriter
+            .write(b"<head><meta></head><body><p>1</p>")
+            .unwrap();
+
+        let handlers = |name: &'static str| {
+            ElementContentHandlers::default().element(move |el: &mut Element<'_, '_>| {
+                let selectors = el.matched_selectors().iter().join(",");
+
+                el.set_attribute(name, &selectors)?;
+                Ok(())
+            })
+        };
+
+        let body_p = rewriter
+            .add_element_handler(&"body p".parse().unwrap(), handlers("a"))
+            .unwrap();
+        let article_p = rewriter
+            .add_element_handler(&"article p".parse().unwrap(), handlers("b"))
+            .unwrap();
+
+        assert_eq!((body_p.index(), article_p.index()), (1, 2));
+
+        rewriter
+            .write(b"<p>2</p><article><p>3</p></article>")
+            .unwrap();
+
+        rewriter.remove_handler(article_p);
+        rewriter
+            .write(b"<article><p>4</p></article></body>")
+            .unwrap();
+        rewriter.end().unwrap();
+
+        // NOTE: `<body>` was open before the handlers were added.
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            concat!(
+                "<head><meta></head><body><p>1</p>",
+                r#"<p>2</p><article><p b="2">3</p></article>"#,
+                "<article><p>4</p></article></body>"
+            )
+        );
+    }
+
+    #[test]
+    fn removed_handlers_of_open_elements() {
+        let mut out = Vec::default();
+        let mut rewriter = HtmlRewriter::new(Settings::new(), |c: &[u8]| out.extend_from_slice(c));
+
+        let id = rewriter
+            .add_element_handler(
+                &"div".parse().unwrap(),
+                ElementContentHandlers::default().text(|t: &mut TextChunk<'_>| {
+                    let text = t.as_str().to_uppercase();
+
+                    t.replace(&text, ContentType::Text);
+                    Ok(())
+                }),
+            )
+            .unwrap();
+
+        rewriter.write(b"<div>a").unwrap();
+        rewriter.remove_handler(id);
+        rewriter.write(b"b</div><div>c</div>").unwrap();
+        rewriter.end().unwrap();
+
+        assert_eq!(String::from_utf8(out).unwrap(), "<div>Ab</div><div>c</div>");
+    }
+
+    #[test]
+    fn removed_handlers_are_dropped() {
+        let mut out = Vec::default();
+        let mut rewriter = HtmlRewriter::new(Settings::new(), |c: &[u8]| out.extend_from_slice(c));
+        let handler_state = Arc::new(());
+
+        let handlers = |name: &'static str| {
+            let handler_state = Arc::clone(&handler_state);
+
+            ElementContentHandlers::default().element(move |el: &mut Element<'_, '_>| {
+                let _ = &handler_state;
+
+                el.set_attribute(name, "")?;
+                Ok(())
+            })
+        };
+
+        let div_p = rewriter
+            .add_element_handler(&"div p".parse().unwrap(), handlers("a"))
+            .unwrap();
+
+        rewriter
+            .add_element_handler(&"div span".parse().unwrap(), handlers("b"))
+            .unwrap();
+
+        rewriter.write(b"<div><p></p>").unwrap();
+        rewriter.remove_handler(div_p);
+
+        assert_eq!(Arc::strong_count(&handler_state), 2);
+
+        // NOTE: the instructions of `div span` are moved in place of the removed ones,
+        // so the jumps of the open `<div>` are relocated.
+        rewriter.write(b"<p></p><span></span></div>").unwrap();
+        rewriter.end().unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            r#"<div><p a=""></p><p></p><span b=""></span></div>"#
+        );
+    }
+
+    #[test]
+    fn added_handlers_ordering() {
+        let rewrite = |handler_ordering| {
+            let calls = RefCell::new(Vec::new());
+            let handlers = |name| {
+                let calls = &calls;
+
+                ElementContentHandlers::default().element(move |_: &mut Element<'_, '_>| {
+                    calls.borrow_mut().push(name);
+                    Ok(())
+                })
+            };
+
+            let mut rewriter = HtmlRewriter::new(
+                Settings {
+                    element_content_handlers: vec![
+                        (Cow::Owned("#foo".parse().unwrap()), handlers("id")),
+                        (Cow::Owned("div.bar".parse().unwrap()), handlers("class")),
+                    ],
+                    handler_ordering,
+                    ..Settings::new()
+                },
+                |_: &[u8]| {},
+            );
+
+            for (selector, name) in [("div", "type"), ("div#foo.bar", "compound"), ("*", "any")] {
+                rewriter
+                    .add_element_handler(&selector.parse().unwrap(), handlers(name))
+                    .unwrap();
+            }
+
+            rewriter
+                .write(br#"<div id="foo" class="bar"></div>"#)
+                .unwrap();
+            rewriter.end().unwrap();
+
+            calls.into_inner()
+        };
+
+        assert_eq!(
+            rewrite(HandlerOrdering::Registration),
+            ["id", "class", "type", "compound", "any"]
+        );
+
+        assert_eq!(
+            rewrite(HandlerOrdering::Specificity),
+            ["any", "type", "class", "id", "compound"]
+        );
+    }
+
+    #[test]
+    fn added_handlers_of_rewriter_without_selectors() {
+        let rewrite = |selector: &str| {
+            let mut out = Vec::default();
+            let mut rewriter =
+                HtmlRewriter::new(Settings::new(), |c: &[u8]| out.extend_from_slice(c));
+
+            rewriter.write(b"<div><h1></h1><p>1</p>").unwrap();
+
+            rewriter
+                .add_element_handler(
+                    &selector.parse().unwrap(),
+                    ElementContentHandlers::default().element(|el: &mut Element<'_, '_>| {
+                        el.set_attribute("m", "")?;
+                        Ok(())
+                    }),
+                )
+                .unwrap();
+
+            rewriter
+                .write(b"<p>2</p><h1></h1></div><p>3</p><section><p>4</p></section>")
+                .unwrap();
+            rewriter.end().unwrap();
+
+            String::from_utf8(out).unwrap()
+        };
+
+        // NOTE: the preceding siblings and the parent of the paragraphs, except for the one
+        // in the `<section>`, were written before the rewriter started matching the elements.
+        assert_eq!(
+            rewrite("p:first-child"),
+            concat!(
+                "<div><h1></h1><p>1</p><p>2</p><h1></h1></div><p>3</p>",
+                r#"<section><p m="">4</p></section>"#
+            )
+        );
+
+        assert_eq!(
+            rewrite(":not(:nth-child(3))"),
+            concat!(
+                "<div><h1></h1><p>1</p><p>2</p><h1></h1></div><p>3</p>",
+                r#"<section><p m="">4</p></section>"#
+            )
+        );
+
+        assert_eq!(
+            rewrite(":root, :root > *"),
+            "<div><h1></h1><p>1</p><p>2</p><h1></h1></div><p>3</p><section><p>4</p></section>"
+        );
+
+        assert_eq!(
+            rewrite("p:only-of-type"),
+            concat!(
+                "<div><h1></h1><p>1</p><p>2</p><h1></h1></div><p>3</p>",
+                r#"<section><p m="">4</p></section>"#
+            )
+        );
+
+        // NOTE: the end tag of `<div>` is unknown to the rewriter, so it can't tell, whether
+        // the following paragraph is a sibling of the heading.
+        assert_eq!(
+            rewrite("h1 + p"),
+            "<div><h1></h1><p>1</p><p>2</p><h1></h1></div><p>3</p><section><p>4</p></section>"
+        );
+    }
+
+    #[test]
+    fn added_nth_of_type_handlers() {
+        let mut out = Vec::default();
+
+        let mut rewriter = HtmlRewriter::new(
+            Settings {
+                element_content_handlers: vec![element!("div", |_| Ok(()))],
+                ..Settings::new()
+            },
+            |c: &[u8]| out.extend_from_slice(c),
+        );
+
+        rewriter.write(b"<div><p>1</p>").unwrap();
+
+        for selector in ["p:nth-of-type(2)", "p:nth-child(2)"] {
+            rewriter
+                .add_element_handler(
+                    &selector.parse().unwrap(),
+                    ElementContentHandlers::default().element(move |el: &mut Element<'_, '_>| {
+                        el.set_attribute(selector, "")?;
+                        Ok(())
+                    }),
+                )
+                .unwrap();
+        }
+
+        rewriter
+            .write(b"<p>2</p><section><p>3</p><p>4</p></section></div>")
+            .unwrap();
+        rewriter.end().unwrap();
+
+        // NOTE: the children of `<div>` were counted before `p:nth-of-type(2)` was added,
+        // but not by their type.
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            concat!(
+                r#"<div><p>1</p><p p:nth-child(2)="">2</p>"#,
+                r#"<section><p>3</p><p p:nth-of-type(2)="" p:nth-child(2)="">4</p></section></div>"#
+            )
+        );
+    }
+
+    #[test]
+    fn compiled_selectors() {
+        let selectors = ["li:last-child", "p", "ul > li"].map(|s| s.parse().unwrap());
+        let compiled_selectors = Arc::new(CompiledSelectors::new(
+            &selectors,
+            AsciiCompatibleEncoding::utf_8(),
+        ));
+
+        let rewrite = |handler_indices: &[usize], added_selector: Option<&str>| {
+            let handlers = |name: &'static str| {
+                ElementContentHandlers::default().element(move |el: &mut Element<'_, '_>| {
+                    let selectors = el.matched_selectors().iter().join(",");
+
+                    el.set_attribute(name, &selectors)?;
+                    Ok(())
+                })
+            };
+
+            let mut out = Vec::default();
+
+            let mut rewriter = HtmlRewriter::with_compiled_selectors(
+                &compiled_selectors,
+                handler_indices
+                    .iter()
+                    .map(|&idx| (idx, handlers("m")))
+                    .collect(),
+                Settings {
+                    element_content_handlers: vec![(
+                        Cow::Owned("ul".parse().unwrap()),
+                        handlers("s"),
+                    )],
+                    ..Settings::new()
+                },
+                |c: &[u8]| out.extend_from_slice(c),
+            );
+
+            if let Some(selector) = added_selector {
+                rewriter
+                    .add_element_handler(&selector.parse().unwrap(), handlers("a"))
+                    .unwrap();
+            }
+
+            rewriter
+                .write(b"<ul><li>1</li><li>2</li></ul><p>3</p>")
+                .unwrap();
+            rewriter.end().unwrap();
+
+            String::from_utf8(out).unwrap()
+        };
+
+        std::thread::scope(|scope| {
+            let threads = [
+                scope.spawn(|| rewrite(&[0, 2], Some("p"))),
+                scope.spawn(|| rewrite(&[1], None)),
+            ];
+
+            let outputs = threads.map(|t| t.join().unwrap());
+
+            assert_eq!(
+                outputs,
+                [
+                    concat!(
+                        r#"<ul s="3"><li m="2">1</li><li m="0,2">2</li></ul>"#,
+                        r#"<p a="4">3</p>"#
+                    ),
+                    r#"<ul s="3"><li>1</li><li>2</li></ul><p m="1">3</p>"#,
+                ]
+            );
+        });
+
+        assert_eq!(compiled_selectors.len(), 3);
+    }
+
+    #[test]
+    fn compiled_selectors_without_handlers() {
+        let compiled_selectors = CompiledSelectors::new(
+            &["li:last-child", "p"].map(|s| s.parse().unwrap()),
+            AsciiCompatibleEncoding::utf_8(),
+        );
+
+        let out = RefCell::new(Vec::default());
+
+        let mut rewriter = HtmlRewriter::with_compiled_selectors(
+            &compiled_selectors,
+            vec![(
+                1,
+                ElementContentHandlers::default().element(|el: &mut Element<'_, '_>| {
+                    el.set_attribute("m", "")?;
+                    Ok(())
+                }),
+            )],
+            Settings::new(),
+            |c: &[u8]| out.borrow_mut().extend_from_slice(c),
+        );
+
+        rewriter.write(b"<ul><li>1</li>").unwrap();
+
+        // NOTE: the output of `<li>` isn't held back until it's known whether
+        // it's the last child.
+        assert_eq!(String::from_utf8_lossy(&out.borrow()), "<ul><li>1</li>");
+
+        rewriter.write(b"<li><p>2</p></li></ul>").unwrap();
+        rewriter.end().unwrap();
+
+        assert_eq!(
+            String::from_utf8(out.into_inner()).unwrap(),
+            r#"<ul><li>1</li><li><p m="">2</p></li></ul>"#
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid index 3 of the compiled selector handlers")]
+    fn compiled_selectors_handler_index_out_of_bounds() {
+        let compiled_selectors =
+            CompiledSelectors::new(&["p".parse().unwrap()], AsciiCompatibleEncoding::utf_8());
+
+        let _ = HtmlRewriter::with_compiled_selectors(
+            &compiled_selectors,
+            vec![
+                (0, ElementContentHandlers::default()),
+                (3, ElementContentHandlers::default()),
+            ],
+            Settings::new(),
+            |_: &[u8]| {},
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "The selectors are compiled for a different encoding")]
+    fn compiled_selectors_encoding_mismatch() {
+        let compiled_selectors =
+            CompiledSelectors::new(&["p".parse().unwrap()], AsciiCompatibleEncoding::utf_8());
+
+        let _ = HtmlRewriter::with_compiled_selectors(
+            &compiled_selectors,
+            vec![],
+            Settings {
+                encoding: encoding_rs::WINDOWS_1252.try_into().unwrap(),
+                ..Settings::new()
+            },
+            |_: &[u8]| {},
+        );
+    }
+
+    #[test]
+    fn selector_matching_stats() {
+        let stats = |enable_selector_matching_stats| {
+            let mut rewriter = HtmlRewriter::new(
+                Settings {
+                    element_content_handlers: vec![
+                        element!("div span", |_| Ok(())),
+                        element!("a[href]", |_| Ok(())),
+                        element!("ul > li:last-child", |_| Ok(())),
+                    ],
+                    enable_selector_matching_stats,
+                    ..Settings::new()
+                },
+                |_: &[u8]| {},
+            );
+
+            rewriter
+                .write(b"<div><p><span><a href=foo></a></span></p></div>")
+                .unwrap();
+            rewriter.write(b"<ul><li></li><li></li></ul>").unwrap();
+
+            rewriter.end_with_selector_matching_stats().unwrap()
+        };
+
+        assert_eq!(stats(false), None);
+
+        assert_eq!(
+            stats(true),
+            Some(SelectorMatchingStats {
+                // NOTE: the entry points for each of the 7 elements, `span` for
+                // the descendants of `<div>` and `li:last-child` for the children of `<ul>`.
+                instructions: 7 * 3 + 3 + 2,
+                jumps: 2,
+                hereditary_jump_scans: 13,
+                attribute_bailouts: 1,
+                selectors: vec![
+                    // NOTE: `span` is executed for `<p>`, `<span>` and `<a>`, for which
+                    // 1, 2 and 3 ancestors are scanned respectively.
+                    SelectorStats {
+                        instructions: 7 + 3,
+                        jumps: 0,
+                        hereditary_jump_scans: 6,
+                        attribute_bailouts: 0,
+                        matches: 1,
+                    },
+                    SelectorStats {
+                        instructions: 7,
+                        jumps: 0,
+                        hereditary_jump_scans: 0,
+                        attribute_bailouts: 1,
+                        matches: 1,
+                    },
+                    SelectorStats {
+                        instructions: 7 + 2,
+                        jumps: 2,
+                        hereditary_jump_scans: 0,
+                        attribute_bailouts: 0,
+                        matches: 1,
+                    },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn capture_subtree() {
+        let rewrite = |html: &str, selector: &str| {
+            let mut captured = Vec::new();
+
+            let output = rewrite_str(
+                html,
+                RewriteStrSettings {
+                    element_content_handlers: vec![
+                        element!("b", |el| {
+                            el.set_attribute("m", "")?;
+                            Ok(())
+                        }),
+                        capture_subtree!(selector, |el, inner_html| {
+                            captured.push(inner_html.to_string());
+                            el.before("!", ContentType::Text);
+                            Ok(())
+                        }),
+                    ],
+                    ..RewriteStrSettings::new()
+                },
+            )
+            .unwrap();
+
+            (output, captured)
+        };
+
+        assert_eq!(
+            rewrite(
+                r#"<script type="application/ld+json">{"a": "<b>"}</script>"#,
+                "script"
+            ),
+            (
+                r#"!<script type="application/ld+json">{"a": "<b>"}</script>"#.into(),
+                vec![r#"{"a": "<b>"}"#.into()]
+            )
+        );
+
+        assert_eq!(
+            rewrite("<div><div><b>1</b></div><!--2--></div>", "div"),
+            (
+                "!<div>!<div><b m=\"\">1</b></div><!--2--></div>".into(),
+                vec![
+                    "<b m=\"\">1</b>".into(),
+                    "!<div><b m=\"\">1</b></div><!--2-->".into()
+                ]
+            )
+        );
+
+        assert_eq!(
+            rewrite("<p><img src=x></p>", "img, p"),
+            (
+                "!<p>!<img src=x></p>".into(),
+                vec!["".into(), "!<img src=x>".into()]
+            )
+        );
+
+        assert_eq!(
+            rewrite("<ul><li>1</li><li>2</li></ul>", "li"),
+            (
+                "<ul>!<li>1</li>!<li>2</li></ul>".into(),
+                vec!["1".into(), "2".into()]
+            )
+        );
+
+        assert_eq!(
+            rewrite("<ul><li>1</li><li>2</li></ul>", "li:last-child"),
+            ("<ul><li>1</li>!<li>2</li></ul>".into(), vec!["2".into()])
+        );
+
+        assert_eq!(
+            rewrite("<div>1<div>2", "div"),
+            ("!<div>1!<div>2".into(), vec!["2".into(), "1!<div>2".into()])
+        );
+    }
+
+    #[test]
+    fn capture_subtree_replacement() {
+        let html = rewrite_str(
+            "<div><template><p>1</p></template><template>2</template></div>",
+            RewriteStrSettings {
+                element_content_handlers: vec![
+                    capture_subtree!("template", |el, inner_html| {
+                        if inner_html.starts_with("<p>") {
+                            el.replace(inner_html, ContentType::Html);
+                        } else {
+                            el.set_inner_content(&inner_html.repeat(2), ContentType::Text);
+                        }
+
+                        Ok(())
+                    }),
+                    capture_subtree!("div", |el, inner_html| {
+                        el.set_attribute("data-len", &inner_html.len().to_string())?;
+                        Ok(())
+                    }),
+                ],
+                ..RewriteStrSettings::new()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            html,
+            "<div data-len=\"31\"><p>1</p><template>22</template></div>"
+        );
+    }
+
+    #[test]
+    fn capture_subtree_across_chunks() {
+        let mut out = Vec::default();
+        let mut captured = Vec::default();
+
+        let mut rewriter = HtmlRewriter::new(
+            Settings {
+                element_content_handlers: vec![capture_subtree!("section", |el, inner_html| {
+                    captured.push(inner_html.to_string());
+                    el.remove();
+                    Ok(())
+                })],
+                ..Settings::new()
+            },
+            |c: &[u8]| out.extend_from_slice(c),
+        );
+
+        for chunk in ["<section><h1 cla", "ss=title>1</h1></sec", "tion>2"] {
+            rewriter.write(chunk.as_bytes()).unwrap();
+        }
+
+        rewriter.end().unwrap();
+
+        assert_eq!(String::from_utf8(out).unwrap(), "2");
+        assert_eq!(captured, ["<h1 class=title>1</h1>"]);
+    }
+
+    #[test]
+    fn text_node_handlers() {
+        let mut out = Vec::default();
+        let mut nodes = Vec::default();
+
+        let mut rewriter = HtmlRewriter::new(
+            Settings {
+                element_content_handlers: vec![
+                    text!("i", |t| {
+                        if t.as_str() == "skip" {
+                            t.replace("[skipped]", ContentType::Text);
+                        }
+
+                        Ok(())
+                    }),
+                    text_node!("p", |t| {
+                        let text = t.as_str().to_uppercase();
+
+                        t.set_str(text);
+                        Ok(())
+                    }),
+                ],
+                document_content_handlers: vec![doc_text_node!(|t| {
+                    nodes.push(t.as_str().to_string());
+                    Ok(())
+                })],
+                ..Settings::new()
+            },
+            |c: &[u8]| out.extend_from_slice(c),
+        );
+
+        for chunk in ["<p>he", "llo <i>fo", "o</i> w", "orld</p><i>ski", "p</i>!"] {
+            rewriter.write(chunk.as_bytes()).unwrap();
+        }
+
+        rewriter.end().unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "<p>HELLO <i>FOO</i> WORLD</p><i>[skipped]</i>!"
+        );
+
+        assert_eq!(nodes, ["HELLO ", "FOO", " WORLD", "skip", "!"]);
+    }
+
+    #[test]
+    fn text_node_and_text_handlers_for_same_text() {
+        let mut out = Vec::default();
+
+        let mut rewriter = HtmlRewriter::new(
+            Settings {
+                element_content_handlers: vec![
+                    text!("p", |t| {
+                        t.before("[", ContentType::Text);
+                        t.after("]", ContentType::Text);
+                        Ok(())
+                    }),
+                    text_node!("p", |t| {
+                        let text = t.as_str().to_uppercase();
+
+                        t.set_str(text);
+                        Ok(())
+                    }),
+                ],
+                ..Settings::new()
+            },
+            |c: &[u8]| out.extend_from_slice(c),
+        );
+
+        for chunk in ["<p>ab", "cd", "ef</p>"] {
+            rewriter.write(chunk.as_bytes()).unwrap();
+        }
+
+        rewriter.end().unwrap();
+
+        assert_eq!(String::from_utf8(out).unwrap(), "<p>[ABCDEF]</p>");
+    }
+
+    #[test]
+    fn inner_text_handlers() {
+        let mut out = Vec::default();
+        let mut texts = Vec::default();
+
+        let mut rewriter = HtmlRewriter::new(
+            Settings {
+                element_content_handlers: vec![
+                    text!("s", |t| {
+                        t.remove();
+                        Ok(())
+                    }),
+                    inner_text!("p:last-child", |_, text| {
+                        while let Some(start) = text.as_str().find("brown fox") {
+                            text.replace_range(start..start + 9, "red & panda", ContentType::Text);
+                        }
+
+                        Ok(())
+                    }),
+                    inner_text!("div", |el, text| {
+                        texts.push(text.as_str().to_string());
+
+                        let len = text.as_str().len();
+
+                        text.replace_range(len..len, "!", ContentType::Html);
+                        el.set_attribute(
+                            "data-chunks",
+                            &text.text_chunk_ranges().len().to_string(),
+                        )?;
+
+                        Ok(())
+                    }),
+                ],
+                ..Settings::new()
+            },
+            |c: &[u8]| out.extend_from_slice(c),
+        );
+
+        for chunk in [
+            "<div><p>The brown fox</p><p>The <b>bro",
+            "wn</b><s>cat</s> fox, the brown ",
+            "<i>fox</i></p></div>",
+        ] {
+            rewriter.write(chunk.as_bytes()).unwrap();
+        }
+
+        rewriter.end().unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            concat!(
+                "<div data-chunks=\"6\"><p>The brown fox</p>",
+                "<p>The <b>red &amp; panda</b><s></s>, the red &amp; panda!<i></i></p></div>"
+            )
+        );
+
+        assert_eq!(
+            texts,
+            ["The brown foxThe red &amp; panda, the red &amp; panda"]
+        );
+    }
+
+    #[test]
+    fn write_esi_tags() {
+        let res = rewrite_str(
+            "<span><esi:include src=a></span>",
+            RewriteStrSettings {
+                element_content_handlers: vec![element!("esi\\:include", |el| {
+                    el.replace("?", ContentType::Text);
+                    Ok(())
+                })],
+                enable_esi_tags: true,
+                ..RewriteStrSettings::new()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(res, "<span>?</span>");
+    }
+
+    #[test]
+    fn test_rewrite_adjust_charset_on_meta_tag_attribute_charset() {
+        use crate::html_content::{ContentType, TextChunk};
+
+        let enthusiastic_text_handler = || {
+            doc_text!(move |text: &mut TextChunk<'_>| {
+                let new_text = text.as_str().replace('!', "!!!");
+                text.replace(&new_text, ContentType::Text);
+                Ok(())
+            })
+        };
+
+        let html: Vec<u8> = [
+            r#"<meta charset="windows-1251"><html><head></head><body>I love "#
+                .as_bytes()
+                .to_vec(),
+            vec![0xd5, 0xec, 0xb3, 0xcb, 0xdc],
+            br"!</body></html>".to_vec(),
+        ]
+        .into_iter()
+        .concat();
+
+        let expected: Vec<u8> = html
+            .iter()
+            .copied()
+            .flat_map(|c| match c {
+                b'!' => vec![b'!', b'!', b'!'],
+                c => vec![c],
+            })
+            .collect();
+
+        let transformed_no_charset_adjustment: Vec<u8> = rewrite_html_bytes(
+            &html,
+            Settings {
+                document_content_handlers: vec![enthusiastic_text_handler()],
+                ..Settings::new()
+            },
+        );
+
+        // Without charset adjustment the response has to be corrupted:
+        assert_ne!(transformed_no_charset_adjustment, expected);
+
+        let transformed_charset_adjustment: Vec<u8> = rewrite_html_bytes(
+            &html,
+            Settings {
+                document_content_handlers: vec![enthusiastic_text_handler()],
+                adjust_charset_on_meta_tag: true,
+                ..Settings::new()
+            },
+        );
+
+        // If it adapts the charset according to the meta tag everything will be correctly
+        // encoded in windows-1251:
+        assert_eq!(transformed_charset_adjustment, expected);
+    }
+
+    #[test]
+    fn test_rewrite_adjust_charset_on_meta_tag_attribute_content_type() {
+        use crate::html_content::{ContentType, TextChunk};
+
+        let enthusiastic_text_handler = || {
+            doc_text!(move |text: &mut TextChunk<'_>| {
+                let new_text = text.as_str().replace('!', "!!!");
+                text.replace(&new_text, ContentType::Text);
+                Ok(())
+            })
+        };
+
+        let html: Vec<u8> = [
+            r#"<meta http-equiv="conTent-type" content="text/html; charset=windows-1251"><html><head>"#.as_bytes(),
+            br#"<meta charset="utf-8"></head><body>I love "#, // second one should be ignored
+            &[0xd5, 0xec, 0xb3, 0xcb, 0xdc],
+            br"!</body></html>",
+        ].concat();
+
+        let expected: Vec<u8> = html
+            .iter()
+            .copied()
+            .flat_map(|c| match c {
+                b'!' => vec![b'!', b'!', b'!'],
+                c => vec![c],
+            })
+            .collect();
+
+        let transformed_no_charset_adjustment: Vec<u8> = rewrite_html_bytes(
+            &html,
+            Settings {
+                document_content_handlers: vec![enthusiastic_text_handler()],
+                ..Settings::new()
+            },
+        );
+
+        // Without charset adjustment the response has to be corrupted:
+        assert_ne!(transformed_no_charset_adjustment, expected);
 
         let transformed_charset_adjustment: Vec<u8> = rewrite_html_bytes(
             &html,
@@ -851,6 +3072,111 @@ mod tests {
             }
         }
 
+        #[test]
+        fn deferred_element_buffer_limit() {
+            const MAX: usize = 1024;
+
+            let mut rewriter = HtmlRewriter::new(
+                Settings {
+                    element_content_handlers: vec![element!("p:last-child", |_| Ok(()))],
+                    memory_settings: MemorySettings {
+                        max_allowed_memory_usage: MAX,
+                        preallocated_parsing_buffer_size: 0,
+                    },
+                    ..Settings::new()
+                },
+                |_: &[u8]| {},
+            );
+
+            rewriter.write(b"<div><p>").unwrap();
+
+            let write_err = rewriter.write("l".repeat(MAX).as_bytes()).unwrap_err();
+
+            match write_err {
+                RewritingError::MemoryLimitExceeded(e) => assert_eq!(e, MemoryLimitExceededError),
+                _ => panic!("{}", write_err),
+            }
+        }
+
+        #[test]
+        fn captured_subtree_buffer_limit() {
+            const MAX: usize = 1024;
+
+            let mut rewriter = HtmlRewriter::new(
+                Settings {
+                    element_content_handlers: vec![capture_subtree!("script", |_, _| Ok(()))],
+                    memory_settings: MemorySettings {
+                        max_allowed_memory_usage: MAX,
+                        preallocated_parsing_buffer_size: 0,
+                    },
+                    ..Settings::new()
+                },
+                |_: &[u8]| {},
+            );
+
+            rewriter.write(b"<script>").unwrap();
+
+            let write_err = rewriter.write("l".repeat(MAX).as_bytes()).unwrap_err();
+
+            match write_err {
+                RewritingError::MemoryLimitExceeded(e) => assert_eq!(e, MemoryLimitExceededError),
+                _ => panic!("{}", write_err),
+            }
+        }
+
+        #[test]
+        fn text_node_buffer_limit() {
+            const MAX: usize = 1024;
+
+            let mut rewriter = HtmlRewriter::new(
+                Settings {
+                    element_content_handlers: vec![text_node!("p", |_| Ok(()))],
+                    memory_settings: MemorySettings {
+                        max_allowed_memory_usage: MAX,
+                        preallocated_parsing_buffer_size: 0,
+                    },
+                    ..Settings::new()
+                },
+                |_: &[u8]| {},
+            );
+
+            rewriter.write(b"<p>").unwrap();
+            rewriter.write("l".repeat(MAX / 2).as_bytes()).unwrap();
+
+            let write_err = rewriter.write("l".repeat(MAX).as_bytes()).unwrap_err();
+
+            match write_err {
+                RewritingError::MemoryLimitExceeded(e) => assert_eq!(e, MemoryLimitExceededError),
+                _ => panic!("{}", write_err),
+            }
+        }
+
+        #[test]
+        fn inner_text_buffer_limit() {
+            const MAX: usize = 1024;
+
+            let mut rewriter = HtmlRewriter::new(
+                Settings {
+                    element_content_handlers: vec![inner_text!("p", |_, _| Ok(()))],
+                    memory_settings: MemorySettings {
+                        max_allowed_memory_usage: MAX,
+                        preallocated_parsing_buffer_size: 0,
+                    },
+                    ..Settings::new()
+                },
+                |_: &[u8]| {},
+            );
+
+            rewriter.write(b"<p>").unwrap();
+
+            let write_err = rewriter.write("l".repeat(MAX).as_bytes()).unwrap_err();
+
+            match write_err {
+                RewritingError::MemoryLimitExceeded(e) => assert_eq!(e, MemoryLimitExceededError),
+                _ => panic!("{}", write_err),
+            }
+        }
+
         #[test]
         #[should_panic(expected = "Attempt to use the HtmlRewriter after a fatal error.")]
         fn poisoning_after_fatal_error() {
@@ -1,30 +1,105 @@
-use super::handlers_dispatcher::{ContentHandlersDispatcher, SelectorHandlersLocator};
-use super::{HandlerTypes, RewritingError};
+use super::handlers_dispatcher::ContentHandlersDispatcher;
+use super::{
+    ElementContentHandlers, HandlerTypes, RewritingError, SelectorMatchingStats, SelectorStats,
+};
 use crate::html::{LocalName, Namespace};
-use crate::rewritable_units::{DocumentEnd, Token, TokenCaptureFlags};
-use crate::selectors_vm::{AuxStartTagInfoRequest, ElementData, SelectorMatchingVm, VmError};
-use crate::transform_stream::{DispatcherError, StartTagHandlingResult, TransformController};
+use crate::memory::SharedMemoryLimiter;
+use crate::rewritable_units::{DocumentEnd, EndTag, StartTag, Token, TokenCaptureFlags};
+use crate::selectors_vm::{
+    Ast, AuxStartTagInfoRequest, ElementData, Program, Selector, SelectorMatchingVm, VmError,
+};
+use crate::transform_stream::{
+    DeferredElementEvent, DeferredOutput, DispatcherError, StartTagHandlingResult,
+    TransformController,
+};
+use encoding_rs::Encoding;
 use hashbrown::HashSet;
+use std::sync::Arc;
 
 #[derive(Default)]
 pub(crate) struct ElementDescriptor {
-    pub matched_content_handlers: HashSet<SelectorHandlersLocator>,
+    pub matched_content_handlers: HashSet<usize>,
+    pub descendant_matched_content_handlers: HashSet<usize>,
     pub end_tag_handler_idx: Option<usize>,
     pub remove_content: bool,
+    pub deferred_element_id: Option<usize>,
+    pub holds_back_text: bool,
 }
 
 impl ElementData for ElementDescriptor {
-    type MatchPayload = SelectorHandlersLocator;
+    /// The payload is mapped to the handlers of the selector by the handlers dispatcher,
+    /// so the compiled program doesn't depend on the handlers and can be shared.
+    type MatchPayload = usize;
 
     #[inline]
-    fn matched_payload_mut(&mut self) -> &mut HashSet<SelectorHandlersLocator> {
+    fn matched_payload_mut(&mut self) -> &mut HashSet<usize> {
         &mut self.matched_content_handlers
     }
+
+    #[inline]
+    fn descendant_matched_payload_mut(&mut self) -> &mut HashSet<usize> {
+        &mut self.descendant_matched_content_handlers
+    }
+}
+
+/// Settings of the selector matching VM, that are also used to construct the VM once the first
+/// selector is added to the rewriter, that didn't have any.
+pub(crate) struct SelectorMatchingVmSettings {
+    pub encoding: &'static Encoding,
+    pub memory_limiter: SharedMemoryLimiter,
+    pub enable_esi_tags: bool,
+    pub enable_stats: bool,
+}
+
+impl SelectorMatchingVmSettings {
+    #[inline]
+    pub fn new_vm(&self, ast: Ast<usize>) -> SelectorMatchingVm<ElementDescriptor> {
+        let vm = SelectorMatchingVm::new(
+            ast,
+            self.encoding,
+            SharedMemoryLimiter::clone(&self.memory_limiter),
+            self.enable_esi_tags,
+        );
+
+        self.with_stats_enabled(vm)
+    }
+
+    #[inline]
+    pub fn new_vm_with_program(
+        &self,
+        program: Arc<Program<usize>>,
+    ) -> SelectorMatchingVm<ElementDescriptor> {
+        let vm = SelectorMatchingVm::with_program(
+            program,
+            self.encoding,
+            SharedMemoryLimiter::clone(&self.memory_limiter),
+            self.enable_esi_tags,
+        );
+
+        self.with_stats_enabled(vm)
+    }
+
+    #[inline]
+    fn with_stats_enabled(
+        &self,
+        mut vm: SelectorMatchingVm<ElementDescriptor>,
+    ) -> SelectorMatchingVm<ElementDescriptor> {
+        if self.enable_stats {
+            vm.enable_stats();
+        }
+
+        vm
+    }
 }
 
 pub(crate) struct HtmlRewriteController<'h, H: HandlerTypes> {
     handlers_dispatcher: ContentHandlersDispatcher<'h, H>,
     selector_matching_vm: Option<SelectorMatchingVm<ElementDescriptor>>,
+    selector_matching_vm_settings: SelectorMatchingVmSettings,
+    selector_count: usize,
+    /// Set once a start tag is handled without the selector matching VM, so the VM created
+    /// later doesn't know about the elements parsed before.
+    has_unmatched_elements: bool,
 }
 
 impl<'h, H: HandlerTypes> HtmlRewriteController<'h, H> {
@@ -32,18 +107,114 @@ impl<'h, H: HandlerTypes> HtmlRewriteController<'h, H> {
     pub(crate) const fn new(
         handlers_dispatcher: ContentHandlersDispatcher<'h, H>,
         selector_matching_vm: Option<SelectorMatchingVm<ElementDescriptor>>,
+        selector_matching_vm_settings: SelectorMatchingVmSettings,
+        selector_count: usize,
     ) -> Self {
         HtmlRewriteController {
             handlers_dispatcher,
             selector_matching_vm,
+            selector_matching_vm_settings,
+            selector_count,
+            has_unmatched_elements: false,
+        }
+    }
+
+    /// Adds the handlers for the selector to the running rewriter. Returns the index
+    /// of the selector, that follows the indices of the selectors from the settings.
+    pub(crate) fn add_element_content_handlers(
+        &mut self,
+        selector: &Selector,
+        handlers: ElementContentHandlers<'h, H>,
+    ) -> usize {
+        let selector_idx = self.selector_count;
+        let payloads = self.handlers_dispatcher.add_selector_associated_handlers(
+            handlers,
+            Some(selector_idx),
+            &selector.distinct_specificities(),
+        );
+
+        let vm_settings = &self.selector_matching_vm_settings;
+        let has_unmatched_elements = self.has_unmatched_elements;
+
+        self.selector_matching_vm
+            .get_or_insert_with(|| {
+                let mut vm = vm_settings.new_vm(Ast::default());
+
+                if has_unmatched_elements {
+                    vm.start_mid_document();
+                }
+
+                vm
+            })
+            .add_selector_by_specificity(selector, &payloads);
+
+        self.selector_count += 1;
+
+        selector_idx
+    }
+
+    /// Stops matching the selector with the given index and drops its handlers.
+    pub(crate) fn remove_element_content_handlers(&mut self, selector_idx: usize) {
+        let payloads = self
+            .handlers_dispatcher
+            .selector_payload(selector_idx)
+            .to_vec();
+
+        for &payload in &payloads {
+            self.handlers_dispatcher
+                .remove_selector_associated_handlers(payload);
+        }
+
+        if let (Some(&payload), Some(vm)) = (payloads.first(), &mut self.selector_matching_vm) {
+            vm.remove_selector(payload);
         }
     }
+
+    /// Returns the selector matching stats, if they are enabled.
+    pub(crate) fn selector_matching_stats(&self) -> Option<SelectorMatchingStats> {
+        if !self.selector_matching_vm_settings.enable_stats {
+            return None;
+        }
+
+        let mut stats = SelectorMatchingStats {
+            selectors: vec![SelectorStats::default(); self.selector_count],
+            ..SelectorMatchingStats::default()
+        };
+
+        if let Some(vm_stats) = self.selector_matching_vm.as_ref().and_then(|vm| vm.stats()) {
+            let execution = vm_stats.execution;
+
+            stats.instructions = execution.instructions;
+            stats.jumps = execution.jumps;
+            stats.hereditary_jump_scans = execution.hereditary_jump_scans;
+            stats.attribute_bailouts = execution.bailouts;
+
+            for (&payload, &count) in &vm_stats.matches {
+                if let Some(idx) = self.handlers_dispatcher.selector_idx(payload) {
+                    stats.selectors[idx].matches += count;
+                }
+            }
+
+            for (&payload, execution) in &vm_stats.payload_execution {
+                if let Some(idx) = self.handlers_dispatcher.selector_idx(payload) {
+                    let selector_stats = &mut stats.selectors[idx];
+
+                    selector_stats.instructions += execution.instructions;
+                    selector_stats.jumps += execution.jumps;
+                    selector_stats.hereditary_jump_scans += execution.hereditary_jump_scans;
+                    selector_stats.attribute_bailouts += execution.bailouts;
+                }
+            }
+        }
+
+        Some(stats)
+    }
 }
 
 impl<H: HandlerTypes> HtmlRewriteController<'_, H> {
     #[inline]
     fn respond_to_aux_info_request(
-        aux_info_req: AuxStartTagInfoRequest<ElementDescriptor, SelectorHandlersLocator>,
+        aux_info_req: AuxStartTagInfoRequest<ElementDescriptor, usize>,
     ) -> StartTagHandlingResult<Self> {
         Err(DispatcherError::InfoRequest(Box::new(
             move |this, aux_info| {
@@ -54,14 +225,33 @@ impl<H: HandlerTypes> HtmlRewriteController<'_, H> {
                         .map_err(RewritingError::MemoryLimitExceeded)?;
                 }
 
+                this.handle_lazy_match_resolutions();
+
                 Ok(this.get_capture_flags())
             },
         )))
     }
 
+    fn handle_lazy_match_resolutions(&mut self) {
+        if let Some(ref mut vm) = self.selector_matching_vm {
+            for resolution in vm.take_lazy_match_resolutions() {
+                self.handlers_dispatcher.resolve_lazy_match(resolution);
+            }
+        }
+    }
+
     #[inline]
     fn get_capture_flags(&self) -> TokenCaptureFlags {
-        self.handlers_dispatcher.get_token_capture_flags()
+        let mut flags = self.handlers_dispatcher.get_token_capture_flags();
+
+        // NOTE: the text is required to decide whether the current element is empty.
+        if let Some(ref vm) = self.selector_matching_vm {
+            if vm.needs_text() {
+                flags |= TokenCaptureFlags::TEXT;
+            }
+        }
+
+        flags
     }
 }
 
@@ -78,10 +268,16 @@ impl<H: HandlerTypes> TransformController for HtmlRewriteController<'_, H> {
     ) -> StartTagHandlingResult<Self> {
         match self.selector_matching_vm {
             Some(ref mut vm) => {
+                self.handlers_dispatcher.prepare_for_start_tag();
+
                 let mut match_handler = |m| self.handlers_dispatcher.start_matching(&m);
 
                 match vm.exec_for_start_tag(local_name, ns, &mut match_handler) {
-                    Ok(()) => Ok(self.get_capture_flags()),
+                    Ok(()) => {
+                        self.handle_lazy_match_resolutions();
+
+                        Ok(self.get_capture_flags())
+                    }
                     Err(VmError::InfoRequest(req)) => Self::respond_to_aux_info_request(req),
                     Err(VmError::MemoryLimitExceeded(e)) => Err(DispatcherError::RewritingError(
                         RewritingError::MemoryLimitExceeded(e),
@@ -90,15 +286,27 @@ impl<H: HandlerTypes> TransformController for HtmlRewriteController<'_, H> {
             }
             // NOTE: fast path - we can skip executing selector matching VM completely
             // and don't need to maintain open element stack if we don't have any selectors.
-            None => Ok(self.get_capture_flags()),
+            None => {
+                self.has_unmatched_elements = true;
+
+                Ok(self.get_capture_flags())
+            }
         }
     }
 
     fn handle_end_tag(&mut self, local_name: LocalName<'_>) -> TokenCaptureFlags {
         if let Some(ref mut vm) = self.selector_matching_vm {
+            // NOTE: the end tag belongs to the first popped element,
+            // the rest of the elements are closed implicitly.
+            let mut has_end_tag = true;
+
             vm.exec_for_end_tag(local_name, |elem_desc| {
-                self.handlers_dispatcher.stop_matching(elem_desc);
+                self.handlers_dispatcher
+                    .stop_matching(elem_desc, has_end_tag);
+                has_end_tag = false;
             });
+
+            self.handle_lazy_match_resolutions();
         }
 
         self.get_capture_flags()
@@ -106,14 +314,35 @@ impl<H: HandlerTypes> TransformController for HtmlRewriteController<'_, H> {
 
     #[inline]
     fn handle_token(&mut self, token: &mut Token<'_>) -> Result<(), RewritingError> {
-        let current_element_data = self
-            .selector_matching_vm
-            .as_mut()
-            .and_then(SelectorMatchingVm::current_element_data_mut);
+        // NOTE: the text node handlers are invoked before the text handlers, so that the content
+        // inserted by the latter stays in place. The chunks buffered for the text node handlers
+        // are passed to the text handlers as the last chunk of the node.
+        let is_buffered_text = match token {
+            Token::TextChunk(text) => self.handlers_dispatcher.handle_text_node_chunk(text)?,
+            _ => false,
+        };
 
-        self.handlers_dispatcher
-            .handle_token(token, current_element_data)
-            .map_err(RewritingError::ContentHandlerError)
+        if !is_buffered_text {
+            let current_element_data = self
+                .selector_matching_vm
+                .as_mut()
+                .and_then(SelectorMatchingVm::current_element_data_mut);
+
+            self.handlers_dispatcher
+                .handle_token(token, current_element_data)
+                .map_err(RewritingError::ContentHandlerError)?;
+        }
+
+        if let Token::TextChunk(text) = token {
+            if let Some(ref mut vm) = self.selector_matching_vm {
+                if vm.needs_text() && !text.as_str().is_empty() {
+                    vm.exec_for_text();
+                    self.handle_lazy_match_resolutions();
+                }
+            }
+        }
+
+        Ok(())
     }
 
     fn handle_end(&mut self, document_end: &mut DocumentEnd<'_>) -> Result<(), RewritingError> {
@@ -128,4 +357,33 @@ impl<H: HandlerTypes> TransformController for HtmlRewriteController<'_, H> {
             .handlers_dispatcher
             .has_matched_elements_with_removed_content()
     }
+
+    fn handle_input_end(&mut self) {
+        if let Some(ref mut vm) = self.selector_matching_vm {
+            vm.exec_for_end();
+
+            self.handle_lazy_match_resolutions();
+        }
+    }
+
+    #[inline]
+    fn take_deferred_element_events(&mut self) -> Vec<DeferredElementEvent> {
+        self.handlers_dispatcher.take_deferred_element_events()
+    }
+
+    fn handle_deferred_element(
+        &mut self,
+        id: usize,
+        start_tag: &mut StartTag<'static>,
+        end_tag: Option<&mut EndTag<'static>>,
+        content: &mut DeferredOutput,
+    ) -> Result<bool, RewritingError> {
+        self.handlers_dispatcher
+            .handle_deferred_element(id, start_tag, end_tag, content)
+    }
+
+    #[inline]
+    fn should_hold_back_text(&self) -> bool {
+        self.handlers_dispatcher.should_hold_back_text()
+    }
 }
@@ -1,5 +1,7 @@
-use crate::rewritable_units::{Comment, Doctype, DocumentEnd, Element, EndTag, TextChunk};
-use crate::selectors_vm::Selector;
+use crate::rewritable_units::{
+    Comment, Doctype, DocumentEnd, Element, EndTag, InnerText, TextChunk, TextNode,
+};
+use crate::selectors_vm::{Selector, SelectorError, SupportedHandlers};
 // N.B. `use crate::` will break this because the constructor is not public, only the struct itself
 use super::AsciiCompatibleEncoding;
 use std::borrow::Cow;
@@ -16,10 +18,19 @@ pub trait HandlerTypes: Sized {
     type CommentHandler<'h>: FnMut(&mut Comment<'_>) -> HandlerResult + 'h;
     /// Handler type for [`TextChunk`].
     type TextHandler<'h>: FnMut(&mut TextChunk<'_>) -> HandlerResult + 'h;
+    /// Handler type for [`TextNode`].
+    type TextNodeHandler<'h>: FnMut(&mut TextNode<'_, '_>) -> HandlerResult + 'h;
     /// Handler type for [`Element`].
     type ElementHandler<'h>: FnMut(&mut Element<'_, '_, Self>) -> HandlerResult + 'h;
     /// Handler type for [`EndTag`].
     type EndTagHandler<'h>: FnOnce(&mut EndTag<'_>) -> HandlerResult + 'h;
+    /// Handler type for [`EndTag`]s of elements matched by a selector.
+    type ElementEndTagHandler<'h>: FnMut(&mut EndTag<'_>) -> HandlerResult + 'h;
+    /// Handler type for [`Element`]s with the captured inner content.
+    type SubtreeHandler<'h>: FnMut(&mut Element<'_, '_, Self>, &str) -> HandlerResult + 'h;
+    /// Handler type for [`Element`]s with the [`InnerText`].
+    type InnerTextHandler<'h>: FnMut(&mut Element<'_, '_, Self>, &mut InnerText) -> HandlerResult
+        + 'h;
     /// Handler type for [`DocumentEnd`].
     type EndHandler<'h>: FnOnce(&mut DocumentEnd<'_>) -> HandlerResult + 'h;
 
@@ -55,8 +66,12 @@ impl HandlerTypes for LocalHandlerTypes {
     type DoctypeHandler<'h> = DoctypeHandler<'h>;
     type CommentHandler<'h> = CommentHandler<'h>;
     type TextHandler<'h> = TextHandler<'h>;
+    type TextNodeHandler<'h> = TextNodeHandler<'h>;
     type ElementHandler<'h> = ElementHandler<'h>;
     type EndTagHandler<'h> = EndTagHandler<'h>;
+    type ElementEndTagHandler<'h> = ElementEndTagHandler<'h>;
+    type SubtreeHandler<'h> = SubtreeHandler<'h>;
+    type InnerTextHandler<'h> = InnerTextHandler<'h>;
     type EndHandler<'h> = EndHandler<'h>;
 
     fn new_end_tag_handler<'h>(
@@ -90,8 +105,12 @@ impl HandlerTypes for SendHandlerTypes {
     type DoctypeHandler<'h> = DoctypeHandlerSend<'h>;
     type CommentHandler<'h> = CommentHandlerSend<'h>;
     type TextHandler<'h> = TextHandlerSend<'h>;
+    type TextNodeHandler<'h> = TextNodeHandlerSend<'h>;
     type ElementHandler<'h> = ElementHandlerSend<'h, Self>;
     type EndTagHandler<'h> = EndTagHandlerSend<'h>;
+    type ElementEndTagHandler<'h> = ElementEndTagHandlerSend<'h>;
+    type SubtreeHandler<'h> = SubtreeHandlerSend<'h, Self>;
+    type InnerTextHandler<'h> = InnerTextHandlerSend<'h, Self>;
     type EndHandler<'h> = EndHandlerSend<'h>;
 
     fn new_end_tag_handler<'h>(
@@ -128,11 +147,21 @@ pub type DoctypeHandler<'h> = Box<dyn FnMut(&mut Doctype<'_>) -> HandlerResult +
 pub type CommentHandler<'h> = Box<dyn FnMut(&mut Comment<'_>) -> HandlerResult + 'h>;
 /// Handler for text chunks present the HTML.
 pub type TextHandler<'h> = Box<dyn FnMut(&mut TextChunk<'_>) -> HandlerResult + 'h>;
+/// Handler for whole text nodes present the HTML.
+pub type TextNodeHandler<'h> = Box<dyn FnMut(&mut TextNode<'_, '_>) -> HandlerResult + 'h>;
 /// Handler for elements matched by a selector.
 pub type ElementHandler<'h> =
     Box<dyn FnMut(&mut Element<'_, '_, LocalHandlerTypes>) -> HandlerResult + 'h>;
 /// Handler for end tags.
 pub type EndTagHandler<'h> = Box<dyn FnOnce(&mut EndTag<'_>) -> HandlerResult + 'h>;
+/// Handler for end tags of elements matched by a selector.
+pub type ElementEndTagHandler<'h> = Box<dyn FnMut(&mut EndTag<'_>) -> HandlerResult + 'h>;
+/// Handler for elements matched by a selector, that receives the inner HTML of the element.
+pub type SubtreeHandler<'h> =
+    Box<dyn FnMut(&mut Element<'_, '_, LocalHandlerTypes>, &str) -> HandlerResult + 'h>;
+/// Handler for elements matched by a selector, that receives the inner text of the element.
+pub type InnerTextHandler<'h> =
+    Box<dyn FnMut(&mut Element<'_, '_, LocalHandlerTypes>, &mut InnerText) -> HandlerResult + 'h>;
 /// Handler for the document end. This is called after the last chunk is processed.
 pub type EndHandler<'h> = Box<dyn FnOnce(&mut DocumentEnd<'_>) -> HandlerResult + 'h>;
 
@@ -144,11 +173,25 @@ pub type DoctypeHandlerSend<'h> = Box<dyn FnMut(&mut Doctype<'_>) -> HandlerResu
 pub type CommentHandlerSend<'h> = Box<dyn FnMut(&mut Comment<'_>) -> HandlerResult + Send + 'h>;
 /// Handler for text chunks present the HTML that are [`Send`]able.
 pub type TextHandlerSend<'h> = Box<dyn FnMut(&mut TextChunk<'_>) -> HandlerResult + Send + 'h>;
+/// Handler for whole text nodes present the HTML that are [`Send`]able.
+pub type TextNodeHandlerSend<'h> =
+    Box<dyn FnMut(&mut TextNode<'_, '_>) -> HandlerResult + Send + 'h>;
 /// Handler for elements matched by a selector that are [`Send`]able.
 pub type ElementHandlerSend<'h, H = SendHandlerTypes> =
     Box<dyn FnMut(&mut Element<'_, '_, H>) -> HandlerResult + Send + 'h>;
 /// Handler for end tags that are [`Send`]able.
 pub type EndTagHandlerSend<'h> = Box<dyn FnOnce(&mut EndTag<'_>) -> HandlerResult + Send + 'h>;
+/// Handler for end tags of elements matched by a selector that are [`Send`]able.
+pub type ElementEndTagHandlerSend<'h> =
+    Box<dyn FnMut(&mut EndTag<'_>) -> HandlerResult + Send + 'h>;
+/// Handler for elements matched by a selector, that receives the inner HTML of the element
+/// and is [`Send`]able.
+pub type SubtreeHandlerSend<'h, H = SendHandlerTypes> =
+    Box<dyn FnMut(&mut Element<'_, '_, H>, &str) -> HandlerResult + Send + 'h>;
+/// Handler for elements matched by a selector, that receives the inner text of the element
+/// and is [`Send`]able.
+pub type InnerTextHandlerSend<'h, H = SendHandlerTypes> =
+    Box<dyn FnMut(&mut Element<'_, '_, H>, &mut InnerText) -> HandlerResult + Send + 'h>;
 /// Handler for the document end that are [`Send`]able. This is called after the last chunk is processed.
 pub type EndHandlerSend<'h> = Box<dyn FnOnce(&mut DocumentEnd<'_>) -> HandlerResult + Send + 'h>;
 
@@ -176,6 +219,14 @@ impl<'h, F: FnMut(&mut TextChunk<'_>) -> HandlerResult + 'h> IntoHandler<TextHan
     }
 }
 
+impl<'h, F: FnMut(&mut TextNode<'_, '_>) -> HandlerResult + 'h> IntoHandler<TextNodeHandler<'h>>
+    for F
+{
+    fn into_handler(self) -> TextNodeHandler<'h> {
+        Box::new(self)
+    }
+}
+
 impl<'h, F: FnMut(&mut Element<'_, '_, LocalHandlerTypes>) -> HandlerResult + 'h>
     IntoHandler<ElementHandler<'h>> for F
 {
@@ -190,6 +241,32 @@ impl<'h, F: FnOnce(&mut EndTag<'_>) -> HandlerResult + 'h> IntoHandler<EndTagHan
     }
 }
 
+impl<'h, F: FnMut(&mut EndTag<'_>) -> HandlerResult + 'h> IntoHandler<ElementEndTagHandler<'h>>
+    for F
+{
+    fn into_handler(self) -> ElementEndTagHandler<'h> {
+        Box::new(self)
+    }
+}
+
+impl<'h, F: FnMut(&mut Element<'_, '_, LocalHandlerTypes>, &str) -> HandlerResult + 'h>
+    IntoHandler<SubtreeHandler<'h>> for F
+{
+    fn into_handler(self) -> SubtreeHandler<'h> {
+        Box::new(self)
+    }
+}
+
+impl<
+        'h,
+        F: FnMut(&mut Element<'_, '_, LocalHandlerTypes>, &mut InnerText) -> HandlerResult + 'h,
+    > IntoHandler<InnerTextHandler<'h>> for F
+{
+    fn into_handler(self) -> InnerTextHandler<'h> {
+        Box::new(self)
+    }
+}
+
 impl<'h, F: FnOnce(&mut DocumentEnd<'_>) -> HandlerResult + 'h> IntoHandler<EndHandler<'h>> for F {
     fn into_handler(self) -> EndHandler<'h> {
         Box::new(self)
@@ -220,6 +297,14 @@ impl<'h, F: FnMut(&mut TextChunk<'_>) -> HandlerResult + Send + 'h> IntoHandler<
     }
 }
 
+impl<'h, F: FnMut(&mut TextNode<'_, '_>) -> HandlerResult + Send + 'h>
+    IntoHandler<TextNodeHandlerSend<'h>> for F
+{
+    fn into_handler(self) -> TextNodeHandlerSend<'h> {
+        Box::new(self)
+    }
+}
+
 impl<'h, H: HandlerTypes, F: FnMut(&mut Element<'_, '_, H>) -> HandlerResult + Send + 'h>
     IntoHandler<ElementHandlerSend<'h, H>> for F
 {
@@ -236,6 +321,33 @@ impl<'h, F: FnOnce(&mut EndTag<'_>) -> HandlerResult + Send + 'h> IntoHandler<En
     }
 }
 
+impl<'h, F: FnMut(&mut EndTag<'_>) -> HandlerResult + Send + 'h>
+    IntoHandler<ElementEndTagHandlerSend<'h>> for F
+{
+    fn into_handler(self) -> ElementEndTagHandlerSend<'h> {
+        Box::new(self)
+    }
+}
+
+impl<'h, H: HandlerTypes, F: FnMut(&mut Element<'_, '_, H>, &str) -> HandlerResult + Send + 'h>
+    IntoHandler<SubtreeHandlerSend<'h, H>> for F
+{
+    fn into_handler(self) -> SubtreeHandlerSend<'h, H> {
+        Box::new(self)
+    }
+}
+
+impl<
+        'h,
+        H: HandlerTypes,
+        F: FnMut(&mut Element<'_, '_, H>, &mut InnerText) -> HandlerResult + Send + 'h,
+    > IntoHandler<InnerTextHandlerSend<'h, H>> for F
+{
+    fn into_handler(self) -> InnerTextHandlerSend<'h, H> {
+        Box::new(self)
+    }
+}
+
 impl<'h, F: FnOnce(&mut DocumentEnd<'_>) -> HandlerResult + Send + 'h>
     IntoHandler<EndHandlerSend<'h>> for F
 {
@@ -245,6 +357,10 @@ impl<'h, F: FnOnce(&mut DocumentEnd<'_>) -> HandlerResult + Send + 'h>
 }
 
 /// Specifies element content handlers associated with a selector.
+///
+/// The handlers are constructed with [`Default::default`] and the builder methods, so that more
+/// handler kinds can be added without breaking the existing code.
+#[non_exhaustive]
 pub struct ElementContentHandlers<'h, H: HandlerTypes = LocalHandlerTypes> {
     /// Element handler. See [`HandlerTypes::ElementHandler`].
     pub element: Option<H::ElementHandler<'h>>,
@@ -252,6 +368,14 @@ pub struct ElementContentHandlers<'h, H: HandlerTypes = LocalHandlerTypes> {
     pub comments: Option<H::CommentHandler<'h>>,
     /// Text handler. See [`HandlerTypes::TextHandler`].
     pub text: Option<H::TextHandler<'h>>,
+    /// Text node handler. See [`HandlerTypes::TextNodeHandler`].
+    pub text_node: Option<H::TextNodeHandler<'h>>,
+    /// End tag handler. See [`HandlerTypes::ElementEndTagHandler`].
+    pub end_tag: Option<H::ElementEndTagHandler<'h>>,
+    /// Subtree capturing handler. See [`HandlerTypes::SubtreeHandler`].
+    pub capture_subtree: Option<H::SubtreeHandler<'h>>,
+    /// Inner text handler. See [`HandlerTypes::InnerTextHandler`].
+    pub inner_text: Option<H::InnerTextHandler<'h>>,
 }
 
 impl<H: HandlerTypes> Default for ElementContentHandlers<'_, H> {
@@ -260,11 +384,41 @@ impl<H: HandlerTypes> Default for ElementContentHandlers<'_, H> {
             element: None,
             comments: None,
             text: None,
+            text_node: None,
+            end_tag: None,
+            capture_subtree: None,
+            inner_text: None,
         }
     }
 }
 
 impl<'h, H: HandlerTypes> ElementContentHandlers<'h, H> {
+    /// Checks that the handlers can be invoked for a selector, that supports the given handlers.
+    pub(crate) fn check_supported(
+        &self,
+        supported: SupportedHandlers,
+    ) -> Result<(), SelectorError> {
+        let has_content_handlers =
+            self.text.is_some() || self.comments.is_some() || self.text_node.is_some();
+
+        let is_supported = match supported {
+            SupportedHandlers::All => true,
+            SupportedHandlers::NoContent => !has_content_handlers,
+            SupportedHandlers::EndTag => {
+                !has_content_handlers
+                    && self.element.is_none()
+                    && self.capture_subtree.is_none()
+                    && self.inner_text.is_none()
+            }
+        };
+
+        if is_supported {
+            Ok(())
+        } else {
+            Err(SelectorError::UnsupportedContentHandlers)
+        }
+    }
+
     /// Sets a handler for elements matched by a selector.
     #[inline]
     #[must_use]
@@ -291,6 +445,64 @@ impl<'h, H: HandlerTypes> ElementContentHandlers<'h, H> {
 
         self
     }
+
+    /// Sets a handler for whole text nodes in the inner content of elements matched by a selector.
+    ///
+    /// Text chunks of the node are buffered until the last chunk of the node, which is limited
+    /// by [`MemorySettings::max_allowed_memory_usage`].
+    ///
+    /// [`MemorySettings::max_allowed_memory_usage`]: crate::MemorySettings::max_allowed_memory_usage
+    #[inline]
+    #[must_use]
+    pub fn text_node(mut self, handler: impl IntoHandler<H::TextNodeHandler<'h>>) -> Self {
+        self.text_node = Some(handler.into_handler());
+
+        self
+    }
+
+    /// Sets a handler for end tags of elements matched by a selector.
+    ///
+    /// This is the only handler supported for selectors with `:has()`, as it's known whether
+    /// such selectors match only once the element ends.
+    #[inline]
+    #[must_use]
+    pub fn end_tag(mut self, handler: impl IntoHandler<H::ElementEndTagHandler<'h>>) -> Self {
+        self.end_tag = Some(handler.into_handler());
+
+        self
+    }
+
+    /// Sets a handler for elements matched by a selector, that is invoked once the element ends
+    /// with the element and its inner HTML.
+    ///
+    /// The output of the element is held back until then, so the handler can still modify
+    /// or replace the whole element. The inner HTML is buffered in memory, which is limited by
+    /// [`MemorySettings::max_allowed_memory_usage`].
+    ///
+    /// [`MemorySettings::max_allowed_memory_usage`]: crate::MemorySettings::max_allowed_memory_usage
+    #[inline]
+    #[must_use]
+    pub fn capture_subtree(mut self, handler: impl IntoHandler<H::SubtreeHandler<'h>>) -> Self {
+        self.capture_subtree = Some(handler.into_handler());
+
+        self
+    }
+
+    /// Sets a handler for elements matched by a selector, that is invoked once the element ends
+    /// with the element and the text of its descendants joined into the [`InnerText`].
+    ///
+    /// The modifications of the inner text are applied to the text chunks it consists of.
+    /// The output of the element is held back until the element ends, which is limited by
+    /// [`MemorySettings::max_allowed_memory_usage`].
+    ///
+    /// [`MemorySettings::max_allowed_memory_usage`]: crate::MemorySettings::max_allowed_memory_usage
+    #[inline]
+    #[must_use]
+    pub fn inner_text(mut self, handler: impl IntoHandler<H::InnerTextHandler<'h>>) -> Self {
+        self.inner_text = Some(handler.into_handler());
+
+        self
+    }
 }
 
 /// Specifies document-level content handlers.
@@ -308,6 +520,10 @@ impl<'h, H: HandlerTypes> ElementContentHandlers<'h, H> {
 /// <!-- I can be captured with a selector -->
 /// </html>
 /// ```
+///
+/// The handlers are constructed with [`Default::default`] and the builder methods, so that more
+/// handler kinds can be added without breaking the existing code.
+#[non_exhaustive]
 pub struct DocumentContentHandlers<'h, H: HandlerTypes = LocalHandlerTypes> {
     /// Doctype handler. See [`HandlerTypes::DoctypeHandler`].
     pub doctype: Option<H::DoctypeHandler<'h>>,
@@ -315,6 +531,8 @@ pub struct DocumentContentHandlers<'h, H: HandlerTypes = LocalHandlerTypes> {
     pub comments: Option<H::CommentHandler<'h>>,
     /// Text handler. See [`HandlerTypes::TextHandler`].
     pub text: Option<H::TextHandler<'h>>,
+    /// Text node handler. See [`HandlerTypes::TextNodeHandler`].
+    pub text_node: Option<H::TextNodeHandler<'h>>,
     /// End handler. See [`HandlerTypes::EndHandler`].
     pub end: Option<H::EndHandler<'h>>,
 }
@@ -325,6 +543,7 @@ impl<H: HandlerTypes> Default for DocumentContentHandlers<'_, H> {
             doctype: None,
             comments: None,
             text: None,
+            text_node: None,
             end: None,
         }
     }
@@ -360,6 +579,20 @@ impl<'h, H: HandlerTypes> DocumentContentHandlers<'h, H> {
         self
     }
 
+    /// Sets a handler for all whole text nodes present in the input HTML markup.
+    ///
+    /// Text chunks of the node are buffered until the last chunk of the node, which is limited
+    /// by [`MemorySettings::max_allowed_memory_usage`].
+    ///
+    /// [`MemorySettings::max_allowed_memory_usage`]: crate::MemorySettings::max_allowed_memory_usage
+    #[inline]
+    #[must_use]
+    pub fn text_node(mut self, handler: impl IntoHandler<H::TextNodeHandler<'h>>) -> Self {
+        self.text_node = Some(handler.into_handler());
+
+        self
+    }
+
     /// Sets a handler for the document end, which is called after the last chunk is processed.
     #[inline]
     #[must_use]
@@ -454,7 +687,7 @@ macro_rules! text {
         #[inline(always)]
         fn type_hint<T>(h: T) -> T
         where
-            T: FnMut(&mut $crate::html_content::TextChunk) -> $crate::HandlerResult,
+            T: FnMut(&mut $crate::html_content::TextChunk<'_>) -> $crate::HandlerResult,
         {
             h
         }
@@ -463,6 +696,48 @@ macro_rules! text {
     }};
 }
 
+/// A convenience macro to construct a rewriting handler for whole text nodes in the inner content
+/// of an element that can be matched by the specified CSS selector.
+///
+/// # Example
+/// ```
+/// use lol_html::{rewrite_str, text_node, RewriteStrSettings};
+/// use lol_html::html_content::ContentType;
+///
+/// let html = rewrite_str(
+///     r#"<span>Call 555-0100</span>"#,
+///     RewriteStrSettings {
+///         element_content_handlers: vec![
+///             text_node!("span", |t| {
+///                 let text = t.as_str().replace("555-0100", "<redacted>");
+///
+///                 t.replace(&text, ContentType::Text);
+///
+///                 Ok(())
+///             })
+///         ],
+///         ..RewriteStrSettings::new()
+///     }
+/// ).unwrap();
+///
+/// assert_eq!(html, r#"<span>Call &lt;redacted&gt;</span>"#);
+/// ```
+#[macro_export(local_inner_macros)]
+macro_rules! text_node {
+    ($selector:expr, $handler:expr) => {{
+        // Without this rust won't be able to always infer the type of the handler.
+        #[inline(always)]
+        fn type_hint<T>(h: T) -> T
+        where
+            T: FnMut(&mut $crate::html_content::TextNode<'_, '_>) -> $crate::HandlerResult,
+        {
+            h
+        }
+
+        __element_content_handler!($selector, text_node, type_hint($handler))
+    }};
+}
+
 /// A convenience macro to construct a rewriting handler for HTML comments in the inner content of
 /// an element that can be matched by the specified CSS selector.
 ///
@@ -503,6 +778,140 @@ macro_rules! comments {
     }};
 }
 
+/// A convenience macro to construct a rewriting handler for end tags of elements that can be
+/// matched by the specified CSS selector.
+///
+/// The handler is not invoked for elements that can't have an end tag (e.g. `<br>`).
+///
+/// # Example
+/// ```
+/// use lol_html::{rewrite_str, end_tag, RewriteStrSettings};
+/// use lol_html::html_content::ContentType;
+///
+/// let html = rewrite_str(
+///     r#"<article><img></article><article><p></p></article>"#,
+///     RewriteStrSettings {
+///         element_content_handlers: vec![
+///             end_tag!("article:has(img)", |t| {
+///                 t.before("<p>Has images</p>", ContentType::Html);
+///
+///                 Ok(())
+///             })
+///         ],
+///         ..RewriteStrSettings::new()
+///     }
+/// ).unwrap();
+///
+/// assert_eq!(
+///     html,
+///     r#"<article><img><p>Has images</p></article><article><p></p></article>"#
+/// );
+/// ```
+#[macro_export(local_inner_macros)]
+macro_rules! end_tag {
+    ($selector:expr, $handler:expr) => {{
+        // Without this rust won't be able to always infer the type of the handler.
+        #[inline(always)]
+        const fn type_hint<T>(h: T) -> T
+        where
+            T: FnMut(&mut $crate::html_content::EndTag<'_>) -> $crate::HandlerResult,
+        {
+            h
+        }
+
+        __element_content_handler!($selector, end_tag, type_hint($handler))
+    }};
+}
+
+/// A convenience macro to construct a subtree capturing handler for elements that can be matched
+/// by the specified CSS selector.
+///
+/// # Example
+/// ```
+/// use lol_html::{rewrite_str, capture_subtree, RewriteStrSettings};
+/// use lol_html::html_content::ContentType;
+///
+/// let html = rewrite_str(
+///     r#"<script type="application/ld+json">{"name": "foo"}</script>"#,
+///     RewriteStrSettings {
+///         element_content_handlers: vec![
+///             capture_subtree!("script[type='application/ld+json']", |el, inner_html| {
+///                 assert_eq!(inner_html, r#"{"name": "foo"}"#);
+///
+///                 el.replace(r#"<meta name="foo">"#, ContentType::Html);
+///
+///                 Ok(())
+///             })
+///         ],
+///         ..RewriteStrSettings::new()
+///     }
+/// ).unwrap();
+///
+/// assert_eq!(html, r#"<meta name="foo">"#);
+/// ```
+#[macro_export(local_inner_macros)]
+macro_rules! capture_subtree {
+    ($selector:expr, $handler:expr) => {{
+        // Without this rust won't be able to always infer the type of the handler.
+        #[inline(always)]
+        const fn type_hint<'h, T, H: $crate::HandlerTypes>(h: T) -> T
+        where
+            T: FnMut(&mut $crate::html_content::Element<'_, '_, H>, &str) -> $crate::HandlerResult
+                + 'h,
+        {
+            h
+        }
+
+        __element_content_handler!($selector, capture_subtree, type_hint($handler))
+    }};
+}
+
+/// A convenience macro to construct an inner text handler for elements that can be matched
+/// by the specified CSS selector.
+///
+/// # Example
+/// ```
+/// use lol_html::{rewrite_str, inner_text, RewriteStrSettings};
+/// use lol_html::html_content::ContentType;
+///
+/// let html = rewrite_str(
+///     r#"<p>Call <a href="tel:5550100">555-<b>0100</b></a></p>"#,
+///     RewriteStrSettings {
+///         element_content_handlers: vec![
+///             inner_text!("p", |_, text| {
+///                 while let Some(start) = text.as_str().find("555-0100") {
+///                     text.replace_range(start..start + 8, "[redacted]", ContentType::Text);
+///                 }
+///
+///                 Ok(())
+///             })
+///         ],
+///         ..RewriteStrSettings::new()
+///     }
+/// ).unwrap();
+///
+/// assert_eq!(html, r#"<p>Call <a href="tel:5550100">[redacted]<b></b></a></p>"#);
+/// ```
+#[macro_export(local_inner_macros)]
+macro_rules! inner_text {
+    ($selector:expr, $handler:expr) => {{
+        // Without this rust won't be able to always infer the type of the handler.
+        #[inline(always)]
+        const fn type_hint<'h, T, H: $crate::HandlerTypes>(h: T) -> T
+        where
+            T: FnMut(
+                    &mut $crate::html_content::Element<'_, '_, H>,
+                    &mut $crate::html_content::InnerText,
+                ) -> $crate::HandlerResult
+                + 'h,
+        {
+            h
+        }
+
+        __element_content_handler!($selector, inner_text, type_hint($handler))
+    }};
+}
+
 /// A convenience macro to construct a `StreamingHandler` from a closure.
 ///
 /// For use with [`Element::streaming_replace`], etc.
@@ -635,6 +1044,46 @@ macro_rules! doc_text {
     }};
 }
 
+/// A convenience macro to construct a rewriting handler for all whole text nodes in the HTML
+/// document.
+///
+/// # Example
+/// ```
+/// use lol_html::{rewrite_str, doc_text_node, RewriteStrSettings};
+/// use lol_html::html_content::ContentType;
+///
+/// let html = rewrite_str(
+///     r#"Hello<span>Hello</span>Hello"#,
+///     RewriteStrSettings {
+///         document_content_handlers: vec![
+///             doc_text_node!(|t| {
+///                 t.after(" world", ContentType::Text);
+///
+///                 Ok(())
+///             })
+///         ],
+///         ..RewriteStrSettings::new()
+///     }
+/// ).unwrap();
+///
+/// assert_eq!(html, r#"Hello world<span>Hello world</span>Hello world"#);
+/// ```
+#[macro_export(local_inner_macros)]
+macro_rules! doc_text_node {
+    ($handler:expr) => {{
+        // Without this rust won't be able to always infer the type of the handler.
+        #[inline(always)]
+        const fn type_hint<T>(h: T) -> T
+        where
+            T: FnMut(&mut $crate::html_content::TextNode<'_, '_>) -> $crate::HandlerResult,
+        {
+            h
+        }
+
+        __document_content_handler!(text_node, type_hint($handler))
+    }};
+}
+
 /// A convenience macro to construct a rewriting handler for all HTML comments in the HTML document.
 ///
 /// # Example
@@ -786,6 +1235,28 @@ impl MemorySettings {
     }
 }
 
+/// Specifies the order in which the handlers of the selectors matching
+/// the same element or content are invoked.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HandlerOrdering {
+    /// The handlers are invoked in the order of their selectors in the
+    /// [`element_content_handlers`](Settings::element_content_handlers).
+    #[default]
+    Registration,
+
+    /// The handlers are invoked in the ascending order of their selectors' [`Specificity`], so
+    /// the handlers of the more specific selectors are invoked later and can override the
+    /// modifications made by the less specific ones, similarly to the CSS cascade. Handlers of
+    /// the selectors with the same specificity are invoked in the order of their registration.
+    ///
+    /// The handlers of a selector list are invoked according to the most specific of the selectors
+    /// in the list, that match the element (e.g. `p, #foo` is ranked as a type selector for
+    /// the paragraphs without the `foo` id).
+    ///
+    /// [`Specificity`]: crate::Specificity
+    Specificity,
+}
+
 /// Specifies settings for [`HtmlRewriter`].
 ///
 /// [`HtmlRewriter`]: struct.HtmlRewriter.html
@@ -929,6 +1400,25 @@ pub struct Settings<'h, 's, H: HandlerTypes = LocalHandlerTypes> {
     ///
     /// `false` when constructed with `Settings::new()`.
     pub adjust_charset_on_meta_tag: bool,
+
+    /// Specifies the order in which the handlers of the selectors matching the same element
+    /// or content are invoked.
+    ///
+    /// ### Default
+    ///
+    /// [`HandlerOrdering::Registration`] when constructed with `Settings::new()`.
+    pub handler_ordering: HandlerOrdering,
+
+    /// If enabled the rewriter collects the statistics of selector matching, e.g. to find
+    /// the selectors that are expensive to match. The statistics are returned by
+    /// [`HtmlRewriter::end_with_selector_matching_stats`].
+    ///
+    /// [`HtmlRewriter::end_with_selector_matching_stats`]: crate::HtmlRewriter::end_with_selector_matching_stats
+    ///
+    /// ### Default
+    ///
+    /// `false` when constructed with `Settings::new()`.
+    pub enable_selector_matching_stats: bool,
 }
 
 impl Default for Settings<'_, '_, LocalHandlerTypes> {
@@ -969,6 +1459,8 @@ impl<H: HandlerTypes> Settings<'_, '_, H> {
             strict: true,
             enable_esi_tags: false,
             adjust_charset_on_meta_tag: false,
+            handler_ordering: HandlerOrdering::Registration,
+            enable_selector_matching_stats: false,
         }
     }
 }
@@ -981,6 +1473,7 @@ impl<'h, 's, H: HandlerTypes> From<RewriteStrSettings<'h, 's, H>> for Settings<'
             document_content_handlers: settings.document_content_handlers,
             strict: settings.strict,
             enable_esi_tags: settings.enable_esi_tags,
+            handler_ordering: settings.handler_ordering,
             ..Settings::new_for_handler_types()
         }
     }
@@ -1086,6 +1579,14 @@ pub struct RewriteStrSettings<'h, 's, H: HandlerTypes = LocalHandlerTypes> {
     /// [Edge Side Includes]: https://www.w3.org/TR/esi-lang/
     /// [void elements]: https://developer.mozilla.org/en-US/docs/Glossary/Void_element
     pub enable_esi_tags: bool,
+
+    /// Specifies the order in which the handlers of the selectors matching the same element
+    /// or content are invoked.
+    ///
+    /// ### Default
+    ///
+    /// [`HandlerOrdering::Registration`] when constructed with `RewriteStrSettings::new()`.
+    pub handler_ordering: HandlerOrdering,
 }
 
 impl Default for RewriteStrSettings<'_, '_, LocalHandlerTypes> {
@@ -1123,6 +1624,7 @@ impl<H: HandlerTypes> RewriteStrSettings<'_, '_, H> {
             document_content_handlers: vec![],
             strict: true,
             enable_esi_tags: true,
+            handler_ordering: HandlerOrdering::Registration,
         }
     }
 }
@@ -0,0 +1,395 @@
+use super::ast::{Ast, AstNode, OnAttributesExpr, OnParentEndExpr, OnTagNameExpr, Predicate};
+use super::Selector;
+
+/// The static analysis of a [`Selector`], that tells which elements it can match without
+/// running it, e.g. to route the documents to the rewriters with the relevant selectors.
+///
+/// See [`Selector::analyze`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SelectorAnalysis {
+    /// The ASCII-lowercased local names of the elements the selector can match, or `None`
+    /// if it can match the elements with any local name (e.g. `.foo`).
+    pub local_names: Option<Vec<String>>,
+    /// The ASCII-lowercased names of the attributes, that the elements matched by the selector
+    /// must have (e.g. `href` for `a[href]` or `class` for `.foo`).
+    pub required_attributes: Vec<String>,
+    /// `true` if matching the selector requires the attributes of the elements, including the
+    /// attributes of their ancestors and siblings (e.g. `.foo > p`) or the negated ones
+    /// (e.g. `p:not([hidden])`).
+    pub needs_attributes: bool,
+    /// `true` if the selector uses descendant combinators (e.g. `ul li` or `div:has(a)`), so
+    /// the ancestors of the elements have to be tracked to match it.
+    pub is_hereditary: bool,
+    /// `true` if the selector uses the pseudo-classes, that require counting the siblings of
+    /// the elements (e.g. `:nth-child()` or `:last-of-type`).
+    pub needs_nth_counters: bool,
+    /// `true` if the selector uses the pseudo-classes, that depend on the following siblings or
+    /// the content of the elements (e.g. `:last-child` or `:empty`). Such selectors are matched
+    /// lazily and don't support text, comment and text node handlers.
+    pub is_matched_lazily: bool,
+    /// `true` if the selector uses `:has()`, so it's known whether an element matches it only
+    /// once the element ends. Such selectors support only end tag handlers.
+    pub is_matched_on_end_tag: bool,
+}
+
+impl SelectorAnalysis {
+    pub(crate) fn new(selector: &Selector) -> Self {
+        let mut ast = Ast::default();
+
+        ast.add_selector(selector, ());
+
+        let mut analysis = SelectorAnalysis {
+            local_names: Some(Vec::new()),
+            is_matched_lazily: selector.is_matched_lazily(),
+            is_matched_on_end_tag: selector.is_matched_on_end_tag(),
+            ..SelectorAnalysis::default()
+        };
+
+        // NOTE: the attributes are required only if all of the selectors in the list require them.
+        let mut required_attributes = None;
+        let mut alternatives = vec![Vec::new(); ast.standalone_selector_count];
+
+        collect_alternatives(&ast.root, &mut alternatives);
+        analysis.visit_nodes(&ast.root, &alternatives, false, &mut required_attributes);
+
+        if let Some(ref mut local_names) = analysis.local_names {
+            local_names.sort_unstable();
+            local_names.dedup();
+        }
+
+        analysis.required_attributes = required_attributes.unwrap_or_default();
+        analysis.required_attributes.sort_unstable();
+
+        analysis
+    }
+
+    fn visit_nodes(
+        &mut self,
+        nodes: &[AstNode<()>],
+        alternatives: &[Vec<&Predicate>],
+        in_relative_selector: bool,
+        required_attributes: &mut Option<Vec<String>>,
+    ) {
+        for node in nodes {
+            let predicate = &node.predicate;
+
+            self.needs_attributes |= !predicate.on_attr_exprs.is_empty();
+
+            self.needs_nth_counters |= predicate
+                .on_parent_end_exprs
+                .iter()
+                .any(|e| e.simple_expr != OnParentEndExpr::Empty)
+                || predicate.on_tag_name_exprs.iter().any(|e| {
+                    matches!(
+                        e.simple_expr,
+                        OnTagNameExpr::NthChild(_) | OnTagNameExpr::NthOfType(_)
+                    )
+                });
+
+            self.is_hereditary |= !node.descendants.is_empty() || !node.has_descendants.is_empty();
+
+            // NOTE: the payload of the nodes for the arguments of `:has()` belongs to the element
+            // that has them, and the nodes of standalone selectors don't have payload.
+            let is_subject = !in_relative_selector
+                && (!node.payload.is_empty()
+                    || !node.has_children.is_empty()
+                    || !node.has_descendants.is_empty());
+
+            if is_subject {
+                self.add_subject(predicate, alternatives, required_attributes);
+            }
+
+            for nodes in [
+                &node.children,
+                &node.descendants,
+                &node.next_siblings,
+                &node.later_siblings,
+            ] {
+                self.visit_nodes(
+                    nodes,
+                    alternatives,
+                    in_relative_selector,
+                    required_attributes,
+                );
+            }
+
+            for nodes in [&node.has_children, &node.has_descendants] {
+                self.visit_nodes(nodes, alternatives, true, required_attributes);
+            }
+        }
+    }
+
+    /// Adds the predicate of the compound selector, that is matched by the elements
+    /// the selector matches (e.g. `li.item` in `ul > li.item`).
+    fn add_subject(
+        &mut self,
+        predicate: &Predicate,
+        alternatives: &[Vec<&Predicate>],
+        required_attributes: &mut Option<Vec<String>>,
+    ) {
+        // NOTE: the compound selector can't match anything (e.g. `a:is(img)`).
+        let Some(subject) = Subject::new(predicate, alternatives) else {
+            return;
+        };
+
+        match (subject.local_names, &mut self.local_names) {
+            (Some(mut local_names), Some(names)) => names.append(&mut local_names),
+            (Some(_), None) => (),
+            (None, _) => self.local_names = None,
+        }
+
+        match required_attributes {
+            Some(required_attributes) => {
+                required_attributes.retain(|a| subject.attributes.contains(a));
+            }
+            None => *required_attributes = Some(subject.attributes),
+        }
+    }
+}
+
+/// Collects the predicates of the nodes matching the standalone selectors by their ids.
+fn collect_alternatives<'a>(nodes: &'a [AstNode<()>], alternatives: &mut [Vec<&'a Predicate>]) {
+    for node in nodes {
+        for &id in &node.standalone_selector_payload {
+            alternatives[id].push(&node.predicate);
+        }
+
+        for nodes in [
+            &node.children,
+            &node.descendants,
+            &node.next_siblings,
+            &node.later_siblings,
+        ] {
+            collect_alternatives(nodes, alternatives);
+        }
+    }
+}
+
+/// The local names and the attributes of the elements matched by a compound selector.
+struct Subject {
+    /// `None` if the elements can have any local name.
+    local_names: Option<Vec<String>>,
+    attributes: Vec<String>,
+}
+
+impl Subject {
+    /// Returns `None` if the compound selector can't match anything. The predicates of the
+    /// standalone selectors for the arguments of `:is()` are looked up in `alternatives` by id.
+    fn new(predicate: &Predicate, alternatives: &[Vec<&Predicate>]) -> Option<Self> {
+        let mut local_names = Vec::new();
+
+        for expr in predicate.on_tag_name_exprs.iter().filter(|e| !e.negation) {
+            match &expr.simple_expr {
+                OnTagNameExpr::LocalName(name) => local_names.push(name.to_ascii_lowercase()),
+                // NOTE: the selector can't match anything (e.g. `|p`).
+                OnTagNameExpr::Unmatchable => return None,
+                _ => (),
+            }
+        }
+
+        local_names.sort_unstable();
+        local_names.dedup();
+
+        let local_names = match local_names.len() {
+            0 => None,
+            1 => Some(local_names),
+            _ => return None,
+        };
+
+        let mut attributes: Vec<_> = predicate
+            .on_attr_exprs
+            .iter()
+            .filter(|e| !e.negation)
+            .map(|e| match &e.simple_expr {
+                OnAttributesExpr::Id(_) => "id".to_owned(),
+                OnAttributesExpr::Class(_) => "class".to_owned(),
+                OnAttributesExpr::AttributeExists(name) => name.to_ascii_lowercase(),
+                OnAttributesExpr::AttributeComparisonExpr(e) => e.name.to_ascii_lowercase(),
+                OnAttributesExpr::AttributePattern(p) => p.name.to_string(),
+            })
+            .collect();
+
+        attributes.sort_unstable();
+        attributes.dedup();
+
+        let mut subject = Self {
+            local_names,
+            attributes,
+        };
+
+        for &id in &predicate.required_selectors {
+            let alternative = alternatives[id]
+                .iter()
+                .filter_map(|p| Self::new(p, alternatives))
+                .reduce(Self::union)?;
+
+            subject = subject.intersection(alternative)?;
+        }
+
+        Some(subject)
+    }
+
+    /// The elements matched by either of the compound selectors.
+    fn union(mut self, other: Self) -> Self {
+        self.local_names = match (self.local_names, other.local_names) {
+            (Some(mut names), Some(mut other_names)) => {
+                names.append(&mut other_names);
+                names.sort_unstable();
+                names.dedup();
+
+                Some(names)
+            }
+            _ => None,
+        };
+
+        self.attributes.retain(|a| other.attributes.contains(a));
+
+        self
+    }
+
+    /// The elements matched by both of the compound selectors, or `None` if there are none.
+    fn intersection(mut self, other: Self) -> Option<Self> {
+        self.local_names = match (self.local_names, other.local_names) {
+            (Some(mut names), Some(other_names)) => {
+                names.retain(|n| other_names.contains(n));
+
+                if names.is_empty() {
+                    return None;
+                }
+
+                Some(names)
+            }
+            (names, None) | (None, names) => names,
+        };
+
+        self.attributes.extend(other.attributes);
+        self.attributes.sort_unstable();
+        self.attributes.dedup();
+
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[track_caller]
+    fn analyze(selector: &str) -> SelectorAnalysis {
+        selector.parse::<Selector>().unwrap().analyze()
+    }
+
+    fn names(names: &[&str]) -> Option<Vec<String>> {
+        Some(names.iter().map(|&n| n.to_owned()).collect())
+    }
+
+    #[test]
+    fn local_names() {
+        for (selector, expected) in [
+            ("a", names(&["a"])),
+            ("A.foo", names(&["a"])),
+            ("ul > li, ol > li, dl dt", names(&["dt", "li"])),
+            ("div :is(a, img)", names(&["a", "img"])),
+            ("a:is(img)", names(&[])),
+            ("div :is(a, :is(img, a.x))", names(&["a", "img"])),
+            ("a:is(img, :is(b, a))", names(&["a"])),
+            ("|p", names(&[])),
+            ("svg|a", names(&["a"])),
+            ("section:has(> h1)", names(&["section"])),
+            ("li:not(nav li)", names(&["li"])),
+            (".foo", None),
+            ("a, *", None),
+            (":not(a)", None),
+            ("a ~ *", None),
+        ] {
+            assert_eq!(analyze(selector).local_names, expected, "{selector}");
+        }
+    }
+
+    #[test]
+    fn required_attributes() {
+        for (selector, expected) in [
+            ("a", &[][..]),
+            ("a[href]", &["href"]),
+            (
+                "#foo.bar[Lang|=en][title]",
+                &["class", "id", "lang", "title"],
+            ),
+            (".foo > p", &[]),
+            ("p:not([hidden])", &[]),
+            ("a[href].foo, img.foo[src]", &["class"]),
+            ("a[href], img", &[]),
+            ("div:has([href])", &[]),
+            (":is(a.x[href], img.x)[title]", &["class", "title"]),
+        ] {
+            assert_eq!(
+                analyze(selector).required_attributes,
+                expected,
+                "{selector}"
+            );
+        }
+    }
+
+    #[test]
+    fn flags() {
+        for (selector, needs_attributes, is_hereditary, needs_nth_counters) in [
+            ("a", false, false, false),
+            ("a[href]", true, false, false),
+            (".foo > p", true, false, false),
+            ("p:not([hidden])", true, false, false),
+            ("li:not(.foo li)", true, true, false),
+            ("div:has(> a[href])", true, false, false),
+            ("ul li", false, true, false),
+            ("div:has(a)", false, true, false),
+            ("p + p", false, false, false),
+            ("li:nth-child(2n)", false, false, true),
+            ("ul > li:first-of-type", false, false, true),
+            ("li:last-child", false, false, true),
+            ("div:empty", false, false, false),
+            (":root > body", false, false, false),
+            ("div :is(p, li:only-child)", false, true, true),
+        ] {
+            let analysis = analyze(selector);
+
+            assert_eq!(
+                (
+                    analysis.needs_attributes,
+                    analysis.is_hereditary,
+                    analysis.needs_nth_counters
+                ),
+                (needs_attributes, is_hereditary, needs_nth_counters),
+                "{selector}"
+            );
+        }
+    }
+
+    #[test]
+    fn lazy_matching() {
+        for (selector, expected) in [
+            ("li:last-child", true),
+            ("div:empty", true),
+            ("div :is(p, li:only-child)", true),
+            ("p:not(:nth-last-of-type(2))", true),
+            ("li:first-child", false),
+            ("ul li:nth-child(2)", false),
+        ] {
+            assert_eq!(analyze(selector).is_matched_lazily, expected, "{selector}");
+        }
+    }
+
+    #[test]
+    fn matching_on_end_tag() {
+        for (selector, expected) in [
+            ("p:has(b)", true),
+            ("li, div:has(> img)", true),
+            ("p:is(.a, .b)", false),
+            ("li:last-child", false),
+        ] {
+            assert_eq!(
+                analyze(selector).is_matched_on_end_tag,
+                expected,
+                "{selector}"
+            );
+        }
+    }
+}
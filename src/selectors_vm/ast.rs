@@ -1,7 +1,11 @@
-use super::parser::{Selector, SelectorImplDescriptor};
+use super::parser::{
+    is_expanded, negated_simple_selector, AttributePattern, NonTSPseudoClassExt, Selector,
+    SelectorImplDescriptor,
+};
+use crate::html::Namespace;
 use hashbrown::HashSet;
-use selectors::attr::{AttrSelectorOperator, ParsedCaseSensitivity};
-use selectors::parser::{Combinator, Component, NthType};
+use selectors::attr::{AttrSelectorOperator, ParsedAttrSelectorOperation, ParsedCaseSensitivity};
+use selectors::parser::{Combinator, Component, NthType, RelativeSelector};
 use std::fmt::{self, Debug, Formatter};
 use std::hash::Hash;
 
@@ -36,12 +40,21 @@ impl NthChild {
             offsetted.wrapping_rem(step) == 0
         }
     }
+
+    /// Returns `true` if neither `index` nor any index following it can match.
+    #[must_use]
+    pub const fn has_no_index_from(self, index: i32) -> bool {
+        self.step <= 0 && index > self.offset
+    }
 }
 
 #[derive(PartialEq, Eq, Debug)]
 pub(crate) enum OnTagNameExpr {
     ExplicitAny,
     Unmatchable,
+    /// The element is the root of the document (`:root` or `:scope`).
+    Root,
+    Namespace(Namespace),
     LocalName(Box<str>),
     NthChild(NthChild),
     NthOfType(NthChild),
@@ -95,6 +108,19 @@ impl Debug for AttributeComparisonExpr {
     }
 }
 
+/// A check that depends on the following siblings of the element. It can be decided
+/// only lazily, once the element's position from the end of its parent is known.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub(crate) enum OnParentEndExpr {
+    NthLastChild(NthChild),
+    NthLastOfType(NthChild),
+    OnlyChild,
+    OnlyOfType,
+    /// Depends on the content of the element instead, so it's decided once the element has
+    /// a child or text, or once it ends, which is at the latest by the end of its parent.
+    Empty,
+}
+
 /// An attribute check when attributes are received and parsed.
 #[derive(PartialEq, Eq, Debug)]
 pub(crate) enum OnAttributesExpr {
@@ -102,14 +128,18 @@ pub(crate) enum OnAttributesExpr {
     Class(Box<str>),
     AttributeExists(Box<str>),
     AttributeComparisonExpr(AttributeComparisonExpr),
+    /// The non-standard `:matches-attr()` pseudo-class.
+    AttributePattern(AttributePattern),
 }
 
 #[derive(PartialEq, Eq, Debug)]
 /// Conditions executed as part of a predicate, or an "expect" in pseudo instructions.
 /// These are executed in order of definition.
+#[allow(clippy::enum_variant_names)]
 enum Condition {
     OnTagName(OnTagNameExpr),
     OnAttributes(OnAttributesExpr),
+    OnParentEnd(OnParentEndExpr),
 }
 
 impl From<&Component<SelectorImplDescriptor>> for Condition {
@@ -123,6 +153,10 @@ impl From<&Component<SelectorImplDescriptor>> for Condition {
                 Self::OnTagName(OnTagNameExpr::ExplicitAny)
             }
             Component::ExplicitNoNamespace => Self::OnTagName(OnTagNameExpr::Unmatchable),
+            // NOTE: there are no scoped selectors, so `:scope` is the same as `:root`.
+            Component::Root | Component::Scope => Self::OnTagName(OnTagNameExpr::Root),
+            Component::Empty => Self::OnParentEnd(OnParentEndExpr::Empty),
+            &Component::Namespace(_, ns) => Self::OnTagName(OnTagNameExpr::Namespace(ns)),
             Component::ID(id) => Self::OnAttributes(OnAttributesExpr::Id(id.to_boxed_slice())),
             Component::Class(c) => Self::OnAttributes(OnAttributesExpr::Class(c.to_boxed_slice())),
             Component::AttributeInNoNamespaceExists { local_name, .. } => Self::OnAttributes(
@@ -141,12 +175,44 @@ impl From<&Component<SelectorImplDescriptor>> for Condition {
                     operator,
                 ),
             )),
+            Component::AttributeOther(attr) => {
+                let name = attr.local_name_lower.to_boxed_slice();
+
+                Self::OnAttributes(match &attr.operation {
+                    ParsedAttrSelectorOperation::Exists => OnAttributesExpr::AttributeExists(name),
+                    &ParsedAttrSelectorOperation::WithValue {
+                        operator,
+                        case_sensitivity,
+                        ref value,
+                    } => OnAttributesExpr::AttributeComparisonExpr(AttributeComparisonExpr::new(
+                        name,
+                        value.to_boxed_slice(),
+                        case_sensitivity,
+                        operator,
+                    )),
+                })
+            }
+            Component::NonTSPseudoClass(NonTSPseudoClassExt::MatchesAttr(pattern)) => {
+                Self::OnAttributes(OnAttributesExpr::AttributePattern(pattern.clone()))
+            }
             Component::Nth(data) if data.ty == NthType::Child => {
                 Self::OnTagName(OnTagNameExpr::NthChild(NthChild::new(data.a, data.b)))
             }
             Component::Nth(data) if data.ty == NthType::OfType => {
                 Self::OnTagName(OnTagNameExpr::NthOfType(NthChild::new(data.a, data.b)))
             }
+            Component::Nth(data) if data.ty == NthType::LastChild => {
+                Self::OnParentEnd(OnParentEndExpr::NthLastChild(NthChild::new(data.a, data.b)))
+            }
+            Component::Nth(data) if data.ty == NthType::LastOfType => Self::OnParentEnd(
+                OnParentEndExpr::NthLastOfType(NthChild::new(data.a, data.b)),
+            ),
+            Component::Nth(data) if data.ty == NthType::OnlyChild => {
+                Self::OnParentEnd(OnParentEndExpr::OnlyChild)
+            }
+            Component::Nth(data) if data.ty == NthType::OnlyOfType => {
+                Self::OnParentEnd(OnParentEndExpr::OnlyOfType)
+            }
             // NOTE: the rest of the components are explicit namespace or
             // pseudo class-related. Ideally none of them should appear in
             // the parsed selector as we should bail earlier in the parser.
@@ -158,7 +224,7 @@ impl From<&Component<SelectorImplDescriptor>> for Condition {
     }
 }
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(PartialEq, Eq, Debug, Clone)]
 pub(crate) struct Expr<E>
 where
     E: PartialEq + Eq + Debug,
@@ -184,6 +250,13 @@ where
 pub(crate) struct Predicate {
     pub on_tag_name_exprs: Vec<Expr<OnTagNameExpr>>,
     pub on_attr_exprs: Vec<Expr<OnAttributesExpr>>,
+    pub on_parent_end_exprs: Vec<Expr<OnParentEndExpr>>,
+    /// Ids of the negated selectors, other than the simple ones, the element must not match
+    /// (e.g. `:not(ul > li)`). These are matched as standalone selectors.
+    pub negated_selectors: Vec<usize>,
+    /// Ids of the standalone selectors for the arguments of `:is()` and `:where()` (e.g.
+    /// `ul > li` and `ol > li` in `:is(ul > li, ol > li)`), the element must match.
+    pub required_selectors: Vec<usize>,
 }
 
 #[inline]
@@ -200,8 +273,36 @@ impl Predicate {
         match Condition::from(component) {
             Condition::OnTagName(e) => add_expr_to_list(&mut self.on_tag_name_exprs, e, negation),
             Condition::OnAttributes(e) => add_expr_to_list(&mut self.on_attr_exprs, e, negation),
+            Condition::OnParentEnd(e) => {
+                add_expr_to_list(&mut self.on_parent_end_exprs, e, negation);
+            }
         }
     }
+
+    /// Adds the negated simple selectors to the predicate, returning the rest of them.
+    #[inline]
+    fn add_negation<'s>(
+        &mut self,
+        selectors: &'s [selectors::parser::Selector<SelectorImplDescriptor>],
+    ) -> Vec<&'s selectors::parser::Selector<SelectorImplDescriptor>> {
+        selectors
+            .iter()
+            .filter(|s| match negated_simple_selector(s) {
+                Some(c) => {
+                    self.add_component(c, true);
+                    false
+                }
+                None => true,
+            })
+            .collect()
+    }
+
+    #[inline]
+    fn is_empty(&self) -> bool {
+        self.on_tag_name_exprs.is_empty()
+            && self.on_attr_exprs.is_empty()
+            && self.on_parent_end_exprs.is_empty()
+    }
 }
 
 #[derive(PartialEq, Eq, Debug)]
@@ -212,7 +313,16 @@ where
     pub predicate: Predicate,
     pub children: Vec<AstNode<P>>,
     pub descendants: Vec<AstNode<P>>,
+    pub next_siblings: Vec<AstNode<P>>,
+    pub later_siblings: Vec<AstNode<P>>,
+    /// Nodes for the arguments of `:has()`, which are matched against the children
+    /// (`:has(> s)`) or descendants (`:has(s)`) of the element. Payload of these nodes
+    /// belongs to the element and is matched once its child or descendant matches.
+    pub has_children: Vec<AstNode<P>>,
+    pub has_descendants: Vec<AstNode<P>>,
     pub payload: HashSet<P>,
+    /// Ids of the standalone selectors (e.g. `ul > li` in `:not(ul > li)`) matched by the node.
+    pub standalone_selector_payload: HashSet<usize>,
 }
 
 impl<P> AstNode<P>
@@ -224,13 +334,68 @@ where
             predicate,
             children: Vec::default(),
             descendants: Vec::default(),
+            next_siblings: Vec::default(),
+            later_siblings: Vec::default(),
+            has_children: Vec::default(),
+            has_descendants: Vec::default(),
             payload: HashSet::default(),
+            standalone_selector_payload: HashSet::default(),
         }
     }
 }
 
+/// Expands `:is()` and `:where()` with the arguments that are matched lazily into the selectors
+/// they consist of, returning the components of each of them in the parse order (e.g.
+/// `li:is(:last-child, .foo)` is expanded into `li:last-child` and `li.foo`). The payload of
+/// the expanded selectors is matched once per element by the VM.
+fn expand_selector(
+    selector: &selectors::parser::Selector<SelectorImplDescriptor>,
+) -> Vec<Vec<&Component<SelectorImplDescriptor>>> {
+    let mut expanded = vec![Vec::new()];
+
+    for component in selector.iter_raw_parse_order_from(0) {
+        let selectors = match component {
+            Component::Is(selectors) | Component::Where(selectors) if is_expanded(component) => {
+                selectors
+            }
+            _ => {
+                expanded.iter_mut().for_each(|c| c.push(component));
+                continue;
+            }
+        };
+
+        let arguments: Vec<_> = selectors.slice().iter().flat_map(expand_selector).collect();
+
+        expanded = expanded
+            .iter()
+            .flat_map(|components| {
+                arguments.iter().map(move |argument| {
+                    // NOTE: complex selectors are supported only in the leftmost compound
+                    // selector, so the rest of the compound is merged into the rightmost
+                    // compound of the argument (e.g. `.x:is(ul li:empty)` is `ul li.x:empty`).
+                    let split_at = argument
+                        .iter()
+                        .rposition(|c| c.is_combinator())
+                        .map_or(0, |idx| idx + 1);
+
+                    let (ancestors, compound) = argument.split_at(split_at);
+
+                    ancestors
+                        .iter()
+                        .chain(components)
+                        .chain(compound)
+                        .copied()
+                        .collect()
+                })
+            })
+            .collect();
+    }
+
+    expanded
+}
+
 // exposed for selectors_ast tool
-#[derive(Default, PartialEq, Eq, Debug)]
+#[derive(PartialEq, Eq, Debug)]
 pub struct Ast<P>
 where
     P: PartialEq + Eq + Copy + Debug + Hash,
@@ -238,6 +403,29 @@ where
     pub(crate) root: Vec<AstNode<P>>,
     // NOTE: used to preallocate instruction vector during compilation.
     pub(crate) cumulative_node_count: usize,
+    pub(crate) standalone_selector_count: usize,
+}
+
+// NOTE: implemented manually, as the derived implementation requires the payload to be `Default`.
+impl<P> Default for Ast<P>
+where
+    P: PartialEq + Eq + Copy + Debug + Hash,
+{
+    fn default() -> Self {
+        Self {
+            root: Vec::default(),
+            cumulative_node_count: 0,
+            standalone_selector_count: 0,
+        }
+    }
+}
+
+/// What is matched by the rightmost compound selector.
+#[derive(Clone, Copy)]
+enum SelectorPayload<P> {
+    Payload(P),
+    /// The id of a standalone selector (e.g. `ul > li` in `:not(ul > li)` or `:is(ul > li)`).
+    StandaloneSelector(usize),
 }
 
 impl<P> Ast<P>
@@ -246,10 +434,15 @@ where
 {
     #[inline]
     fn host_expressions(
-        predicate: Predicate,
+        mut predicate: Predicate,
         branches: &mut Vec<AstNode<P>>,
         cumulative_node_count: &mut usize,
     ) -> usize {
+        // NOTE: `:has()` or negated selectors without other conditions match any element.
+        if predicate.is_empty() {
+            predicate.add_component(&Component::ExplicitUniversalType, false);
+        }
+
         branches
             .iter()
             .enumerate()
@@ -264,45 +457,153 @@ where
     }
 
     pub fn add_selector(&mut self, selector: &Selector, payload: P) {
-        for selector_item in (selector.0).slice() {
-            let mut predicate = Predicate::default();
-            let mut branches = &mut self.root;
+        for components in (selector.0).slice().iter().flat_map(expand_selector) {
+            self.add_selector_components(components, SelectorPayload::Payload(payload));
+        }
+    }
+
+    /// Adds the selectors of the list with the payload for their specificity, i.e. with the
+    /// element of `payloads` at the index of the specificity in
+    /// [`Selector::distinct_specificities`].
+    pub fn add_selector_by_specificity(&mut self, selector: &Selector, payloads: &[P]) {
+        let specificities = selector.distinct_specificities();
+
+        for (complex_selector, specificity) in (selector.0)
+            .slice()
+            .iter()
+            .zip(selector.list_specificities())
+        {
+            let idx = specificities
+                .iter()
+                .position(|&s| s == specificity)
+                .unwrap_or_default();
+
+            for components in expand_selector(complex_selector) {
+                self.add_selector_components(components, SelectorPayload::Payload(payloads[idx]));
+            }
+        }
+    }
+
+    fn add_selector_components(
+        &mut self,
+        components: Vec<&Component<SelectorImplDescriptor>>,
+        payload: SelectorPayload<P>,
+    ) {
+        let mut predicate = Predicate::default();
+        let mut branches = &mut self.root;
+        let mut standalone_selectors = Vec::new();
+
+        macro_rules! host_and_switch_branch_vec {
+            ($branches:ident) => {{
+                let node_idx =
+                    Self::host_expressions(predicate, branches, &mut self.cumulative_node_count);
+
+                branches = &mut branches[node_idx].$branches;
+                predicate = Predicate::default();
+            }};
+        }
+
+        let mut relative_selectors = None;
+
+        for component in components {
+            match component {
+                Component::Combinator(c) => match c {
+                    Combinator::Child => host_and_switch_branch_vec!(children),
+                    Combinator::Descendant => host_and_switch_branch_vec!(descendants),
+                    Combinator::NextSibling => host_and_switch_branch_vec!(next_siblings),
+                    Combinator::LaterSibling => host_and_switch_branch_vec!(later_siblings),
+                    _ => unreachable!(
+                        "Unsupported selector components should be filtered out by the parser."
+                    ),
+                },
+                Component::Negation(ss) => {
+                    for selector in predicate.add_negation(ss.slice()) {
+                        let id = self.standalone_selector_count;
+
+                        self.standalone_selector_count += 1;
+                        predicate.negated_selectors.push(id);
+                        standalone_selectors.push((id, selector));
+                    }
+                }
+                // NOTE: the standalone selectors are added after the selector, so their
+                // ids are greater than the ids of the standalone selectors it belongs to.
+                Component::Is(ss) | Component::Where(ss) => {
+                    let id = self.standalone_selector_count;
 
-            macro_rules! host_and_switch_branch_vec {
-                ($branches:ident) => {{
-                    let node_idx = Self::host_expressions(
-                        predicate,
-                        branches,
+                    self.standalone_selector_count += 1;
+                    predicate.required_selectors.push(id);
+                    standalone_selectors.extend(ss.slice().iter().map(|s| (id, s)));
+                }
+                // NOTE: `:has()` is supported only in the rightmost compound selector.
+                Component::Has(ss) => relative_selectors = Some(ss),
+                _ => predicate.add_component(component, false),
+            }
+        }
+
+        let node_idx = Self::host_expressions(predicate, branches, &mut self.cumulative_node_count);
+        let node = &mut branches[node_idx];
+
+        match payload {
+            SelectorPayload::Payload(payload) => match relative_selectors {
+                Some(relative_selectors) => {
+                    Self::add_relative_selectors(
+                        node,
+                        relative_selectors,
+                        payload,
                         &mut self.cumulative_node_count,
                     );
+                }
+                None => {
+                    node.payload.insert(payload);
+                }
+            },
+            SelectorPayload::StandaloneSelector(id) => {
+                node.standalone_selector_payload.insert(id);
+            }
+        }
 
-                    branches = &mut branches[node_idx].$branches;
-                    predicate = Predicate::default();
-                }};
+        for (id, selector) in standalone_selectors {
+            for components in expand_selector(selector) {
+                self.add_selector_components(components, SelectorPayload::StandaloneSelector(id));
             }
+        }
+    }
 
-            for component in selector_item.iter_raw_parse_order_from(0) {
+    fn add_relative_selectors(
+        node: &mut AstNode<P>,
+        relative_selectors: &[RelativeSelector<SelectorImplDescriptor>],
+        payload: P,
+        cumulative_node_count: &mut usize,
+    ) {
+        for relative_selector in relative_selectors {
+            let mut predicate = Predicate::default();
+            let mut has_branches = &mut node.has_descendants;
+
+            for component in relative_selector.selector.iter_raw_parse_order_from(0) {
                 match component {
-                    Component::Combinator(c) => match c {
-                        Combinator::Child => host_and_switch_branch_vec!(children),
-                        Combinator::Descendant => host_and_switch_branch_vec!(descendants),
-                        _ => unreachable!(
-                            "Unsupported selector components should be filtered out by the parser."
-                        ),
-                    },
+                    Component::RelativeSelectorAnchor => (),
+                    Component::Combinator(Combinator::Child) => {
+                        has_branches = &mut node.has_children;
+                    }
+                    Component::Combinator(Combinator::Descendant) => {
+                        has_branches = &mut node.has_descendants;
+                    }
                     Component::Negation(ss) => {
-                        ss.slice()
-                            .iter()
-                            .for_each(|s| s.iter().for_each(|c| predicate.add_component(c, true)));
+                        let negated_selectors = predicate.add_negation(ss.slice());
+
+                        debug_assert!(
+                            negated_selectors.is_empty(),
+                            "Complex negations in `:has()` should be filtered out by the parser."
+                        );
                     }
                     _ => predicate.add_component(component, false),
                 }
             }
 
-            let node_idx =
-                Self::host_expressions(predicate, branches, &mut self.cumulative_node_count);
+            let has_node_idx =
+                Self::host_expressions(predicate, has_branches, cumulative_node_count);
 
-            branches[node_idx].payload.insert(payload);
+            has_branches[has_node_idx].payload.insert(payload);
         }
     }
 }
@@ -331,7 +632,10 @@ mod tests {
 
     #[track_caller]
     fn assert_err(selector: &str, expected_err: SelectorError) {
-        assert_eq!(selector.parse::<Selector>().unwrap_err(), expected_err);
+        assert_eq!(
+            selector.parse::<Selector>().unwrap_err().error(),
+            expected_err
+        );
     }
 
     #[test]
@@ -369,9 +673,15 @@ mod tests {
                         },
                         children: vec![],
                         descendants: vec![],
+                        next_siblings: vec![],
+                        later_siblings: vec![],
+                        has_children: vec![],
+                        has_descendants: vec![],
                         payload: set![0],
+                        standalone_selector_payload: set![],
                     }],
                     cumulative_node_count: 1,
+                    standalone_selector_count: 0,
                 },
             );
         }
@@ -415,6 +725,23 @@ mod tests {
                     negation: false,
                 },
             ),
+            (
+                // NOTE: the values of the attributes, that are case-insensitive in HTML
+                // documents, are flagged by the selectors parser.
+                r#"[TYPE="TEXT"]"#,
+                Expr {
+                    simple_expr: OnAttributesExpr::AttributeComparisonExpr(
+                        AttributeComparisonExpr {
+                            name: "type".into(),
+                            value: "TEXT".into(),
+                            case_sensitivity:
+                                ParsedCaseSensitivity::AsciiCaseInsensitiveIfInHtmlElementInHtmlDocument,
+                            operator: AttrSelectorOperator::Equal,
+                        },
+                    ),
+                    negation: false,
+                },
+            ),
             (
                 r#"[foo*=""]"#,
                 Expr {
@@ -457,6 +784,20 @@ mod tests {
                     negation: false,
                 },
             ),
+            (
+                r#"[FOO="bar"]"#,
+                Expr {
+                    simple_expr: OnAttributesExpr::AttributeComparisonExpr(
+                        AttributeComparisonExpr {
+                            name: "foo".into(),
+                            value: "bar".into(),
+                            case_sensitivity: ParsedCaseSensitivity::CaseSensitive,
+                            operator: AttrSelectorOperator::Equal,
+                        },
+                    ),
+                    negation: false,
+                },
+            ),
             (
                 r#"[foo^="bar"]"#,
                 Expr {
@@ -524,9 +865,85 @@ mod tests {
                         },
                         children: vec![],
                         descendants: vec![],
+                        next_siblings: vec![],
+                        later_siblings: vec![],
+                        has_children: vec![],
+                        has_descendants: vec![],
+                        payload: set![0],
+                        standalone_selector_payload: set![],
+                    }],
+                    cumulative_node_count: 1,
+                    standalone_selector_count: 0,
+                },
+            );
+        }
+    }
+
+    #[test]
+    fn on_parent_end_expression() {
+        for (selector, expected) in [
+            (
+                ":last-child",
+                Expr {
+                    simple_expr: OnParentEndExpr::NthLastChild(NthChild::new(0, 1)),
+                    negation: false,
+                },
+            ),
+            (
+                ":nth-last-child(2n+1)",
+                Expr {
+                    simple_expr: OnParentEndExpr::NthLastChild(NthChild::new(2, 1)),
+                    negation: false,
+                },
+            ),
+            (
+                ":last-of-type",
+                Expr {
+                    simple_expr: OnParentEndExpr::NthLastOfType(NthChild::new(0, 1)),
+                    negation: false,
+                },
+            ),
+            (
+                ":nth-last-of-type(3)",
+                Expr {
+                    simple_expr: OnParentEndExpr::NthLastOfType(NthChild::new(0, 3)),
+                    negation: false,
+                },
+            ),
+            (
+                ":only-child",
+                Expr {
+                    simple_expr: OnParentEndExpr::OnlyChild,
+                    negation: false,
+                },
+            ),
+            (
+                ":not(:only-of-type)",
+                Expr {
+                    simple_expr: OnParentEndExpr::OnlyOfType,
+                    negation: true,
+                },
+            ),
+        ] {
+            assert_ast(
+                &[selector],
+                Ast {
+                    root: vec![AstNode {
+                        predicate: Predicate {
+                            on_parent_end_exprs: vec![expected],
+                            ..Default::default()
+                        },
+                        children: vec![],
+                        descendants: vec![],
+                        next_siblings: vec![],
+                        later_siblings: vec![],
+                        has_children: vec![],
+                        has_descendants: vec![],
                         payload: set![0],
+                        standalone_selector_payload: set![],
                     }],
                     cumulative_node_count: 1,
+                    standalone_selector_count: 0,
                 },
             );
         }
@@ -557,16 +974,71 @@ mod tests {
                                 negation: false,
                             },
                         ],
+                        ..Default::default()
                     },
                     children: vec![],
                     descendants: vec![],
+                    next_siblings: vec![],
+                    later_siblings: vec![],
+                    has_children: vec![],
+                    has_descendants: vec![],
                     payload: set![0],
+                    standalone_selector_payload: set![],
                 }],
                 cumulative_node_count: 1,
+                standalone_selector_count: 0,
             },
         );
     }
 
+    #[test]
+    fn namespaced_selectors() {
+        for (selector, ns, local_name_expr) in [
+            (
+                "svg|a",
+                Namespace::Svg,
+                OnTagNameExpr::LocalName("a".into()),
+            ),
+            ("math|*", Namespace::MathML, OnTagNameExpr::ExplicitAny),
+            (
+                "html|a",
+                Namespace::Html,
+                OnTagNameExpr::LocalName("a".into()),
+            ),
+        ] {
+            assert_ast(
+                &[selector],
+                Ast {
+                    root: vec![AstNode {
+                        predicate: Predicate {
+                            on_tag_name_exprs: vec![
+                                Expr {
+                                    simple_expr: local_name_expr,
+                                    negation: false,
+                                },
+                                Expr {
+                                    simple_expr: OnTagNameExpr::Namespace(ns),
+                                    negation: false,
+                                },
+                            ],
+                            ..Default::default()
+                        },
+                        children: vec![],
+                        descendants: vec![],
+                        next_siblings: vec![],
+                        later_siblings: vec![],
+                        has_children: vec![],
+                        has_descendants: vec![],
+                        payload: set![0],
+                        standalone_selector_payload: set![],
+                    }],
+                    cumulative_node_count: 1,
+                    standalone_selector_count: 0,
+                },
+            );
+        }
+    }
+
     #[test]
     fn multiple_payloads() {
         assert_ast(
@@ -582,9 +1054,15 @@ mod tests {
                     },
                     children: vec![],
                     descendants: vec![],
+                    next_siblings: vec![],
+                    later_siblings: vec![],
+                    has_children: vec![],
+                    has_descendants: vec![],
                     payload: set![0, 1],
+                    standalone_selector_payload: set![],
                 }],
                 cumulative_node_count: 1,
+                standalone_selector_count: 0,
             },
         );
     }
@@ -613,7 +1091,12 @@ mod tests {
                             },
                             children: vec![],
                             descendants: vec![],
+                            next_siblings: vec![],
+                            later_siblings: vec![],
+                            has_children: vec![],
+                            has_descendants: vec![],
                             payload: set![0],
+                            standalone_selector_payload: set![],
                         },
                         AstNode {
                             predicate: Predicate {
@@ -625,7 +1108,12 @@ mod tests {
                             },
                             children: vec![],
                             descendants: vec![],
+                            next_siblings: vec![],
+                            later_siblings: vec![],
+                            has_children: vec![],
+                            has_descendants: vec![],
                             payload: set![0],
+                            standalone_selector_payload: set![],
                         },
                         AstNode {
                             predicate: Predicate {
@@ -637,7 +1125,12 @@ mod tests {
                             },
                             children: vec![],
                             descendants: vec![],
+                            next_siblings: vec![],
+                            later_siblings: vec![],
+                            has_children: vec![],
+                            has_descendants: vec![],
                             payload: set![1],
+                            standalone_selector_payload: set![],
                         },
                         AstNode {
                             predicate: Predicate {
@@ -649,13 +1142,24 @@ mod tests {
                             },
                             children: vec![],
                             descendants: vec![],
+                            next_siblings: vec![],
+                            later_siblings: vec![],
+                            has_children: vec![],
+                            has_descendants: vec![],
                             payload: set![1],
+                            standalone_selector_payload: set![],
                         },
                     ],
                     descendants: vec![],
+                    next_siblings: vec![],
+                    later_siblings: vec![],
+                    has_children: vec![],
+                    has_descendants: vec![],
                     payload: set![],
+                    standalone_selector_payload: set![],
                 }],
                 cumulative_node_count: 5,
+                standalone_selector_count: 0,
             },
         );
     }
@@ -711,9 +1215,19 @@ mod tests {
                                             },
                                             children: vec![],
                                             descendants: vec![],
+                                            next_siblings: vec![],
+                                            later_siblings: vec![],
+                                            has_children: vec![],
+                                            has_descendants: vec![],
                                             payload: set![0],
+                                            standalone_selector_payload: set![],
                                         }],
+                                        next_siblings: vec![],
+                                        later_siblings: vec![],
+                                        has_children: vec![],
+                                        has_descendants: vec![],
                                         payload: set![],
+                                        standalone_selector_payload: set![],
                                     },
                                     AstNode {
                                         predicate: Predicate {
@@ -725,10 +1239,20 @@ mod tests {
                                         },
                                         children: vec![],
                                         descendants: vec![],
+                                        next_siblings: vec![],
+                                        later_siblings: vec![],
+                                        has_children: vec![],
+                                        has_descendants: vec![],
                                         payload: set![1],
+                                        standalone_selector_payload: set![],
                                     },
                                 ],
+                                next_siblings: vec![],
+                                later_siblings: vec![],
+                                has_children: vec![],
+                                has_descendants: vec![],
                                 payload: set![],
+                                standalone_selector_payload: set![],
                             },
                             AstNode {
                                 predicate: Predicate {
@@ -740,7 +1264,12 @@ mod tests {
                                 },
                                 children: vec![],
                                 descendants: vec![],
+                                next_siblings: vec![],
+                                later_siblings: vec![],
+                                has_children: vec![],
+                                has_descendants: vec![],
                                 payload: set![2],
+                                standalone_selector_payload: set![],
                             },
                         ],
                         descendants: vec![
@@ -754,7 +1283,12 @@ mod tests {
                                 },
                                 children: vec![],
                                 descendants: vec![],
+                                next_siblings: vec![],
+                                later_siblings: vec![],
+                                has_children: vec![],
+                                has_descendants: vec![],
                                 payload: set![3],
+                                standalone_selector_payload: set![],
                             },
                             AstNode {
                                 predicate: Predicate {
@@ -779,12 +1313,27 @@ mod tests {
                                     },
                                     children: vec![],
                                     descendants: vec![],
+                                    next_siblings: vec![],
+                                    later_siblings: vec![],
+                                    has_children: vec![],
+                                    has_descendants: vec![],
                                     payload: set![4],
+                                    standalone_selector_payload: set![],
                                 }],
+                                next_siblings: vec![],
+                                later_siblings: vec![],
+                                has_children: vec![],
+                                has_descendants: vec![],
                                 payload: set![],
+                                standalone_selector_payload: set![],
                             },
                         ],
+                        next_siblings: vec![],
+                        later_siblings: vec![],
+                        has_children: vec![],
+                        has_descendants: vec![],
                         payload: set![],
+                        standalone_selector_payload: set![],
                     },
                     AstNode {
                         predicate: Predicate {
@@ -796,10 +1345,115 @@ mod tests {
                         },
                         children: vec![],
                         descendants: vec![],
+                        next_siblings: vec![],
+                        later_siblings: vec![],
+                        has_children: vec![],
+                        has_descendants: vec![],
                         payload: set![5],
+                        standalone_selector_payload: set![],
                     },
                 ],
                 cumulative_node_count: 10,
+                standalone_selector_count: 0,
+            },
+        );
+    }
+
+    #[test]
+    fn sibling_combinators() {
+        assert_ast(
+            &["h2 + p", "h2 ~ p > span", "h2 + p.c1"],
+            Ast {
+                root: vec![AstNode {
+                    predicate: Predicate {
+                        on_tag_name_exprs: vec![Expr {
+                            simple_expr: OnTagNameExpr::LocalName("h2".into()),
+                            negation: false,
+                        }],
+                        ..Default::default()
+                    },
+                    children: vec![],
+                    descendants: vec![],
+                    next_siblings: vec![
+                        AstNode {
+                            predicate: Predicate {
+                                on_tag_name_exprs: vec![Expr {
+                                    simple_expr: OnTagNameExpr::LocalName("p".into()),
+                                    negation: false,
+                                }],
+                                ..Default::default()
+                            },
+                            children: vec![],
+                            descendants: vec![],
+                            next_siblings: vec![],
+                            later_siblings: vec![],
+                            has_children: vec![],
+                            has_descendants: vec![],
+                            payload: set![0],
+                            standalone_selector_payload: set![],
+                        },
+                        AstNode {
+                            predicate: Predicate {
+                                on_tag_name_exprs: vec![Expr {
+                                    simple_expr: OnTagNameExpr::LocalName("p".into()),
+                                    negation: false,
+                                }],
+                                on_attr_exprs: vec![Expr {
+                                    simple_expr: OnAttributesExpr::Class("c1".into()),
+                                    negation: false,
+                                }],
+                                ..Default::default()
+                            },
+                            children: vec![],
+                            descendants: vec![],
+                            next_siblings: vec![],
+                            later_siblings: vec![],
+                            has_children: vec![],
+                            has_descendants: vec![],
+                            payload: set![2],
+                            standalone_selector_payload: set![],
+                        },
+                    ],
+                    later_siblings: vec![AstNode {
+                        predicate: Predicate {
+                            on_tag_name_exprs: vec![Expr {
+                                simple_expr: OnTagNameExpr::LocalName("p".into()),
+                                negation: false,
+                            }],
+                            ..Default::default()
+                        },
+                        children: vec![AstNode {
+                            predicate: Predicate {
+                                on_tag_name_exprs: vec![Expr {
+                                    simple_expr: OnTagNameExpr::LocalName("span".into()),
+                                    negation: false,
+                                }],
+                                ..Default::default()
+                            },
+                            children: vec![],
+                            descendants: vec![],
+                            next_siblings: vec![],
+                            later_siblings: vec![],
+                            has_children: vec![],
+                            has_descendants: vec![],
+                            payload: set![1],
+                            standalone_selector_payload: set![],
+                        }],
+                        descendants: vec![],
+                        next_siblings: vec![],
+                        later_siblings: vec![],
+                        has_children: vec![],
+                        has_descendants: vec![],
+                        payload: set![],
+                        standalone_selector_payload: set![],
+                    }],
+                    has_children: vec![],
+                    has_descendants: vec![],
+                    payload: set![],
+                    standalone_selector_payload: set![],
+                }],
+                cumulative_node_count: 5,
+                standalone_selector_count: 0,
             },
         );
     }
@@ -815,11 +1469,10 @@ mod tests {
             r#"div[foo~"bar"]"#,
             SelectorError::UnexpectedTokenInAttribute,
         );
-        assert_err("svg|img", SelectorError::NamespacedSelector);
+        assert_err("foo|img", SelectorError::NamespacedSelector);
+        assert_err("[svg|href]", SelectorError::NamespacedSelector);
         assert_err(".foo()", SelectorError::InvalidClassName);
         assert_err(":not()", SelectorError::EmptySelector);
-        assert_err("div + span", SelectorError::UnsupportedCombinator('+'));
-        assert_err("div ~ span", SelectorError::UnsupportedCombinator('~'));
     }
 
     #[test]
@@ -835,7 +1488,6 @@ mod tests {
             ":dir(rtl)",
             ":disabled",
             ":drop",
-            ":empty",
             ":enabled",
             ":first",
             ":fullscreen",
@@ -843,7 +1495,6 @@ mod tests {
             ":focus",
             ":focus-visible",
             ":focus-within",
-            ":has(div)",
             ":host",
             ":host(h1)",
             ":host-context(h1)",
@@ -851,19 +1502,12 @@ mod tests {
             ":indeterminate",
             ":in-range",
             ":invalid",
-            ":is(header)",
             ":lang(en)",
-            ":last-child",
-            ":last-of-type",
             ":left",
             ":link",
             ":local-link",
             ":nth-col(1)",
-            ":nth-last-child(1)",
             ":nth-last-col(1)",
-            ":nth-last-of-type(1)",
-            ":only-child",
-            ":only-of-type",
             ":optional",
             ":out-of-range",
             ":past",
@@ -872,14 +1516,11 @@ mod tests {
             ":read-write",
             ":required",
             ":right",
-            ":root",
-            ":scope",
             ":target",
             ":target-within",
             ":user-invalid",
             ":valid",
             ":visited",
-            ":where(p)",
         ]
         .iter()
         .for_each(|s| assert_err(s, SelectorError::UnsupportedPseudoClassOrElement));
@@ -908,11 +1549,272 @@ mod tests {
     #[test]
     fn negated_pseudo_class_parse_error() {
         assert_err(
-            ":not(:nth-last-child(even))",
+            ":not(:nth-last-col(even))",
             SelectorError::UnsupportedPseudoClassOrElement,
         );
     }
 
+    #[test]
+    fn on_parent_end_pseudo_class_position_parse_errors() {
+        [
+            "li:last-child span",
+            "li:only-child > span",
+            "li:nth-last-child(2) + li",
+            "li:nth-last-of-type(2) ~ li",
+            "div:not(:last-of-type) p",
+        ]
+        .iter()
+        .for_each(|s| assert_err(s, SelectorError::UnsupportedPseudoClassPosition));
+    }
+
+    #[test]
+    fn has_pseudo_class() {
+        let tag_name = |name: &str| Predicate {
+            on_tag_name_exprs: vec![Expr {
+                simple_expr: OnTagNameExpr::LocalName(name.into()),
+                negation: false,
+            }],
+            ..Default::default()
+        };
+
+        let leaf = |predicate, payload| AstNode {
+            predicate,
+            children: vec![],
+            descendants: vec![],
+            next_siblings: vec![],
+            later_siblings: vec![],
+            has_children: vec![],
+            has_descendants: vec![],
+            payload,
+            standalone_selector_payload: set![],
+        };
+
+        assert_ast(
+            &["div:has(> a, img)", "div", "div:has(img)", ":has(a)"],
+            Ast {
+                root: vec![
+                    AstNode {
+                        predicate: tag_name("div"),
+                        children: vec![],
+                        descendants: vec![],
+                        next_siblings: vec![],
+                        later_siblings: vec![],
+                        has_children: vec![leaf(tag_name("a"), set![0])],
+                        has_descendants: vec![leaf(tag_name("img"), set![0, 2])],
+                        payload: set![1],
+                        standalone_selector_payload: set![],
+                    },
+                    AstNode {
+                        predicate: Predicate {
+                            on_tag_name_exprs: vec![Expr {
+                                simple_expr: OnTagNameExpr::ExplicitAny,
+                                negation: false,
+                            }],
+                            ..Default::default()
+                        },
+                        children: vec![],
+                        descendants: vec![],
+                        next_siblings: vec![],
+                        later_siblings: vec![],
+                        has_children: vec![],
+                        has_descendants: vec![leaf(tag_name("a"), set![3])],
+                        payload: set![],
+                        standalone_selector_payload: set![],
+                    },
+                ],
+                cumulative_node_count: 5,
+                standalone_selector_count: 0,
+            },
+        );
+    }
+
+    #[test]
+    fn has_pseudo_class_parse_errors() {
+        [
+            ":has(+ div)",
+            ":has(~ div)",
+            ":has(div p)",
+            ":has(> div > p)",
+            ":has(:has(p))",
+            "div:has(a):has(b)",
+            "li:last-child:has(a)",
+            ":not(:has(a))",
+        ]
+        .iter()
+        .for_each(|s| assert_err(s, SelectorError::UnsupportedSyntax));
+
+        ["div:has(a) p", "div:has(a) + p", ":has(li:last-child)"]
+            .iter()
+            .for_each(|s| assert_err(s, SelectorError::UnsupportedPseudoClassPosition));
+    }
+
+    #[test]
+    fn is_and_where_pseudo_classes() {
+        let ast = |selector: &str| {
+            let mut ast = Ast::default();
+
+            ast.add_selector(&selector.parse().unwrap(), 0);
+
+            ast
+        };
+
+        let node = |name: &str, children, payload, standalone_selector_payload| AstNode {
+            predicate: Predicate {
+                on_tag_name_exprs: vec![Expr {
+                    simple_expr: OnTagNameExpr::LocalName(name.into()),
+                    negation: false,
+                }],
+                ..Default::default()
+            },
+            children,
+            descendants: vec![],
+            next_siblings: vec![],
+            later_siblings: vec![],
+            has_children: vec![],
+            has_descendants: vec![],
+            payload,
+            standalone_selector_payload,
+        };
+
+        assert_eq!(
+            ast(":where(ul, ol) > li"),
+            Ast {
+                root: vec![
+                    AstNode {
+                        predicate: Predicate {
+                            on_tag_name_exprs: vec![Expr {
+                                simple_expr: OnTagNameExpr::ExplicitAny,
+                                negation: false,
+                            }],
+                            required_selectors: vec![0],
+                            ..Default::default()
+                        },
+                        children: vec![node("li", vec![], set![0], set![])],
+                        descendants: vec![],
+                        next_siblings: vec![],
+                        later_siblings: vec![],
+                        has_children: vec![],
+                        has_descendants: vec![],
+                        payload: set![],
+                        standalone_selector_payload: set![],
+                    },
+                    node("ul", vec![], set![], set![0]),
+                    node("ol", vec![], set![], set![0]),
+                ],
+                cumulative_node_count: 4,
+                standalone_selector_count: 1,
+            }
+        );
+
+        assert_eq!(
+            ast("a:is(b > c, :is(d, e))"),
+            Ast {
+                root: vec![
+                    AstNode {
+                        predicate: Predicate {
+                            on_tag_name_exprs: vec![Expr {
+                                simple_expr: OnTagNameExpr::LocalName("a".into()),
+                                negation: false,
+                            }],
+                            required_selectors: vec![0],
+                            ..Default::default()
+                        },
+                        children: vec![],
+                        descendants: vec![],
+                        next_siblings: vec![],
+                        later_siblings: vec![],
+                        has_children: vec![],
+                        has_descendants: vec![],
+                        payload: set![0],
+                        standalone_selector_payload: set![],
+                    },
+                    node(
+                        "b",
+                        vec![node("c", vec![], set![], set![0])],
+                        set![],
+                        set![]
+                    ),
+                    AstNode {
+                        predicate: Predicate {
+                            on_tag_name_exprs: vec![Expr {
+                                simple_expr: OnTagNameExpr::ExplicitAny,
+                                negation: false,
+                            }],
+                            required_selectors: vec![1],
+                            ..Default::default()
+                        },
+                        children: vec![],
+                        descendants: vec![],
+                        next_siblings: vec![],
+                        later_siblings: vec![],
+                        has_children: vec![],
+                        has_descendants: vec![],
+                        payload: set![],
+                        standalone_selector_payload: set![0],
+                    },
+                    node("d", vec![], set![], set![1]),
+                    node("e", vec![], set![], set![1]),
+                ],
+                cumulative_node_count: 6,
+                standalone_selector_count: 2,
+            }
+        );
+
+        // NOTE: the pseudo-classes with the arguments that are matched lazily are expanded.
+        for (selector, expanded) in [
+            ("li:is(:last-child, .a)", "li:last-child, li.a"),
+            (":is(ul > li:only-child, .a)", "ul > li:only-child, .a"),
+            (".x:is(ul li:empty)", "ul li.x:empty"),
+            (":is(:is(a, b:empty), c)", "a, b:empty, c"),
+            (":is(a:empty, a:empty)", "a:empty"),
+        ] {
+            assert_eq!(ast(selector), ast(expanded), "{selector}");
+        }
+    }
+
+    #[test]
+    fn is_and_where_pseudo_classes_parse_errors() {
+        [
+            "div :is(ul li:empty)",
+            ":is(ul li:empty):is(ol li:empty)",
+            "a :is(:is(ul li:empty))",
+            ":is(:has(a))",
+            ":where(div:has(a))",
+            ":is(a, ::before)",
+        ]
+        .iter()
+        .for_each(|s| assert_err(s, SelectorError::UnsupportedSyntax));
+
+        [":is(li:last-child) a", ":is(li:last-child a)"]
+            .iter()
+            .for_each(|s| assert_err(s, SelectorError::UnsupportedPseudoClassPosition));
+
+        assert_err(":is(a, b@)", SelectorError::UnexpectedToken);
+
+        assert_err(
+            &format!("li{}", ":is(.a:empty, .b, .c, .d, .e, .f)".repeat(4)),
+            SelectorError::TooManyExpandedSelectors,
+        );
+    }
+
+    #[test]
+    fn complex_negation_parse_errors() {
+        [
+            ":not(li:last-child)",
+            ":not(ul > li:only-child)",
+            ":not(div:has(a) p)",
+            ":not(b :is(ul li:empty))",
+        ]
+        .iter()
+        .for_each(|s| assert_err(s, SelectorError::UnsupportedSyntax));
+
+        [":not(a :not(b c))", ":not(:is(a, :not(b c)))"]
+            .iter()
+            .for_each(|s| assert_err(s, SelectorError::NestedNegation));
+
+        assert_err(":has(:not(a b))", SelectorError::UnsupportedSyntax);
+    }
+
     #[test]
     fn nth_child_is_index() {
         let even = NthChild::new(2, 0);
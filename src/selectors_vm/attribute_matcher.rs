@@ -1,7 +1,9 @@
 use super::compiler::AttrExprOperands;
+use super::AttributeValueMatcher;
 use crate::base::Bytes;
 use crate::html::Namespace;
 use crate::parser::{AttributeBuffer, AttributeOutline};
+use encoding_rs::Encoding;
 use memchr::{memchr, memchr2};
 use selectors::attr::{CaseSensitivity, ParsedCaseSensitivity};
 use std::cell::OnceCell;
@@ -178,6 +180,18 @@ impl<'i> AttributeMatcher<'i> {
         })
     }
 
+    #[inline]
+    pub fn attr_matches_pattern(
+        &self,
+        name: &Bytes<'_>,
+        encoding: &'static Encoding,
+        matcher: &AttributeValueMatcher,
+    ) -> bool {
+        self.value_matches(name, |actual_value| {
+            matcher(&encoding.decode_without_bom_handling(&actual_value).0)
+        })
+    }
+
     #[inline]
     pub fn has_attr_with_substring(&self, operand: &AttrExprOperands) -> bool {
         self.value_matches(&operand.name, |actual_value| {
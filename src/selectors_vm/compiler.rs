@@ -1,22 +1,25 @@
 use super::attribute_matcher::AttributeMatcher;
-use super::program::{AddressRange, ExecutionBranch, Instruction, Program};
+use super::program::{AddressRange, ExecutionBranch, Instruction, LazyPayload, Program};
 use super::{
-    Ast, AstNode, AttributeComparisonExpr, Expr, OnAttributesExpr, OnTagNameExpr, Predicate,
-    SelectorState,
+    Ast, AstNode, AttributeComparisonExpr, Expr, OnAttributesExpr, OnParentEndExpr, OnTagNameExpr,
+    Predicate, SelectorState,
 };
 use crate::base::{Bytes, HasReplacementsError};
 use crate::html::LocalName;
 use encoding_rs::Encoding;
+use hashbrown::HashSet;
 use selectors::attr::{AttrSelectorOperator, ParsedCaseSensitivity};
 use std::fmt::Debug;
 use std::hash::Hash;
 use std::iter;
+use std::sync::Arc;
 
 /// An expression using only the tag name of an element.
-pub type CompiledLocalNameExpr = Box<dyn Fn(&SelectorState<'_>, &LocalName<'_>) -> bool + Send>;
+pub type CompiledLocalNameExpr =
+    Arc<dyn Fn(&SelectorState<'_>, &LocalName<'_>) -> bool + Send + Sync>;
 /// An expression using the attributes of an element.
 pub type CompiledAttributeExpr =
-    Box<dyn Fn(&SelectorState<'_>, &AttributeMatcher<'_>) -> bool + Send>;
+    Arc<dyn Fn(&SelectorState<'_>, &AttributeMatcher<'_>) -> bool + Send + Sync>;
 
 #[derive(Default)]
 struct ExprSet {
@@ -32,16 +35,32 @@ pub(crate) struct AttrExprOperands {
 
 impl Expr<OnTagNameExpr> {
     #[inline]
-    pub fn compile_expr<F: Fn(&SelectorState<'_>, &LocalName<'_>) -> bool + Send + 'static>(
+    pub fn compile_expr<
+        F: Fn(&SelectorState<'_>, &LocalName<'_>) -> bool + Send + Sync + 'static,
+    >(
         &self,
         f: F,
     ) -> CompiledLocalNameExpr {
         if self.negation {
-            Box::new(move |s, a| !f(s, a))
+            Arc::new(move |s, a| !f(s, a))
         } else {
-            Box::new(f)
+            Arc::new(f)
         }
     }
+
+    /// Compiles the expression, that depends on the state of the preceding elements. If the
+    /// state is unknown, the expression doesn't match, regardless of its negation.
+    #[inline]
+    pub fn compile_known_expr<
+        F: Fn(&SelectorState<'_>, &LocalName<'_>) -> Option<bool> + Send + Sync + 'static,
+    >(
+        &self,
+        f: F,
+    ) -> CompiledLocalNameExpr {
+        let negation = self.negation;
+
+        Arc::new(move |s, a| f(s, a).is_some_and(|r| r != negation))
+    }
 }
 
 trait Compilable {
@@ -63,6 +82,8 @@ impl Compilable for Expr<OnTagNameExpr> {
         let expr = match &self.simple_expr {
             OnTagNameExpr::ExplicitAny => self.compile_expr(|_, _| true),
             OnTagNameExpr::Unmatchable => self.compile_expr(|_, _| false),
+            OnTagNameExpr::Root => self.compile_known_expr(|state, _| state.is_root),
+            &OnTagNameExpr::Namespace(ns) => self.compile_expr(move |state, _| state.ns == ns),
             OnTagNameExpr::LocalName(local_name) => {
                 match LocalName::from_str_without_replacements(local_name, encoding)
                     .map(LocalName::into_owned)
@@ -74,16 +95,11 @@ impl Compilable for Expr<OnTagNameExpr> {
                 }
             }
             &OnTagNameExpr::NthChild(nth) => {
-                self.compile_expr(move |state, _| state.cumulative.is_nth(nth))
+                self.compile_known_expr(move |state, _| state.cumulative.map(|c| c.is_nth(nth)))
             }
             &OnTagNameExpr::NthOfType(nth) => {
                 *enable_nth_of_type = true;
-                self.compile_expr(move |state, _| {
-                    state
-                        .typed
-                        .expect("Counter for type required at this point")
-                        .is_nth(nth)
-                })
+                self.compile_known_expr(move |state, _| state.typed.map(|c| c.is_nth(nth)))
             }
         };
 
@@ -94,15 +110,15 @@ impl Compilable for Expr<OnTagNameExpr> {
 impl Expr<OnAttributesExpr> {
     #[inline]
     pub fn compile_expr<
-        F: Fn(&SelectorState<'_>, &AttributeMatcher<'_>) -> bool + Send + 'static,
+        F: Fn(&SelectorState<'_>, &AttributeMatcher<'_>) -> bool + Send + Sync + 'static,
     >(
         &self,
         f: F,
     ) -> CompiledAttributeExpr {
         if self.negation {
-            Box::new(move |s, a| !f(s, a))
+            Arc::new(move |s, a| !f(s, a))
         } else {
-            Box::new(f)
+            Arc::new(f)
         }
     }
 }
@@ -137,49 +153,58 @@ fn compile_operands(
 
 impl Compilable for Expr<OnAttributesExpr> {
     fn compile(&self, encoding: &'static Encoding, exprs: &mut ExprSet, _: &mut bool) {
-        let expr_result =
-            match &self.simple_expr {
-                OnAttributesExpr::Id(id) => compile_literal(encoding, id)
-                    .map(|id| self.compile_expr(move |_, m| m.has_id(&id))),
-
-                OnAttributesExpr::Class(class) => compile_literal(encoding, class)
-                    .map(|class| self.compile_expr(move |_, m| m.has_class(&class))),
-
-                OnAttributesExpr::AttributeExists(name) => compile_literal(encoding, name)
-                    .map(|name| self.compile_expr(move |_, m| m.has_attribute(&name))),
+        let expr_result = match &self.simple_expr {
+            OnAttributesExpr::Id(id) => {
+                compile_literal(encoding, id).map(|id| self.compile_expr(move |_, m| m.has_id(&id)))
+            }
 
-                &OnAttributesExpr::AttributeComparisonExpr(AttributeComparisonExpr {
-                    ref name,
-                    ref value,
+            OnAttributesExpr::Class(class) => compile_literal(encoding, class)
+                .map(|class| self.compile_expr(move |_, m| m.has_class(&class))),
+
+            OnAttributesExpr::AttributeExists(name) => compile_literal_lowercase(encoding, name)
+                .map(|name| self.compile_expr(move |_, m| m.has_attribute(&name))),
+
+            &OnAttributesExpr::AttributeComparisonExpr(AttributeComparisonExpr {
+                ref name,
+                ref value,
+                case_sensitivity,
+                operator,
+            }) => compile_operands(encoding, name, value).map(move |(name, value)| {
+                let operands = AttrExprOperands {
+                    name,
+                    value,
                     case_sensitivity,
-                    operator,
-                }) => compile_operands(encoding, name, value).map(move |(name, value)| {
-                    let operands = AttrExprOperands {
-                        name,
-                        value,
-                        case_sensitivity,
-                    };
-                    match operator {
-                        AttrSelectorOperator::Equal => {
-                            self.compile_expr(move |_, m| m.attr_eq(&operands))
-                        }
-                        AttrSelectorOperator::Includes => self
-                            .compile_expr(move |_, m| m.matches_splitted_by_whitespace(&operands)),
-                        AttrSelectorOperator::DashMatch => {
-                            self.compile_expr(move |_, m| m.has_dash_matching_attr(&operands))
-                        }
-                        AttrSelectorOperator::Prefix => {
-                            self.compile_expr(move |_, m| m.has_attr_with_prefix(&operands))
-                        }
-                        AttrSelectorOperator::Suffix => {
-                            self.compile_expr(move |_, m| m.has_attr_with_suffix(&operands))
-                        }
-                        AttrSelectorOperator::Substring => {
-                            self.compile_expr(move |_, m| m.has_attr_with_substring(&operands))
-                        }
+                };
+                match operator {
+                    AttrSelectorOperator::Equal => {
+                        self.compile_expr(move |_, m| m.attr_eq(&operands))
                     }
-                }),
-            };
+                    AttrSelectorOperator::Includes => {
+                        self.compile_expr(move |_, m| m.matches_splitted_by_whitespace(&operands))
+                    }
+                    AttrSelectorOperator::DashMatch => {
+                        self.compile_expr(move |_, m| m.has_dash_matching_attr(&operands))
+                    }
+                    AttrSelectorOperator::Prefix => {
+                        self.compile_expr(move |_, m| m.has_attr_with_prefix(&operands))
+                    }
+                    AttrSelectorOperator::Suffix => {
+                        self.compile_expr(move |_, m| m.has_attr_with_suffix(&operands))
+                    }
+                    AttrSelectorOperator::Substring => {
+                        self.compile_expr(move |_, m| m.has_attr_with_substring(&operands))
+                    }
+                }
+            }),
+
+            OnAttributesExpr::AttributePattern(pattern) => {
+                let matcher = Arc::clone(&pattern.matcher);
+
+                compile_literal(encoding, &pattern.name).map(|name| {
+                    self.compile_expr(move |_, m| m.attr_matches_pattern(&name, encoding, &matcher))
+                })
+            }
+        };
 
         exprs
             .attribute_exprs
@@ -214,6 +239,9 @@ where
         Predicate {
             on_tag_name_exprs,
             on_attr_exprs,
+            on_parent_end_exprs,
+            negated_selectors: _,
+            required_selectors: _,
         }: &Predicate,
         branch: ExecutionBranch<P>,
         enable_nth_of_type: &mut bool,
@@ -233,10 +261,21 @@ where
         } = exprs;
 
         debug_assert!(
-            !local_name_exprs.is_empty() || !attribute_exprs.is_empty(),
+            !local_name_exprs.is_empty()
+                || !attribute_exprs.is_empty()
+                || !on_parent_end_exprs.is_empty(),
             "Predicate should contain expressions"
         );
 
+        if on_parent_end_exprs.iter().any(|e| {
+            matches!(
+                e.simple_expr,
+                OnParentEndExpr::NthLastOfType(_) | OnParentEndExpr::OnlyOfType
+            )
+        }) {
+            *enable_nth_of_type = true;
+        }
+
         Instruction {
             associated_branch: branch,
             local_name_exprs: local_name_exprs.into(),
@@ -279,10 +318,37 @@ where
         let addr_range = self.reserve(&nodes);
 
         for (node, position) in nodes.into_iter().zip(addr_range.clone()) {
+            // NOTE: conditions on the following siblings can't be checked at the time
+            // instruction is executed, so the payload is matched lazily.
+            let (matched_payload, lazy_payload) = if node.predicate.on_parent_end_exprs.is_empty() {
+                (node.payload, None)
+            } else {
+                let lazy_payload = LazyPayload {
+                    payload: node.payload,
+                    exprs: node.predicate.on_parent_end_exprs.clone().into(),
+                };
+
+                (HashSet::default(), Some(lazy_payload))
+            };
+
             let branch = ExecutionBranch {
-                matched_payload: node.payload,
+                matched_payload,
+                lazy_payload,
+                negated_selectors: node.predicate.negated_selectors.clone().into(),
+                required_selectors: node.predicate.required_selectors.clone().into(),
+                matched_standalone_selectors: node
+                    .standalone_selector_payload
+                    .into_iter()
+                    .collect(),
                 jumps: self.compile_descendants(node.children, enable_nth_of_type),
                 hereditary_jumps: self.compile_descendants(node.descendants, enable_nth_of_type),
+                next_sibling_jumps: self
+                    .compile_descendants(node.next_siblings, enable_nth_of_type),
+                later_sibling_jumps: self
+                    .compile_descendants(node.later_siblings, enable_nth_of_type),
+                has_jumps: self.compile_descendants(node.has_children, enable_nth_of_type),
+                hereditary_has_jumps: self
+                    .compile_descendants(node.has_descendants, enable_nth_of_type),
             };
 
             self.instructions[position] =
@@ -310,6 +376,7 @@ where
                 .collect(),
             entry_points,
             enable_nth_of_type,
+            standalone_selector_count: ast.standalone_selector_count,
         }
     }
 }
@@ -412,8 +479,10 @@ mod tests {
             with_start_tag(input, encoding, |local_name, attr_matcher| {
                 let counter = Default::default();
                 let state = SelectorState {
-                    cumulative: &counter,
+                    cumulative: Some(&counter),
                     typed: None,
+                    ns: Namespace::Html,
+                    is_root: Some(false),
                 };
                 action(input, matching_data, &state, local_name, attr_matcher);
             });
@@ -767,6 +836,36 @@ mod tests {
                 ],
             );
 
+            assert_attr_expr_matches_and_negation_reverses_match(
+                r#"[lang|="en" i]"#,
+                encoding,
+                &[
+                    ("<div lang='EN-gb'>", true),
+                    ("<div LANG='En'>", true),
+                    ("<div lang='english'>", false),
+                ],
+            );
+
+            assert_attr_expr_matches_and_negation_reverses_match(
+                r#"[type="text"]"#,
+                encoding,
+                &[
+                    ("<input type='text'>", true),
+                    ("<input TYPE='TeXt'>", true),
+                    ("<input type='texts'>", false),
+                ],
+            );
+
+            assert_attr_expr_matches_and_negation_reverses_match(
+                r#"[type="text" s]"#,
+                encoding,
+                &[
+                    ("<input type='text'>", true),
+                    ("<input TYPE='TeXt'>", false),
+                    ("<input type='texts'>", false),
+                ],
+            );
+
             assert_attr_expr_matches_and_negation_reverses_match(
                 r#"[lang|="en"]"#,
                 encoding,
@@ -975,8 +1074,10 @@ mod tests {
                 let mut hereditary_jumps = Vec::default();
                 let counter = Default::default();
                 let state = SelectorState {
-                    cumulative: &counter,
+                    cumulative: Some(&counter),
                     typed: None,
+                    ns: Namespace::Html,
+                    is_root: Some(false),
                 };
 
                 with_start_tag($html, UTF_8, |local_name, attr_matcher| {
@@ -1049,4 +1150,31 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn instruction_owners() {
+        let program = compile(
+            &["div > .c1", "div #d1", "li:not(ul > li)", "span"],
+            UTF_8,
+            4,
+        );
+        let owners = program.instruction_owners();
+
+        let mut entry_point_owners = program
+            .entry_points
+            .clone()
+            .map(|addr| {
+                let mut owners = owners[addr].iter().copied().collect::<Vec<_>>();
+
+                owners.sort_unstable();
+                owners
+            })
+            .collect::<Vec<_>>();
+
+        entry_point_owners.sort_unstable();
+
+        // NOTE: the instruction for `div` is executed for the first two selectors, and the one
+        // for `ul` is executed for the negated selector it belongs to.
+        assert_eq!(entry_point_owners, [vec![0, 1], vec![2], vec![2], vec![3]]);
+    }
 }
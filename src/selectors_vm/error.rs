@@ -1,5 +1,8 @@
-use cssparser::{BasicParseErrorKind, ParseErrorKind};
+use cssparser::{
+    BasicParseErrorKind, ParseError, ParseErrorKind, Parser, ParserInput, SourceLocation, Token,
+};
 use selectors::parser::{SelectorParseError, SelectorParseErrorKind};
+use std::ops::Range;
 use thiserror::Error;
 
 /// A CSS selector parsing error.
@@ -37,8 +40,9 @@ pub enum SelectorError {
     #[error("Nested negation in selector.")]
     NestedNegation,
 
-    /// Selectors with explicit namespaces are not supported.
-    #[error("Selectors with explicit namespaces are not supported.")]
+    /// Unknown namespace prefix or namespaced attribute in selector. Only the `html`, `svg`
+    /// and `math` prefixes are supported for type selectors.
+    #[error("Unknown namespace prefix or namespaced attribute in selector.")]
     NamespacedSelector,
 
     /// Invalid or unescaped class name in selector.
@@ -54,9 +58,31 @@ pub enum SelectorError {
     #[error("Unsupported combinator `{0}` in selector.")]
     UnsupportedCombinator(char),
 
+    /// Pseudo-classes that depend on the following siblings or the descendants of the element
+    /// (e.g. `:last-child` or `:has()`) are only supported in the rightmost compound selector.
+    #[error("Pseudo-class that depends on the following siblings or descendants is only supported in the rightmost compound selector.")]
+    UnsupportedPseudoClassPosition,
+
     /// CSS syntax in the selector which is yet unsupported.
     #[error("Unsupported syntax in selector.")]
     UnsupportedSyntax,
+
+    /// The pattern of the `:matches-attr()` pseudo-class was rejected by its compiler.
+    #[error("Invalid attribute value pattern in selector.")]
+    InvalidAttributeValuePattern,
+
+    /// Content handlers, that are never invoked for the selector: text, comment or text node
+    /// handlers for a selector that is matched lazily (e.g. `li:last-child`), as the content of
+    /// the element is processed before it's known whether the element matches, or any handlers
+    /// but the end tag handler for a selector with `:has()`.
+    #[error("Content handlers are not supported for the selector.")]
+    UnsupportedContentHandlers,
+
+    /// The selector is expanded into too many selectors by the `:is()` and `:where()`
+    /// pseudo-classes with the arguments that are matched lazily (e.g. by many of them
+    /// like `:is(.a:last-child, .b:only-child)` in a compound selector).
+    #[error("The selector is expanded into too many selectors.")]
+    TooManyExpandedSelectors,
 }
 
 impl From<SelectorParseError<'_>> for SelectorError {
@@ -109,11 +135,347 @@ impl From<SelectorParseError<'_>> for SelectorError {
                     Self::UnexpectedTokenInAttribute
                 }
                 SelectorParseErrorKind::ClassNeedsIdent(_) => Self::InvalidClassName,
-                SelectorParseErrorKind::InvalidState => {
-                    debug_assert!(false, "invalid state");
-                    Self::UnsupportedSyntax
+                // NOTE: e.g. nested `:has()`.
+                SelectorParseErrorKind::InvalidState => Self::UnsupportedSyntax,
+            },
+        }
+    }
+}
+
+/// A CSS selector parsing error with the location of the error in the selector.
+///
+/// Returned by [`str::parse`] for the [`Selector`], e.g. to show where an invalid selector
+/// is wrong:
+///
+/// ```text
+/// Unexpected token in the attribute selector.
+/// a[href=foo bar]
+///            ^^^
+/// ```
+///
+/// It converts into the [`SelectorError`] of the same kind, e.g. with the `?` operator.
+///
+/// # Example
+/// ```
+/// use lol_html::errors::SelectorError;
+/// use lol_html::Selector;
+///
+/// let err = "div > a[href=foo bar]".parse::<Selector>().unwrap_err();
+///
+/// assert_eq!(err.error(), SelectorError::UnexpectedToken);
+/// assert_eq!(err.offset(), 17);
+/// assert_eq!(err.token(), Some("bar"));
+/// assert_eq!(err.snippet(), "div > a[href=foo bar]\n                 ^^^");
+///
+/// // NOTE: the unsupported components are reported as well.
+/// let err = "li:last-child > a".parse::<Selector>().unwrap_err();
+///
+/// assert_eq!(err.error(), SelectorError::UnsupportedPseudoClassPosition);
+/// assert_eq!(err.token(), Some(":last-child"));
+/// ```
+///
+/// [`Selector`]: crate::Selector
+#[derive(Error, Debug, Eq, PartialEq, Clone)]
+#[error("{error}\n{snippet}")]
+pub struct DetailedSelectorError {
+    error: SelectorError,
+    offset: usize,
+    token: Option<String>,
+    snippet: String,
+}
+
+impl DetailedSelectorError {
+    /// Creates the error for the token of the `selector` at the `location`.
+    #[cold]
+    pub(crate) fn new(selector: &str, error: SelectorError, location: SourceLocation) -> Self {
+        let span = token_at(selector, byte_offset(selector, location));
+
+        Self::with_span(selector, error, Some(span))
+    }
+
+    /// Creates the error for the `span` of the `selector`. The `span` is `None` for the errors
+    /// caused by the selector as a whole (e.g. the ones it's too complex for), in which case
+    /// the whole selector is reported.
+    #[cold]
+    pub(crate) fn with_span(
+        selector: &str,
+        error: SelectorError,
+        span: Option<Range<usize>>,
+    ) -> Self {
+        let Range { start, end } = span.unwrap_or_else(|| {
+            let start = selector.len() - selector.trim_start().len();
+
+            start..selector.trim_end().len().max(start)
+        });
+
+        let token = (start < end).then(|| selector[start..end].to_owned());
+
+        DetailedSelectorError {
+            error,
+            offset: start,
+            token,
+            snippet: snippet(selector, start, end),
+        }
+    }
+
+    /// Returns the kind of the error.
+    #[inline]
+    #[must_use]
+    pub const fn error(&self) -> SelectorError {
+        self.error
+    }
+
+    /// Returns the byte offset of the error in the selector.
+    #[inline]
+    #[must_use]
+    pub const fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Returns the source text of the offending token, or of the unsupported component of the
+    /// selector (e.g. `:last-child` in `li:last-child > a`). The whole selector is returned, if
+    /// it's unsupported as a whole.
+    ///
+    /// Returns `None` if the error occurs at the end of the selector (e.g. `div >`).
+    #[inline]
+    #[must_use]
+    pub fn token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+
+    /// Returns the line of the selector with the error, followed by a line with carets
+    /// pointing at the offending token.
+    #[inline]
+    #[must_use]
+    pub fn snippet(&self) -> &str {
+        &self.snippet
+    }
+}
+
+impl From<DetailedSelectorError> for SelectorError {
+    #[inline]
+    fn from(err: DetailedSelectorError) -> Self {
+        err.error
+    }
+}
+
+/// Converts the `location`, in which the columns are counted in UTF-16 code units,
+/// into the byte offset in the `selector`.
+fn byte_offset(selector: &str, location: SourceLocation) -> usize {
+    let mut line = 0;
+    let mut column = 1;
+    let mut chars = selector.char_indices().peekable();
+
+    while let Some((offset, ch)) = chars.next() {
+        if line > location.line || (line == location.line && column >= location.column) {
+            return offset;
+        }
+
+        match ch {
+            '\r' if matches!(chars.peek(), Some((_, '\n'))) => (),
+            '\n' | '\r' | '\x0C' => {
+                line += 1;
+                column = 1;
+            }
+            _ => column += ch.len_utf16() as u32,
+        }
+    }
+
+    selector.len()
+}
+
+/// Returns the byte range of the token that starts at `offset`, skipping the preceding
+/// whitespace. The range is empty if there are no more tokens in the `selector`.
+fn token_at(selector: &str, offset: usize) -> Range<usize> {
+    let mut input = ParserInput::new(&selector[offset..]);
+    let mut parser = Parser::new(&mut input);
+
+    parser.skip_whitespace();
+
+    let start = offset + parser.position().byte_index();
+
+    match parser.next() {
+        Ok(_) => start..offset + parser.position().byte_index(),
+        Err(_) => start..start,
+    }
+}
+
+/// Returns the byte ranges of the pseudo-classes, including their arguments, and of the
+/// attribute selectors in the `selector`, in the order they're written in. The pseudo-classes
+/// are keyed by their lowercased names with the leading colon (e.g. `:nth-child`), while
+/// the attribute selectors are keyed by `[`.
+pub(crate) fn simple_selector_spans(selector: &str) -> Vec<(String, Range<usize>)> {
+    let mut input = ParserInput::new(selector);
+    let mut parser = Parser::new(&mut input);
+    let mut spans = Vec::new();
+
+    collect_simple_selector_spans(&mut parser, &mut spans);
+
+    spans
+}
+
+fn collect_simple_selector_spans(
+    parser: &mut Parser<'_, '_>,
+    spans: &mut Vec<(String, Range<usize>)>,
+) {
+    loop {
+        parser.skip_whitespace();
+
+        let start = parser.position().byte_index();
+
+        let Ok(token) = parser.next().cloned() else {
+            break;
+        };
+
+        match token {
+            Token::Colon => match parser.next_including_whitespace().cloned() {
+                Ok(Token::Ident(name)) => {
+                    let end = parser.position().byte_index();
+
+                    spans.push((format!(":{}", name.to_ascii_lowercase()), start..end));
+                }
+                Ok(Token::Function(name)) => {
+                    let idx = spans.len();
+
+                    spans.push((format!(":{}", name.to_ascii_lowercase()), start..start));
+
+                    // NOTE: the arguments (e.g. of `:not()`) can contain pseudo-classes as well.
+                    let _ = parser.parse_nested_block(|arguments| {
+                        collect_simple_selector_spans(arguments, spans);
+                        Ok::<_, ParseError<'_, ()>>(())
+                    });
+
+                    spans[idx].1.end = parser.position().byte_index();
                 }
+                _ => (),
             },
+            Token::SquareBracketBlock => {
+                let _ = parser.parse_nested_block(|attr| {
+                    while attr.next().is_ok() {}
+                    Ok::<_, ParseError<'_, ()>>(())
+                });
+
+                spans.push(("[".into(), start..parser.position().byte_index()));
+            }
+            _ => (),
         }
     }
 }
+
+fn snippet(selector: &str, start: usize, end: usize) -> String {
+    let line_start = selector[..start]
+        .rfind(['\n', '\r', '\x0C'])
+        .map_or(0, |idx| idx + 1);
+
+    let line_end = selector[start..]
+        .find(['\n', '\r', '\x0C'])
+        .map_or(selector.len(), |idx| start + idx);
+
+    let indent = selector[line_start..start].chars().count();
+    let width = selector[start..end.min(line_end)].chars().count().max(1);
+
+    format!(
+        "{}\n{}{}",
+        &selector[line_start..line_end],
+        " ".repeat(indent),
+        "^".repeat(width)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Selector;
+
+    #[track_caller]
+    fn assert_err(selector: &str, offset: usize, token: Option<&str>, snippet: &str) {
+        let err = selector.parse::<Selector>().unwrap_err();
+
+        assert_eq!(err.offset(), offset, "{selector:?}");
+        assert_eq!(err.token(), token, "{selector:?}");
+        assert_eq!(err.snippet(), snippet, "{selector:?}");
+    }
+
+    #[test]
+    fn detailed_errors() {
+        assert_err(
+            "a[href=foo bar]",
+            11,
+            Some("bar"),
+            "a[href=foo bar]\n           ^^^",
+        );
+        assert_err("div.1foo", 3, Some(".1foo"), "div.1foo\n   ^^^^^");
+        assert_err("p:foo", 2, Some("foo"), "p:foo\n  ^^^");
+        assert_err("div >", 5, None, "div >\n     ^");
+        assert_err("ul,\n  li$", 8, Some("$"), "  li$\n    ^");
+        assert_err("é > ü$", 7, Some("$"), "é > ü$\n     ^");
+    }
+
+    #[test]
+    fn detailed_validation_errors() {
+        assert_err(
+            " li:last-child > a ",
+            3,
+            Some(":last-child"),
+            " li:last-child > a \n   ^^^^^^^^^^^",
+        );
+        assert_err(
+            "li:LAST-child, li:last-child > a:last-child",
+            17,
+            Some(":last-child"),
+            "li:LAST-child, li:last-child > a:last-child\n                 ^^^^^^^^^^^",
+        );
+        assert_err(
+            "a[title=':last-child'], li:last-child a",
+            26,
+            Some(":last-child"),
+            "a[title=':last-child'], li:last-child a\n                          ^^^^^^^^^^^",
+        );
+        assert_err(
+            "ul:has(a):HAS( b)",
+            9,
+            Some(":HAS( b)"),
+            "ul:has(a):HAS( b)\n         ^^^^^^^^",
+        );
+        assert_err(
+            "ul:has(li:last-child)",
+            9,
+            Some(":last-child"),
+            "ul:has(li:last-child)\n         ^^^^^^^^^^^",
+        );
+        assert_err(
+            "ul:has(+ li)",
+            2,
+            Some(":has(+ li)"),
+            "ul:has(+ li)\n  ^^^^^^^^^^",
+        );
+        assert_err(
+            "p:not(:empty) a",
+            1,
+            Some(":not(:empty)"),
+            "p:not(:empty) a\n ^^^^^^^^^^^^",
+        );
+        assert_err(
+            "div :is(ul li:empty)",
+            4,
+            Some(":is(ul li:empty)"),
+            "div :is(ul li:empty)\n    ^^^^^^^^^^^^^^^^",
+        );
+
+        // NOTE: the selectors that are unsupported as a whole are reported entirely.
+        let selector = format!("li{}", ":is(.a:empty, .b, .c, .d, .e, .f)".repeat(4));
+        let err = selector.parse::<Selector>().unwrap_err();
+
+        assert_eq!(err.offset(), 0);
+        assert_eq!(err.token(), Some(&*selector));
+    }
+
+    #[test]
+    fn display() {
+        let err = "div > p:foo".parse::<Selector>().unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "Unsupported pseudo-class or pseudo-element in selector.\ndiv > p:foo\n        ^^^"
+        );
+    }
+}
@@ -1,5 +1,6 @@
 #![allow(clippy::needless_pass_by_value)]
 
+mod analysis;
 mod ast;
 mod attribute_matcher;
 mod compiler;
@@ -8,24 +9,35 @@ mod parser;
 mod program;
 mod stack;
 
-use self::program::AddressRange;
+use self::program::{relocation_after_removal, AddressRange, OnParentEndExprs};
 use self::stack::StackDirective;
 use crate::html::{LocalName, Namespace};
 use crate::memory::{MemoryLimitExceededError, SharedMemoryLimiter};
 use crate::transform_stream::AuxStartTagInfo;
 use encoding_rs::Encoding;
+use hashbrown::{HashMap, HashSet};
+use std::cmp::Reverse;
+use std::ops::Range;
+use std::sync::Arc;
 
+pub use self::analysis::SelectorAnalysis;
 pub use self::ast::*;
 pub(crate) use self::attribute_matcher::AttributeMatcher;
 pub(crate) use self::compiler::Compiler;
-pub use self::error::SelectorError;
-pub use self::parser::Selector;
+pub use self::error::{DetailedSelectorError, SelectorError};
+pub(crate) use self::parser::SupportedHandlers;
+pub use self::parser::{AttributeValueMatcher, Selector, Specificity};
 pub(crate) use self::program::{ExecutionBranch, Program, TryExecResult};
-pub(crate) use self::stack::{ChildCounter, ElementData, Stack, StackItem};
+pub(crate) use self::stack::{
+    ChildCounter, ElementData, LazyMatch, LazyMatchResolution, Stack, StackItem,
+};
 
 pub(crate) struct MatchInfo<P> {
     pub payload: P,
     pub with_content: bool,
+    /// Set if the match depends on the following siblings of the element. The outcome
+    /// of such match is reported later as a [`LazyMatchResolution`] with the same element id.
+    pub lazy_element_id: Option<usize>,
 }
 
 pub(crate) type AuxStartTagInfoRequest<E, P> = Box<
@@ -68,14 +80,73 @@ struct Bailout<T> {
     recovery_point: T,
 }
 
+/// Counters of the work done by the VM.
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct ExecutionStats {
+    pub instructions: usize,
+    pub jumps: usize,
+    pub hereditary_jump_scans: usize,
+    pub bailouts: usize,
+}
+
+impl ExecutionStats {
+    #[inline]
+    fn add(&mut self, other: &ExecutionStats) {
+        self.instructions += other.instructions;
+        self.jumps += other.jumps;
+        self.hereditary_jump_scans += other.hereditary_jump_scans;
+        self.bailouts += other.bailouts;
+    }
+}
+
+/// Stats of the VM, that are collected only if enabled, as the work is attributed to
+/// the payload.
+#[derive(Debug)]
+pub(crate) struct VmStats<P> {
+    pub execution: ExecutionStats,
+    /// The work done for each payload. The work shared by multiple payload (e.g. the
+    /// instructions for `div` in `div > a, div > p`) is counted for each of them.
+    pub payload_execution: HashMap<P, ExecutionStats>,
+    pub matches: HashMap<P, usize>,
+    /// Payload the instructions are executed for, by their addresses.
+    instruction_owners: Vec<HashSet<P>>,
+}
+
+/// Stats of the execution for an element, that are added to the stats of the VM once
+/// the execution is finished.
+struct ElementExecutionStats<P> {
+    total: ExecutionStats,
+    by_payload: HashMap<P, ExecutionStats>,
+}
+
 /// A container for tracking state from various places on the stack.
+///
+/// The state is `None` if it depends on the elements, that the VM hasn't seen, e.g. the
+/// preceding siblings of the element that were parsed before the VM has been created in the
+/// middle of the document, or before it has started counting the children by their type.
 pub(crate) struct SelectorState<'i> {
-    pub cumulative: &'i ChildCounter,
+    pub cumulative: Option<&'i ChildCounter>,
     pub typed: Option<&'i ChildCounter>,
+    pub ns: Namespace,
+    /// Set if the element is the root of the document, i.e. the first top-level `<html>` element.
+    pub is_root: Option<bool>,
 }
 
 struct ExecutionCtx<'i, E: ElementData> {
     stack_item: StackItem<'i, E>,
+    lazy_payload: Vec<(E::MatchPayload, OnParentEndExprs)>,
+    /// Payload of the ancestors (by their stack level), whose `:has()` argument is
+    /// matched by the element.
+    descendant_matches: Vec<(usize, E::MatchPayload)>,
+    /// Ids of the standalone selectors (e.g. `ul > li` in `:not(ul > li)`) matched by the element.
+    matched_standalone_selectors: Vec<usize>,
+    /// Addresses of the instructions, whose branches depend on the standalone selectors matched
+    /// by the element. These branches are taken once all the instructions are executed.
+    conditional_branch_addrs: Vec<usize>,
+    next_sibling_jumps: Vec<AddressRange>,
+    later_sibling_jumps: Vec<AddressRange>,
+    /// Set only if the stats of the VM are enabled.
+    stats: Option<ElementExecutionStats<E::MatchPayload>>,
     with_content: bool,
     ns: Namespace,
     enable_esi_tags: bool,
@@ -83,9 +154,24 @@ struct ExecutionCtx<'i, E: ElementData> {
 
 impl<'i, E: ElementData> ExecutionCtx<'i, E> {
     #[inline]
-    pub fn new(local_name: LocalName<'i>, ns: Namespace, enable_esi_tags: bool) -> Self {
+    pub fn new(
+        local_name: LocalName<'i>,
+        ns: Namespace,
+        enable_esi_tags: bool,
+        enable_stats: bool,
+    ) -> Self {
         ExecutionCtx {
             stack_item: StackItem::new(local_name),
+            lazy_payload: Vec::default(),
+            descendant_matches: Vec::default(),
+            matched_standalone_selectors: Vec::default(),
+            conditional_branch_addrs: Vec::default(),
+            next_sibling_jumps: Vec::default(),
+            later_sibling_jumps: Vec::default(),
+            stats: enable_stats.then(|| ElementExecutionStats {
+                total: ExecutionStats::default(),
+                by_payload: HashMap::default(),
+            }),
             with_content: true,
             ns,
             enable_esi_tags,
@@ -93,10 +179,26 @@ impl<'i, E: ElementData> ExecutionCtx<'i, E> {
     }
 
     pub fn add_execution_branch(
+        &mut self,
+        addr: usize,
+        branch: &ExecutionBranch<E::MatchPayload>,
+        match_handler: &mut dyn FnMut(MatchInfo<E::MatchPayload>),
+    ) {
+        if branch.is_conditional() {
+            self.conditional_branch_addrs.push(addr);
+        } else {
+            self.take_execution_branch(branch, match_handler);
+        }
+    }
+
+    fn take_execution_branch(
         &mut self,
         branch: &ExecutionBranch<E::MatchPayload>,
         match_handler: &mut dyn FnMut(MatchInfo<E::MatchPayload>),
     ) {
+        self.matched_standalone_selectors
+            .extend_from_slice(&branch.matched_standalone_selectors);
+
         for &payload in &branch.matched_payload {
             let element_payload = self.stack_item.element_data.matched_payload_mut();
 
@@ -104,12 +206,31 @@ impl<'i, E: ElementData> ExecutionCtx<'i, E> {
                 match_handler(MatchInfo {
                     payload,
                     with_content: self.with_content,
+                    lazy_element_id: None,
                 });
 
                 element_payload.insert(payload);
             }
         }
 
+        if let Some(ref lazy_payload) = branch.lazy_payload {
+            for &payload in &lazy_payload.payload {
+                self.lazy_payload
+                    .push((payload, lazy_payload.exprs.clone()));
+            }
+        }
+
+        // NOTE: siblings can follow elements that can't have content,
+        // so sibling jumps are registered regardless of it.
+        if let Some(ref next_sibling_jumps) = branch.next_sibling_jumps {
+            self.next_sibling_jumps.push(next_sibling_jumps.to_owned());
+        }
+
+        if let Some(ref later_sibling_jumps) = branch.later_sibling_jumps {
+            self.later_sibling_jumps
+                .push(later_sibling_jumps.to_owned());
+        }
+
         if self.with_content {
             if let Some(ref jumps) = branch.jumps {
                 self.stack_item.jumps.push(jumps.to_owned());
@@ -120,6 +241,16 @@ impl<'i, E: ElementData> ExecutionCtx<'i, E> {
                     .hereditary_jumps
                     .push(hereditary_jumps.to_owned());
             }
+
+            if let Some(ref has_jumps) = branch.has_jumps {
+                self.stack_item.has_jumps.push(has_jumps.to_owned());
+            }
+
+            if let Some(ref hereditary_has_jumps) = branch.hereditary_has_jumps {
+                self.stack_item
+                    .hereditary_has_jumps
+                    .push(hereditary_has_jumps.to_owned());
+            }
         }
     }
 
@@ -127,6 +258,13 @@ impl<'i, E: ElementData> ExecutionCtx<'i, E> {
     pub fn into_owned(self) -> ExecutionCtx<'static, E> {
         ExecutionCtx {
             stack_item: self.stack_item.into_owned(),
+            lazy_payload: self.lazy_payload,
+            descendant_matches: self.descendant_matches,
+            matched_standalone_selectors: self.matched_standalone_selectors,
+            conditional_branch_addrs: self.conditional_branch_addrs,
+            next_sibling_jumps: self.next_sibling_jumps,
+            later_sibling_jumps: self.later_sibling_jumps,
+            stats: self.stats,
             with_content: self.with_content,
             ns: self.ns,
             enable_esi_tags: self.enable_esi_tags,
@@ -134,6 +272,14 @@ impl<'i, E: ElementData> ExecutionCtx<'i, E> {
     }
 }
 
+/// A selector added to the program of the VM, that can be removed later along with its
+/// instructions.
+struct AddedSelector<P> {
+    payload: P,
+    instructions: AddressRange,
+    standalone_selectors: Range<usize>,
+}
+
 macro_rules! aux_info_request {
     ($req:expr) => {
         Err(VmError::InfoRequest(Box::new($req)))
@@ -141,9 +287,19 @@ macro_rules! aux_info_request {
 }
 
 pub(crate) struct SelectorMatchingVm<E: ElementData> {
-    program: Program<E::MatchPayload>,
+    /// The program can be shared by multiple VMs. It's copied on write, once the selectors
+    /// of the VM are modified.
+    program: Arc<Program<E::MatchPayload>>,
+    /// Entry points of the program and of the programs appended to it since.
+    entry_points: Vec<AddressRange>,
+    /// Selectors appended to the program, with the entry points following the entry points
+    /// of the program in the same order.
+    added_selectors: Vec<AddedSelector<E::MatchPayload>>,
+    encoding: &'static Encoding,
     stack: Stack<E>,
     enable_esi_tags: bool,
+    lazily_matched_element_count: usize,
+    stats: Option<VmStats<E::MatchPayload>>,
 }
 
 impl<E> SelectorMatchingVm<E>
@@ -159,13 +315,151 @@ where
         enable_esi_tags: bool,
     ) -> Self {
         let program = Compiler::new(encoding).compile(ast);
+
+        Self::with_program(Arc::new(program), encoding, memory_limiter, enable_esi_tags)
+    }
+
+    /// Creates the VM for the `program`, that has been compiled for the `encoding`.
+    #[inline]
+    #[must_use]
+    pub fn with_program(
+        program: Arc<Program<E::MatchPayload>>,
+        encoding: &'static Encoding,
+        memory_limiter: SharedMemoryLimiter,
+        enable_esi_tags: bool,
+    ) -> Self {
         let enable_nth_of_type = program.enable_nth_of_type;
 
         Self {
+            entry_points: vec![program.entry_points.clone()],
+            added_selectors: Vec::default(),
             program,
+            encoding,
             enable_esi_tags,
             stack: Stack::new(memory_limiter, enable_nth_of_type),
+            lazily_matched_element_count: 0,
+            stats: None,
+        }
+    }
+
+    /// Enables collection of the stats of the VM.
+    #[inline]
+    pub fn enable_stats(&mut self) {
+        let instruction_owners = self.program.instruction_owners();
+
+        self.stats.get_or_insert_with(|| VmStats {
+            execution: ExecutionStats::default(),
+            payload_execution: HashMap::default(),
+            matches: HashMap::default(),
+            instruction_owners,
+        });
+    }
+
+    /// Updates the payload of the instructions, once the program is modified.
+    fn update_instruction_owners(&mut self) {
+        if let Some(ref mut stats) = self.stats {
+            stats.instruction_owners = self.program.instruction_owners();
+        }
+    }
+
+    /// Returns the stats collected since the stats were enabled, if they are.
+    #[inline]
+    pub fn stats(&self) -> Option<&VmStats<E::MatchPayload>> {
+        self.stats.as_ref()
+    }
+
+    /// Makes the VM aware, that it's created in the middle of the document, so the elements
+    /// parsed earlier are unknown to it. See [`SelectorState`].
+    #[inline]
+    pub fn start_mid_document(&mut self) {
+        self.stack.start_mid_document();
+    }
+
+    /// Adds the selector to the program. The selector is matched only against the elements,
+    /// whose start tags are handled after that. So, it doesn't take into account the elements
+    /// parsed earlier, e.g. `body p` doesn't match paragraphs of an already open `<body>`.
+    pub fn add_selector(&mut self, selector: &Selector, payload: E::MatchPayload) {
+        let mut ast = Ast::default();
+
+        ast.add_selector(selector, payload);
+        self.add_selector_ast(ast, payload);
+    }
+
+    /// Adds the selector to the program like [`add_selector`], but matches the selectors of the
+    /// list with the payload for their specificity (see [`Ast::add_selector_by_specificity`]).
+    /// The selector is removed with the first of the `payloads`.
+    ///
+    /// [`add_selector`]: Self::add_selector
+    pub fn add_selector_by_specificity(
+        &mut self,
+        selector: &Selector,
+        payloads: &[E::MatchPayload],
+    ) {
+        let mut ast = Ast::default();
+
+        ast.add_selector_by_specificity(selector, payloads);
+        self.add_selector_ast(ast, payloads[0]);
+    }
+
+    fn add_selector_ast(&mut self, ast: Ast<E::MatchPayload>, payload: E::MatchPayload) {
+        // NOTE: the existing instructions can't be recompiled, as their addresses are
+        // referenced by the open elements, so the selector is compiled separately.
+        let program = Compiler::new(self.encoding).compile(ast);
+
+        if program.enable_nth_of_type {
+            self.stack.enable_nth_of_type();
+        }
+
+        let target = Arc::make_mut(&mut self.program);
+        let addr_offset = target.instructions.len();
+        let standalone_selector_offset = target.standalone_selector_count;
+        let entry_points = target.append(program);
+
+        self.added_selectors.push(AddedSelector {
+            payload,
+            instructions: addr_offset..target.instructions.len(),
+            standalone_selectors: standalone_selector_offset..target.standalone_selector_count,
+        });
+
+        self.entry_points.push(entry_points);
+        self.update_instruction_owners();
+    }
+
+    /// Removes the selector added with the `payload`, along with its instructions. The jumps
+    /// of the open elements to these instructions are removed as well, so the selector doesn't
+    /// match any of the elements from now on. The lazy matches of the elements, whose start
+    /// tags have already been handled, are still resolved.
+    pub fn remove_selector(&mut self, payload: E::MatchPayload) {
+        let Some(idx) = self
+            .added_selectors
+            .iter()
+            .position(|s| s.payload == payload)
+        else {
+            return;
+        };
+
+        let removed = self.added_selectors.remove(idx);
+        let relocate_addr = relocation_after_removal(&removed.instructions);
+        let relocate_id = relocation_after_removal(&removed.standalone_selectors);
+
+        self.entry_points.remove(idx + 1);
+
+        for (selector, entry_points) in self.added_selectors[idx..]
+            .iter_mut()
+            .zip(&mut self.entry_points[idx + 1..])
+        {
+            for range in [&mut selector.instructions, entry_points] {
+                *range = relocate_addr(range.start)..relocate_addr(range.end);
+            }
+
+            selector.standalone_selectors = relocate_id(selector.standalone_selectors.start)
+                ..relocate_id(selector.standalone_selectors.end);
         }
+
+        self.stack.remove_jumps(&removed.instructions);
+
+        Arc::make_mut(&mut self.program).remove(removed.instructions, removed.standalone_selectors);
+        self.update_instruction_owners();
     }
 
     pub fn exec_for_start_tag(
@@ -178,7 +472,7 @@ where
 
         self.stack.add_child(&local_name);
 
-        let mut ctx = ExecutionCtx::new(local_name, ns, self.enable_esi_tags);
+        let mut ctx = ExecutionCtx::new(local_name, ns, self.enable_esi_tags, self.stats.is_some());
 
         match Stack::get_stack_directive(&ctx.stack_item, ns, ctx.enable_esi_tags) {
             PopImmediately => {
@@ -205,6 +499,40 @@ where
             .pop_up_to(local_name, unmatched_element_data_handler);
     }
 
+    /// Resolves the lazy matches of the current element, that depend on its content
+    /// (e.g. `:empty`), once the element has text.
+    #[inline]
+    pub fn exec_for_text(&mut self) {
+        self.stack.set_current_element_non_empty();
+    }
+
+    /// Returns `true` if the text of the current element is required to resolve its lazy
+    /// matches (e.g. `:empty`).
+    #[inline]
+    pub fn needs_text(&self) -> bool {
+        self.stack.current_element_needs_content()
+    }
+
+    /// Resolves the remaining lazy matches once the document ends.
+    #[inline]
+    pub fn exec_for_end(&mut self) {
+        self.stack.resolve_all_lazy_matches();
+    }
+
+    /// Returns the outcomes of the lazy matches, that were decided since the last call.
+    #[inline]
+    pub fn take_lazy_match_resolutions(&mut self) -> Vec<LazyMatchResolution<E::MatchPayload>> {
+        let resolutions = self.stack.take_lazy_match_resolutions();
+
+        if let Some(ref mut stats) = self.stats {
+            for resolution in resolutions.iter().filter(|r| r.is_match) {
+                *stats.matches.entry(resolution.payload).or_default() += 1;
+            }
+        }
+
+        resolutions
+    }
+
     #[inline]
     pub fn current_element_data_mut(&mut self) -> Option<&mut E> {
         self.stack.current_element_data_mut()
@@ -221,11 +549,10 @@ where
 
         ctx.with_content = !aux_info.self_closing;
 
-        self.exec_instr_set_with_attrs(
-            &self.program.entry_points,
+        self.exec_entry_points_with_attrs(
             &attr_matcher,
             &mut ctx,
-            0,
+            JumpPtr::default(),
             match_handler,
         );
 
@@ -238,18 +565,243 @@ where
             match_handler,
         );
 
+        self.exec_sibling_jumps_with_attrs(
+            &attr_matcher,
+            &mut ctx,
+            JumpPtr::default(),
+            match_handler,
+        );
+
+        self.exec_has_jumps_with_attrs(&attr_matcher, &mut ctx);
+
+        self.finish_exec(ctx, match_handler)
+    }
+
+    fn add_lazy_matches(
+        &mut self,
+        ctx: &mut ExecutionCtx<'_, E>,
+        match_handler: &mut dyn FnMut(MatchInfo<E::MatchPayload>),
+    ) {
+        let state = self.stack.build_state(&ctx.stack_item.local_name, ctx.ns);
+        let index = state.cumulative.map(ChildCounter::index);
+        let index_of_type = state.typed.map(ChildCounter::index);
+        let element_id = self.lazily_matched_element_count;
+        let mut lazily_matched_payload = Vec::new();
+
+        for (payload, exprs) in std::mem::take(&mut ctx.lazy_payload) {
+            let element_payload = ctx.stack_item.element_data.matched_payload_mut();
+
+            if element_payload.contains(&payload) {
+                continue;
+            }
+
+            // NOTE: the same payload can be matched lazily by multiple branches (e.g. for
+            // `:is(.a:last-child, .b:not(:only-child))`). If it's already matched lazily, the
+            // other outcomes are reported as the resolutions of the lazy match, so the handlers
+            // are invoked only once.
+            let is_lazily_matched = lazily_matched_payload.contains(&payload);
+
+            // NOTE: the element doesn't match, if its preceding siblings are unknown to the VM
+            // (see `SelectorState`), and the match depends on them.
+            let is_unknown = exprs.iter().any(|e| match e.simple_expr {
+                OnParentEndExpr::NthLastChild(_) | OnParentEndExpr::OnlyChild => index.is_none(),
+                OnParentEndExpr::NthLastOfType(_) | OnParentEndExpr::OnlyOfType => {
+                    index_of_type.is_none()
+                }
+                OnParentEndExpr::Empty => false,
+            });
+
+            let lazy_match = LazyMatch {
+                element_id,
+                payload,
+                exprs,
+                local_name: ctx.stack_item.local_name.clone().into_owned(),
+                index: index.unwrap_or_default(),
+                index_of_type: index_of_type.unwrap_or_default(),
+                // NOTE: the elements that can't have content are empty.
+                is_empty: (!ctx.with_content).then_some(true),
+            };
+
+            let depends_on_content = lazy_match
+                .exprs
+                .iter()
+                .any(|e| e.simple_expr == OnParentEndExpr::Empty);
+
+            // NOTE: some of the matches can be decided right away (e.g. `:only-child`
+            // for an element that has preceding siblings).
+            let is_match = if is_unknown {
+                Some(false)
+            } else {
+                self.stack.add_lazy_match(lazy_match)
+            };
+
+            match is_match {
+                Some(true) if is_lazily_matched => {
+                    match_handler(MatchInfo {
+                        payload,
+                        with_content: ctx.with_content,
+                        lazy_element_id: Some(element_id),
+                    });
+
+                    self.stack.add_lazy_match_resolution(LazyMatchResolution {
+                        element_id,
+                        payload,
+                        is_match: true,
+                    });
+                }
+                Some(true) => {
+                    match_handler(MatchInfo {
+                        payload,
+                        with_content: ctx.with_content,
+                        lazy_element_id: None,
+                    });
+
+                    element_payload.insert(payload);
+                }
+                Some(false) => (),
+                None => {
+                    match_handler(MatchInfo {
+                        payload,
+                        with_content: ctx.with_content,
+                        lazy_element_id: Some(element_id),
+                    });
+
+                    if depends_on_content {
+                        ctx.stack_item.content_lazy_element_id = Some(element_id);
+                    }
+
+                    lazily_matched_payload.push(payload);
+                }
+            }
+        }
+
+        if !lazily_matched_payload.is_empty() {
+            self.lazily_matched_element_count += 1;
+        }
+    }
+
+    fn finish_exec(
+        &mut self,
+        mut ctx: ExecutionCtx<'_, E>,
+        match_handler: &mut dyn FnMut(MatchInfo<E::MatchPayload>),
+    ) -> Result<(), MemoryLimitExceededError> {
+        let mut conditional_branch_addrs = std::mem::take(&mut ctx.conditional_branch_addrs);
+
+        // NOTE: the ids of the standalone selectors a branch depends on are greater than the ids
+        // of the standalone selectors it matches (e.g. the branch for `.a:is(.b, .c)` in
+        // `:is(.a:is(.b, .c), .d)`), so the branches that depend on greater ids are decided first.
+        if conditional_branch_addrs.len() > 1 {
+            conditional_branch_addrs.sort_unstable_by_key(|&addr| {
+                Reverse(
+                    self.program.instructions[addr]
+                        .associated_branch
+                        .min_condition_id(),
+                )
+            });
+        }
+
+        for addr in conditional_branch_addrs {
+            let branch = &self.program.instructions[addr].associated_branch;
+
+            if branch.is_taken(&ctx.matched_standalone_selectors) {
+                ctx.take_execution_branch(branch, match_handler);
+            }
+        }
+
+        if !ctx.lazy_payload.is_empty() {
+            self.add_lazy_matches(&mut ctx, match_handler);
+        }
+
+        for (level, payload) in ctx.descendant_matches.drain(..) {
+            let is_new_match = self.stack.add_descendant_match(level, payload);
+
+            if let (true, Some(stats)) = (is_new_match, &mut self.stats) {
+                *stats.matches.entry(payload).or_default() += 1;
+            }
+        }
+
+        if let Some(ref mut stats) = self.stats {
+            if let Some(element_stats) = ctx.stats.take() {
+                stats.execution.add(&element_stats.total);
+
+                for (payload, payload_stats) in element_stats.by_payload {
+                    stats
+                        .payload_execution
+                        .entry(payload)
+                        .or_default()
+                        .add(&payload_stats);
+                }
+            }
+
+            for &payload in ctx.stack_item.element_data.matched_payload_mut().iter() {
+                *stats.matches.entry(payload).or_default() += 1;
+            }
+        }
+
+        self.stack
+            .sibling_jumps_mut()
+            .update(ctx.next_sibling_jumps, ctx.later_sibling_jumps);
+
         if ctx.with_content {
-            self.stack.push_item(ctx.stack_item)?;
+            self.stack.push_item(ctx.stack_item.into_owned())?;
         }
 
         Ok(())
     }
 
+    /// Counts the work for the instructions at the `addrs` and for the payload they are
+    /// executed for. Does nothing unless the stats are enabled.
+    #[inline]
+    fn count(
+        &self,
+        ctx: &mut ExecutionCtx<'_, E>,
+        counter: fn(&mut ExecutionStats) -> &mut usize,
+        addrs: impl IntoIterator<Item = usize>,
+    ) {
+        let (Some(element_stats), Some(stats)) = (&mut ctx.stats, &self.stats) else {
+            return;
+        };
+
+        *counter(&mut element_stats.total) += 1;
+
+        let owners = addrs
+            .into_iter()
+            .flat_map(|addr| &stats.instruction_owners[addr])
+            .collect::<HashSet<_>>();
+
+        for &payload in owners {
+            *counter(element_stats.by_payload.entry(payload).or_default()) += 1;
+        }
+    }
+
+    /// Counts the scan of the hereditary (or `:has()` hereditary) jumps of the ancestor at
+    /// the `level`, which is done for the jumps of the ancestor and of the ones above it.
+    #[inline]
+    fn count_hereditary_jump_scan(
+        &self,
+        ctx: &mut ExecutionCtx<'_, E>,
+        level: usize,
+        is_has_scan: bool,
+    ) {
+        let addrs = self.stack.items()[..=level]
+            .iter()
+            .flat_map(|item| match is_has_scan {
+                true => &item.hereditary_has_jumps,
+                false => &item.hereditary_jumps,
+            })
+            .flat_map(Clone::clone);
+
+        self.count(ctx, |s| &mut s.hereditary_jump_scans, addrs);
+    }
+
     fn bailout<T: 'static + Send>(
-        ctx: ExecutionCtx<'_, E>,
+        &self,
+        mut ctx: ExecutionCtx<'_, E>,
         bailout: Bailout<T>,
         recovery_point_handler: RecoveryPointHandler<T, E, E::MatchPayload>,
     ) -> Result<(), VmError<E, E::MatchPayload>> {
+        self.count(&mut ctx, |s| &mut s.bailouts, [bailout.at_addr]);
+
         let mut ctx = ctx.into_owned();
 
         aux_info_request!(move |this, aux_info, match_handler| {
@@ -270,11 +822,27 @@ where
                 match_handler,
             );
 
-            if ctx.with_content {
-                this.stack.push_item(ctx.stack_item)?;
-            }
+            this.exec_has_jumps_with_attrs(&attr_matcher, &mut ctx);
 
-            Ok(())
+            this.finish_exec(ctx, match_handler)
+        })
+    }
+
+    fn bailout_in_has_jumps(
+        &self,
+        mut ctx: ExecutionCtx<'_, E>,
+        bailout: Bailout<()>,
+    ) -> Result<(), VmError<E, E::MatchPayload>> {
+        self.count(&mut ctx, |s| &mut s.bailouts, [bailout.at_addr]);
+
+        let mut ctx = ctx.into_owned();
+
+        aux_info_request!(move |this, aux_info, match_handler| {
+            let attr_matcher = AttributeMatcher::new(aux_info.input, aux_info.attr_buffer, ctx.ns);
+
+            this.exec_has_jumps_with_attrs(&attr_matcher, &mut ctx);
+
+            this.finish_exec(ctx, match_handler)
         })
     }
 
@@ -282,16 +850,10 @@ where
         &mut self,
         ctx: &mut ExecutionCtx<'static, E>,
         attr_matcher: &AttributeMatcher<'_>,
-        recovery_point: usize,
+        recovery_point: JumpPtr,
         match_handler: &mut dyn FnMut(MatchInfo<E::MatchPayload>),
     ) {
-        self.exec_instr_set_with_attrs(
-            &self.program.entry_points,
-            attr_matcher,
-            ctx,
-            recovery_point,
-            match_handler,
-        );
+        self.exec_entry_points_with_attrs(attr_matcher, ctx, recovery_point, match_handler);
 
         self.exec_jumps_with_attrs(attr_matcher, ctx, JumpPtr::default(), match_handler);
 
@@ -301,6 +863,8 @@ where
             HereditaryJumpPtr::default(),
             match_handler,
         );
+
+        self.exec_sibling_jumps_with_attrs(attr_matcher, ctx, JumpPtr::default(), match_handler);
     }
 
     fn recover_after_bailout_in_jumps(
@@ -318,9 +882,10 @@ where
             HereditaryJumpPtr::default(),
             match_handler,
         );
+
+        self.exec_sibling_jumps_with_attrs(attr_matcher, ctx, JumpPtr::default(), match_handler);
     }
 
-    #[inline]
     fn recover_after_bailout_in_hereditary_jumps(
         &mut self,
         ctx: &mut ExecutionCtx<'static, E>,
@@ -329,6 +894,19 @@ where
         match_handler: &mut dyn FnMut(MatchInfo<E::MatchPayload>),
     ) {
         self.exec_hereditary_jumps_with_attrs(attr_matcher, ctx, recovery_point, match_handler);
+
+        self.exec_sibling_jumps_with_attrs(attr_matcher, ctx, JumpPtr::default(), match_handler);
+    }
+
+    #[inline]
+    fn recover_after_bailout_in_sibling_jumps(
+        &mut self,
+        ctx: &mut ExecutionCtx<'static, E>,
+        attr_matcher: &AttributeMatcher<'_>,
+        recovery_point: JumpPtr,
+        match_handler: &mut dyn FnMut(MatchInfo<E::MatchPayload>),
+    ) {
+        self.exec_sibling_jumps_with_attrs(attr_matcher, ctx, recovery_point, match_handler);
     }
 
     fn exec_without_attrs(
@@ -336,29 +914,28 @@ where
         mut ctx: ExecutionCtx<'_, E>,
         match_handler: &mut dyn FnMut(MatchInfo<E::MatchPayload>),
     ) -> Result<(), VmError<E, E::MatchPayload>> {
-        if let Err(b) = self.try_exec_instr_set_without_attrs(
-            self.program.entry_points.clone(),
-            &mut ctx,
-            match_handler,
-        ) {
-            return Self::bailout(ctx, b, Self::recover_after_bailout_in_entry_points);
+        if let Err(b) = self.try_exec_entry_points_without_attrs(&mut ctx, match_handler) {
+            return self.bailout(ctx, b, Self::recover_after_bailout_in_entry_points);
         }
 
         if let Err(b) = self.try_exec_jumps_without_attrs(&mut ctx, match_handler) {
-            return Self::bailout(ctx, b, Self::recover_after_bailout_in_jumps);
+            return self.bailout(ctx, b, Self::recover_after_bailout_in_jumps);
         }
 
         if let Err(b) = self.try_exec_hereditary_jumps_without_attrs(&mut ctx, match_handler) {
-            return Self::bailout(ctx, b, Self::recover_after_bailout_in_hereditary_jumps);
+            return self.bailout(ctx, b, Self::recover_after_bailout_in_hereditary_jumps);
         }
 
-        if ctx.with_content {
-            self.stack
-                .push_item(ctx.stack_item.into_owned())
-                .map_err(VmError::MemoryLimitExceeded)
-        } else {
-            Ok(())
+        if let Err(b) = self.try_exec_sibling_jumps_without_attrs(&mut ctx, match_handler) {
+            return self.bailout(ctx, b, Self::recover_after_bailout_in_sibling_jumps);
+        }
+
+        if let Err(b) = self.try_exec_has_jumps_without_attrs(&mut ctx) {
+            return self.bailout_in_has_jumps(ctx, b);
         }
+
+        self.finish_exec(ctx, match_handler)
+            .map_err(VmError::MemoryLimitExceeded)
     }
 
     #[inline]
@@ -369,11 +946,11 @@ where
         ctx: &mut ExecutionCtx<'_, E>,
         match_handler: &mut dyn FnMut(MatchInfo<E::MatchPayload>),
     ) {
-        let state = self.stack.build_state(&ctx.stack_item.local_name);
+        let state = self.stack.build_state(&ctx.stack_item.local_name, ctx.ns);
         if let Some(branch) =
             self.program.instructions[addr].complete_exec_with_attrs(&state, attr_matcher)
         {
-            ctx.add_execution_branch(branch, match_handler);
+            ctx.add_execution_branch(addr, branch, match_handler);
         }
     }
 
@@ -385,13 +962,17 @@ where
         match_handler: &mut dyn FnMut(MatchInfo<E::MatchPayload>),
     ) -> Result<(), Bailout<usize>> {
         let start = addr_range.start;
-        let state = self.stack.build_state(&ctx.stack_item.local_name);
+        let state = self.stack.build_state(&ctx.stack_item.local_name, ctx.ns);
 
         for addr in addr_range {
+            self.count(ctx, |s| &mut s.instructions, [addr]);
+
             match self.program.instructions[addr]
                 .try_exec_without_attrs(&state, &ctx.stack_item.local_name)
             {
-                TryExecResult::Branch(branch) => ctx.add_execution_branch(branch, match_handler),
+                TryExecResult::Branch(branch) => {
+                    ctx.add_execution_branch(addr, branch, match_handler);
+                }
                 TryExecResult::AttributesRequired => {
                     return Err(Bailout {
                         at_addr: addr,
@@ -414,12 +995,59 @@ where
         offset: usize,
         match_handler: &mut dyn FnMut(MatchInfo<E::MatchPayload>),
     ) {
-        let state = self.stack.build_state(&ctx.stack_item.local_name);
+        let state = self.stack.build_state(&ctx.stack_item.local_name, ctx.ns);
         for addr in addr_range.start + offset..addr_range.end {
             let instr = &self.program.instructions[addr];
 
+            self.count(ctx, |s| &mut s.instructions, [addr]);
+
             if let Some(branch) = instr.exec(&state, &ctx.stack_item.local_name, attr_matcher) {
-                ctx.add_execution_branch(branch, match_handler);
+                ctx.add_execution_branch(addr, branch, match_handler);
+            }
+        }
+    }
+
+    fn try_exec_entry_points_without_attrs(
+        &self,
+        ctx: &mut ExecutionCtx<'_, E>,
+        match_handler: &mut dyn FnMut(MatchInfo<E::MatchPayload>),
+    ) -> Result<(), Bailout<JumpPtr>> {
+        for (i, entry_points) in self.entry_points.iter().enumerate() {
+            self.try_exec_instr_set_without_attrs(entry_points.clone(), ctx, match_handler)
+                .map_err(|b| Bailout {
+                    at_addr: b.at_addr,
+                    recovery_point: JumpPtr {
+                        instr_set_idx: i,
+                        offset: b.recovery_point,
+                    },
+                })?;
+        }
+
+        Ok(())
+    }
+
+    fn exec_entry_points_with_attrs(
+        &self,
+        attr_matcher: &AttributeMatcher<'_>,
+        ctx: &mut ExecutionCtx<'_, E>,
+        ptr: JumpPtr,
+        match_handler: &mut dyn FnMut(MatchInfo<E::MatchPayload>),
+    ) {
+        let mut entry_points = self.entry_points.iter().skip(ptr.instr_set_idx);
+
+        // NOTE: execute pointed entry points with the offset.
+        if let Some(ptr_entry_points) = entry_points.next() {
+            self.exec_instr_set_with_attrs(
+                ptr_entry_points,
+                attr_matcher,
+                ctx,
+                ptr.offset,
+                match_handler,
+            );
+
+            // NOTE: execute remaining entry points as usual.
+            for entry_points in entry_points {
+                self.exec_instr_set_with_attrs(entry_points, attr_matcher, ctx, 0, match_handler);
             }
         }
     }
@@ -431,6 +1059,8 @@ where
     ) -> Result<(), Bailout<JumpPtr>> {
         if let Some(parent) = self.stack.items().last() {
             for (i, jumps) in parent.jumps.iter().enumerate() {
+                self.count(ctx, |s| &mut s.jumps, jumps.clone());
+
                 self.try_exec_instr_set_without_attrs(jumps.clone(), ctx, match_handler)
                     .map_err(|b| Bailout {
                         at_addr: b.at_addr,
@@ -455,6 +1085,11 @@ where
         // NOTE: find pointed jumps instruction set and execute it with the offset.
         if let Some(parent) = self.stack.items().last() {
             if let Some(ptr_jumps) = parent.jumps.get(ptr.instr_set_idx) {
+                // NOTE: the jumps are counted once, if their execution is resumed.
+                if ptr.offset == 0 {
+                    self.count(ctx, |s| &mut s.jumps, ptr_jumps.clone());
+                }
+
                 self.exec_instr_set_with_attrs(
                     ptr_jumps,
                     attr_matcher,
@@ -465,6 +1100,8 @@ where
 
                 // NOTE: execute remaining jumps instruction sets as usual.
                 for jumps in parent.jumps.iter().skip(ptr.instr_set_idx + 1) {
+                    self.count(ctx, |s| &mut s.jumps, jumps.clone());
+
                     self.exec_instr_set_with_attrs(jumps, attr_matcher, ctx, 0, match_handler);
                 }
             }
@@ -476,8 +1113,12 @@ where
         ctx: &mut ExecutionCtx<'_, E>,
         match_handler: &mut dyn FnMut(MatchInfo<E::MatchPayload>),
     ) -> Result<(), Bailout<HereditaryJumpPtr>> {
-        for (i, ancestor) in self.stack.items().iter().rev().enumerate() {
-            for (j, jumps) in ancestor.hereditary_jumps.iter().cloned().enumerate() {
+        let items = self.stack.items();
+
+        for (i, ancestor) in items.iter().rev().enumerate() {
+            self.count_hereditary_jump_scan(ctx, items.len() - 1 - i, false);
+
+            for (j, jumps) in ancestor.hereditary_jumps.iter().cloned().enumerate() {
                 self.try_exec_instr_set_without_attrs(jumps, ctx, match_handler)
                     .map_err(|b| Bailout {
                         at_addr: b.at_addr,
@@ -515,6 +1156,11 @@ where
         // NOTE: first find pointed ancestor, then jump instruction
         // set and execute it with the offset.
         if let Some(ptr_ancestor) = items.get(ptr_ancestor_idx) {
+            // NOTE: the ancestor is counted once, if the execution of its jumps is resumed.
+            if ptr.offset == 0 {
+                self.count_hereditary_jump_scan(ctx, ptr_ancestor_idx, false);
+            }
+
             if let Some(ptr_jumps) = ptr_ancestor.hereditary_jumps.get(ptr.instr_set_idx) {
                 self.exec_instr_set_with_attrs(
                     ptr_jumps,
@@ -536,7 +1182,9 @@ where
 
             // NOTE: execute hereditary jumps in remaining ancestors as usual.
             if ptr_ancestor.has_ancestor_with_hereditary_jumps {
-                for ancestor in items.iter().rev().skip(ptr.stack_offset + 1) {
+                for (level, ancestor) in items[..ptr_ancestor_idx].iter().enumerate().rev() {
+                    self.count_hereditary_jump_scan(ctx, level, false);
+
                     for jumps in &ancestor.hereditary_jumps {
                         self.exec_instr_set_with_attrs(jumps, attr_matcher, ctx, 0, match_handler);
                     }
@@ -548,6 +1196,147 @@ where
             }
         }
     }
+
+    fn try_exec_sibling_jumps_without_attrs(
+        &self,
+        ctx: &mut ExecutionCtx<'_, E>,
+        match_handler: &mut dyn FnMut(MatchInfo<E::MatchPayload>),
+    ) -> Result<(), Bailout<JumpPtr>> {
+        for (i, jumps) in self.stack.sibling_jumps().iter().enumerate() {
+            self.count(ctx, |s| &mut s.jumps, jumps.clone());
+
+            self.try_exec_instr_set_without_attrs(jumps.clone(), ctx, match_handler)
+                .map_err(|b| Bailout {
+                    at_addr: b.at_addr,
+                    recovery_point: JumpPtr {
+                        instr_set_idx: i,
+                        offset: b.recovery_point,
+                    },
+                })?;
+        }
+
+        Ok(())
+    }
+
+    fn exec_sibling_jumps_with_attrs(
+        &self,
+        attr_matcher: &AttributeMatcher<'_>,
+        ctx: &mut ExecutionCtx<'_, E>,
+        ptr: JumpPtr,
+        match_handler: &mut dyn FnMut(MatchInfo<E::MatchPayload>),
+    ) {
+        let mut sibling_jumps = self.stack.sibling_jumps().iter().skip(ptr.instr_set_idx);
+
+        // NOTE: execute pointed jumps instruction set with the offset.
+        if let Some(ptr_jumps) = sibling_jumps.next() {
+            if ptr.offset == 0 {
+                self.count(ctx, |s| &mut s.jumps, ptr_jumps.clone());
+            }
+
+            self.exec_instr_set_with_attrs(ptr_jumps, attr_matcher, ctx, ptr.offset, match_handler);
+
+            // NOTE: execute remaining jumps instruction sets as usual.
+            for jumps in sibling_jumps {
+                self.count(ctx, |s| &mut s.jumps, jumps.clone());
+
+                self.exec_instr_set_with_attrs(jumps, attr_matcher, ctx, 0, match_handler);
+            }
+        }
+    }
+
+    #[inline]
+    fn try_exec_has_jumps_without_attrs(
+        &self,
+        ctx: &mut ExecutionCtx<'_, E>,
+    ) -> Result<(), Bailout<()>> {
+        self.exec_has_jumps(None, ctx)
+    }
+
+    #[inline]
+    fn exec_has_jumps_with_attrs(
+        &self,
+        attr_matcher: &AttributeMatcher<'_>,
+        ctx: &mut ExecutionCtx<'_, E>,
+    ) {
+        // NOTE: execution without attributes might have been interrupted halfway.
+        ctx.descendant_matches.clear();
+
+        // NOTE: the execution doesn't bail out, once the attributes are provided.
+        let _ = self.exec_has_jumps(Some(attr_matcher), ctx);
+    }
+
+    /// Executes `:has()` jumps of the ancestors and records the ancestors whose
+    /// `:has()` argument is matched by the element. Unlike the other jumps, the
+    /// execution isn't resumed after bailout, but restarted, as it doesn't produce
+    /// matches for the element itself.
+    fn exec_has_jumps(
+        &self,
+        attr_matcher: Option<&AttributeMatcher<'_>>,
+        ctx: &mut ExecutionCtx<'_, E>,
+    ) -> Result<(), Bailout<()>> {
+        let items = self.stack.items();
+        let state = self.stack.build_state(&ctx.stack_item.local_name, ctx.ns);
+
+        if let Some(parent) = items.last() {
+            for jumps in &parent.has_jumps {
+                self.exec_has_instr_set(attr_matcher, &state, ctx, items.len() - 1, jumps)?;
+            }
+        }
+
+        for (level, ancestor) in items.iter().enumerate().rev() {
+            self.count_hereditary_jump_scan(ctx, level, true);
+
+            for jumps in &ancestor.hereditary_has_jumps {
+                self.exec_has_instr_set(attr_matcher, &state, ctx, level, jumps)?;
+            }
+
+            if !ancestor.has_ancestor_with_hereditary_has_jumps {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn exec_has_instr_set(
+        &self,
+        attr_matcher: Option<&AttributeMatcher<'_>>,
+        state: &SelectorState<'_>,
+        ctx: &mut ExecutionCtx<'_, E>,
+        level: usize,
+        addr_range: &AddressRange,
+    ) -> Result<(), Bailout<()>> {
+        self.count(ctx, |s| &mut s.jumps, addr_range.clone());
+
+        for addr in addr_range.clone() {
+            let instr = &self.program.instructions[addr];
+
+            self.count(ctx, |s| &mut s.instructions, [addr]);
+
+            let local_name = &ctx.stack_item.local_name;
+
+            let branch = match attr_matcher {
+                Some(attr_matcher) => instr.exec(state, local_name, attr_matcher),
+                None => match instr.try_exec_without_attrs(state, local_name) {
+                    TryExecResult::Branch(branch) => Some(branch),
+                    TryExecResult::AttributesRequired => {
+                        return Err(Bailout {
+                            at_addr: addr,
+                            recovery_point: (),
+                        });
+                    }
+                    TryExecResult::Fail => None,
+                },
+            };
+
+            if let Some(branch) = branch {
+                ctx.descendant_matches
+                    .extend(branch.matched_payload.iter().map(|&p| (level, p)));
+            }
+        }
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -573,7 +1362,7 @@ mod tests {
     }
 
     #[derive(Default)]
-    struct TestElementData(HashSet<usize>);
+    struct TestElementData(HashSet<usize>, HashSet<usize>);
 
     impl ElementData for TestElementData {
         type MatchPayload = usize;
@@ -581,6 +1370,10 @@ mod tests {
         fn matched_payload_mut(&mut self) -> &mut HashSet<usize> {
             &mut self.0
         }
+
+        fn descendant_matched_payload_mut(&mut self) -> &mut HashSet<usize> {
+            &mut self.1
+        }
     }
 
     struct TestTransformController<T: FnMut(&mut Token<'_>)>(T);
@@ -737,9 +1530,21 @@ mod tests {
 
     macro_rules! exec_for_end_tag_and_assert {
         ($vm:expr, $tag_html:expr, $expected_unmatched_payload:expr) => {
+            exec_for_end_tag_and_assert!($vm, $tag_html, $expected_unmatched_payload, set![])
+        };
+
+        // NOTE: the payload of the `:has()` selectors, whose arguments are matched by
+        // the descendants of the ended elements, is expected separately.
+        (
+            $vm:expr,
+            $tag_html:expr,
+            $expected_unmatched_payload:expr,
+            $expected_descendant_matched_payload:expr
+        ) => {
             test_with_token($tag_html, UTF_8, |t| match t {
                 Token::EndTag(t) => {
                     let mut unmatched_payload = HashMap::default();
+                    let mut descendant_matched_payload = HashSet::default();
 
                     $vm.exec_for_end_tag(local_name!(t), |elem_data: TestElementData| {
                         for payload in elem_data.0 {
@@ -748,9 +1553,15 @@ mod tests {
                                 .and_modify(|c| *c += 1)
                                 .or_insert(1);
                         }
+
+                        descendant_matched_payload.extend(elem_data.1);
                     });
 
                     assert_eq!(unmatched_payload, $expected_unmatched_payload);
+                    assert_eq!(
+                        descendant_matched_payload,
+                        $expected_descendant_matched_payload
+                    );
                 }
                 _ => panic!("End tag expected"),
             });
@@ -1744,4 +2555,604 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn bailout_in_has_jumps() {
+        let mut vm = create_vm!(&["div:has(> span.c1)", "section:has(b.c2)", "div > b.c1"]);
+
+        // Stack after:
+        // - <section>
+        exec_for_start_tag_and_assert!(
+            vm,
+            "<section>",
+            Namespace::Html,
+            Expectation {
+                should_bailout: false,
+                should_match_with_content: true,
+                matched_payload: set![],
+            }
+        );
+
+        // Stack after:
+        // - <section>
+        // - <div>
+        exec_for_start_tag_and_assert!(
+            vm,
+            "<div>",
+            Namespace::Html,
+            Expectation {
+                should_bailout: false,
+                should_match_with_content: true,
+                matched_payload: set![],
+            }
+        );
+
+        // Stack after:
+        // - <section>
+        // - <div>
+        // - <span class=c1>
+        exec_for_start_tag_and_assert!(
+            vm,
+            "<span class=c1>",
+            Namespace::Html,
+            Expectation {
+                should_bailout: true,
+                should_match_with_content: true,
+                matched_payload: set![],
+            }
+        );
+
+        // Stack after:
+        // - <section>
+        // - <div>
+        exec_for_end_tag_and_assert!(vm, "</span>", map![]);
+
+        // Stack after:
+        // - <section>
+        // - <div>
+        // - <b class=c1> (2)
+        exec_for_start_tag_and_assert!(
+            vm,
+            "<b class=c1>",
+            Namespace::Html,
+            Expectation {
+                should_bailout: true,
+                should_match_with_content: true,
+                matched_payload: set![2],
+            }
+        );
+
+        // Stack after:
+        // - <section>
+        // - <div>
+        exec_for_end_tag_and_assert!(vm, "</b>", map![(2, 1)]);
+
+        // Stack after:
+        // - <section>
+        exec_for_end_tag_and_assert!(vm, "</div>", map![], set![0]);
+
+        // Stack after:
+        // - <section>
+        // - <p>
+        exec_for_start_tag_and_assert!(
+            vm,
+            "<p>",
+            Namespace::Html,
+            Expectation {
+                should_bailout: false,
+                should_match_with_content: true,
+                matched_payload: set![],
+            }
+        );
+
+        // Stack after:
+        // - <section>
+        // - <p>
+        // - <b class=c2>
+        exec_for_start_tag_and_assert!(
+            vm,
+            "<b class=c2>",
+            Namespace::Html,
+            Expectation {
+                should_bailout: true,
+                should_match_with_content: true,
+                matched_payload: set![],
+            }
+        );
+
+        // Stack after:
+        // - <section>
+        // - <p>
+        exec_for_end_tag_and_assert!(vm, "</b>", map![]);
+
+        // Stack after:
+        // - <section>
+        exec_for_end_tag_and_assert!(vm, "</p>", map![]);
+
+        // Stack after is empty
+        exec_for_end_tag_and_assert!(vm, "</section>", map![], set![1]);
+    }
+
+    #[test]
+    fn bailout_in_standalone_selectors() {
+        let mut vm = create_vm!(&["li:not(ul > .c1)", "p:is(div > .c2, section .c3)"]);
+
+        // Stack after:
+        // - <ul>
+        exec_for_start_tag_and_assert!(
+            vm,
+            "<ul>",
+            Namespace::Html,
+            Expectation {
+                should_bailout: false,
+                should_match_with_content: true,
+                matched_payload: set![],
+            }
+        );
+
+        // Stack after:
+        // - <ul>
+        // - <li class=c1>
+        exec_for_start_tag_and_assert!(
+            vm,
+            "<li class=c1>",
+            Namespace::Html,
+            Expectation {
+                should_bailout: true,
+                should_match_with_content: true,
+                matched_payload: set![],
+            }
+        );
+
+        // Stack after:
+        // - <ul>
+        exec_for_end_tag_and_assert!(vm, "</li>", map![]);
+
+        // Stack after:
+        // - <ul>
+        // - <li> (0)
+        exec_for_start_tag_and_assert!(
+            vm,
+            "<li>",
+            Namespace::Html,
+            Expectation {
+                should_bailout: true,
+                should_match_with_content: true,
+                matched_payload: set![0],
+            }
+        );
+
+        // Stack after:
+        // - <ul>
+        exec_for_end_tag_and_assert!(vm, "</li>", map![(0, 1)]);
+
+        // Stack after is empty
+        exec_for_end_tag_and_assert!(vm, "</ul>", map![]);
+
+        // Stack after:
+        // - <section>
+        exec_for_start_tag_and_assert!(
+            vm,
+            "<section>",
+            Namespace::Html,
+            Expectation {
+                should_bailout: false,
+                should_match_with_content: true,
+                matched_payload: set![],
+            }
+        );
+
+        // Stack after:
+        // - <section>
+        // - <div>
+        exec_for_start_tag_and_assert!(
+            vm,
+            "<div>",
+            Namespace::Html,
+            Expectation {
+                should_bailout: true,
+                should_match_with_content: true,
+                matched_payload: set![],
+            }
+        );
+
+        // Stack after:
+        // - <section>
+        // - <div>
+        // - <p class=c3> (1)
+        exec_for_start_tag_and_assert!(
+            vm,
+            "<p class=c3>",
+            Namespace::Html,
+            Expectation {
+                should_bailout: true,
+                should_match_with_content: true,
+                matched_payload: set![1],
+            }
+        );
+
+        // Stack after:
+        // - <section>
+        // - <div>
+        exec_for_end_tag_and_assert!(vm, "</p>", map![(1, 1)]);
+
+        // Stack after:
+        // - <section>
+        // - <div>
+        // - <p class=c2> (1)
+        exec_for_start_tag_and_assert!(
+            vm,
+            "<p class=c2>",
+            Namespace::Html,
+            Expectation {
+                should_bailout: true,
+                should_match_with_content: true,
+                matched_payload: set![1],
+            }
+        );
+
+        // Stack after:
+        // - <section>
+        // - <div>
+        exec_for_end_tag_and_assert!(vm, "</p>", map![(1, 1)]);
+
+        // Stack after:
+        // - <section>
+        // - <div>
+        // - <p class=c4>
+        exec_for_start_tag_and_assert!(
+            vm,
+            "<p class=c4>",
+            Namespace::Html,
+            Expectation {
+                should_bailout: true,
+                should_match_with_content: true,
+                matched_payload: set![],
+            }
+        );
+
+        // Stack after is empty
+        exec_for_end_tag_and_assert!(vm, "</section>", map![]);
+    }
+
+    #[test]
+    fn sibling_jumps() {
+        let mut vm = create_vm!(&["h2 + p", "h2 ~ p", "div > h2 ~ .c1"]);
+
+        // Stack after:
+        // - <div>
+        exec_for_start_tag_and_assert!(
+            vm,
+            "<div>",
+            Namespace::Html,
+            Expectation {
+                should_bailout: false,
+                should_match_with_content: true,
+                matched_payload: set![],
+            }
+        );
+
+        // Stack after:
+        // - <div>
+        // - <p>
+        exec_for_start_tag_and_assert!(
+            vm,
+            "<p>",
+            Namespace::Html,
+            Expectation {
+                should_bailout: false,
+                should_match_with_content: true,
+                matched_payload: set![],
+            }
+        );
+
+        // Stack after:
+        // - <div>
+        exec_for_end_tag_and_assert!(vm, "</p>", map![]);
+
+        // Stack after:
+        // - <div>
+        // - <h2>
+        exec_for_start_tag_and_assert!(
+            vm,
+            "<h2>",
+            Namespace::Html,
+            Expectation {
+                should_bailout: false,
+                should_match_with_content: true,
+                matched_payload: set![],
+            }
+        );
+
+        // Stack after:
+        // - <div>
+        // - <h2>
+        // - <p>
+        exec_for_start_tag_and_assert!(
+            vm,
+            "<p>",
+            Namespace::Html,
+            Expectation {
+                should_bailout: false,
+                should_match_with_content: true,
+                matched_payload: set![],
+            }
+        );
+
+        // Stack after:
+        // - <div>
+        exec_for_end_tag_and_assert!(vm, "</h2>", map![]);
+
+        // Void element.
+        // Stack after:
+        // - <div>
+        exec_for_start_tag_and_assert!(
+            vm,
+            "<img class=c1>",
+            Namespace::Html,
+            Expectation {
+                should_bailout: true,
+                should_match_with_content: false,
+                matched_payload: set![2],
+            }
+        );
+
+        // Stack after:
+        // - <div>
+        // - <p> (1)
+        exec_for_start_tag_and_assert!(
+            vm,
+            "<p>",
+            Namespace::Html,
+            Expectation {
+                should_bailout: true,
+                should_match_with_content: true,
+                matched_payload: set![1],
+            }
+        );
+
+        // Stack after:
+        // - <div>
+        exec_for_end_tag_and_assert!(vm, "</p>", map![(1, 1)]);
+
+        // Stack after:
+        // - <div>
+        // - <h2>
+        exec_for_start_tag_and_assert!(
+            vm,
+            "<h2>",
+            Namespace::Html,
+            Expectation {
+                should_bailout: true,
+                should_match_with_content: true,
+                matched_payload: set![],
+            }
+        );
+
+        // Stack after:
+        // - <div>
+        exec_for_end_tag_and_assert!(vm, "</h2>", map![]);
+
+        // Stack after:
+        // - <div>
+        // - <p class=c1> (0, 1, 2)
+        exec_for_start_tag_and_assert!(
+            vm,
+            "<p class=c1>",
+            Namespace::Html,
+            Expectation {
+                should_bailout: true,
+                should_match_with_content: true,
+                matched_payload: set![0, 1, 2],
+            }
+        );
+
+        // Stack after: empty
+        exec_for_end_tag_and_assert!(vm, "</div>", map![(0, 1), (1, 1), (2, 1)]);
+
+        // Root level siblings of the closed <div> don't see its children.
+        exec_for_start_tag_and_assert!(
+            vm,
+            "<p class=c1>",
+            Namespace::Html,
+            Expectation {
+                should_bailout: false,
+                should_match_with_content: true,
+                matched_payload: set![],
+            }
+        );
+    }
+
+    macro_rules! exec_for_start_tag_and_get_matches {
+        ($vm:expr, $tag_html:expr) => {{
+            let mut matches = Vec::new();
+
+            test_with_token($tag_html, UTF_8, |t| match t {
+                Token::StartTag(t) => {
+                    let result = $vm.exec_for_start_tag(
+                        local_name!(t),
+                        Namespace::Html,
+                        &mut |m: MatchInfo<_>| matches.push((m.payload, m.lazy_element_id)),
+                    );
+
+                    assert!(result.is_ok(), "Should match without bailout");
+                }
+                _ => panic!("Start tag expected"),
+            });
+
+            matches.sort_unstable();
+            matches
+        }};
+    }
+
+    fn resolution(element_id: usize, payload: usize, is_match: bool) -> LazyMatchResolution<usize> {
+        LazyMatchResolution {
+            element_id,
+            payload,
+            is_match,
+        }
+    }
+
+    #[test]
+    fn lazy_matches() {
+        let mut vm = create_vm!(&[
+            "li:last-child",
+            "li:nth-last-of-type(2)",
+            "li:not(:only-child)"
+        ]);
+
+        assert_eq!(exec_for_start_tag_and_get_matches!(vm, "<ul>"), vec![]);
+
+        assert_eq!(
+            exec_for_start_tag_and_get_matches!(vm, "<li>"),
+            vec![(0, Some(0)), (1, Some(0)), (2, Some(0))]
+        );
+
+        exec_for_end_tag_and_assert!(vm, "</li>", map![]);
+        assert_eq!(vm.take_lazy_match_resolutions(), vec![]);
+
+        // NOTE: the second child makes `:last-child` impossible for the first one,
+        // and `:not(:only-child)` is known right away for the second one.
+        assert_eq!(
+            exec_for_start_tag_and_get_matches!(vm, "<li>"),
+            vec![(0, Some(1)), (1, Some(1)), (2, None)]
+        );
+
+        assert_eq!(
+            vm.take_lazy_match_resolutions(),
+            vec![resolution(0, 0, false), resolution(0, 2, true)]
+        );
+
+        exec_for_end_tag_and_assert!(vm, "</li>", map![(2, 1)]);
+        exec_for_end_tag_and_assert!(vm, "</ul>", map![]);
+
+        assert_eq!(
+            vm.take_lazy_match_resolutions(),
+            vec![
+                resolution(0, 1, true),
+                resolution(1, 0, true),
+                resolution(1, 1, false)
+            ]
+        );
+
+        // NOTE: lazy matches of the elements that are not closed
+        // are resolved at the end of the document.
+        assert_eq!(
+            exec_for_start_tag_and_get_matches!(vm, "<li>"),
+            vec![(0, Some(2)), (1, Some(2)), (2, None)]
+        );
+
+        vm.exec_for_end();
+
+        assert_eq!(
+            vm.take_lazy_match_resolutions(),
+            vec![resolution(2, 0, true), resolution(2, 1, false)]
+        );
+    }
+
+    #[test]
+    fn added_and_removed_selectors() {
+        let mut vm = create_vm!(&["div", "a:not(div *)"]);
+        let instruction_count = vm.program.instructions.len();
+
+        exec_for_start_tag_and_assert!(
+            vm,
+            "<div>",
+            Namespace::Html,
+            Expectation {
+                should_bailout: false,
+                should_match_with_content: true,
+                matched_payload: set![0],
+            }
+        );
+
+        vm.add_selector(&"div > span[foo]".parse().unwrap(), 2);
+        vm.add_selector(&"span".parse().unwrap(), 3);
+        vm.add_selector(&"*:not(section > *)".parse().unwrap(), 4);
+
+        // NOTE: the `<div>` was open before the selectors were added.
+        exec_for_start_tag_and_assert!(
+            vm,
+            "<span foo>",
+            Namespace::Html,
+            Expectation {
+                should_bailout: false,
+                should_match_with_content: true,
+                matched_payload: set![3, 4],
+            }
+        );
+
+        exec_for_end_tag_and_assert!(vm, "</span>", map![(3, 1), (4, 1)]);
+        exec_for_end_tag_and_assert!(vm, "</div>", map![(0, 1)]);
+
+        exec_for_start_tag_and_assert!(
+            vm,
+            "<div>",
+            Namespace::Html,
+            Expectation {
+                should_bailout: false,
+                should_match_with_content: true,
+                matched_payload: set![0, 4],
+            }
+        );
+
+        exec_for_start_tag_and_assert!(
+            vm,
+            "<span foo>",
+            Namespace::Html,
+            Expectation {
+                should_bailout: true,
+                should_match_with_content: true,
+                matched_payload: set![2, 3, 4],
+            }
+        );
+
+        exec_for_end_tag_and_assert!(vm, "</span>", map![(2, 1), (3, 1), (4, 1)]);
+
+        vm.remove_selector(3);
+
+        exec_for_start_tag_and_assert!(
+            vm,
+            "<span foo>",
+            Namespace::Html,
+            Expectation {
+                should_bailout: true,
+                should_match_with_content: true,
+                matched_payload: set![2, 4],
+            }
+        );
+
+        exec_for_end_tag_and_assert!(vm, "</span>", map![(2, 1), (4, 1)]);
+        exec_for_end_tag_and_assert!(vm, "</div>", map![(0, 1), (4, 1)]);
+
+        exec_for_start_tag_and_assert!(
+            vm,
+            "<section>",
+            Namespace::Html,
+            Expectation {
+                should_bailout: false,
+                should_match_with_content: true,
+                matched_payload: set![4],
+            }
+        );
+
+        // NOTE: the negated selectors of the added selectors don't
+        // interfere with the negated selectors of the existing ones.
+        exec_for_start_tag_and_assert!(
+            vm,
+            "<a>",
+            Namespace::Html,
+            Expectation {
+                should_bailout: false,
+                should_match_with_content: true,
+                matched_payload: set![1],
+            }
+        );
+
+        vm.remove_selector(2);
+        vm.remove_selector(4);
+
+        assert_eq!(vm.program.instructions.len(), instruction_count);
+        assert_eq!(vm.entry_points.len(), 1);
+    }
 }
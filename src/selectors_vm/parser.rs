@@ -1,13 +1,20 @@
-use super::SelectorError;
+use super::error::simple_selector_spans;
+use super::{DetailedSelectorError, SelectorAnalysis, SelectorError};
 use crate::html::Namespace;
-use cssparser::{Parser as CssParser, ParserInput, ToCss};
+use cssparser::{CowRcStr, ParseError, ParseErrorKind, Parser as CssParser, ParserInput, ToCss};
 use selectors::parser::{
     Combinator, Component, NonTSPseudoClass, Parser, PseudoElement, SelectorImpl, SelectorList,
-    SelectorParseErrorKind,
+    SelectorParseError, SelectorParseErrorKind,
 };
-use selectors::parser::{NthSelectorData, ParseRelative};
+use selectors::parser::{
+    NthSelectorData, NthType, ParseRelative, RelativeSelector, RelativeSelectorMatchHint,
+};
+use selectors::visitor::SelectorVisitor;
 use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
 use std::str::FromStr;
+use std::sync::Arc;
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub(crate) struct SelectorImplDescriptor;
@@ -36,7 +43,7 @@ impl std::ops::Deref for CssString {
 
 impl ToCss for CssString {
     fn to_css<W: fmt::Write>(&self, dest: &mut W) -> fmt::Result {
-        dest.write_str(&self.0)
+        cssparser::serialize_identifier(&self.0, dest)
     }
 }
 
@@ -52,6 +59,28 @@ impl precomputed_hash::PrecomputedHash for CssString {
     }
 }
 
+/// Attribute values are serialized as CSS strings, unlike the identifiers.
+#[derive(Clone, Default, Eq, PartialEq)]
+pub struct CssAttrValue(Box<str>);
+
+impl<'a> From<&'a str> for CssAttrValue {
+    fn from(value: &'a str) -> Self {
+        Self(value.into())
+    }
+}
+
+impl CssAttrValue {
+    pub fn to_boxed_slice(&self) -> Box<str> {
+        self.0.clone()
+    }
+}
+
+impl ToCss for CssAttrValue {
+    fn to_css<W: fmt::Write>(&self, dest: &mut W) -> fmt::Result {
+        cssparser::serialize_string(&self.0, dest)
+    }
+}
+
 impl precomputed_hash::PrecomputedHash for Namespace {
     fn precomputed_hash(&self) -> u32 {
         *self as u32
@@ -59,7 +88,7 @@ impl precomputed_hash::PrecomputedHash for Namespace {
 }
 
 impl SelectorImpl for SelectorImplDescriptor {
-    type AttrValue = CssString;
+    type AttrValue = CssAttrValue;
     type Identifier = CssString;
     type LocalName = CssString;
     type NamespacePrefix = CssString;
@@ -67,7 +96,7 @@ impl SelectorImpl for SelectorImplDescriptor {
     type BorrowedNamespaceUrl = Namespace;
     type BorrowedLocalName = CssString;
 
-    type NonTSPseudoClass = NonTSPseudoClassStub;
+    type NonTSPseudoClass = NonTSPseudoClassExt;
     type PseudoElement = PseudoElementStub;
 
     type ExtraMatchingData<'unused> = ();
@@ -87,37 +116,148 @@ impl PseudoElement for PseudoElementStub {
     type Impl = SelectorImplDescriptor;
 }
 
+/// A matcher of the attribute values for the non-standard `:matches-attr()` pseudo-class,
+/// e.g. a compiled regular expression.
+///
+/// See [`Selector::parse_with_attr_matchers`].
+pub type AttributeValueMatcher = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
+/// The `:matches-attr(name, "pattern")` pseudo-class, whose pattern is compiled into a matcher
+/// of the attribute values by the user-provided function.
+#[derive(Clone)]
+pub(crate) struct AttributePattern {
+    pub name: CssString,
+    pub pattern: Box<str>,
+    pub matcher: AttributeValueMatcher,
+}
+
+// NOTE: the same pattern can be compiled by the different functions, so the patterns are equal
+// only if they share the matcher.
+impl PartialEq for AttributePattern {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+            && self.pattern == other.pattern
+            && Arc::ptr_eq(&self.matcher, &other.matcher)
+    }
+}
+
+impl Eq for AttributePattern {}
+
+impl Hash for AttributePattern {
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (*self.name).hash(state);
+        self.pattern.hash(state);
+    }
+}
+
+impl fmt::Debug for AttributePattern {
+    #[cold]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AttributePattern")
+            .field("name", &&*self.name)
+            .field("pattern", &self.pattern)
+            .finish_non_exhaustive()
+    }
+}
+
 #[derive(PartialEq, Eq, Clone, Debug, Hash)]
-pub(crate) enum NonTSPseudoClassStub {}
+pub(crate) enum NonTSPseudoClassExt {
+    MatchesAttr(AttributePattern),
+}
 
-impl NonTSPseudoClass for NonTSPseudoClassStub {
+impl NonTSPseudoClass for NonTSPseudoClassExt {
     type Impl = SelectorImplDescriptor;
 
     fn is_active_or_hover(&self) -> bool {
-        #[allow(clippy::uninhabited_references)]
-        match *self {}
+        false
     }
 
     fn is_user_action_state(&self) -> bool {
-        #[allow(clippy::uninhabited_references)]
-        match *self {}
+        false
     }
 }
 
-impl ToCss for NonTSPseudoClassStub {
-    fn to_css<W: fmt::Write>(&self, _dest: &mut W) -> fmt::Result {
-        #[allow(clippy::uninhabited_references)]
-        match *self {}
+impl ToCss for NonTSPseudoClassExt {
+    fn to_css<W: fmt::Write>(&self, dest: &mut W) -> fmt::Result {
+        match self {
+            Self::MatchesAttr(AttributePattern { name, pattern, .. }) => {
+                dest.write_str(":matches-attr(")?;
+                name.to_css(dest)?;
+                dest.write_str(", ")?;
+                cssparser::serialize_string(pattern, dest)?;
+                dest.write_char(')')
+            }
+        }
+    }
+}
+
+/// The error of the validation of a parsed selector, along with the component that causes it,
+/// unless it's caused by the selector as a whole.
+struct ValidationError<'s> {
+    error: SelectorError,
+    component: Option<&'s Component<SelectorImplDescriptor>>,
+}
+
+impl<'s> ValidationError<'s> {
+    #[inline]
+    fn at(error: SelectorError, component: &'s Component<SelectorImplDescriptor>) -> Self {
+        Self {
+            error,
+            component: Some(component),
+        }
+    }
+
+    /// Attributes the error to the `component`, unless it's attributed to one of the
+    /// components of its arguments.
+    #[inline]
+    fn or_at(mut self, component: &'s Component<SelectorImplDescriptor>) -> Self {
+        self.component.get_or_insert(component);
+        self
+    }
+}
+
+impl From<SelectorError> for ValidationError<'_> {
+    #[inline]
+    fn from(error: SelectorError) -> Self {
+        Self {
+            error,
+            component: None,
+        }
+    }
+}
+
+/// Errors of the selector parser, including the ones of the non-standard pseudo-classes.
+pub(crate) enum ParserErrorKind<'i> {
+    Selector(SelectorParseErrorKind<'i>),
+    InvalidAttributeValuePattern,
+}
+
+impl<'i> From<SelectorParseErrorKind<'i>> for ParserErrorKind<'i> {
+    #[inline]
+    fn from(kind: SelectorParseErrorKind<'i>) -> Self {
+        Self::Selector(kind)
     }
 }
 
-#[allow(dead_code)]
-struct SelectorsParser;
+type CompileAttrPattern<'p> = &'p dyn Fn(&str) -> Option<AttributeValueMatcher>;
 
-impl SelectorsParser {
+struct SelectorsParser<'p> {
+    /// Enables the `:matches-attr()` pseudo-class.
+    compile_attr_pattern: Option<CompileAttrPattern<'p>>,
+}
+
+impl SelectorsParser<'_> {
     fn validate_component(
         component: &Component<SelectorImplDescriptor>,
-    ) -> Result<(), SelectorError> {
+    ) -> Result<(), ValidationError<'_>> {
+        Self::validate_component_kind(component).map_err(|err| err.or_at(component))
+    }
+
+    fn validate_component_kind(
+        component: &Component<SelectorImplDescriptor>,
+    ) -> Result<(), ValidationError<'_>> {
         // NOTE: always use explicit variants in this match, so we
         // get compile-time error if new component types were added to
         // the parser.
@@ -125,12 +265,13 @@ impl SelectorsParser {
         match component {
             Component::Combinator(combinator) => match combinator {
                 // Supported
-                Combinator::Child | Combinator::Descendant => Ok(()),
+                Combinator::Child
+                | Combinator::Descendant
+                | Combinator::NextSibling
+                | Combinator::LaterSibling => Ok(()),
 
                 // Unsupported
-                Combinator::Part => Err(SelectorError::UnsupportedPseudoClassOrElement),
-                Combinator::NextSibling => Err(SelectorError::UnsupportedCombinator('+')),
-                Combinator::LaterSibling => Err(SelectorError::UnsupportedCombinator('~')),
+                Combinator::Part => Err(SelectorError::UnsupportedPseudoClassOrElement.into()),
                 Combinator::PseudoElement | Combinator::SlotAssignment => {
                     unreachable!("Pseudo element combinators should be filtered out at this point")
                 }
@@ -141,90 +282,525 @@ impl SelectorsParser {
             | Component::ExplicitUniversalType
             | Component::ExplicitAnyNamespace
             | Component::ExplicitNoNamespace
+            | Component::Namespace(_, _)
             | Component::ID(_)
             | Component::Class(_)
             | Component::AttributeInNoNamespaceExists { .. }
-            | Component::AttributeInNoNamespace { .. } => Ok(()),
+            | Component::AttributeInNoNamespace { .. }
+            | Component::Root
+            | Component::Scope
+            | Component::Empty
+            | Component::NonTSPseudoClass(NonTSPseudoClassExt::MatchesAttr(_)) => Ok(()),
 
-            Component::Nth(data) => Self::validate_nth(data),
+            Component::Nth(data) => Ok(Self::validate_nth(data)?),
             Component::NthOf(data) => {
-                Self::validate_selectors(data.selectors())?;
-                Self::validate_nth(data.nth_data())
+                Self::validate_selectors(data.selectors(), false)?;
+                Ok(Self::validate_nth(data.nth_data())?)
             }
 
-            Component::Negation(selectors) => selectors
-                .slice()
-                .iter()
-                .try_for_each(|s| s.iter().try_for_each(Self::validate_component)),
+            Component::Negation(selectors) => {
+                Self::validate_selectors(selectors.slice(), false)?;
+
+                // NOTE: the selectors that aren't simple are matched as standalone selectors,
+                // so their outcome must be known once the element's start tag is parsed.
+                for selector in selectors.slice() {
+                    if negated_simple_selector(selector).is_some() {
+                        continue;
+                    }
+
+                    for component in selector.iter_raw_match_order() {
+                        if Self::depends_on_following_siblings(component) {
+                            return Err(ValidationError::at(
+                                SelectorError::UnsupportedSyntax,
+                                component,
+                            ));
+                        }
+
+                        if Self::has_complex_negation(component) {
+                            return Err(ValidationError::at(
+                                SelectorError::NestedNegation,
+                                component,
+                            ));
+                        }
+                    }
+                }
+
+                Ok(())
+            }
+
+            Component::Is(selectors) | Component::Where(selectors) => {
+                Self::validate_selectors(selectors.slice(), false)
+            }
 
             // Unsupported
-            Component::Empty
-            | Component::Part(_)
+            Component::Part(_)
             | Component::Host(_)
-            | Component::Is(_)
-            | Component::Root
-            | Component::Scope
-            | Component::Where(_)
             | Component::PseudoElement(_)
-            | Component::NonTSPseudoClass(_)
-            | Component::Slotted(_) => Err(SelectorError::UnsupportedPseudoClassOrElement),
+            | Component::Slotted(_) => Err(SelectorError::UnsupportedPseudoClassOrElement.into()),
 
-            Component::DefaultNamespace(_)
-            | Component::Namespace(_, _)
-            | Component::AttributeOther(_) => Err(SelectorError::NamespacedSelector),
+            // NOTE: attribute selectors with non-lowercase names end up here as well.
+            Component::AttributeOther(attr) if attr.namespace.is_none() => Ok(()),
+
+            Component::DefaultNamespace(_) | Component::AttributeOther(_) => {
+                Err(SelectorError::NamespacedSelector.into())
+            }
 
             Component::ImplicitScope
             | Component::ParentSelector
             | Component::RelativeSelectorAnchor
-            | Component::Has(_) => Err(SelectorError::UnsupportedSyntax),
-            Component::Invalid(_) => Err(SelectorError::UnexpectedToken),
+            | Component::Has(_) => Err(SelectorError::UnsupportedSyntax.into()),
+            Component::Invalid(_) => Err(SelectorError::UnexpectedToken.into()),
         }
     }
 
-    fn validate(
-        selector_list: SelectorList<SelectorImplDescriptor>,
-    ) -> Result<SelectorList<SelectorImplDescriptor>, SelectorError> {
-        Self::validate_selectors(selector_list.slice())?;
-        Ok(selector_list)
-    }
-
     fn validate_selectors(
         selector_list: &[selectors::parser::Selector<SelectorImplDescriptor>],
-    ) -> Result<(), SelectorError> {
+        allow_has: bool,
+    ) -> Result<(), ValidationError<'_>> {
         for selector in selector_list {
+            // NOTE: components are visited in the match order, so the rightmost
+            // compound selector comes first.
+            let mut in_rightmost_compound = true;
+            let mut has_component = None;
+            let mut is_lazily_matched = false;
+            let mut complex_selector_argument = None;
+
             for component in selector.iter_raw_match_order() {
+                // NOTE: complex selectors in the expanded `:is()` and `:where()` are merged with
+                // the rest of the selector, which is possible only for the leftmost compound
+                // selector (e.g. `:is(ul > li:last-child)` is matched as `ul > li:last-child`).
+                if Self::has_complex_expanded_argument(component) {
+                    if complex_selector_argument.is_some() {
+                        return Err(ValidationError::at(
+                            SelectorError::UnsupportedSyntax,
+                            component,
+                        ));
+                    }
+
+                    complex_selector_argument = Some(component);
+                }
+
+                match component {
+                    Component::Combinator(_) => {
+                        if let Some(argument) = complex_selector_argument {
+                            return Err(ValidationError::at(
+                                SelectorError::UnsupportedSyntax,
+                                argument,
+                            ));
+                        }
+
+                        in_rightmost_compound = false;
+                    }
+                    Component::Has(relative_selectors) if allow_has => {
+                        if !in_rightmost_compound {
+                            return Err(ValidationError::at(
+                                SelectorError::UnsupportedPseudoClassPosition,
+                                component,
+                            ));
+                        }
+
+                        // NOTE: multiple `:has()` pseudo-classes in a compound selector
+                        // would require all of them to match.
+                        if has_component.is_some() {
+                            return Err(ValidationError::at(
+                                SelectorError::UnsupportedSyntax,
+                                component,
+                            ));
+                        }
+
+                        has_component = Some(component);
+
+                        relative_selectors
+                            .iter()
+                            .try_for_each(Self::validate_relative_selector)
+                            .map_err(|err| err.or_at(component))?;
+
+                        continue;
+                    }
+                    _ if Self::depends_on_following_siblings(component) => {
+                        if !in_rightmost_compound {
+                            return Err(ValidationError::at(
+                                SelectorError::UnsupportedPseudoClassPosition,
+                                component,
+                            ));
+                        }
+
+                        is_lazily_matched = true;
+                    }
+                    _ => (),
+                }
+
                 Self::validate_component(component)?;
             }
+
+            if let (Some(has_component), true) = (has_component, is_lazily_matched) {
+                return Err(ValidationError::at(
+                    SelectorError::UnsupportedSyntax,
+                    has_component,
+                ));
+            }
+
+            if Self::expanded_selector_count(selector) > MAX_EXPANDED_SELECTORS {
+                return Err(SelectorError::TooManyExpandedSelectors.into());
+            }
         }
         Ok(())
     }
 
+    /// `:has()` is matched once a descendant of the element matches its argument, without
+    /// looking ahead in the document. So, only relative selectors consisting of a single compound
+    /// selector, matched against the children or descendants of the element, are supported
+    /// (e.g. `:has(> img)` or `:has(img.large)`, but not `:has(+ img)` or `:has(figure img)`).
+    fn validate_relative_selector(
+        relative_selector: &RelativeSelector<SelectorImplDescriptor>,
+    ) -> Result<(), ValidationError<'_>> {
+        let combinator_count = relative_selector
+            .selector
+            .iter_raw_parse_order_from(0)
+            .filter(|c| c.is_combinator())
+            .count();
+
+        match relative_selector.match_hint {
+            RelativeSelectorMatchHint::InChild | RelativeSelectorMatchHint::InSubtree
+                if combinator_count == 1 => {}
+            _ => return Err(SelectorError::UnsupportedSyntax.into()),
+        }
+
+        for component in relative_selector.selector.iter_raw_parse_order_from(0) {
+            match component {
+                Component::RelativeSelectorAnchor | Component::Combinator(_) => (),
+                _ if Self::depends_on_following_siblings(component) => {
+                    return Err(ValidationError::at(
+                        SelectorError::UnsupportedPseudoClassPosition,
+                        component,
+                    ));
+                }
+                Component::Is(_) | Component::Where(_) => {
+                    return Err(ValidationError::at(
+                        SelectorError::UnsupportedSyntax,
+                        component,
+                    ));
+                }
+                _ if Self::has_complex_negation(component) => {
+                    return Err(ValidationError::at(
+                        SelectorError::UnsupportedSyntax,
+                        component,
+                    ));
+                }
+                _ => Self::validate_component(component)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn has_complex_negation(component: &Component<SelectorImplDescriptor>) -> bool {
+        match component {
+            Component::Negation(selectors) => selectors
+                .slice()
+                .iter()
+                .any(|s| negated_simple_selector(s).is_none()),
+            Component::Is(selectors) | Component::Where(selectors) => selectors
+                .slice()
+                .iter()
+                .any(|s| s.iter_raw_match_order().any(Self::has_complex_negation)),
+            _ => false,
+        }
+    }
+
+    fn has_complex_expanded_argument(component: &Component<SelectorImplDescriptor>) -> bool {
+        match component {
+            Component::Is(selectors) | Component::Where(selectors) if is_expanded(component) => {
+                selectors.slice().iter().any(|s| {
+                    s.iter_raw_match_order()
+                        .any(|c| c.is_combinator() || Self::has_complex_expanded_argument(c))
+                })
+            }
+            _ => false,
+        }
+    }
+
+    /// The number of selectors the selector is expanded into (e.g. 4 for
+    /// `:is(a, b):is(:last-child, :only-child)`), saturated at `usize::MAX`.
+    fn expanded_selector_count(
+        selector: &selectors::parser::Selector<SelectorImplDescriptor>,
+    ) -> usize {
+        selector
+            .iter_raw_match_order()
+            .map(|component| match component {
+                Component::Is(selectors) | Component::Where(selectors)
+                    if is_expanded(component) =>
+                {
+                    selectors
+                        .slice()
+                        .iter()
+                        .map(Self::expanded_selector_count)
+                        .fold(0, usize::saturating_add)
+                }
+                _ => 1,
+            })
+            .fold(1, usize::saturating_mul)
+    }
+
+    /// Pseudo-classes like `:last-child` can be decided only once all the following
+    /// siblings of the element have been seen (or its content for `:empty`). So, they are
+    /// matched lazily and can't be used for anything but the element the selector is matching.
+    fn depends_on_following_siblings(component: &Component<SelectorImplDescriptor>) -> bool {
+        match component {
+            Component::Empty => true,
+            Component::Nth(data) => matches!(
+                data.ty,
+                NthType::LastChild | NthType::OnlyChild | NthType::LastOfType | NthType::OnlyOfType
+            ),
+            Component::Negation(selectors) => selectors
+                .slice()
+                .iter()
+                .any(|s| s.iter().any(Self::depends_on_following_siblings)),
+            Component::Is(selectors) | Component::Where(selectors) => selectors
+                .slice()
+                .iter()
+                .any(|s| s.iter().any(Self::depends_on_following_siblings)),
+            _ => false,
+        }
+    }
+
     fn validate_nth(nth: &NthSelectorData) -> Result<(), SelectorError> {
         match nth.ty {
-            selectors::parser::NthType::Child | selectors::parser::NthType::OfType => Ok(()),
-            selectors::parser::NthType::LastChild
-            | selectors::parser::NthType::OnlyChild
-            | selectors::parser::NthType::LastOfType
-            | selectors::parser::NthType::OnlyOfType => {
-                Err(SelectorError::UnsupportedPseudoClassOrElement)
-            }
+            NthType::Child
+            | NthType::OfType
+            | NthType::LastChild
+            | NthType::OnlyChild
+            | NthType::LastOfType
+            | NthType::OnlyOfType => Ok(()),
         }
     }
 
-    #[inline]
-    pub fn parse(selector: &str) -> Result<SelectorList<SelectorImplDescriptor>, SelectorError> {
+    pub fn parse(
+        selector: &str,
+        compile_attr_pattern: Option<CompileAttrPattern<'_>>,
+    ) -> Result<SelectorList<SelectorImplDescriptor>, DetailedSelectorError> {
         let mut input = ParserInput::new(selector);
         let mut css_parser = CssParser::new(&mut input);
 
-        SelectorList::parse(&Self, &mut css_parser, ParseRelative::No)
-            .map_err(SelectorError::from)
-            .and_then(Self::validate)
+        let parser = SelectorsParser {
+            compile_attr_pattern,
+        };
+
+        let selector_list = SelectorList::parse(&parser, &mut css_parser, ParseRelative::No)
+            .map_err(|err| {
+                let location = err.location;
+
+                DetailedSelectorError::new(selector, Self::to_selector_error(err), location)
+            })?;
+
+        if let Err(err) = Self::validate_selectors(selector_list.slice(), true) {
+            let span = err
+                .component
+                .and_then(|c| Self::component_span(selector, &selector_list, c));
+
+            return Err(DetailedSelectorError::with_span(selector, err.error, span));
+        }
+
+        Ok(selector_list)
+    }
+
+    /// Returns the byte range of the `component` of the `selector_list` in the `selector`,
+    /// if it's a pseudo-class or an attribute selector.
+    #[cold]
+    fn component_span(
+        selector: &str,
+        selector_list: &SelectorList<SelectorImplDescriptor>,
+        component: &Component<SelectorImplDescriptor>,
+    ) -> Option<Range<usize>> {
+        let key = Self::source_key(component)?;
+        let mut idx = 0;
+
+        if !Self::find_component(selector_list.slice(), component, &key, &mut idx) {
+            return None;
+        }
+
+        simple_selector_spans(selector)
+            .into_iter()
+            .filter(|(k, _)| *k == key)
+            .nth(idx)
+            .map(|(_, span)| span)
+    }
+
+    /// Returns the key of the component, that is the same as the one of its source text
+    /// returned by [`simple_selector_spans`].
+    fn source_key(component: &Component<SelectorImplDescriptor>) -> Option<String> {
+        let css = component.to_css_string();
+
+        match css.as_bytes().first() {
+            Some(b':') => Some(css[..css.find('(').unwrap_or(css.len())].to_ascii_lowercase()),
+            Some(b'[') => Some("[".into()),
+            _ => None,
+        }
+    }
+
+    /// Looks for the `target` component in the source order, counting the preceding
+    /// components with the same `key` in `idx`.
+    fn find_component(
+        selector_list: &[selectors::parser::Selector<SelectorImplDescriptor>],
+        target: &Component<SelectorImplDescriptor>,
+        key: &str,
+        idx: &mut usize,
+    ) -> bool {
+        for component in selector_list
+            .iter()
+            .flat_map(compounds_in_parse_order)
+            .flatten()
+        {
+            if std::ptr::eq(component, target) {
+                return true;
+            }
+
+            if Self::source_key(component).as_deref() == Some(key) {
+                *idx += 1;
+            }
+
+            let is_found = match component {
+                Component::Negation(list) | Component::Is(list) | Component::Where(list) => {
+                    Self::find_component(list.slice(), target, key, idx)
+                }
+                Component::NthOf(data) => Self::find_component(data.selectors(), target, key, idx),
+                Component::Has(relative_selectors) => relative_selectors.iter().any(|r| {
+                    Self::find_component(std::slice::from_ref(&r.selector), target, key, idx)
+                }),
+                _ => false,
+            };
+
+            if is_found {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    #[cold]
+    fn to_selector_error(err: ParseError<'_, ParserErrorKind<'_>>) -> SelectorError {
+        let kind = match err.kind {
+            ParseErrorKind::Basic(kind) => ParseErrorKind::Basic(kind),
+            ParseErrorKind::Custom(ParserErrorKind::Selector(kind)) => ParseErrorKind::Custom(kind),
+            ParseErrorKind::Custom(ParserErrorKind::InvalidAttributeValuePattern) => {
+                return SelectorError::InvalidAttributeValuePattern;
+            }
+        };
+
+        SelectorError::from(SelectorParseError {
+            kind,
+            location: err.location,
+        })
+    }
+
+    /// Parses the arguments of `:matches-attr(name, "pattern")`.
+    fn parse_matches_attr<'i>(
+        compile_attr_pattern: CompileAttrPattern<'_>,
+        arguments: &mut CssParser<'i, '_>,
+    ) -> Result<NonTSPseudoClassExt, ParseError<'i, ParserErrorKind<'i>>> {
+        let name = CssString::from(&*arguments.expect_ident()?.to_ascii_lowercase());
+
+        arguments.expect_comma()?;
+
+        let location = arguments.current_source_location();
+        let pattern: Box<str> = (&**arguments.expect_string()?).into();
+
+        let matcher = compile_attr_pattern(&pattern).ok_or_else(|| {
+            location.new_custom_error(ParserErrorKind::InvalidAttributeValuePattern)
+        })?;
+
+        Ok(NonTSPseudoClassExt::MatchesAttr(AttributePattern {
+            name,
+            pattern,
+            matcher,
+        }))
     }
 }
 
-impl<'i> Parser<'i> for SelectorsParser {
+impl<'i> Parser<'i> for SelectorsParser<'_> {
     type Impl = SelectorImplDescriptor;
-    type Error = SelectorParseErrorKind<'i>;
+    type Error = ParserErrorKind<'i>;
+
+    fn parse_has(&self) -> bool {
+        true
+    }
+
+    fn parse_is_and_where(&self) -> bool {
+        true
+    }
+
+    /// Invalid selectors in `:is()` and `:where()` are reported instead of being ignored.
+    fn allow_forgiving_selectors(&self) -> bool {
+        false
+    }
+
+    /// There are no `@namespace` rules, so only the predefined prefixes of
+    /// the namespaces the parser can put an element into are supported.
+    fn namespace_for_prefix(&self, prefix: &CssString) -> Option<Namespace> {
+        match &**prefix {
+            "html" => Some(Namespace::Html),
+            "svg" => Some(Namespace::Svg),
+            "math" => Some(Namespace::MathML),
+            _ => None,
+        }
+    }
+
+    fn parse_non_ts_functional_pseudo_class<'t>(
+        &self,
+        name: CowRcStr<'i>,
+        arguments: &mut CssParser<'i, 't>,
+        _after_part: bool,
+    ) -> Result<NonTSPseudoClassExt, ParseError<'i, ParserErrorKind<'i>>> {
+        match self.compile_attr_pattern {
+            Some(compile_attr_pattern) if name.eq_ignore_ascii_case("matches-attr") => {
+                Self::parse_matches_attr(compile_attr_pattern, arguments)
+            }
+            _ => Err(arguments.new_custom_error(
+                SelectorParseErrorKind::UnsupportedPseudoClassOrElement(name),
+            )),
+        }
+    }
+}
+
+/// The maximum number of selectors a selector can be expanded into (see [`is_expanded`]).
+const MAX_EXPANDED_SELECTORS: usize = 1024;
+
+/// `:is()` and `:where()` are matched as standalone selectors, one of which the element must
+/// match. The ones with the arguments that are matched lazily (e.g. `:is(li:last-child, .foo)`)
+/// are expanded into the selectors they consist of instead, as the outcome of the standalone
+/// selectors must be known once the element's start tag is parsed.
+pub(crate) fn is_expanded(component: &Component<SelectorImplDescriptor>) -> bool {
+    matches!(component, Component::Is(_) | Component::Where(_))
+        && SelectorsParser::depends_on_following_siblings(component)
+}
+
+/// Returns the only simple selector of a negated compound selector, ignoring the universal
+/// selector (e.g. `.foo` for `:not(*.foo)`). Such negations are matched as a part of the compound
+/// selector they belong to, while the rest (e.g. `:not(ul > li)` or `:not(a.foo)`) are matched
+/// as standalone selectors, that the element must not match.
+pub(crate) fn negated_simple_selector(
+    selector: &selectors::parser::Selector<SelectorImplDescriptor>,
+) -> Option<&Component<SelectorImplDescriptor>> {
+    let mut components = selector.iter_raw_parse_order_from(0).filter(|c| {
+        !matches!(
+            c,
+            Component::ExplicitUniversalType | Component::ExplicitAnyNamespace
+        )
+    });
+
+    match (components.next(), components.next()) {
+        // NOTE: `*` (or `*|*`) is unmatchable when negated.
+        (None, _) => selector.iter_raw_parse_order_from(0).next(),
+        (Some(component), None) => match component {
+            Component::Combinator(_)
+            | Component::Negation(_)
+            | Component::Is(_)
+            | Component::Where(_) => None,
+            _ => Some(component),
+        },
+        _ => None,
+    }
 }
 
 /// Parsed CSS selector.
@@ -251,11 +827,23 @@ impl<'i> Parser<'i> for SelectorsParser {
 /// ------------------------------ | --------------------------------------------------------------------------------------------------------------------------- |
 /// `*`                            | any element                                                                                                                 |
 /// `E`                            | any element of type `E`                                                                                                     |
+/// <code>ns&#124;E</code>         | an `E` element in the namespace `ns`, which is one of `html`, `svg` or `math`                                               |
+/// <code>ns&#124;*</code>         | any element in the namespace `ns`                                                                                           |
 /// `E:nth-child(n)`               | an `E` element, the n-th child of its parent                                                                                |
 /// `E:first-child`                | an `E` element, first child of its parent                                                                                   |
 /// `E:nth-of-type(n)`             | an `E` element, the n-th sibling of its type                                                                                |
 /// `E:first-of-type`              | an `E` element, first sibling of its type                                                                                   |
-/// `E:not(s)`                     | an `E` element that does not match either compound selector `s`                                                             |
+/// `E:nth-last-child(n)`          | an `E` element, the n-th child of its parent, counting from the last one\*                                                  |
+/// `E:last-child`                 | an `E` element, last child of its parent\*                                                                                  |
+/// `E:only-child`                 | an `E` element, only child of its parent\*                                                                                  |
+/// `E:nth-last-of-type(n)`        | an `E` element, the n-th sibling of its type, counting from the last one\*                                                  |
+/// `E:last-of-type`               | an `E` element, last sibling of its type\*                                                                                  |
+/// `E:only-of-type`               | an `E` element, only sibling of its type\*                                                                                  |
+/// `E:empty`                      | an `E` element that has no children, including text\*                                                                       |
+/// `E:root`, `E:scope`            | an `E` element that is the root of the document, i.e. the first top-level `html` element                                    |
+/// `E:not(s)`                     | an `E` element that does not match any of the selectors `s`\*\*\*\*                                                         |
+/// `E:has(s)`, `E:has(> s)`       | an `E` element that has a descendant (or a child) matching compound selector `s`\*\*                                        |
+/// `E:is(s)`, `E:where(s)`        | an `E` element that matches any of the selectors `s`\*\*\*                                                                  |
 /// `E.warning`                    | an `E` element belonging to the class `warning`                                                                             |
 /// `E#myid`                       | an `E` element with `ID` equal to `"myid"`.                                                                                 |
 /// `E[foo]`                       | an `E` element with a `foo` attribute                                                                                       |
@@ -267,21 +855,601 @@ impl<'i> Parser<'i> for SelectorsParser {
 /// `E[foo$="bar"]`                | an `E` element whose foo attribute value ends exactly with the string `"bar"`                                               |
 /// `E[foo*="bar"]`                | an `E` element whose foo attribute value contains the substring `"bar"`                                                     |
 /// <code>E\[foo&#124;="en"\]</code> | an `E` element whose foo attribute value is a hyphen-separated list of values beginning with `"en"`                         |
+/// `E:matches-attr(foo, "p")`     | an `E` element whose foo attribute value is matched by the pattern `"p"`, if enabled\*\*\*\*\*                              |
 /// `E F`                          | an `F` element descendant of an `E` element                                                                                 |
 /// `E > F`                        | an `F` element child of an `E` element                                                                                      |
+/// `E + F`                        | an `F` element immediately preceded by an `E` element                                                                       |
+/// `E ~ F`                        | an `F` element preceded by an `E` element                                                                                   |
+///
+/// \* These pseudo-classes depend on the following siblings of the element (or on its content for
+/// `:empty`), which are not yet known when the element's start tag is parsed. Such selectors are
+/// matched lazily: the element's output is held back until either its next sibling (or its first
+/// child or text) or the end of its parent is reached, and only then the element content handler
+/// is invoked. They are supported only in the rightmost compound selector (e.g.
+/// `ul > li:last-child`, but not `li:last-child > a`) and don't support text, comment and text
+/// node handlers, as the content of the element has already been processed by that time. Whether an element is empty
+/// is decided by its markup, so the content removed by the handlers isn't taken into account,
+/// while the comments are ignored.
+///
+/// \*\* Whether an element has a matching descendant is known only once the element ends. So, `:has()`
+/// is supported only in the rightmost compound selector and only with [end tag handlers]. Its
+/// argument can be a list of compound selectors, each optionally prefixed with the `>` combinator
+/// (e.g. `article:has(img, > video)`).
+///
+/// \*\*\* The arguments of `:is()` and `:where()` can't contain `:has()`. If they contain the
+/// pseudo-classes that are matched lazily (e.g. `:is(li:last-child, .foo)`), complex selectors
+/// are supported in them only if the selector consists of a single compound selector (e.g.
+/// `:is(ul > li:last-child, .foo)`, but not `main :is(ul > li:last-child, .foo)`).
+///
+/// \*\*\*\* The arguments of `:not()` can be complex selectors (e.g. `li:not(nav li)`), which
+/// can't contain the pseudo-classes that are matched lazily or nested complex `:not()` arguments.
+///
+/// \*\*\*\*\* The non-standard `:matches-attr()` pseudo-class is supported only by the selectors
+/// parsed with [`Selector::parse_with_attr_matchers`], which compiles its patterns.
 ///
 /// [`str`]: https://doc.rust-lang.org/std/primitive.str.html
 /// [`parse`]: https://doc.rust-lang.org/std/primitive.str.html#method.parse
 /// [element content handlers]: struct.Settings.html#structfield.element_content_handlers
+/// [end tag handlers]: struct.ElementContentHandlers.html#method.end_tag
 /// [`FromStr`]: https://doc.rust-lang.org/std/str/trait.FromStr.html
 #[derive(Clone, Debug)]
-pub struct Selector(pub(crate) SelectorList<SelectorImplDescriptor>);
+pub struct Selector(
+    pub(crate) SelectorList<SelectorImplDescriptor>,
+    /// The normalized text of the selector, that the selectors are compared and hashed by.
+    Box<str>,
+    /// The `:matches-attr()` pseudo-classes of the selector, whose matchers aren't reflected
+    /// in the normalized text.
+    Box<[AttributePattern]>,
+);
 
 impl FromStr for Selector {
-    type Err = SelectorError;
+    type Err = DetailedSelectorError;
 
     #[inline]
     fn from_str(selector: &str) -> Result<Self, Self::Err> {
-        Ok(Self(SelectorsParser::parse(selector)?))
+        Ok(Self::new(SelectorsParser::parse(selector, None)?))
+    }
+}
+
+impl Selector {
+    #[inline]
+    fn new(list: SelectorList<SelectorImplDescriptor>) -> Self {
+        let normalized = NormalizedCss(&list).to_string().into_boxed_str();
+        let mut patterns = AttributePatterns::default();
+
+        for selector in list.slice() {
+            selector.visit(&mut patterns);
+        }
+
+        Self(list, normalized, patterns.0.into())
+    }
+
+    /// Parses the selector like [`str::parse`] does, additionally enabling the non-standard `:matches-attr(name, "pattern")` pseudo-class.
+    ///
+    /// `:matches-attr()` matches the elements that have the `name` attribute, whose value is
+    /// matched by the `pattern`. The pattern is compiled into an [`AttributeValueMatcher`] by
+    /// `compile_pattern`, which returns `None` for invalid patterns. So, the pattern syntax is
+    /// up to the caller, e.g. the regular expressions of the [`regex`] crate. As with the other
+    /// attribute selectors, the attribute name is matched case-insensitively, while the raw
+    /// attribute value, without the character references being decoded, is passed to the matcher.
+    ///
+    /// # Example
+    /// ```
+    /// use lol_html::{AttributeValueMatcher, Selector};
+    /// use std::sync::Arc;
+    ///
+    /// // NOTE: a toy pattern syntax: a list of the allowed prefixes separated by `|`.
+    /// let compile_pattern = |pattern: &str| -> Option<AttributeValueMatcher> {
+    ///     let prefixes: Vec<String> = pattern.split('|').map(str::to_owned).collect();
+    ///
+    ///     Some(Arc::new(move |value| prefixes.iter().any(|p| value.starts_with(p))))
+    /// };
+    ///
+    /// let selector =
+    ///     Selector::parse_with_attr_matchers(r#"a:matches-attr(href, "http://|https://")"#, &compile_pattern)
+    ///         .unwrap();
+    ///
+    /// assert_eq!(selector.to_css(), r#"a:matches-attr(href, "http://|https://")"#);
+    ///
+    /// // NOTE: the pseudo-class isn't supported by the other parsing methods.
+    /// assert!(selector.to_css().parse::<Selector>().is_err());
+    /// ```
+    ///
+    /// [`regex`]: https://docs.rs/regex
+    #[inline]
+    pub fn parse_with_attr_matchers(
+        selector: &str,
+        compile_pattern: &dyn Fn(&str) -> Option<AttributeValueMatcher>,
+    ) -> Result<Self, DetailedSelectorError> {
+        Ok(Self::new(SelectorsParser::parse(
+            selector,
+            Some(compile_pattern),
+        )?))
+    }
+
+    /// Returns the [specificity] of the selector.
+    ///
+    /// The specificity of a selector list (e.g. `div, .foo`) is the specificity
+    /// of its most specific selector.
+    ///
+    /// # Example
+    /// ```
+    /// use lol_html::{Selector, Specificity};
+    ///
+    /// let selector: Selector = "ul > li.item:first-child".parse().unwrap();
+    ///
+    /// assert_eq!(
+    ///     selector.specificity(),
+    ///     Specificity { ids: 0, classes: 2, elements: 2 }
+    /// );
+    /// ```
+    ///
+    /// [specificity]: https://www.w3.org/TR/selectors-4/#specificity-rules
+    #[must_use]
+    pub fn specificity(&self) -> Specificity {
+        self.list_specificities().max().unwrap_or_default()
+    }
+
+    /// Returns the specificity of each selector in the list.
+    pub(crate) fn list_specificities(&self) -> impl Iterator<Item = Specificity> + '_ {
+        self.0
+            .slice()
+            .iter()
+            .map(|s| Specificity::from_packed(s.specificity()))
+    }
+
+    /// Returns the distinct specificities of the selectors in the list, in the order
+    /// of their first occurrence.
+    pub(crate) fn distinct_specificities(&self) -> Vec<Specificity> {
+        let mut specificities = Vec::new();
+
+        for specificity in self.list_specificities() {
+            if !specificities.contains(&specificity) {
+                specificities.push(specificity);
+            }
+        }
+
+        specificities
+    }
+
+    /// Returns the static analysis of the selector, e.g. the local names of the elements it can
+    /// match.
+    ///
+    /// # Example
+    /// ```
+    /// use lol_html::Selector;
+    ///
+    /// let selector: Selector = "nav a[href], nav area[href]".parse().unwrap();
+    /// let analysis = selector.analyze();
+    ///
+    /// assert_eq!(analysis.local_names.unwrap(), ["a", "area"]);
+    /// assert_eq!(analysis.required_attributes, ["href"]);
+    /// assert!(analysis.needs_attributes);
+    /// assert!(analysis.is_hereditary);
+    /// assert!(!analysis.needs_nth_counters);
+    /// ```
+    #[must_use]
+    pub fn analyze(&self) -> SelectorAnalysis {
+        SelectorAnalysis::new(self)
+    }
+
+    /// Returns `true` if the selector uses the pseudo-classes, that are matched lazily
+    /// (e.g. `:last-child`).
+    pub(crate) fn is_matched_lazily(&self) -> bool {
+        self.0.slice().iter().any(|s| {
+            s.iter_raw_match_order()
+                .any(SelectorsParser::depends_on_following_siblings)
+        })
+    }
+
+    /// Returns `true` if the selector uses `:has()`, so it's known whether an element matches it
+    /// only once the element ends.
+    pub(crate) fn is_matched_on_end_tag(&self) -> bool {
+        self.0.slice().iter().any(|s| {
+            s.iter_raw_match_order()
+                .any(|c| matches!(c, Component::Has(_)))
+        })
+    }
+
+    /// Returns the content handlers, that can be invoked for the elements matched by the selector.
+    pub(crate) fn supported_handlers(&self) -> SupportedHandlers {
+        if self.is_matched_on_end_tag() {
+            SupportedHandlers::EndTag
+        } else if self.is_matched_lazily() {
+            SupportedHandlers::NoContent
+        } else {
+            SupportedHandlers::All
+        }
+    }
+
+    /// Serializes the selector to its canonical CSS text.
+    ///
+    /// The selector is serialized following the [CSSOM] rules, with the local names and the
+    /// attribute names lowercased, as they're matched case-insensitively. So, the selectors that
+    /// differ only in whitespace, quoting, escaping or the case of the names (e.g. `A[HREF='foo']`
+    /// and `a[href="foo"]`) have the same canonical text. Parsing the canonical text produces
+    /// an equivalent selector.
+    ///
+    /// Selectors are compared and hashed by their canonical text. The selectors with
+    /// `:matches-attr()` are equal only if they also share the matchers of the patterns (e.g. if
+    /// one of them is a clone of the other), as the same pattern can be compiled into different
+    /// matchers.
+    ///
+    /// # Example
+    /// ```
+    /// use lol_html::Selector;
+    ///
+    /// let selector: Selector = "ul>li:nth-child( 2n + 1 ) ,  A[HREF='foo']".parse().unwrap();
+    ///
+    /// assert_eq!(selector.to_css(), r#"ul > li:nth-child(2n+1), a[href="foo"]"#);
+    /// assert_eq!(selector, selector.to_css().parse().unwrap());
+    /// ```
+    ///
+    /// [CSSOM]: https://drafts.csswg.org/cssom/#serializing-selectors
+    #[must_use]
+    pub fn to_css(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl fmt::Display for Selector {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.1)
+    }
+}
+
+impl PartialEq for Selector {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        self.1 == other.1 && self.2 == other.2
+    }
+}
+
+impl Eq for Selector {}
+
+impl Hash for Selector {
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.1.hash(state);
+    }
+}
+
+/// Returns the compound selectors of the `selector` in the order they're written in.
+fn compounds_in_parse_order(
+    selector: &selectors::parser::Selector<SelectorImplDescriptor>,
+) -> impl Iterator<Item = &[Component<SelectorImplDescriptor>]> {
+    // NOTE: the compound selectors are stored in the reverse order, unlike their contents.
+    selector
+        .iter_raw_match_order()
+        .as_slice()
+        .split(Component::is_combinator)
+        .rev()
+}
+
+/// Collects the `:matches-attr()` pseudo-classes, including the ones in the arguments
+/// of the other pseudo-classes.
+#[derive(Default)]
+struct AttributePatterns(Vec<AttributePattern>);
+
+impl SelectorVisitor for AttributePatterns {
+    type Impl = SelectorImplDescriptor;
+
+    fn visit_simple_selector(&mut self, component: &Component<SelectorImplDescriptor>) -> bool {
+        if let Component::NonTSPseudoClass(NonTSPseudoClassExt::MatchesAttr(pattern)) = component {
+            self.0.push(pattern.clone());
+        }
+
+        true
+    }
+}
+
+/// Serializes the selector list like [`ToCss`] does, but with the lowercased local names and
+/// attribute names.
+struct NormalizedCss<'s>(&'s SelectorList<SelectorImplDescriptor>);
+
+impl NormalizedCss<'_> {
+    fn write_list<W: fmt::Write>(
+        list: &SelectorList<SelectorImplDescriptor>,
+        dest: &mut W,
+    ) -> fmt::Result {
+        for (idx, selector) in list.slice().iter().enumerate() {
+            if idx > 0 {
+                dest.write_str(", ")?;
+            }
+
+            Self::write_selector(selector, dest)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_selector<W: fmt::Write>(
+        selector: &selectors::parser::Selector<SelectorImplDescriptor>,
+        dest: &mut W,
+    ) -> fmt::Result {
+        let mut combinators = selector
+            .iter_raw_match_order()
+            .rev()
+            .filter_map(Component::as_combinator);
+
+        for compound in compounds_in_parse_order(selector) {
+            for component in compound {
+                Self::write_component(component, dest)?;
+            }
+
+            if let Some(combinator) = combinators.next() {
+                // NOTE: the relative combinator of the `:has()` argument is written without
+                // the leading space, as the anchor before it isn't written.
+                if let [Component::RelativeSelectorAnchor] = compound {
+                    let mut css = String::new();
+
+                    combinator.to_css(&mut css)?;
+                    dest.write_str(css.trim_start())?;
+                } else {
+                    combinator.to_css(dest)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_component<W: fmt::Write>(
+        component: &Component<SelectorImplDescriptor>,
+        dest: &mut W,
+    ) -> fmt::Result {
+        match component {
+            Component::LocalName(name) => name.lower_name.to_css(dest),
+            Component::AttributeInNoNamespaceExists {
+                local_name_lower, ..
+            } => {
+                dest.write_char('[')?;
+                local_name_lower.to_css(dest)?;
+                dest.write_char(']')
+            }
+            Component::AttributeOther(attr) => {
+                let mut attr = attr.clone();
+
+                attr.local_name = attr.local_name_lower.clone();
+                Component::AttributeOther(attr).to_css(dest)
+            }
+            Component::Negation(list) => Self::write_pseudo_class(":not(", list, dest),
+            Component::Is(list) => Self::write_pseudo_class(":is(", list, dest),
+            Component::Where(list) => Self::write_pseudo_class(":where(", list, dest),
+            Component::Has(relative_selectors) => {
+                dest.write_str(":has(")?;
+
+                for (idx, relative_selector) in relative_selectors.iter().enumerate() {
+                    if idx > 0 {
+                        dest.write_str(", ")?;
+                    }
+
+                    Self::write_selector(&relative_selector.selector, dest)?;
+                }
+
+                dest.write_char(')')
+            }
+            Component::RelativeSelectorAnchor => Ok(()),
+            component => component.to_css(dest),
+        }
+    }
+
+    fn write_pseudo_class<W: fmt::Write>(
+        prefix: &str,
+        list: &SelectorList<SelectorImplDescriptor>,
+        dest: &mut W,
+    ) -> fmt::Result {
+        dest.write_str(prefix)?;
+        Self::write_list(list, dest)?;
+        dest.write_char(')')
+    }
+}
+
+impl fmt::Display for NormalizedCss<'_> {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Self::write_list(self.0, f)
+    }
+}
+
+/// The content handlers, that can be invoked for the elements matched by a [`Selector`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum SupportedHandlers {
+    All,
+    /// The selector is matched lazily, once the content of the element is already processed.
+    NoContent,
+    /// The selector is matched on the end tag of the element, as it uses `:has()`.
+    EndTag,
+}
+
+/// The [specificity] of a [`Selector`].
+///
+/// Specificities are compared by the number of ID selectors first, then by the number of class,
+/// attribute and pseudo-class selectors, and then by the number of type selectors.
+///
+/// [specificity]: https://www.w3.org/TR/selectors-4/#specificity-rules
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Specificity {
+    /// The number of ID selectors.
+    pub ids: u32,
+    /// The number of class selectors, attribute selectors and pseudo-classes.
+    pub classes: u32,
+    /// The number of type selectors.
+    pub elements: u32,
+}
+
+impl Specificity {
+    /// Unpacks the specificity computed by the `selectors` crate.
+    #[inline]
+    const fn from_packed(packed: u32) -> Self {
+        const BITS: u32 = 10;
+        const MASK: u32 = (1 << BITS) - 1;
+
+        Self {
+            ids: packed >> (2 * BITS),
+            classes: (packed >> BITS) & MASK,
+            elements: packed & MASK,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn to_css() {
+        for (selector, expected) in [
+            ("*", "*"),
+            ("div", "div"),
+            ("  ul>li  ", "ul > li"),
+            ("a b+c~d", "a b + c ~ d"),
+            ("#foo.bar", "#foo.bar"),
+            (".\\31 23", ".\\31 23"),
+            ("[foo]", "[foo]"),
+            ("[foo=bar]", r#"[foo="bar"]"#),
+            ("[foo='b\"ar']", r#"[foo="b\"ar"]"#),
+            ("[foo~=bar i]", r#"[foo~="bar" i]"#),
+            ("[foo|=bar s]", r#"[foo|="bar" s]"#),
+            (
+                "[foo^=bar][foo$=bar][foo*=bar]",
+                r#"[foo^="bar"][foo$="bar"][foo*="bar"]"#,
+            ),
+            ("svg|a, |p, *|b", "svg|a, |p, b"),
+            (":nth-child( 2n + 1 )", ":nth-child(2n+1)"),
+            (":nth-of-type(odd)", ":nth-of-type(2n+1)"),
+            (":first-child:last-of-type", ":first-child:last-of-type"),
+            ("p:not( .foo ,[bar] )", "p:not(.foo, [bar])"),
+            ("li:not(nav li)", "li:not(nav li)"),
+            ("div:is(a, img)", "div:is(a, img)"),
+            ("section:has(>h1, a)", "section:has(> h1, a)"),
+            ("UL > Li:not([DATA-x=Y])", r#"ul > li:not([data-x="Y"])"#),
+            (
+                "A[HREF=Bar i]:has(> SPAN)",
+                r#"a[href="Bar" i]:has(> span)"#,
+            ),
+        ] {
+            let parsed: Selector = selector.parse().unwrap();
+
+            assert_eq!(parsed.to_css(), expected, "{selector}");
+            assert_eq!(parsed.to_string(), expected, "{selector}");
+
+            let reparsed: Selector = expected.parse().unwrap();
+
+            assert_eq!(reparsed.to_css(), expected, "{selector}");
+        }
+    }
+
+    #[test]
+    fn matches_attr() {
+        let compile_pattern = |pattern: &str| -> Option<AttributeValueMatcher> {
+            let pattern = pattern.to_owned();
+
+            (!pattern.is_empty()).then(|| Arc::new(move |value: &str| value == pattern) as _)
+        };
+
+        let parse = |selector| Selector::parse_with_attr_matchers(selector, &compile_pattern);
+
+        for (selector, expected) in [
+            (
+                r#"a:matches-attr(href, "foo")"#,
+                r#"a:matches-attr(href, "foo")"#,
+            ),
+            (
+                "a:MATCHES-ATTR( HREF ,'f\"oo' )",
+                r#"a:matches-attr(href, "f\"oo")"#,
+            ),
+            (
+                r#":not(:matches-attr(data-x, "foo"))"#,
+                r#":not(:matches-attr(data-x, "foo"))"#,
+            ),
+        ] {
+            assert_eq!(parse(selector).unwrap().to_css(), expected, "{selector}");
+        }
+
+        for (selector, error, offset) in [
+            (
+                r#"a:matches-attr(href, "")"#,
+                SelectorError::InvalidAttributeValuePattern,
+                21,
+            ),
+            (
+                r"a:matches-attr(href, foo)",
+                SelectorError::UnexpectedToken,
+                21,
+            ),
+            (
+                r#"a:matches-attr("foo")"#,
+                SelectorError::UnexpectedToken,
+                15,
+            ),
+            (r"a:matches-attr(href)", SelectorError::UnexpectedEnd, 19),
+        ] {
+            let err = parse(selector).unwrap_err();
+
+            assert_eq!((err.error(), err.offset()), (error, offset), "{selector}");
+        }
+
+        assert_eq!(
+            r#"a:matches-attr(href, "foo")"#.parse::<Selector>().unwrap_err().error(),
+            SelectorError::UnsupportedPseudoClassOrElement
+        );
+
+        let selector = parse(r#"p, :not(a:matches-attr(href, "foo"))"#).unwrap();
+
+        assert_eq!(selector, selector.clone());
+
+        // NOTE: the selectors with the same pattern compiled into the different matchers aren't
+        // equal, even though they have the same text.
+        assert_ne!(
+            selector,
+            Selector::parse_with_attr_matchers(r#"p, :not(a:matches-attr(href, "foo"))"#, &|_| {
+                Some(Arc::new(|_: &str| true))
+            })
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn eq_and_hash() {
+        let selectors: HashSet<Selector> =
+            ["a[href='foo']", "a[href=\"foo\"]", " a[ href = foo ] "]
+                .into_iter()
+                .map(|s| s.parse().unwrap())
+                .collect();
+
+        assert_eq!(selectors.len(), 1);
+
+        let a: Selector = "a, b".parse().unwrap();
+        let b: Selector = "b, a".parse().unwrap();
+
+        assert_ne!(a, b);
+
+        let selectors: HashSet<Selector> = [
+            "ul > li:not(.Foo [data-X]), A[HREF=Bar i]:has(> SPAN)",
+            "UL > Li:not(.Foo [DATA-x]), a[href=Bar i]:has(> span)",
+        ]
+        .into_iter()
+        .map(|s| s.parse().unwrap())
+        .collect();
+
+        assert_eq!(selectors.len(), 1);
+
+        // NOTE: the class names, the ids and the attribute values are case-sensitive.
+        for (a, b) in [
+            (".foo", ".FOO"),
+            ("#foo", "#FOO"),
+            ("[foo=bar]", "[foo=BAR]"),
+            (":is(a, [foo=bar])", ":is(a, [foo=BAR])"),
+            ("a.b", ".ba"),
+            ("a.b c", "a .bc"),
+        ] {
+            assert_ne!(
+                a.parse::<Selector>().unwrap(),
+                b.parse::<Selector>().unwrap()
+            );
+        }
     }
 }
@@ -1,21 +1,144 @@
 use super::attribute_matcher::AttributeMatcher;
 use super::compiler::{CompiledAttributeExpr, CompiledLocalNameExpr};
-use super::SelectorState;
+use super::{Expr, OnParentEndExpr, SelectorState};
 use crate::html::LocalName;
-use hashbrown::HashSet;
+use hashbrown::{HashMap, HashSet};
 use std::hash::Hash;
 use std::ops::Range;
 
 pub(crate) type AddressRange = Range<usize>;
+pub(crate) type OnParentEndExprs = Box<[Expr<OnParentEndExpr>]>;
 
-#[derive(Debug, PartialEq, Eq)]
+/// Returns the new location of the address (or the id) once the `removed` ones are freed and
+/// the following ones are moved in their place.
+#[inline]
+pub(crate) fn relocation_after_removal(removed: &Range<usize>) -> impl Fn(usize) -> usize {
+    let Range { start, end } = *removed;
+
+    move |addr| {
+        if addr >= end {
+            addr - (end - start)
+        } else {
+            addr
+        }
+    }
+}
+
+/// Payload that is matched only if the conditions on the following siblings
+/// of the element (e.g. `:last-child`) hold. These are decided lazily.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub(crate) struct LazyPayload<P>
+where
+    P: Hash + Eq,
+{
+    pub payload: HashSet<P>,
+    pub exprs: OnParentEndExprs,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub(crate) struct ExecutionBranch<P>
 where
     P: Hash + Eq,
 {
     pub matched_payload: HashSet<P>,
+    pub lazy_payload: Option<LazyPayload<P>>,
+    /// Ids of the negated selectors the element must not match (e.g. `:not(ul > li)`).
+    /// The branch is taken once all the instructions for the element are executed.
+    pub negated_selectors: Box<[usize]>,
+    /// Ids of the standalone selectors the element must match (e.g. `:is(ul > li, ol > li)`).
+    /// The branch is taken once all the instructions for the element are executed.
+    pub required_selectors: Box<[usize]>,
+    /// Ids of the standalone selectors matched by the element.
+    pub matched_standalone_selectors: Box<[usize]>,
     pub jumps: Option<AddressRange>,
     pub hereditary_jumps: Option<AddressRange>,
+    pub next_sibling_jumps: Option<AddressRange>,
+    pub later_sibling_jumps: Option<AddressRange>,
+    /// Instructions for the arguments of `:has()`, that are executed for the children
+    /// of the element. Payload of the matched instructions belongs to the element.
+    pub has_jumps: Option<AddressRange>,
+    /// Same as `has_jumps`, but executed for all the descendants of the element.
+    pub hereditary_has_jumps: Option<AddressRange>,
+}
+
+impl<P> ExecutionBranch<P>
+where
+    P: Hash + Eq,
+{
+    /// Addresses of the instructions the branch jumps to.
+    fn jump_addrs(&self) -> impl Iterator<Item = usize> + '_ {
+        [
+            &self.jumps,
+            &self.hereditary_jumps,
+            &self.next_sibling_jumps,
+            &self.later_sibling_jumps,
+            &self.has_jumps,
+            &self.hereditary_has_jumps,
+        ]
+        .into_iter()
+        .flatten()
+        .cloned()
+        .flatten()
+    }
+
+    /// Relocates the addresses of the jumps and the ids of the standalone selectors of the branch.
+    fn relocate(
+        &mut self,
+        relocate_addr: &impl Fn(usize) -> usize,
+        relocate_id: &impl Fn(usize) -> usize,
+    ) {
+        for jumps in [
+            &mut self.jumps,
+            &mut self.hereditary_jumps,
+            &mut self.next_sibling_jumps,
+            &mut self.later_sibling_jumps,
+            &mut self.has_jumps,
+            &mut self.hereditary_has_jumps,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *jumps = relocate_addr(jumps.start)..relocate_addr(jumps.end);
+        }
+
+        for id in self
+            .negated_selectors
+            .iter_mut()
+            .chain(self.required_selectors.iter_mut())
+            .chain(self.matched_standalone_selectors.iter_mut())
+        {
+            *id = relocate_id(*id);
+        }
+    }
+
+    /// Whether the branch depends on the standalone selectors matched by the element.
+    #[inline]
+    pub fn is_conditional(&self) -> bool {
+        !self.negated_selectors.is_empty() || !self.required_selectors.is_empty()
+    }
+
+    /// Whether the branch is taken, given the ids of the standalone selectors matched by the
+    /// element.
+    #[inline]
+    pub fn is_taken(&self, matched_standalone_selectors: &[usize]) -> bool {
+        self.required_selectors
+            .iter()
+            .all(|id| matched_standalone_selectors.contains(id))
+            && !self
+                .negated_selectors
+                .iter()
+                .any(|id| matched_standalone_selectors.contains(id))
+    }
+
+    /// The smallest id of the standalone selectors the branch depends on.
+    #[inline]
+    pub fn min_condition_id(&self) -> Option<usize> {
+        self.negated_selectors
+            .iter()
+            .chain(self.required_selectors.iter())
+            .copied()
+            .min()
+    }
 }
 
 /// The result of trying to execute an instruction without having parsed all attributes
@@ -31,6 +154,7 @@ where
     Fail,
 }
 
+#[derive(Clone)]
 pub(crate) struct Instruction<P>
 where
     P: Hash + Eq,
@@ -89,13 +213,141 @@ where
     }
 }
 
+#[derive(Clone)]
 pub(crate) struct Program<P>
 where
     P: Hash + Eq,
 {
-    pub instructions: Box<[Instruction<P>]>,
+    pub instructions: Vec<Instruction<P>>,
     pub entry_points: AddressRange,
     /// Enables tracking child types for nth-of-type selectors.
     /// This is disabled if no nth-of-type selectors are used in the program.
     pub enable_nth_of_type: bool,
+    pub standalone_selector_count: usize,
+}
+
+impl<P> Program<P>
+where
+    P: Hash + Eq,
+{
+    /// Appends the instructions of the `program` to the end of the program, so the addresses
+    /// of the existing instructions stay valid. Returns the relocated entry points of the
+    /// appended program.
+    pub fn append(&mut self, program: Program<P>) -> AddressRange {
+        let addr_offset = self.instructions.len();
+        let standalone_selector_offset = self.standalone_selector_count;
+
+        self.instructions
+            .extend(program.instructions.into_iter().map(|mut instr| {
+                instr
+                    .associated_branch
+                    .relocate(&|addr| addr + addr_offset, &|id| {
+                        id + standalone_selector_offset
+                    });
+
+                instr
+            }));
+
+        self.enable_nth_of_type |= program.enable_nth_of_type;
+        self.standalone_selector_count += program.standalone_selector_count;
+
+        program.entry_points.start + addr_offset..program.entry_points.end + addr_offset
+    }
+
+    /// Returns the payload of the selectors, that each of the instructions is executed for,
+    /// i.e. the payload matched by the instruction or by the instructions that follow it.
+    pub fn instruction_owners(&self) -> Vec<HashSet<P>>
+    where
+        P: Copy,
+    {
+        let mut dependent_addrs = HashMap::<usize, Vec<usize>>::default();
+
+        for (addr, instr) in self.instructions.iter().enumerate() {
+            let branch = &instr.associated_branch;
+
+            for &id in branch
+                .negated_selectors
+                .iter()
+                .chain(branch.required_selectors.iter())
+            {
+                dependent_addrs.entry(id).or_default().push(addr);
+            }
+        }
+
+        let mut owners: Vec<HashSet<P>> = self
+            .instructions
+            .iter()
+            .map(|instr| {
+                let branch = &instr.associated_branch;
+
+                branch
+                    .matched_payload
+                    .iter()
+                    .chain(branch.lazy_payload.iter().flat_map(|l| &l.payload))
+                    .copied()
+                    .collect()
+            })
+            .collect();
+
+        // NOTE: the instructions of the standalone selectors are executed for the selectors,
+        // whose branches depend on them.
+        let following_addrs = self
+            .instructions
+            .iter()
+            .map(|instr| {
+                let branch = &instr.associated_branch;
+
+                branch
+                    .jump_addrs()
+                    .chain(
+                        branch
+                            .matched_standalone_selectors
+                            .iter()
+                            .filter_map(|id| dependent_addrs.get(id))
+                            .flatten()
+                            .copied(),
+                    )
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        let mut is_changed = true;
+
+        while is_changed {
+            is_changed = false;
+
+            for (addr, following_addrs) in following_addrs.iter().enumerate().rev() {
+                for &following_addr in following_addrs {
+                    let new_owners: Vec<_> = owners[following_addr]
+                        .difference(&owners[addr])
+                        .copied()
+                        .collect();
+
+                    if !new_owners.is_empty() {
+                        owners[addr].extend(new_owners);
+                        is_changed = true;
+                    }
+                }
+            }
+        }
+
+        owners
+    }
+
+    /// Removes the instructions and the standalone selectors of an appended program. The
+    /// following instructions are moved in place of the removed ones, so the jumps to them
+    /// need to be relocated with [`relocation_after_removal`].
+    pub fn remove(&mut self, instructions: AddressRange, standalone_selectors: Range<usize>) {
+        let relocate_addr = relocation_after_removal(&instructions);
+        let relocate_id = relocation_after_removal(&standalone_selectors);
+
+        self.standalone_selector_count -= standalone_selectors.len();
+        self.instructions.drain(instructions);
+
+        for instr in &mut self.instructions {
+            instr
+                .associated_branch
+                .relocate(&relocate_addr, &relocate_id);
+        }
+    }
 }
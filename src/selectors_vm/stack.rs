@@ -1,5 +1,5 @@
-use super::ast::NthChild;
-use super::program::AddressRange;
+use super::ast::{NthChild, OnParentEndExpr};
+use super::program::{relocation_after_removal, AddressRange, OnParentEndExprs};
 use super::SelectorState;
 use crate::html::{LocalName, Namespace, Tag};
 use crate::memory::{LimitedVec, MemoryLimitExceededError, SharedMemoryLimiter};
@@ -38,9 +38,13 @@ fn is_void_element(local_name: &LocalName<'_>, enable_esi_tags: bool) -> bool {
 }
 
 pub(crate) trait ElementData: Default + 'static {
-    type MatchPayload: PartialEq + Eq + Copy + Debug + Hash + 'static;
+    type MatchPayload: PartialEq + Eq + Copy + Debug + Hash + Send + 'static;
 
     fn matched_payload_mut(&mut self) -> &mut HashSet<Self::MatchPayload>;
+
+    /// Payload matched by the element once its child or descendant has matched
+    /// the argument of `:has()`.
+    fn descendant_matched_payload_mut(&mut self) -> &mut HashSet<Self::MatchPayload>;
 }
 
 pub(crate) enum StackDirective {
@@ -71,6 +75,128 @@ impl ChildCounter {
     pub const fn is_nth(&self, nth: NthChild) -> bool {
         nth.has_index(self.cumulative)
     }
+
+    #[inline]
+    #[must_use]
+    pub const fn index(&self) -> i32 {
+        self.cumulative
+    }
+}
+
+/// A match of an element that depends on the element's following siblings (e.g. `:last-child`)
+/// or on its content (`:empty`).
+///
+/// Lazy matches are stored in the element's parent and get resolved either once a
+/// sibling or the content of the element makes the match impossible, or once the parent ends.
+pub(crate) struct LazyMatch<P> {
+    pub element_id: usize,
+    pub payload: P,
+    pub exprs: OnParentEndExprs,
+    pub local_name: LocalName<'static>,
+    pub index: i32,
+    pub index_of_type: i32,
+    /// Whether the element is empty, once it's known.
+    pub is_empty: Option<bool>,
+}
+
+/// Lazy match with a known outcome.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct LazyMatchResolution<P> {
+    pub element_id: usize,
+    pub payload: P,
+    pub is_match: bool,
+}
+
+impl<P: Copy> LazyMatch<P> {
+    /// Returns the outcome of the match if it's already known. `count` and `count_of_type`
+    /// are the numbers of siblings seen so far, and are final if `parent_ended` is set.
+    fn resolve(&self, count: i32, count_of_type: i32, parent_ended: bool) -> Option<bool> {
+        let mut is_match = Some(true);
+
+        for expr in &*self.exprs {
+            let expr_result = match expr.simple_expr {
+                // NOTE: the element has ended by the time its parent ends.
+                OnParentEndExpr::Empty if parent_ended => Some(self.is_empty.unwrap_or(true)),
+                OnParentEndExpr::Empty => self.is_empty,
+                OnParentEndExpr::NthLastChild(nth) => {
+                    Self::resolve_nth_last(nth, self.index, count, parent_ended)
+                }
+                OnParentEndExpr::NthLastOfType(nth) => {
+                    Self::resolve_nth_last(nth, self.index_of_type, count_of_type, parent_ended)
+                }
+                OnParentEndExpr::OnlyChild if self.index != 1 => Some(false),
+                OnParentEndExpr::OnlyChild => {
+                    Self::resolve_nth_last(NthChild::new(0, 1), self.index, count, parent_ended)
+                }
+                OnParentEndExpr::OnlyOfType if self.index_of_type != 1 => Some(false),
+                OnParentEndExpr::OnlyOfType => Self::resolve_nth_last(
+                    NthChild::new(0, 1),
+                    self.index_of_type,
+                    count_of_type,
+                    parent_ended,
+                ),
+            };
+
+            match expr_result.map(|r| r != expr.negation) {
+                Some(false) => return Some(false),
+                Some(true) => (),
+                None => is_match = None,
+            }
+        }
+
+        is_match
+    }
+
+    #[inline]
+    fn resolve_nth_last(nth: NthChild, index: i32, count: i32, parent_ended: bool) -> Option<bool> {
+        let index_from_end = count - index + 1;
+
+        if parent_ended {
+            Some(nth.has_index(index_from_end))
+        } else if nth.has_no_index_from(index_from_end) {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    fn into_resolution(self, is_match: bool) -> LazyMatchResolution<P> {
+        LazyMatchResolution {
+            element_id: self.element_id,
+            payload: self.payload,
+            is_match,
+        }
+    }
+}
+
+/// Jumps registered by the already seen children of an element, that should be
+/// executed for their subsequent element siblings.
+#[derive(Default)]
+pub(crate) struct SiblingJumps {
+    /// Jumps for the next element sibling only (`+` combinator).
+    pub next: Vec<AddressRange>,
+    /// Jumps for all the following element siblings (`~` combinator).
+    pub later: Vec<AddressRange>,
+}
+
+impl SiblingJumps {
+    #[inline]
+    pub fn iter(&self) -> impl Iterator<Item = &AddressRange> {
+        self.next.iter().chain(self.later.iter())
+    }
+
+    /// Replaces jumps for the next sibling and adds jumps for all the later siblings.
+    /// Called when an element sibling has been executed.
+    #[inline]
+    pub fn update(&mut self, next: Vec<AddressRange>, later: Vec<AddressRange>) {
+        self.next = next;
+
+        for jumps in later {
+            if !self.later.contains(&jumps) {
+                self.later.push(jumps);
+            }
+        }
+    }
 }
 
 struct CounterItem {
@@ -171,8 +297,17 @@ pub(crate) struct StackItem<'i, E: ElementData> {
     pub element_data: E,
     pub jumps: Vec<AddressRange>,
     pub hereditary_jumps: Vec<AddressRange>,
+    pub has_jumps: Vec<AddressRange>,
+    pub hereditary_has_jumps: Vec<AddressRange>,
     pub child_counter: ChildCounter,
+    /// Sibling jumps registered by the children of this element.
+    pub sibling_jumps: SiblingJumps,
+    /// Unresolved lazy matches of the children of this element.
+    pub lazy_matches: Vec<LazyMatch<E::MatchPayload>>,
+    /// Id of the element in its unresolved lazy matches, that depend on its content.
+    pub content_lazy_element_id: Option<usize>,
     pub has_ancestor_with_hereditary_jumps: bool,
+    pub has_ancestor_with_hereditary_has_jumps: bool,
     pub stack_directive: StackDirective,
 }
 
@@ -185,8 +320,14 @@ impl<'i, E: ElementData> StackItem<'i, E> {
             element_data: E::default(),
             jumps: Vec::default(),
             hereditary_jumps: Vec::default(),
+            has_jumps: Vec::default(),
+            hereditary_has_jumps: Vec::default(),
             child_counter: Default::default(),
+            sibling_jumps: SiblingJumps::default(),
+            lazy_matches: Vec::default(),
+            content_lazy_element_id: None,
             has_ancestor_with_hereditary_jumps: false,
+            has_ancestor_with_hereditary_has_jumps: false,
             stack_directive: StackDirective::Push,
         }
     }
@@ -198,8 +339,14 @@ impl<'i, E: ElementData> StackItem<'i, E> {
             element_data: self.element_data,
             jumps: self.jumps,
             hereditary_jumps: self.hereditary_jumps,
+            has_jumps: self.has_jumps,
+            hereditary_has_jumps: self.hereditary_has_jumps,
             child_counter: self.child_counter,
+            sibling_jumps: self.sibling_jumps,
+            lazy_matches: self.lazy_matches,
+            content_lazy_element_id: self.content_lazy_element_id,
             has_ancestor_with_hereditary_jumps: self.has_ancestor_with_hereditary_jumps,
+            has_ancestor_with_hereditary_has_jumps: self.has_ancestor_with_hereditary_has_jumps,
             stack_directive: self.stack_directive,
         }
     }
@@ -208,8 +355,20 @@ impl<'i, E: ElementData> StackItem<'i, E> {
 pub(crate) struct Stack<E: ElementData> {
     /// A counter for root elements
     root_child_counter: ChildCounter,
+    /// Sibling jumps registered by root elements
+    root_sibling_jumps: SiblingJumps,
+    /// Unresolved lazy matches of root elements
+    root_lazy_matches: Vec<LazyMatch<E::MatchPayload>>,
+    /// Lazy matches resolved since the last call of `take_lazy_match_resolutions`
+    lazy_match_resolutions: Vec<LazyMatchResolution<E::MatchPayload>>,
     /// A typed counter for all elements on all frames. This is optional to indicate if types are actually being counted.
     typed_child_counters: Option<TypedChildCounterMap>,
+    /// The level, starting from which the children are counted by their type. The children on
+    /// the lower levels might have been added before the counting has started.
+    typed_child_counters_start_level: usize,
+    /// Set if the stack doesn't start at the beginning of the document, so the preceding
+    /// siblings and the ancestors of the elements at the bottom of the stack are unknown.
+    starts_mid_document: bool,
     items: LimitedVec<StackItem<'static, E>>,
 }
 
@@ -218,17 +377,42 @@ impl<E: ElementData> Stack<E> {
     pub fn new(memory_limiter: SharedMemoryLimiter, enable_nth_of_type: bool) -> Self {
         Self {
             root_child_counter: Default::default(),
+            root_sibling_jumps: SiblingJumps::default(),
+            root_lazy_matches: Vec::default(),
+            lazy_match_resolutions: Vec::default(),
             typed_child_counters: if enable_nth_of_type {
                 Some(Default::default())
             } else {
                 None
             },
+            typed_child_counters_start_level: 0,
+            starts_mid_document: false,
             items: LimitedVec::new(memory_limiter),
         }
     }
 
+    /// Starts counting the children by their type. The children added before aren't counted,
+    /// so the state of the type counters is unknown for the levels of the currently open
+    /// elements.
+    #[inline]
+    pub fn enable_nth_of_type(&mut self) {
+        if self.typed_child_counters.is_none() {
+            self.typed_child_counters = Some(Default::default());
+            self.typed_child_counters_start_level = self.items.len() + 1;
+        }
+    }
+
+    /// Marks the stack as starting in the middle of the document, e.g. if the VM is created
+    /// once some of the elements have already been parsed.
+    #[inline]
+    pub fn start_mid_document(&mut self) {
+        self.starts_mid_document = true;
+    }
+
     /// Adds a child to child counters. Called before pushing the element to the stack.
     pub fn add_child(&mut self, name: &LocalName<'_>) {
+        self.set_current_element_non_empty();
+
         match self.items.last_mut() {
             Some(last) => &mut last.child_counter,
             None => &mut self.root_child_counter,
@@ -238,23 +422,219 @@ impl<E: ElementData> Stack<E> {
         if let Some(counters) = &mut self.typed_child_counters {
             counters.add_child(name, self.items.len());
         }
+
+        // NOTE: the new child might make lazy matches of its preceding siblings impossible.
+        let level = self.items.len();
+        let (counter, lazy_matches) = match self.items.last_mut() {
+            Some(last) => (&last.child_counter, &mut last.lazy_matches),
+            None => (&self.root_child_counter, &mut self.root_lazy_matches),
+        };
+
+        Self::resolve_lazy_matches(
+            lazy_matches,
+            counter,
+            self.typed_child_counters.as_ref(),
+            level,
+            false,
+            &mut self.lazy_match_resolutions,
+        );
+    }
+
+    /// Adds a lazy match of the current element to its parent. Returns the outcome of the
+    /// match instead, if it can be decided right away.
+    pub fn add_lazy_match(&mut self, lazy_match: LazyMatch<E::MatchPayload>) -> Option<bool> {
+        let level = self.items.len();
+        let (counter, lazy_matches) = match self.items.last_mut() {
+            Some(last) => (&last.child_counter, &mut last.lazy_matches),
+            None => (&self.root_child_counter, &mut self.root_lazy_matches),
+        };
+
+        let count_of_type = Self::count_of_type(
+            self.typed_child_counters.as_ref(),
+            &lazy_match.local_name,
+            level,
+        );
+
+        let is_match = lazy_match.resolve(counter.index(), count_of_type, false);
+
+        if is_match.is_none() {
+            lazy_matches.push(lazy_match);
+        }
+
+        is_match
     }
 
+    #[inline]
+    fn count_of_type(
+        typed_child_counters: Option<&TypedChildCounterMap>,
+        local_name: &LocalName<'_>,
+        level: usize,
+    ) -> i32 {
+        typed_child_counters
+            .and_then(|c| c.get(local_name, level))
+            .map_or(0, ChildCounter::index)
+    }
+
+    fn resolve_lazy_matches(
+        lazy_matches: &mut Vec<LazyMatch<E::MatchPayload>>,
+        counter: &ChildCounter,
+        typed_child_counters: Option<&TypedChildCounterMap>,
+        level: usize,
+        parent_ended: bool,
+        resolutions: &mut Vec<LazyMatchResolution<E::MatchPayload>>,
+    ) {
+        let mut i = 0;
+
+        while i < lazy_matches.len() {
+            let lazy_match = &lazy_matches[i];
+            let count_of_type =
+                Self::count_of_type(typed_child_counters, &lazy_match.local_name, level);
+
+            match lazy_match.resolve(counter.index(), count_of_type, parent_ended) {
+                Some(is_match) => {
+                    resolutions.push(lazy_matches.remove(i).into_resolution(is_match));
+                }
+                None => i += 1,
+            }
+        }
+    }
+
+    /// Resolves the lazy matches of the current element, that depend on its content,
+    /// once it has a child or text.
+    #[inline]
+    pub fn set_current_element_non_empty(&mut self) {
+        if let Some(level) = self.items.len().checked_sub(1) {
+            self.set_element_emptiness(level, false);
+        }
+    }
+
+    /// Returns `true` if the current element has unresolved lazy matches, that depend
+    /// on its content.
+    #[inline]
+    #[must_use]
+    pub fn current_element_needs_content(&self) -> bool {
+        self.items
+            .last()
+            .is_some_and(|item| item.content_lazy_element_id.is_some())
+    }
+
+    fn set_element_emptiness(&mut self, level: usize, is_empty: bool) {
+        let Some(element_id) = self.items[level].content_lazy_element_id.take() else {
+            return;
+        };
+
+        let (counter, lazy_matches) = match level.checked_sub(1) {
+            Some(parent_level) => {
+                let parent = &mut self.items[parent_level];
+
+                (&parent.child_counter, &mut parent.lazy_matches)
+            }
+            None => (&self.root_child_counter, &mut self.root_lazy_matches),
+        };
+
+        for lazy_match in lazy_matches
+            .iter_mut()
+            .filter(|m| m.element_id == element_id)
+        {
+            lazy_match.is_empty = Some(is_empty);
+        }
+
+        Self::resolve_lazy_matches(
+            lazy_matches,
+            counter,
+            self.typed_child_counters.as_ref(),
+            level,
+            false,
+            &mut self.lazy_match_resolutions,
+        );
+    }
+
+    /// Resolves all the remaining lazy matches. Called once the document ends.
+    pub fn resolve_all_lazy_matches(&mut self) {
+        for level in (0..self.items.len()).rev() {
+            if let Some(c) = self.typed_child_counters.as_mut() {
+                c.pop_to(level + 1);
+            }
+
+            let item = &mut self.items[level];
+
+            Self::resolve_lazy_matches(
+                &mut item.lazy_matches,
+                &item.child_counter,
+                self.typed_child_counters.as_ref(),
+                level + 1,
+                true,
+                &mut self.lazy_match_resolutions,
+            );
+        }
+
+        if let Some(c) = self.typed_child_counters.as_mut() {
+            c.pop_to(0);
+        }
+
+        Self::resolve_lazy_matches(
+            &mut self.root_lazy_matches,
+            &self.root_child_counter,
+            self.typed_child_counters.as_ref(),
+            0,
+            true,
+            &mut self.lazy_match_resolutions,
+        );
+    }
+
+    /// Adds the outcome of a lazy match that has been decided right away.
+    #[inline]
+    pub fn add_lazy_match_resolution(&mut self, resolution: LazyMatchResolution<E::MatchPayload>) {
+        self.lazy_match_resolutions.push(resolution);
+    }
+
+    #[inline]
+    pub fn take_lazy_match_resolutions(&mut self) -> Vec<LazyMatchResolution<E::MatchPayload>> {
+        std::mem::take(&mut self.lazy_match_resolutions)
+    }
+
+    /// Sibling jumps registered by the preceding siblings of the current element.
+    #[inline]
     #[must_use]
-    pub fn build_state<'a, 'i>(&'a self, name: &LocalName<'i>) -> SelectorState<'i>
+    pub fn sibling_jumps(&self) -> &SiblingJumps {
+        self.items
+            .last()
+            .map_or(&self.root_sibling_jumps, |last| &last.sibling_jumps)
+    }
+
+    #[inline]
+    pub fn sibling_jumps_mut(&mut self) -> &mut SiblingJumps {
+        match self.items.last_mut() {
+            Some(last) => &mut last.sibling_jumps,
+            None => &mut self.root_sibling_jumps,
+        }
+    }
+
+    #[must_use]
+    pub fn build_state<'a, 'i>(&'a self, name: &LocalName<'i>, ns: Namespace) -> SelectorState<'i>
     where
         'a: 'i, // 'a outlives 'i, required to downcast 'a lifetimes into 'i
     {
+        let level = self.items.len();
+        let is_known = level > 0 || !self.starts_mid_document;
         let cumulative = self
             .items
             .last()
             .map_or(&self.root_child_counter, |last| &last.child_counter);
         SelectorState {
-            cumulative,
+            cumulative: is_known.then_some(cumulative),
             typed: self
                 .typed_child_counters
                 .as_ref()
-                .and_then(|f| f.get(name, self.items.len())),
+                .filter(|_| is_known && level >= self.typed_child_counters_start_level)
+                .and_then(|f| f.get(name, level)),
+            ns,
+            // NOTE: the root of an HTML document is the `<html>` element, which is implied by
+            // the parser if the document doesn't start with it. So, neither the other top-level
+            // elements, nor the elements following the `<html>` element are the root.
+            is_root: is_known.then(|| {
+                level == 0 && cumulative.index() == 1 && ns == Namespace::Html && *name == Tag::Html
+            }),
         }
     }
 
@@ -286,16 +666,79 @@ impl<E: ElementData> Stack<E> {
             .iter()
             .rposition(|item| item.local_name == local_name);
         if let Some(index) = pop_to_index {
+            // NOTE: all the children of the popped elements have been seen by now,
+            // so their lazy matches can be resolved.
+            for level in (index..self.items.len()).rev() {
+                if let Some(c) = self.typed_child_counters.as_mut() {
+                    c.pop_to(level + 1);
+                }
+
+                let item = &mut self.items[level];
+
+                Self::resolve_lazy_matches(
+                    &mut item.lazy_matches,
+                    &item.child_counter,
+                    self.typed_child_counters.as_ref(),
+                    level + 1,
+                    true,
+                    &mut self.lazy_match_resolutions,
+                );
+            }
+
             if let Some(c) = self.typed_child_counters.as_mut() {
                 c.pop_to(index);
             }
+
+            // NOTE: the lazy matches of the rest of the popped elements have been resolved
+            // above, as their parents have been popped as well.
+            self.set_element_emptiness(index, true);
+
             self.items
                 .drain(index..)
                 .map(|i| i.element_data)
                 .for_each(popped_element_data_handler);
+
+            self.typed_child_counters_start_level =
+                self.typed_child_counters_start_level.min(index + 1);
+        } else if self.starts_mid_document && self.items.is_empty() {
+            // NOTE: the end tag might belong to the parent of the elements at the bottom
+            // of the stack, so the following elements aren't necessarily their siblings.
+            self.root_sibling_jumps = SiblingJumps::default();
         }
     }
 
+    /// Removes the jumps to the `removed` instructions and relocates the jumps to the following
+    /// instructions, that are moved in place of the removed ones.
+    pub fn remove_jumps(&mut self, removed: &AddressRange) {
+        let relocate = relocation_after_removal(removed);
+
+        let remove_from = |jumps: &mut Vec<AddressRange>| {
+            jumps.retain(|j| j.end <= removed.start || j.start >= removed.end);
+
+            for j in jumps {
+                *j = relocate(j.start)..relocate(j.end);
+            }
+        };
+
+        for level in 0..self.items.len() {
+            let item = &mut self.items[level];
+
+            for jumps in [
+                &mut item.jumps,
+                &mut item.hereditary_jumps,
+                &mut item.has_jumps,
+                &mut item.hereditary_has_jumps,
+                &mut item.sibling_jumps.next,
+                &mut item.sibling_jumps.later,
+            ] {
+                remove_from(jumps);
+            }
+        }
+
+        remove_from(&mut self.root_sibling_jumps.next);
+        remove_from(&mut self.root_sibling_jumps.later);
+    }
+
     #[inline]
     #[must_use]
     pub fn items(&self) -> &[StackItem<'_, E>] {
@@ -316,11 +759,27 @@ impl<E: ElementData> Stack<E> {
             if last.has_ancestor_with_hereditary_jumps || !last.hereditary_jumps.is_empty() {
                 item.has_ancestor_with_hereditary_jumps = true;
             }
+
+            if last.has_ancestor_with_hereditary_has_jumps || !last.hereditary_has_jumps.is_empty()
+            {
+                item.has_ancestor_with_hereditary_has_jumps = true;
+            }
         }
 
         self.items.push(item)?;
         Ok(())
     }
+
+    /// Adds payload to the element on the given level of the stack, whose
+    /// descendant has matched the argument of `:has()`.
+    #[inline]
+    /// Returns `false` if the payload has already been matched by the element.
+    pub fn add_descendant_match(&mut self, level: usize, payload: E::MatchPayload) -> bool {
+        self.items[level]
+            .element_data
+            .descendant_matched_payload_mut()
+            .insert(payload)
+    }
 }
 
 #[cfg(test)]
@@ -338,6 +797,10 @@ mod tests {
         fn matched_payload_mut(&mut self) -> &mut HashSet<()> {
             unreachable!();
         }
+
+        fn descendant_matched_payload_mut(&mut self) -> &mut HashSet<()> {
+            unreachable!();
+        }
     }
 
     fn local_name(name: &'static str) -> LocalName<'static> {
@@ -0,0 +1,447 @@
+use super::OutputSink;
+use crate::base::Bytes;
+use crate::memory::{LimitedVec, MemoryLimitExceededError, SharedMemoryLimiter};
+use crate::rewritable_units::{EndTag, Serialize, StartTag};
+use crate::rewriter::RewritingError;
+use encoding_rs::Encoding;
+use std::borrow::Cow;
+
+// Pub only for integration tests
+/// Events of the elements, whose output is held back until it's known whether they match
+/// a selector (e.g. `li:last-child` can't be decided before the following sibling of `li` or
+/// the end of its parent) or until they end, if their content is captured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeferredElementEvent {
+    /// The start tag that has just been handled belongs to a deferred element.
+    Deferred { id: usize, can_have_content: bool },
+    /// The deferred element has been closed. If the element has an end tag,
+    /// it's the next end tag token. Otherwise, the element is closed implicitly
+    /// by the next end tag token, which belongs to its ancestor.
+    Ended { id: usize, has_end_tag: bool },
+    /// It's now known whether the deferred element matches. If the element awaits
+    /// its end tag, it's resolved once the end tag is taken.
+    Resolved { id: usize, is_match: bool },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DeferredElementState {
+    Open,
+    AwaitingEndTag,
+    AwaitingAncestorEndTag,
+    Closed,
+}
+
+/// Handler of the matched deferred element, that receives the element id, its tags
+/// and the output of its content. Returns `true` if the element content should be removed.
+pub(super) trait DeferredElementHandler:
+    FnMut(
+    usize,
+    &mut StartTag<'static>,
+    Option<&mut EndTag<'static>>,
+    &mut DeferredOutput,
+) -> Result<bool, RewritingError>
+{
+}
+
+impl<F> DeferredElementHandler for F where
+    F: FnMut(
+        usize,
+        &mut StartTag<'static>,
+        Option<&mut EndTag<'static>>,
+        &mut DeferredOutput,
+    ) -> Result<bool, RewritingError>
+{
+}
+
+/// Text chunk, whose text is held back separately from the rest of the output,
+/// so it can be modified once the deferred element ends.
+struct TextSlot {
+    offset: usize,
+    text: String,
+    encoding: &'static Encoding,
+}
+
+// Pub only for integration tests
+/// The output held back by a deferred element.
+pub struct DeferredOutput {
+    bytes: LimitedVec<u8>,
+    text_slots: Vec<TextSlot>,
+    memory_limiter: SharedMemoryLimiter,
+}
+
+impl DeferredOutput {
+    fn new(memory_limiter: &SharedMemoryLimiter) -> Self {
+        Self {
+            bytes: LimitedVec::new(SharedMemoryLimiter::clone(memory_limiter)),
+            text_slots: Vec::default(),
+            memory_limiter: SharedMemoryLimiter::clone(memory_limiter),
+        }
+    }
+
+    #[inline]
+    fn push_bytes(&mut self, chunk: &[u8]) -> Result<(), MemoryLimitExceededError> {
+        self.bytes.extend_from_slice(chunk)
+    }
+
+    fn push_text_slot(
+        &mut self,
+        text: String,
+        encoding: &'static Encoding,
+    ) -> Result<(), MemoryLimitExceededError> {
+        self.memory_limiter.increase_usage(text.len())?;
+
+        self.text_slots.push(TextSlot {
+            offset: self.bytes.len(),
+            text,
+            encoding,
+        });
+
+        Ok(())
+    }
+
+    /// Returns the texts of the text slots in the order of their appearance in the output.
+    pub(crate) fn texts(&self) -> impl Iterator<Item = &str> {
+        self.text_slots.iter().map(|slot| slot.text.as_str())
+    }
+
+    /// Sets the text of the text slot with the given index.
+    pub(crate) fn set_text(
+        &mut self,
+        idx: usize,
+        text: String,
+    ) -> Result<(), MemoryLimitExceededError> {
+        let slot = &mut self.text_slots[idx];
+
+        self.memory_limiter.decrease_usage(slot.text.len());
+        slot.text = String::new();
+        self.memory_limiter.increase_usage(text.len())?;
+        slot.text = text;
+
+        Ok(())
+    }
+
+    /// Writes the output with the texts of the text slots in place.
+    pub(crate) fn write_to(&self, output_handler: &mut dyn FnMut(&[u8])) {
+        let bytes: &[u8] = &self.bytes;
+        let mut pos = 0;
+
+        for slot in &self.text_slots {
+            output_handler(&bytes[pos..slot.offset]);
+            output_handler(&Bytes::from_str(&slot.text, slot.encoding));
+            pos = slot.offset;
+        }
+
+        output_handler(&bytes[pos..]);
+    }
+
+    /// Returns the output with the texts of the text slots in place.
+    pub(crate) fn to_bytes(&self) -> Cow<'_, [u8]> {
+        if self.text_slots.is_empty() {
+            return Cow::Borrowed(&self.bytes);
+        }
+
+        let mut bytes = Vec::with_capacity(self.bytes.len());
+
+        self.write_to(&mut |chunk| bytes.extend_from_slice(chunk));
+
+        Cow::Owned(bytes)
+    }
+
+    /// Appends the other output, keeping its text slots.
+    fn append(&mut self, mut other: Self) -> Result<(), MemoryLimitExceededError> {
+        let base_offset = self.bytes.len();
+
+        self.push_bytes(&other.bytes)?;
+
+        for mut slot in other.text_slots.drain(..) {
+            // NOTE: the memory usage of the text is moved along with the slot.
+            slot.offset += base_offset;
+            self.text_slots.push(slot);
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for DeferredOutput {
+    fn drop(&mut self) {
+        for slot in &self.text_slots {
+            self.memory_limiter.decrease_usage(slot.text.len());
+        }
+    }
+}
+
+struct DeferredElement {
+    id: usize,
+    start_tag: Option<StartTag<'static>>,
+    content: DeferredOutput,
+    end_tag: Option<EndTag<'static>>,
+    trailing: DeferredOutput,
+    state: DeferredElementState,
+    resolution: Option<bool>,
+}
+
+impl DeferredElement {
+    #[inline]
+    fn current_section(&mut self) -> &mut DeferredOutput {
+        match self.state {
+            DeferredElementState::Open
+            | DeferredElementState::AwaitingEndTag
+            | DeferredElementState::AwaitingAncestorEndTag => &mut self.content,
+            DeferredElementState::Closed => &mut self.trailing,
+        }
+    }
+}
+
+/// Output sink wrapper that buffers the output of deferred elements, as well as all
+/// the output that follows them, until the elements are resolved.
+pub(super) struct DeferringOutputSink<O> {
+    output_sink: O,
+    elements: Vec<DeferredElement>,
+    memory_limiter: SharedMemoryLimiter,
+    memory_limit_error: Option<MemoryLimitExceededError>,
+}
+
+impl<O: OutputSink> DeferringOutputSink<O> {
+    pub fn new(output_sink: O, memory_limiter: SharedMemoryLimiter) -> Self {
+        Self {
+            output_sink,
+            elements: Vec::default(),
+            memory_limiter,
+            memory_limit_error: None,
+        }
+    }
+
+    #[inline]
+    pub fn has_deferred_elements(&self) -> bool {
+        !self.elements.is_empty()
+    }
+
+    /// Starts buffering of the element output. `start_tag` is `None` if the
+    /// start tag is not emitted.
+    pub fn defer(
+        &mut self,
+        id: usize,
+        start_tag: Option<StartTag<'static>>,
+        can_have_content: bool,
+    ) {
+        self.elements.push(DeferredElement {
+            id,
+            start_tag,
+            content: DeferredOutput::new(&self.memory_limiter),
+            end_tag: None,
+            trailing: DeferredOutput::new(&self.memory_limiter),
+            state: if can_have_content {
+                DeferredElementState::Open
+            } else {
+                DeferredElementState::Closed
+            },
+            resolution: None,
+        });
+    }
+
+    pub fn end(&mut self, id: usize, has_end_tag: bool) {
+        if let Some(element) = self.elements.iter_mut().find(|e| e.id == id) {
+            if element.state == DeferredElementState::Open {
+                element.state = if has_end_tag {
+                    DeferredElementState::AwaitingEndTag
+                } else {
+                    DeferredElementState::AwaitingAncestorEndTag
+                };
+            }
+        }
+    }
+
+    /// Closes the elements that are closed by the end tag and takes the end tag, if the last
+    /// deferred element awaits it. Returns the end tag back otherwise, so it can be emitted
+    /// as usual.
+    ///
+    /// NOTE: the implicitly closed elements are descendants of the element the end tag belongs
+    /// to, so they are closed first and their output gets to the content of the element.
+    pub fn try_take_end_tag<'i>(
+        &mut self,
+        end_tag: EndTag<'i>,
+        emission_enabled: bool,
+        handle_element: &mut impl DeferredElementHandler,
+    ) -> Result<Option<EndTag<'i>>, RewritingError> {
+        for idx in (0..self.elements.len()).rev() {
+            if self.elements[idx].state == DeferredElementState::AwaitingAncestorEndTag {
+                self.close(idx, handle_element)?;
+            }
+        }
+
+        let idx = match self.elements.last() {
+            Some(element) if element.state == DeferredElementState::AwaitingEndTag => {
+                self.elements.len() - 1
+            }
+            _ => return Ok(Some(end_tag)),
+        };
+
+        if emission_enabled && self.elements[idx].start_tag.is_some() {
+            self.elements[idx].end_tag = Some(end_tag.into_owned());
+        }
+
+        self.close(idx, handle_element)?;
+
+        Ok(None)
+    }
+
+    fn close(
+        &mut self,
+        idx: usize,
+        handle_element: &mut impl DeferredElementHandler,
+    ) -> Result<(), RewritingError> {
+        let element = &mut self.elements[idx];
+
+        element.state = DeferredElementState::Closed;
+
+        match element.resolution {
+            Some(is_match) => self.resolve_at(idx, is_match, handle_element),
+            None => Ok(()),
+        }
+    }
+
+    /// Removes the element and writes its output to the preceding deferred element
+    /// or to the underlying sink. The resolution of the element that awaits its end tag
+    /// is postponed until the end tag is taken, so the element handler gets the whole
+    /// content and the end tag of the element.
+    pub fn resolve(
+        &mut self,
+        id: usize,
+        is_match: bool,
+        handle_element: &mut impl DeferredElementHandler,
+    ) -> Result<(), RewritingError> {
+        let Some(idx) = self.elements.iter().position(|e| e.id == id) else {
+            return Ok(());
+        };
+
+        match self.elements[idx].state {
+            DeferredElementState::Closed => self.resolve_at(idx, is_match, handle_element),
+            _ => {
+                self.elements[idx].resolution = Some(is_match);
+
+                Ok(())
+            }
+        }
+    }
+
+    fn resolve_at(
+        &mut self,
+        idx: usize,
+        is_match: bool,
+        handle_element: &mut impl DeferredElementHandler,
+    ) -> Result<(), RewritingError> {
+        let mut element = self.elements.remove(idx);
+
+        let remove_content = match element.start_tag {
+            Some(ref mut start_tag) if is_match => handle_element(
+                element.id,
+                start_tag,
+                element.end_tag.as_mut(),
+                &mut element.content,
+            )?,
+            _ => false,
+        };
+
+        if let Some(start_tag) = element.start_tag {
+            start_tag.into_bytes(&mut |chunk| self.write_before(idx, chunk))?;
+        }
+
+        if !remove_content {
+            self.write_output_before(idx, element.content);
+        }
+
+        if let Some(end_tag) = element.end_tag {
+            end_tag.into_bytes(&mut |chunk| self.write_before(idx, chunk))?;
+        }
+
+        self.write_output_before(idx, element.trailing);
+
+        self.check_memory_limit()
+    }
+
+    /// Resolves the remaining elements once the input ends. The elements,
+    /// whose matching is not decided, are resolved as unmatched.
+    pub fn resolve_all(
+        &mut self,
+        handle_element: &mut impl DeferredElementHandler,
+    ) -> Result<(), RewritingError> {
+        while let Some(element) = self.elements.last() {
+            let is_match = element.resolution.unwrap_or(false);
+
+            self.resolve_at(self.elements.len() - 1, is_match, handle_element)?;
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    pub fn check_memory_limit(&mut self) -> Result<(), RewritingError> {
+        match self.memory_limit_error.take() {
+            Some(e) => Err(RewritingError::MemoryLimitExceeded(e)),
+            None => Ok(()),
+        }
+    }
+
+    fn write_before(&mut self, idx: usize, chunk: &[u8]) {
+        if chunk.is_empty() {
+            return;
+        }
+
+        match idx.checked_sub(1) {
+            Some(prev_idx) => self.buffer(prev_idx, chunk),
+            None => self.output_sink.handle_chunk(chunk),
+        }
+    }
+
+    /// Writes the output of the resolved element, keeping its text slots
+    /// if the output is written to the preceding deferred element.
+    fn write_output_before(&mut self, idx: usize, output: DeferredOutput) {
+        match idx.checked_sub(1) {
+            Some(prev_idx) => {
+                if let Err(e) = self.elements[prev_idx].current_section().append(output) {
+                    self.memory_limit_error = Some(e);
+                }
+            }
+            None => output.write_to(&mut |chunk| {
+                if !chunk.is_empty() {
+                    self.output_sink.handle_chunk(chunk);
+                }
+            }),
+        }
+    }
+
+    fn buffer(&mut self, idx: usize, chunk: &[u8]) {
+        if let Err(e) = self.elements[idx].current_section().push_bytes(chunk) {
+            self.memory_limit_error = Some(e);
+        }
+    }
+
+    /// Holds back the text separately from the rest of the output of the last
+    /// deferred element, so it can be modified once the element ends.
+    pub fn handle_text_slot(&mut self, text: String, encoding: &'static Encoding) {
+        match self.elements.last_mut() {
+            Some(element) => {
+                if let Err(e) = element.current_section().push_text_slot(text, encoding) {
+                    self.memory_limit_error = Some(e);
+                }
+            }
+            None if !text.is_empty() => {
+                self.output_sink
+                    .handle_chunk(&Bytes::from_str(&text, encoding));
+            }
+            None => (),
+        }
+    }
+}
+
+impl<O: OutputSink> OutputSink for DeferringOutputSink<O> {
+    #[inline]
+    fn handle_chunk(&mut self, chunk: &[u8]) {
+        match self.elements.len().checked_sub(1) {
+            // NOTE: the last chunk has zero length and should always reach the sink.
+            Some(last_idx) if !chunk.is_empty() => self.buffer(last_idx, chunk),
+            _ => self.output_sink.handle_chunk(chunk),
+        }
+    }
+}
@@ -1,13 +1,15 @@
+use super::deferred_elements::{DeferredElementEvent, DeferredOutput, DeferringOutputSink};
 use crate::base::{Bytes, Range, SharedEncoding};
 use crate::html::{LocalName, Namespace};
-use crate::html_content::{TextChunk, TextType};
+use crate::html_content::{EndTag, StartTag, TextChunk, TextType};
+use crate::memory::SharedMemoryLimiter;
 use crate::parser::{
     AttributeBuffer, Lexeme, LexemeSink, NonTagContentLexeme, ParserDirective, ParserOutputSink,
     TagHintSink, TagLexeme, TagTokenOutline,
 };
-use crate::rewritable_units::TextDecoder;
 use crate::rewritable_units::ToTokenResult;
 use crate::rewritable_units::{DocumentEnd, Serialize, ToToken, Token, TokenCaptureFlags};
+use crate::rewritable_units::{SourceLocation, SourceLocator, TextDecoder};
 use crate::rewriter::RewritingError;
 use encoding_rs::Encoding;
 
@@ -43,6 +45,32 @@ pub trait TransformController: Sized {
     fn handle_token(&mut self, token: &mut Token<'_>) -> Result<(), RewritingError>;
     fn handle_end(&mut self, document_end: &mut DocumentEnd<'_>) -> Result<(), RewritingError>;
     fn should_emit_content(&self) -> bool;
+
+    /// Called once the input ends, before `handle_end`.
+    fn handle_input_end(&mut self) {}
+
+    /// Returns events of the deferred elements, that happened since the last call.
+    fn take_deferred_element_events(&mut self) -> Vec<DeferredElementEvent> {
+        Vec::new()
+    }
+
+    /// Handles the deferred element once it's resolved as matched. `content` is the output
+    /// of the element content. Returns `true` if the element content should be removed.
+    fn handle_deferred_element(
+        &mut self,
+        _id: usize,
+        _start_tag: &mut StartTag<'static>,
+        _end_tag: Option<&mut EndTag<'static>>,
+        _content: &mut DeferredOutput,
+    ) -> Result<bool, RewritingError> {
+        Ok(false)
+    }
+
+    /// Returns `true` if the text of the text chunks should be held back separately from
+    /// the rest of the deferred element output, so it can be modified once the element ends.
+    fn should_hold_back_text(&self) -> bool {
+        false
+    }
 }
 
 /// Defines an interface for the [`HtmlRewriter`]'s output.
@@ -79,10 +107,13 @@ pub struct Dispatcher<C, O> {
 /// Fields split out of `Dispatcher` for borrow checking of event handlers
 struct DispatcherDelegate<C, O> {
     transform_controller: C,
-    output_sink: O,
+    output_sink: DeferringOutputSink<O>,
     remaining_content_start: usize,
     capture_flags: TokenCaptureFlags,
     emission_enabled: bool,
+    source_locator: SourceLocator,
+    /// Offset in the input of the bytes, that the next text chunk is decoded from.
+    text_start: usize,
 }
 
 impl<C, O> DispatcherDelegate<C, O>
@@ -90,7 +121,11 @@ where
     C: TransformController,
     O: OutputSink,
 {
-    fn flush_remaining_input(&mut self, input: &[u8], consumed_byte_count: usize) {
+    fn flush_remaining_input(
+        &mut self,
+        input: &[u8],
+        consumed_byte_count: usize,
+    ) -> Result<(), RewritingError> {
         let output = &input[self.remaining_content_start..consumed_byte_count];
 
         if self.emission_enabled && !output.is_empty() {
@@ -98,10 +133,25 @@ where
         }
 
         self.remaining_content_start = 0;
+        self.source_locator
+            .chunk_consumed(input, consumed_byte_count);
+
+        self.output_sink.check_memory_limit()
     }
 
     fn finish(&mut self, encoding: &'static Encoding, input: &[u8]) -> Result<(), RewritingError> {
-        self.flush_remaining_input(input, input.len());
+        self.flush_remaining_input(input, input.len())?;
+
+        self.transform_controller.handle_input_end();
+        self.handle_deferred_element_events()?;
+
+        // NOTE: the elements, that await their end tags, are resolved
+        // here, as well as the ones that are still undecided.
+        self.output_sink
+            .resolve_all(&mut |id, start_tag, end_tag, content| {
+                self.transform_controller
+                    .handle_deferred_element(id, start_tag, end_tag, content)
+            })?;
 
         let mut document_end = DocumentEnd::new(&mut self.output_sink, encoding);
 
@@ -138,30 +188,147 @@ where
 
         self.transform_controller.handle_token(&mut token)?;
 
-        if self.emission_enabled {
-            token.into_bytes(&mut |c| self.output_sink.handle_chunk(c))?;
+        let token = match token {
+            Token::StartTag(start_tag) => self.handle_deferred_element_start_tag(start_tag)?,
+            Token::EndTag(end_tag) if self.output_sink.has_deferred_elements() => self
+                .output_sink
+                .try_take_end_tag(
+                    end_tag,
+                    self.emission_enabled,
+                    &mut |id, start_tag, end_tag, content| {
+                        self.transform_controller
+                            .handle_deferred_element(id, start_tag, end_tag, content)
+                    },
+                )?
+                .map(Token::EndTag),
+            token => Some(token),
+        };
+
+        if let Some(token) = token {
+            if self.emission_enabled {
+                token.into_bytes(&mut |c| self.output_sink.handle_chunk(c))?;
+            }
         }
+
+        self.output_sink.check_memory_limit()
+    }
+
+    /// Defers the output of the start tag, if its element is deferred.
+    /// Returns the start tag back otherwise.
+    fn handle_deferred_element_start_tag<'i>(
+        &mut self,
+        start_tag: StartTag<'i>,
+    ) -> Result<Option<Token<'i>>, RewritingError> {
+        let mut start_tag = Some(start_tag);
+
+        for event in self.transform_controller.take_deferred_element_events() {
+            match event {
+                DeferredElementEvent::Deferred {
+                    id,
+                    can_have_content,
+                } => {
+                    let start_tag = start_tag
+                        .take()
+                        .filter(|_| self.emission_enabled)
+                        .map(StartTag::into_owned);
+
+                    self.output_sink.defer(id, start_tag, can_have_content);
+                }
+                event => self.handle_deferred_element_event(event)?,
+            }
+        }
+
+        Ok(start_tag.map(Token::StartTag))
+    }
+
+    fn handle_deferred_element_events(&mut self) -> Result<(), RewritingError> {
+        for event in self.transform_controller.take_deferred_element_events() {
+            self.handle_deferred_element_event(event)?;
+        }
+
         Ok(())
     }
 
+    fn handle_deferred_element_event(
+        &mut self,
+        event: DeferredElementEvent,
+    ) -> Result<(), RewritingError> {
+        match event {
+            DeferredElementEvent::Deferred { id, .. } => self.output_sink.defer(id, None, false),
+            DeferredElementEvent::Ended { id, has_end_tag } => {
+                self.output_sink.end(id, has_end_tag);
+            }
+            DeferredElementEvent::Resolved { id, is_match } => {
+                self.output_sink.resolve(
+                    id,
+                    is_match,
+                    &mut |id, start_tag, end_tag, content| {
+                        self.transform_controller
+                            .handle_deferred_element(id, start_tag, end_tag, content)
+                    },
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the location of the text decoded from the next `input_len` bytes of the text node.
+    /// The `chunk` can be empty if the bytes are already consumed.
+    #[inline]
+    fn locate_text(&mut self, chunk: &[u8], input_len: usize) -> SourceLocation {
+        let start = self.text_start;
+
+        self.text_start += input_len;
+
+        self.source_locator
+            .locate_in_stream(chunk, start, self.text_start)
+    }
+
     fn text_token_produced(
         &mut self,
         text: &str,
+        raw: &[u8],
+        source_location: SourceLocation,
         encoding: &'static Encoding,
         text_type: TextType,
         is_last_in_node: bool,
     ) -> Result<(), RewritingError> {
-        let mut token =
-            Token::TextChunk(TextChunk::new(text, text_type, is_last_in_node, encoding));
+        let mut token = Token::TextChunk(TextChunk::new(
+            text,
+            raw,
+            text_type,
+            is_last_in_node,
+            source_location,
+            encoding,
+        ));
 
         trace!(@output token);
 
         self.transform_controller.handle_token(&mut token)?;
 
         if self.emission_enabled {
-            token.into_bytes(&mut |c| self.output_sink.handle_chunk(c))?;
+            match token {
+                Token::TextChunk(chunk) if self.transform_controller.should_hold_back_text() => {
+                    let (content_before, text, content_after) = chunk.into_parts()?;
+
+                    if !content_before.is_empty() {
+                        self.output_sink.handle_chunk(&content_before);
+                    }
+
+                    if let Some(text) = text {
+                        self.output_sink.handle_text_slot(text, encoding);
+                    }
+
+                    if !content_after.is_empty() {
+                        self.output_sink.handle_chunk(&content_after);
+                    }
+                }
+                token => token.into_bytes(&mut |c| self.output_sink.handle_chunk(c))?,
+            }
         }
-        Ok(())
+
+        self.output_sink.check_memory_limit()
     }
 
     #[inline]
@@ -175,16 +342,23 @@ where
     C: TransformController,
     O: OutputSink,
 {
-    pub fn new(transform_controller: C, output_sink: O, encoding: SharedEncoding) -> Self {
+    pub fn new(
+        transform_controller: C,
+        output_sink: O,
+        encoding: SharedEncoding,
+        memory_limiter: SharedMemoryLimiter,
+    ) -> Self {
         let capture_flags = transform_controller.initial_capture_flags();
 
         Self {
             delegate: DispatcherDelegate {
                 transform_controller,
-                output_sink,
+                output_sink: DeferringOutputSink::new(output_sink, memory_limiter),
                 capture_flags,
                 remaining_content_start: 0,
                 emission_enabled: true,
+                source_locator: SourceLocator::new(),
+                text_start: 0,
             },
             text_decoder: TextDecoder::new(SharedEncoding::clone(&encoding)),
             last_text_type: TextType::Data,
@@ -194,6 +368,11 @@ where
         }
     }
 
+    #[inline]
+    pub fn transform_controller_mut(&mut self) -> &mut C {
+        &mut self.delegate.transform_controller
+    }
+
     #[inline(never)]
     fn try_produce_token_from_lexeme<'i, T>(
         &mut self,
@@ -203,13 +382,25 @@ where
         Lexeme<'i, T>: ToToken,
     {
         let lexeme_consumed_end;
-
-        match lexeme.to_token(&mut self.delegate.capture_flags, self.encoding.get()) {
+        let source_location = self
+            .delegate
+            .source_locator
+            .locate(lexeme.input(), lexeme.raw_range());
+
+        match lexeme.to_token(
+            &mut self.delegate.capture_flags,
+            source_location,
+            self.encoding.get(),
+        ) {
             ToTokenResult::Token(token) => {
                 self.text_decoder
-                    .flush_pending(&mut |text, is_last, encoding| {
+                    .flush_pending(&mut |text, raw, is_last, encoding| {
+                        let location = self.delegate.locate_text(&[], raw.len());
+
                         self.delegate.text_token_produced(
                             text,
+                            raw,
+                            location,
                             encoding,
                             self.last_text_type,
                             is_last,
@@ -221,26 +412,46 @@ where
             ToTokenResult::Text(text_type) => {
                 lexeme_consumed_end = self.delegate.lexeme_consumed(lexeme);
 
+                if !self.text_decoder.has_pending() {
+                    self.delegate.text_start = source_location.bytes().start;
+                }
+
+                let input = lexeme.input();
+
                 self.last_text_type = text_type;
                 self.text_decoder.feed_text(
                     &lexeme.raw(),
                     false,
-                    &mut |text, is_last, encoding| {
+                    &mut |text, raw, is_last, encoding| {
+                        let location = self.delegate.locate_text(input, raw.len());
+
                         self.delegate.text_token_produced(
                             text,
+                            raw,
+                            location,
                             encoding,
                             self.last_text_type,
                             is_last,
                         )
                     },
                 )?;
+
+                // NOTE: the text node can be finished by the next lexeme, when
+                // the input of this one is no longer available.
+                self.delegate
+                    .source_locator
+                    .skip_to(input, lexeme_consumed_end);
             }
             ToTokenResult::None => {
                 return self
                     .text_decoder
-                    .flush_pending(&mut |text, is_last, encoding| {
+                    .flush_pending(&mut |text, raw, is_last, encoding| {
+                        let location = self.delegate.locate_text(&[], raw.len());
+
                         self.delegate.text_token_produced(
                             text,
+                            raw,
+                            location,
                             encoding,
                             self.last_text_type,
                             is_last,
@@ -331,7 +542,7 @@ where
         match capture_flags {
             Ok(flags) => {
                 self.delegate.capture_flags = flags;
-                Ok(())
+                self.delegate.handle_deferred_element_events()
             }
             Err(e) => Err(e),
         }
@@ -343,22 +554,41 @@ where
         flags: TokenCaptureFlags,
     ) -> ParserDirective {
         self.delegate.capture_flags = flags;
-        self.got_flags_from_hint = true;
-        self.get_next_parser_directive()
+
+        let directive = self.get_next_parser_directive();
+
+        // NOTE: the flags are consumed only if the tag is lexed. Otherwise, the next tag
+        // lexeme, that wasn't reported as a hint (e.g. an integration point start tag, that
+        // requires the tree builder feedback), would be mistaken for the hinted one.
+        self.got_flags_from_hint = matches!(directive, ParserDirective::Lex);
+
+        directive
     }
 
     #[inline]
     fn flush_pending_captured_text(&mut self) -> Result<(), RewritingError> {
         self.text_decoder
-            .flush_pending(&mut |text, is_last, encoding| {
-                self.delegate
-                    .text_token_produced(text, encoding, self.last_text_type, is_last)
+            .flush_pending(&mut |text, raw, is_last, encoding| {
+                let location = self.delegate.locate_text(&[], raw.len());
+
+                self.delegate.text_token_produced(
+                    text,
+                    raw,
+                    location,
+                    encoding,
+                    self.last_text_type,
+                    is_last,
+                )
             })
     }
 
-    pub fn flush_remaining_input(&mut self, input: &[u8], consumed_byte_count: usize) {
+    pub fn flush_remaining_input(
+        &mut self,
+        input: &[u8],
+        consumed_byte_count: usize,
+    ) -> Result<(), RewritingError> {
         self.delegate
-            .flush_remaining_input(input, consumed_byte_count);
+            .flush_remaining_input(input, consumed_byte_count)
     }
 
     pub fn finish(&mut self, input: &[u8]) -> Result<(), RewritingError> {
@@ -423,6 +653,8 @@ where
             .handle_start_tag(name, ns)
         {
             Ok(flags) => {
+                self.delegate.handle_deferred_element_events()?;
+
                 Ok(self.apply_capture_flags_from_hint_and_get_next_parser_directive(flags))
             }
             Err(DispatcherError::InfoRequest(aux_info_req)) => {
@@ -451,6 +683,8 @@ where
             flags |= TokenCaptureFlags::NEXT_END_TAG;
         }
 
+        self.delegate.handle_deferred_element_events()?;
+
         Ok(self.apply_capture_flags_from_hint_and_get_next_parser_directive(flags))
     }
 }
@@ -1,5 +1,7 @@
+mod deferred_elements;
 mod dispatcher;
 
+pub use self::deferred_elements::{DeferredElementEvent, DeferredOutput};
 use self::dispatcher::Dispatcher;
 pub use self::dispatcher::OutputSink;
 pub(crate) use self::dispatcher::{AuxStartTagInfo, DispatcherError};
@@ -54,6 +56,7 @@ where
             settings.transform_controller,
             settings.output_sink,
             settings.encoding,
+            SharedMemoryLimiter::clone(&settings.memory_limiter),
         );
 
         let buffer = Arena::new(
@@ -109,7 +112,7 @@ where
 
         self.parser
             .get_dispatcher()
-            .flush_remaining_input(chunk, consumed_byte_count);
+            .flush_remaining_input(chunk, consumed_byte_count)?;
 
         if consumed_byte_count < chunk.len() {
             self.buffer_blocked_bytes(data, consumed_byte_count)?;
@@ -120,6 +123,11 @@ where
         Ok(())
     }
 
+    #[inline]
+    pub(crate) fn transform_controller_mut(&mut self) -> &mut C {
+        self.parser.get_dispatcher().transform_controller_mut()
+    }
+
     pub fn end(&mut self) -> Result<(), RewritingError> {
         trace!(@end);
 
@@ -2,7 +2,7 @@ use crate::harness::suites::selectors_tests::{get_test_cases, TestCase};
 use crate::harness::TestFixture;
 use lol_html::html_content::ContentType;
 use lol_html::test_utils::Output;
-use lol_html::{comments, element, text, HtmlRewriter, Settings};
+use lol_html::{comments, element, text, HtmlRewriter, Selector, Settings};
 
 pub struct SelectorMatchingTests;
 
@@ -16,57 +16,79 @@ impl TestFixture<TestCase> for SelectorMatchingTests {
         let mut output = Output::new(encoding.into());
         let mut first_text_chunk_expected = true;
 
+        // NOTE: text and comment handlers are not supported for the selectors that are matched
+        // lazily (e.g. `li:last-child`), so only the element markers are expected for them.
+        let is_matched_lazily = test
+            .selector
+            .parse::<Selector>()
+            .unwrap()
+            .analyze()
+            .is_matched_lazily;
+
+        let mut expected = test.expected.clone();
+
+        if is_matched_lazily {
+            for marker in ["TEXT", "/TEXT", "COMMENT", "/COMMENT"] {
+                expected = expected.replace(&format!("<!--[{marker}('{}')]-->", test.selector), "");
+            }
+        }
+
         {
-            let mut rewriter = HtmlRewriter::new(
-                Settings {
-                    element_content_handlers: vec![
-                        element!(test.selector, |el| {
-                            el.before(
-                                &format!("<!--[ELEMENT('{}')]-->", test.selector),
+            let mut element_content_handlers = vec![element!(test.selector, |el| {
+                el.before(
+                    &format!("<!--[ELEMENT('{}')]-->", test.selector),
+                    ContentType::Html,
+                );
+
+                el.after(
+                    &format!("<!--[/ELEMENT('{}')]-->", test.selector),
+                    ContentType::Html,
+                );
+
+                Ok(())
+            })];
+
+            if !is_matched_lazily {
+                element_content_handlers.extend([
+                    comments!(test.selector, |c| {
+                        c.before(
+                            &format!("<!--[COMMENT('{}')]-->", test.selector),
+                            ContentType::Html,
+                        );
+                        c.after(
+                            &format!("<!--[/COMMENT('{}')]-->", test.selector),
+                            ContentType::Html,
+                        );
+
+                        Ok(())
+                    }),
+                    text!(test.selector, |t| {
+                        if first_text_chunk_expected {
+                            t.before(
+                                &format!("<!--[TEXT('{}')]-->", test.selector),
                                 ContentType::Html,
                             );
 
-                            el.after(
-                                &format!("<!--[/ELEMENT('{}')]-->", test.selector),
-                                ContentType::Html,
-                            );
+                            first_text_chunk_expected = false;
+                        }
 
-                            Ok(())
-                        }),
-                        comments!(test.selector, |c| {
-                            c.before(
-                                &format!("<!--[COMMENT('{}')]-->", test.selector),
-                                ContentType::Html,
-                            );
-                            c.after(
-                                &format!("<!--[/COMMENT('{}')]-->", test.selector),
+                        if t.last_in_text_node() {
+                            t.after(
+                                &format!("<!--[/TEXT('{}')]-->", test.selector),
                                 ContentType::Html,
                             );
 
-                            Ok(())
-                        }),
-                        text!(test.selector, |t| {
-                            if first_text_chunk_expected {
-                                t.before(
-                                    &format!("<!--[TEXT('{}')]-->", test.selector),
-                                    ContentType::Html,
-                                );
-
-                                first_text_chunk_expected = false;
-                            }
-
-                            if t.last_in_text_node() {
-                                t.after(
-                                    &format!("<!--[/TEXT('{}')]-->", test.selector),
-                                    ContentType::Html,
-                                );
-
-                                first_text_chunk_expected = true;
-                            }
-
-                            Ok(())
-                        }),
-                    ],
+                            first_text_chunk_expected = true;
+                        }
+
+                        Ok(())
+                    }),
+                ]);
+            }
+
+            let mut rewriter = HtmlRewriter::new(
+                Settings {
+                    element_content_handlers,
                     encoding,
                     ..Settings::new()
                 },
@@ -82,7 +104,7 @@ impl TestFixture<TestCase> for SelectorMatchingTests {
 
         let actual: String = output.into();
 
-        assert_eq!(actual, test.expected);
+        assert_eq!(actual, expected);
     }
 }
 